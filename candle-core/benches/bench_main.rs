@@ -8,4 +8,8 @@ criterion_main!(
     benchmarks::where_cond::benches,
     benchmarks::conv_transpose2d::benches,
     benchmarks::qmatmul::benches,
+    benchmarks::qcuda_load::benches,
+    benchmarks::qcuda_gather::benches,
+    benchmarks::qcuda_mmvq::benches,
+    benchmarks::qcuda_prefill::benches,
 );
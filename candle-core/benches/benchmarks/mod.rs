@@ -1,6 +1,10 @@
 pub(crate) mod affine;
 pub(crate) mod conv_transpose2d;
 pub(crate) mod matmul;
+pub(crate) mod qcuda_gather;
+pub(crate) mod qcuda_load;
+pub(crate) mod qcuda_mmvq;
+pub(crate) mod qcuda_prefill;
 pub(crate) mod qmatmul;
 pub(crate) mod random;
 pub(crate) mod where_cond;
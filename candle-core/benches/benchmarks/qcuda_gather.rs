@@ -0,0 +1,45 @@
+use criterion::{criterion_group, Criterion};
+
+#[cfg(feature = "cuda")]
+fn run_bench(c: &mut Criterion, device: &candle_core::CudaDevice) {
+    use candle_core::quantized::{cuda::QCudaStorage, GgmlDType};
+    use criterion::{black_box, Throughput};
+    use std::time::Instant;
+
+    // A llama sized token embedding table and a prefill worth of token ids.
+    let (nrows, ncols, n_ids) = (32000, 4096, 512);
+    let xs = QCudaStorage::zeros(device, nrows * ncols, GgmlDType::Q4_0).unwrap();
+    let emb = xs.to_embedding_layout(ncols).unwrap();
+    let ids: Vec<u32> = (0..n_ids).map(|i| (i * 7919 % nrows) as u32).collect();
+    let ids = device.htod_sync_copy(&ids).unwrap();
+
+    let mut group = c.benchmark_group("cuda_qgather_q4_0");
+    group.throughput(Throughput::Bytes((n_ids * ncols * 4) as u64));
+    for (name, storage) in [("standard", &xs), ("embedding", &emb)] {
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                let start = Instant::now();
+                for _i in 0..iters {
+                    storage
+                        .gather_rows(black_box(&ids.slice(..)), ncols)
+                        .unwrap();
+                }
+                device.synchronize().unwrap();
+                start.elapsed()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn criterion_benchmark(_c: &mut Criterion) {
+    #[cfg(feature = "cuda")]
+    {
+        let device = candle_core::Device::new_cuda(0).unwrap();
+        if let candle_core::Device::Cuda(device) = device {
+            run_bench(_c, &device)
+        }
+    }
+}
+
+criterion_group!(benches, criterion_benchmark);
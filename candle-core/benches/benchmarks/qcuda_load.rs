@@ -0,0 +1,34 @@
+use criterion::{criterion_group, Criterion};
+
+#[cfg(feature = "cuda")]
+fn run_bench(c: &mut Criterion, device: &candle_core::CudaDevice) {
+    use candle_core::quantized::{cuda, GgmlDType};
+    use criterion::{black_box, Throughput};
+
+    let dtype = GgmlDType::Q4_0;
+    let size_in_bytes = 64 * 1024 * 1024 / dtype.type_size() * dtype.type_size();
+    let data = vec![0u8; size_in_bytes];
+
+    let mut group = c.benchmark_group("cuda_qload");
+    group.throughput(Throughput::Bytes(size_in_bytes as u64));
+    group.bench_function("pageable", |b| {
+        b.iter(|| cuda::load_quantized_bytes(device, dtype, black_box(&data)).unwrap())
+    });
+    let mut loader = cuda::PinnedStagingLoader::new(device, 16 * 1024 * 1024).unwrap();
+    group.bench_function("pinned_staging", |b| {
+        b.iter(|| loader.load(dtype, black_box(&data)).unwrap())
+    });
+    group.finish();
+}
+
+fn criterion_benchmark(_c: &mut Criterion) {
+    #[cfg(feature = "cuda")]
+    {
+        let device = candle_core::Device::new_cuda(0).unwrap();
+        if let candle_core::Device::Cuda(device) = device {
+            run_bench(_c, &device)
+        }
+    }
+}
+
+criterion_group!(benches, criterion_benchmark);
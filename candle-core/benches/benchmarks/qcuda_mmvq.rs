@@ -0,0 +1,119 @@
+use criterion::{criterion_group, Criterion};
+
+#[cfg(feature = "cuda")]
+fn run_bench(c: &mut Criterion, device: &candle_core::Device) {
+    use crate::benchmarks::BenchDevice;
+    use candle_core::quantized::{cuda::QuantCudaConfig, GgmlDType, QMatMul, QTensor};
+    use candle_core::{Device, Module, Tensor};
+    use criterion::black_box;
+    use std::time::Instant;
+
+    let cuda_device = match device {
+        Device::Cuda(cuda_device) => cuda_device,
+        _ => return,
+    };
+    let (n, k) = (4096, 4096);
+    let lhs = Tensor::ones((1, k), candle_core::DType::F32, device).unwrap();
+    let rhs = Tensor::ones((n, k), candle_core::DType::F32, device).unwrap();
+    let qtensor = QTensor::quantize(&rhs, GgmlDType::Q4K).unwrap();
+    let matmul = QMatMul::from_qtensor(qtensor).unwrap();
+
+    let mut group = c.benchmark_group(device.bench_name("qmatmul_vec_q4k_nwarps"));
+    for nwarps in [2, 4, 8] {
+        let config = QuantCudaConfig {
+            mmvq_nwarps: nwarps,
+            ..Default::default()
+        };
+        QuantCudaConfig::set_for_device(cuda_device, config).unwrap();
+        group.bench_function(format!("nwarps_{nwarps}"), |b| {
+            b.iter_custom(|iters| {
+                let start = Instant::now();
+                for _i in 0..iters {
+                    matmul.forward(black_box(&lhs)).unwrap();
+                }
+                device.sync().unwrap();
+                start.elapsed()
+            })
+        });
+    }
+    QuantCudaConfig::set_for_device(cuda_device, QuantCudaConfig::default()).unwrap();
+    group.finish();
+
+    // The integer fast path on activations that take it and on ones that only pay for the check.
+    let lhs_frac = (lhs.clone() * 0.5).unwrap();
+    let mut group = c.benchmark_group(device.bench_name("qmatmul_vec_q4k_q8_1_int"));
+    for fast_path in [false, true] {
+        let config = QuantCudaConfig {
+            q8_1_integer_fast_path: fast_path,
+            ..Default::default()
+        };
+        QuantCudaConfig::set_for_device(cuda_device, config).unwrap();
+        for (name, lhs) in [("int", &lhs), ("frac", &lhs_frac)] {
+            group.bench_function(format!("{name}_fast_path_{fast_path}"), |b| {
+                b.iter_custom(|iters| {
+                    let start = Instant::now();
+                    for _i in 0..iters {
+                        matmul.forward(black_box(lhs)).unwrap();
+                    }
+                    device.sync().unwrap();
+                    start.elapsed()
+                })
+            });
+        }
+    }
+    QuantCudaConfig::set_for_device(cuda_device, QuantCudaConfig::default()).unwrap();
+    group.finish();
+
+    // Both kernels on each dtype, to pick the `MatMulVecDefaults` entries of a device.
+    let mut group = c.benchmark_group(device.bench_name("qmatmul_vec_dmmv_vs_q8_1"));
+    let dtypes = [
+        GgmlDType::Q4_0,
+        GgmlDType::Q4_1,
+        GgmlDType::Q5_0,
+        GgmlDType::Q5_1,
+        GgmlDType::Q8_0,
+        GgmlDType::Q2K,
+        GgmlDType::Q3K,
+        GgmlDType::Q4K,
+        GgmlDType::Q5K,
+        GgmlDType::Q6K,
+    ];
+    for k in [512, 1024, 2048, 4096, 8192, 16384] {
+        let lhs = Tensor::ones((1, k), candle_core::DType::F32, device).unwrap();
+        let rhs = Tensor::ones((k, k), candle_core::DType::F32, device).unwrap();
+        for dtype in dtypes {
+            let qtensor = QTensor::quantize(&rhs, dtype).unwrap();
+            let matmul = QMatMul::from_qtensor(qtensor).unwrap();
+            for force_dmmv in [false, true] {
+                let config = QuantCudaConfig {
+                    force_dmmv,
+                    ..Default::default()
+                };
+                QuantCudaConfig::set_for_device(cuda_device, config).unwrap();
+                let kernel = if force_dmmv { "dmmv" } else { "q8_1" };
+                group.bench_function(format!("{dtype:?}_{k}_{kernel}"), |b| {
+                    b.iter_custom(|iters| {
+                        let start = Instant::now();
+                        for _i in 0..iters {
+                            matmul.forward(black_box(&lhs)).unwrap();
+                        }
+                        device.sync().unwrap();
+                        start.elapsed()
+                    })
+                });
+            }
+        }
+    }
+    QuantCudaConfig::set_for_device(cuda_device, QuantCudaConfig::default()).unwrap();
+    group.finish();
+}
+
+fn criterion_benchmark(_c: &mut Criterion) {
+    #[cfg(feature = "cuda")]
+    {
+        let device = candle_core::Device::new_cuda(0).unwrap();
+        run_bench(_c, &device)
+    }
+}
+
+criterion_group!(benches, criterion_benchmark);
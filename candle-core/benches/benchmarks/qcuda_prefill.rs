@@ -0,0 +1,63 @@
+use criterion::{criterion_group, Criterion};
+
+#[cfg(feature = "cuda")]
+fn run_bench(c: &mut Criterion, device: &candle_core::CudaDevice) {
+    use candle_core::quantized::{cuda, GgmlDType};
+    use candle_core::{CudaStorage, Layout};
+    use criterion::black_box;
+    use std::time::Instant;
+
+    // A 4096 tokens prefill run by chunks of 512 tokens.
+    let dtype = GgmlDType::Q4K;
+    let (n, k, chunk, nchunks) = (4096, 4096, 512, 8);
+    let data = vec![0u8; n * k / dtype.block_size() * dtype.type_size()];
+    let weights = cuda::load_quantized_bytes(device, dtype, &data).unwrap();
+    let xs = device
+        .htod_sync_copy(&vec![1f32; chunk * nchunks * k])
+        .unwrap();
+    let xs = CudaStorage::wrap_cuda_slice(xs, device.clone());
+    let self_shape = candle_core::Shape::from((n, k));
+    let layouts: Vec<_> = (0..nchunks)
+        .map(|i| Layout::contiguous_with_offset((1, chunk, k), i * chunk * k))
+        .collect();
+
+    let mut group = c.benchmark_group("cuda_qmatmul_chunked_prefill_q4k");
+    group.bench_function("dequantize_per_chunk", |b| {
+        b.iter_custom(|iters| {
+            let start = Instant::now();
+            for _i in 0..iters {
+                for layout in layouts.iter() {
+                    weights.fwd(&self_shape, black_box(&xs), layout).unwrap();
+                }
+            }
+            device.synchronize().unwrap();
+            start.elapsed()
+        })
+    });
+    group.bench_function("dequantize_once", |b| {
+        b.iter_custom(|iters| {
+            let start = Instant::now();
+            for _i in 0..iters {
+                let dense = weights.dequantize_for_matmul(&self_shape).unwrap();
+                for layout in layouts.iter() {
+                    dense.fwd(black_box(&xs), layout).unwrap();
+                }
+            }
+            device.synchronize().unwrap();
+            start.elapsed()
+        })
+    });
+    group.finish();
+}
+
+fn criterion_benchmark(_c: &mut Criterion) {
+    #[cfg(feature = "cuda")]
+    {
+        let device = candle_core::Device::new_cuda(0).unwrap();
+        if let candle_core::Device::Cuda(device) = device {
+            run_bench(_c, &device)
+        }
+    }
+}
+
+criterion_group!(benches, criterion_benchmark);
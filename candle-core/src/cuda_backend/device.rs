@@ -55,6 +55,13 @@ impl CudaDevice {
         self.id
     }
 
+    /// The amount of free device memory in bytes, as reported by the driver.
+    pub fn available_memory(&self) -> Result<usize> {
+        self.device.bind_to_thread().w()?;
+        let (free, _total) = cudarc::driver::result::mem_get_info().w()?;
+        Ok(free)
+    }
+
     fn const_impl(&self, v: f64, shape: &Shape, dtype: DType) -> Result<CudaStorage> {
         let elem_count = shape.elem_count();
         let cfg = LaunchConfig::for_num_elems(elem_count as u32);
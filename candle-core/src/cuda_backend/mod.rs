@@ -1053,7 +1053,7 @@ impl CudaStorage {
     }
 }
 
-fn gemm_config<T>(
+pub(crate) fn gemm_config<T>(
     alpha: T,
     beta: T,
     (b, m, n, k): (usize, usize, usize, usize),
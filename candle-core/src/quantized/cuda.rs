@@ -1,456 +1,8705 @@
+use super::cuda_dequant_cache::DequantCache;
+use super::cuda_dispatch::{
+    ceil_div, check_matmul_data, data_elem_count, dequantize_clamped_launch,
+    dequantize_colmajor_launch, dequantize_energy_launch, dequantize_ftz_launch, dequantize_launch,
+    dequantize_scales_kernel, dequantize_stats_launch, dmmv_grid, dmmv_kernel_name, kernel_dim,
+    mmvq_batched_kernel, mmvq_moe_kernel, pad, q8_1_buffer_size, q8_1_row_padding,
+    DequantizeLaunch, MAX_GRID_DIM_X, MAX_GRID_DIM_Y,
+};
+pub use super::cuda_dispatch::{CUDA_DEQUANTIZE_BLOCK_SIZE, MATRIX_ROW_PADDING};
+use super::cuda_record::QuantRecorder;
 use super::{GgmlDType, QStorage};
 use crate::quantized::k_quants::GgmlType;
-use crate::{backend::BackendDevice, cuda_backend::WrapErr};
+use crate::{
+    backend::BackendDevice,
+    cuda_backend::{CudaDType, WrapErr},
+};
 use crate::{CudaDevice, CudaStorage, Result};
 
-use cudarc::driver::{CudaSlice, CudaView, DeviceSlice};
+use cudarc::driver::{CudaSlice, CudaView, DevicePtr, DeviceSlice};
+
+/// Output layout of [`QCudaStorage::dequantize_interleaved`] for `n` weights of shape
+/// `(nrows, ncols)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterleaveMode {
+    /// The weights are stored one after the other, `(n, nrows, ncols)`.
+    Stacked,
+    /// The rows of the weights are interleaved, `(nrows, n, ncols)`.
+    RowInterleaved,
+}
 
 #[derive(Clone, Debug)]
 pub struct QCudaStorage {
-    data: CudaSlice<u8>,
+    // Shared by the clones of the storage, see [`QCudaStorage::shares_data_with`].
+    data: std::sync::Arc<CudaSlice<u8>>,
     dtype: GgmlDType,
     device: CudaDevice,
+    // Rows stored with all their block scales first and then all their quants, this speeds up
+    // row gathers for token embeddings but is not supported by the dequantize/matmul kernels.
+    embedding_layout: bool,
+    // The shape declared for these weights, e.g. by the gguf file they were loaded from.
+    shape: Option<crate::Shape>,
+    // The weights of a `(n, k)` linear layer are stored as `(k, n)` rows, as done by some
+    // converters. Only the dequantizing matmul supports this layout.
+    transposed_in_file: bool,
+    // Kernel parameters picked by `autotune`, these override the device configuration in fwd.
+    tuned: Option<TunedKernels>,
+    // Rows kept in f16, substituted by dequantize and fwd, see [`ExceptionRows`].
+    exceptions: Option<std::sync::Arc<ExceptionRows>>,
+    // Accounts for `data` in `quant_vram_report`, shared along with it.
+    _vram: std::sync::Arc<VramTicket>,
 }
 
-static FORCE_DMMV: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
-
-pub fn set_force_dmmv(f: bool) {
-    FORCE_DMMV.store(f, std::sync::atomic::Ordering::Relaxed)
+/// Rows of the weights kept in f16 rather than quantized, typically the few outlier rows of a
+/// layer with a heavy tailed weight distribution that lose the most to quantization.
+///
+/// The exception set is stored on the device as two buffers:
+/// - `rows`, the indices of the exception rows as `u32` values, sorted and distinct,
+/// - `values`, their f16 values as a row major `(rows.len(), ncols)` matrix, its i-th row
+///   replacing row `rows[i]` of the weights.
+///
+/// The quantized blocks of these rows are kept as is so the bulk of the weights keeps its layout.
+/// [`QCudaStorage::dequantize`] overwrites the rows with their f16 values and
+/// [`QCudaStorage::fwd`] recomputes the matching output columns from them.
+#[derive(Debug)]
+pub struct ExceptionRows {
+    device: CudaDevice,
+    rows: CudaSlice<u32>,
+    values: CudaSlice<half::f16>,
+    num_rows: usize,
+    ncols: usize,
+    _vram: VramTicket,
 }
 
-pub const WARP_SIZE: usize = 32;
-pub const MMQ_X_Q4_0_AMPERE: usize = 4;
-pub const MMQ_Y_Q4_0_AMPERE: usize = 32;
-pub const NWARPS_Q4_0_AMPERE: usize = 4;
-pub const GGML_CUDA_MMV_X: usize = 32;
-pub const GGML_CUDA_MMV_Y: usize = 1;
-pub const CUDA_QUANTIZE_BLOCK_SIZE: usize = 256;
-pub const CUDA_DEQUANTIZE_BLOCK_SIZE: usize = 256;
-pub const MATRIX_ROW_PADDING: usize = 512;
+impl ExceptionRows {
+    /// Uploads the exception rows `rows` with their values, `rows.len() * ncols` f16 values.
+    pub fn new(
+        device: &CudaDevice,
+        rows: &[u32],
+        values: &[half::f16],
+        ncols: usize,
+    ) -> Result<Self> {
+        if ncols == 0 || values.len() != rows.len() * ncols {
+            crate::bail!(
+                "unexpected exception values size {} for {} rows of {ncols} values",
+                values.len(),
+                rows.len()
+            )
+        }
+        if rows.windows(2).any(|w| w[0] >= w[1]) {
+            crate::bail!("the exception rows are not sorted and distinct {rows:?}")
+        }
+        let bytes = std::mem::size_of_val(rows) + std::mem::size_of_val(values);
+        Ok(Self {
+            device: device.clone(),
+            rows: device.htod_sync_copy(rows).w()?,
+            values: device.htod_sync_copy(values).w()?,
+            num_rows: rows.len(),
+            ncols,
+            _vram: VramTicket::new(device, VramKind::Quantized, bytes),
+        })
+    }
 
-fn ceil_div(p: usize, q: usize) -> usize {
-    (p + q - 1) / q
-}
+    /// The number of exception rows.
+    pub fn num_rows(&self) -> usize {
+        self.num_rows
+    }
 
-fn pad(p: usize, q: usize) -> usize {
-    ceil_div(p, q) * q
-}
+    /// The number of values per row.
+    pub fn ncols(&self) -> usize {
+        self.ncols
+    }
 
-fn quantize_q8_1(
-    src: &CudaView<f32>,
-    dst: &mut CudaSlice<u8>,
-    elem_count: usize,
-    dev: &CudaDevice,
-) -> Result<()> {
-    use cudarc::driver::LaunchAsync;
+    /// The indices of the exception rows, copied to the host.
+    pub fn rows(&self) -> Result<Vec<u32>> {
+        self.device.dtoh_sync_copy(&self.rows).w()
+    }
 
-    let kx = elem_count;
-    let kx_padded = pad(kx, MATRIX_ROW_PADDING);
-    let num_blocks = ceil_div(kx_padded, CUDA_QUANTIZE_BLOCK_SIZE);
-    let func = dev.get_or_load_func("quantize_q8_1", candle_kernels::QUANTIZED)?;
-    let cfg = cudarc::driver::LaunchConfig {
-        grid_dim: (num_blocks as u32, 1, 1),
-        block_dim: (CUDA_QUANTIZE_BLOCK_SIZE as u32, 1, 1),
-        shared_mem_bytes: 0,
-    };
-    let params = (src, dst, kx as i32, kx_padded as i32);
-    unsafe { func.launch(cfg, params) }.w()?;
-    Ok(())
+    /// The f16 values of the exception rows, copied to the host.
+    pub fn values(&self) -> Result<Vec<half::f16>> {
+        self.device.dtoh_sync_copy(&self.values).w()
+    }
 }
 
-fn dequantize(
-    data: &CudaSlice<u8>,
-    dtype: GgmlDType,
-    elem_count: usize,
-    dev: &CudaDevice,
-) -> Result<CudaStorage> {
-    use cudarc::driver::LaunchAsync;
-
-    let nb = (elem_count + 255) / 256;
-    let (kernel_name, is_k, block_dim, num_blocks) = match dtype {
-        GgmlDType::Q4_0 => ("dequantize_block_q4_0", false, 32, nb),
-        GgmlDType::Q4_1 => ("dequantize_block_q4_1", false, 32, nb),
-        GgmlDType::Q5_0 => (
-            "dequantize_block_q5_0",
-            false,
-            CUDA_DEQUANTIZE_BLOCK_SIZE,
-            ceil_div(elem_count, 2 * CUDA_DEQUANTIZE_BLOCK_SIZE),
-        ),
-        GgmlDType::Q5_1 => (
-            "dequantize_block_q5_1",
-            false,
-            CUDA_DEQUANTIZE_BLOCK_SIZE,
-            ceil_div(elem_count, 2 * CUDA_DEQUANTIZE_BLOCK_SIZE),
-        ),
-        GgmlDType::Q8_0 => ("dequantize_block_q8_0", false, 32, nb),
-        GgmlDType::Q2K => ("dequantize_block_q2_K", true, 64, nb),
-        GgmlDType::Q3K => ("dequantize_block_q3_K", true, 64, nb),
-        GgmlDType::Q4K => ("dequantize_block_q4_K", true, 32, nb),
-        GgmlDType::Q5K => ("dequantize_block_q5_K", true, 64, nb),
-        GgmlDType::Q6K => ("dequantize_block_q6_K", true, 64, nb),
-        GgmlDType::Q8K => ("dequantize_block_q8_K", true, 32, nb),
-        _ => crate::bail!("unsupported dtype for dequantize {dtype:?}"),
-    };
-    let func = dev.get_or_load_func(kernel_name, candle_kernels::QUANTIZED)?;
-    let dst = unsafe { dev.alloc::<f32>(elem_count).w()? };
-    // See e.g.
-    // https://github.com/ggerganov/llama.cpp/blob/cbbd1efa06f8c09f9dff58ff9d9af509cc4c152b/ggml-cuda.cu#L7270
-    let cfg = cudarc::driver::LaunchConfig {
-        grid_dim: (num_blocks as u32, 1, 1),
-        block_dim: (block_dim as u32, 1, 1),
-        shared_mem_bytes: 0,
-    };
-
-    if is_k {
-        let params = (data, &dst);
-        unsafe { func.launch(cfg, params) }.w()?;
-    } else {
-        let nb32 = match dtype {
-            GgmlDType::Q5_0 | GgmlDType::Q5_1 => elem_count,
-            _ => elem_count / 32,
-        };
-        let params = (data, &dst, nb32 as i32);
-        unsafe { func.launch(cfg, params) }.w()?;
-    }
-    Ok(CudaStorage::wrap_cuda_slice(dst, dev.clone()))
+/// Tunables of the quantized cuda kernels. A configuration can be set per device with
+/// [`QuantCudaConfig::set_for_device`], devices without one use the default configuration set via
+/// [`QuantCudaConfig::set_default`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuantCudaConfig {
+    /// Always use the dmmv kernels, which dequantize the weights and use f32 activations, rather
+    /// than quantizing the activations to q8_1 for matmul-vec.
+    pub force_dmmv: bool,
+    /// Dequantize the weights to f16 rather than f32 in the non-vector matmul fallback and run the
+    /// gemm in f16. This is faster and uses less memory at the cost of some accuracy.
+    pub dequantize_matmul_f16: bool,
+    /// Let the f32 gemm of the non-vector matmul fallback use the TF32 tensor cores, this only
+    /// has an effect on Ampere or newer devices. TF32 keeps the f32 range but only 10 bits of
+    /// mantissa, which is usually fine for inference.
+    pub dequantize_matmul_tf32: bool,
+    /// Experimental: quantize the activations to 4 bits rather than q8_1 for q4_0 matmul-vec. This
+    /// halves the activation reads at the cost of accuracy. Contiguous non-vector activations are
+    /// also quantized to 4 bits rather than dequantizing the q4_0 weights.
+    pub experimental_q4_activation: bool,
+    /// When set, matmul-vec first computes the max-abs of the activation and uses the dmmv kernels
+    /// when it exceeds this threshold. This avoids losing precision on layers with large
+    /// activation outliers at the cost of an extra reduction and sync.
+    pub q8_1_overflow_threshold: Option<f32>,
+    /// Number of rows processed by each block of the dmmv kernels.
+    pub mmv_y: usize,
+    /// Rounding used when quantizing the activations to q8_1.
+    pub q8_1_rounding: Q8_1Rounding,
+    /// Store the q8_1 activation blocks whose values are all integers in `[-127, 127]` as is with
+    /// a scale of 1, e.g. for activations produced by a quantized op. This skips the max reduction
+    /// of these blocks and quantizes them exactly, other blocks are unaffected. The check itself
+    /// costs a warp vote on every block so this only pays off on such pipelines.
+    pub q8_1_integer_fast_path: bool,
+    /// Number of threads per block of the q8_1 activation quantize kernel, a multiple of the warp
+    /// size up to 1024. A warp quantizes a q8_1 block.
+    pub quantize_q8_1_block_size: usize,
+    /// When set, the non-vector matmul fallback checks the free device memory before
+    /// dequantizing the weights. If materializing them would leave less than this many bytes
+    /// free, the weights are dequantized and multiplied by chunks of rows instead.
+    pub dequantize_memory_headroom: Option<usize>,
+    /// When set, the non-vector matmul fallback always dequantizes the weights by slabs of at
+    /// most this many rows, i.e. output columns, and runs a gemm per slab. This bounds the
+    /// transient f32 weights to `n_chunk * k` values during prefill on wide layers, whatever the
+    /// free memory. With `dequantize_memory_headroom` also set, the smaller slab is used.
+    pub dequantize_matmul_n_chunk: Option<usize>,
+    /// Number of warps per block of the q8_1 matmul-vec kernels, between 1 and
+    /// [`MMVQ_MAX_NWARPS`]. Each warp handles a slice of the row blocks.
+    pub mmvq_nwarps: usize,
+    /// Overrides `mmvq_nwarps` for the k-quants, whose larger blocks can favor fewer warps.
+    pub mmvq_nwarps_k_quants: Option<usize>,
+    /// Flush the subnormal values of the f32 weights returned by [`QCudaStorage::dequantize`] and
+    /// [`QCudaStorage::dequantize_range`] to zero, keeping their sign. This matches downstream gemms running with flush-to-zero, which
+    /// would otherwise treat these weights differently than a reference. Off by default.
+    pub dequantize_ftz: bool,
+    /// Compatibility shim for code written against the cpu backend: a f32 activation on the cpu
+    /// passed to `QMatMul::forward` or `quantized_matmul` with weights on this device is uploaded
+    /// first and the result stays on the device. Off by default as this hides a host to device
+    /// copy on every call.
+    pub auto_upload_activations: bool,
+    /// Per dtype choice between the dmmv and q8_1 matmul-vec kernels, used when `force_dmmv` is
+    /// not set and no kernels were tuned for the weights.
+    pub mmv_defaults: MatMulVecDefaults,
 }
 
-fn dequantize_mul_mat_vec(
-    data: &CudaSlice<u8>,
-    y: &CudaView<f32>,
+/// Weights dequantized once by [`QCudaStorage::dequantize_for_matmul`]. These can be multiplied
+/// with multiple activations without dequantizing the weights again, at the cost of keeping a
+/// dense copy of them on the device until this is dropped.
+#[derive(Debug)]
+pub struct DequantizedWeights {
+    data: CudaStorage,
+    shape: crate::Shape,
     dtype: GgmlDType,
-    ncols: usize,
-    nrows: usize,
-    dev: &CudaDevice,
-) -> Result<CudaStorage> {
-    use cudarc::driver::LaunchAsync;
+    f16: bool,
+    transposed_in_file: bool,
+    _vram: VramTicket,
+}
 
-    let data_elems = data.len() / dtype.type_size() * dtype.block_size();
-    if data_elems < ncols * nrows {
-        crate::bail!("unexpected data size {}, ncols {ncols} {nrows}", data_elems)
+impl DequantizedWeights {
+    /// The `(n, k)` shape of the weights.
+    pub fn shape(&self) -> &crate::Shape {
+        &self.shape
     }
-    if y.len() != ncols {
-        crate::bail!("unexpected y size {}, ncols {ncols} {nrows}", y.len())
+
+    /// The quantized type the weights were dequantized from.
+    pub fn dtype(&self) -> GgmlDType {
+        self.dtype
     }
-    let kernel_name = match dtype {
-        GgmlDType::Q4_0 => "dequantize_mul_mat_vec_q4_0_cuda",
-        GgmlDType::Q4_1 => "dequantize_mul_mat_vec_q4_1_cuda",
-        GgmlDType::Q5_0 => "dequantize_mul_mat_vec_q5_0_cuda",
-        GgmlDType::Q5_1 => "dequantize_mul_mat_vec_q5_1_cuda",
-        GgmlDType::Q8_0 => "dequantize_mul_mat_vec_q8_0_cuda",
-        GgmlDType::Q2K => "dequantize_mul_mat_vec_q2_k",
-        GgmlDType::Q3K => "dequantize_mul_mat_vec_q3_k",
-        GgmlDType::Q4K => "dequantize_mul_mat_vec_q4_k",
-        GgmlDType::Q5K => "dequantize_mul_mat_vec_q5_k",
-        GgmlDType::Q6K => "dequantize_mul_mat_vec_q6_k",
-        _ => crate::bail!("unsupported dtype for quantized matmul {dtype:?}"),
-    };
-    let func = dev.get_or_load_func(kernel_name, candle_kernels::QUANTIZED)?;
-    let dst = unsafe { dev.alloc::<f32>(nrows).w()? };
-    let block_num_y = ceil_div(nrows, GGML_CUDA_MMV_Y);
-    let cfg = cudarc::driver::LaunchConfig {
-        grid_dim: (block_num_y as u32, 1, 1),
-        block_dim: (WARP_SIZE as u32, GGML_CUDA_MMV_Y as u32, 1),
-        shared_mem_bytes: 0,
-    };
 
-    let params = (data, y, &dst, ncols as i32, nrows as i32);
-    unsafe { func.launch(cfg, params) }.w()?;
-    Ok(CudaStorage::wrap_cuda_slice(dst, dev.clone()))
+    pub fn is_f16(&self) -> bool {
+        self.f16
+    }
+
+    /// Same as [`QCudaStorage::fwd`] with the dense gemm, for any activation shape.
+    pub fn fwd(
+        &self,
+        storage: &CudaStorage,
+        layout: &crate::Layout,
+    ) -> Result<(CudaStorage, crate::Shape)> {
+        use crate::backend::BackendStorage;
+
+        f32_activation(storage)?;
+        let (b, m, n, k) = dense_matmul_dims(&self.shape, layout)?;
+        let rhs_l = dense_rhs_layout(self.transposed_in_file, (b, n, k))?;
+        let config = QuantCudaConfig::for_device(storage.device());
+        dense_matmul(
+            &config,
+            &self.data,
+            self.f16,
+            storage,
+            layout,
+            (b, m, n, k),
+            &rhs_l,
+        )
+    }
 }
 
-fn mul_mat_vec_via_q8_1(
-    data: &CudaSlice<u8>,
-    y: &CudaView<f32>,
-    dtype: GgmlDType,
-    ncols: usize,
-    nrows: usize,
-    dev: &CudaDevice,
-) -> Result<CudaStorage> {
-    use cudarc::driver::LaunchAsync;
+/// Reconstruction error of a quantized tensor, see [`QCudaStorage::quantize_with_report`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuantReport {
+    pub elem_count: usize,
+    /// Mean squared error between the source and the dequantized values.
+    pub mse: f64,
+    pub max_abs_error: f32,
+    /// Root mean square of the source values, useful to put `mse` in scale.
+    pub src_rms: f64,
+}
 
-    let data_elems = data.len() / dtype.type_size() * dtype.block_size();
-    if data_elems < ncols * nrows {
-        crate::bail!("unexpected data size {}, ncols {ncols} {nrows}", data_elems)
+impl QuantReport {
+    fn new(src: &[f32], dequantized: &[f32]) -> Self {
+        let mut sq_err = 0f64;
+        let mut sq_src = 0f64;
+        let mut max_abs_error = 0f32;
+        for (s, d) in src.iter().zip(dequantized.iter()) {
+            let err = (s - d).abs();
+            max_abs_error = max_abs_error.max(err);
+            sq_err += (err as f64) * (err as f64);
+            sq_src += (*s as f64) * (*s as f64);
+        }
+        let n = src.len().max(1) as f64;
+        Self {
+            elem_count: src.len(),
+            mse: sq_err / n,
+            max_abs_error,
+            src_rms: (sq_src / n).sqrt(),
+        }
     }
-    if y.len() != ncols {
-        crate::bail!("unexpected y size {}, ncols {ncols} {nrows}", y.len())
+
+    /// The root mean squared error relative to the root mean square of the source, comparable
+    /// with [`GgmlDType::expected_rel_error`].
+    pub fn rel_rmse(&self) -> f64 {
+        if self.src_rms == 0. {
+            self.mse.sqrt()
+        } else {
+            self.mse.sqrt() / self.src_rms
+        }
     }
-    // Start by quantizing y
-    let ncols_padded = pad(ncols, MATRIX_ROW_PADDING);
-    let y_size_in_bytes = ncols_padded * GgmlDType::Q8_1.type_size() / GgmlDType::Q8_1.block_size();
-    let mut y_q8_1 = unsafe { dev.alloc::<u8>(y_size_in_bytes).w()? };
-    quantize_q8_1(y, &mut y_q8_1, ncols, dev)?;
-
-    let kernel_name = match dtype {
-        GgmlDType::Q4_0 => "mul_mat_vec_q4_0_q8_1_cuda",
-        GgmlDType::Q4_1 => "mul_mat_vec_q4_1_q8_1_cuda",
-        GgmlDType::Q5_0 => "mul_mat_vec_q5_0_q8_1_cuda",
-        GgmlDType::Q5_1 => "mul_mat_vec_q5_1_q8_1_cuda",
-        GgmlDType::Q8_0 => "mul_mat_vec_q8_0_q8_1_cuda",
-        GgmlDType::Q2K => "mul_mat_vec_q2_K_q8_1_cuda",
-        GgmlDType::Q3K => "mul_mat_vec_q3_K_q8_1_cuda",
-        GgmlDType::Q4K => "mul_mat_vec_q4_K_q8_1_cuda",
-        GgmlDType::Q5K => "mul_mat_vec_q5_K_q8_1_cuda",
-        GgmlDType::Q6K => "mul_mat_vec_q6_K_q8_1_cuda",
-        _ => crate::bail!("unsupported dtype for quantized matmul {dtype:?}"),
-    };
-    let func = dev.get_or_load_func(kernel_name, candle_kernels::QUANTIZED)?;
-    let dst = unsafe { dev.alloc::<f32>(nrows).w()? };
-    let cfg = cudarc::driver::LaunchConfig {
-        grid_dim: (nrows as u32, 1, 1),
-        block_dim: (WARP_SIZE as u32, 4, 1),
-        shared_mem_bytes: 0,
-    };
+}
 
-    let params = (
-        data,
-        &y_q8_1,
-        &dst,
-        /* ncols_x */ ncols as i32,
-        /* nrows_x */ nrows as i32,
-        /* nrows_y */ ncols as i32,
-        /* nrows_dst */ nrows as i32,
-    );
-    unsafe { func.launch(cfg, params) }.w()?;
-    Ok(CudaStorage::wrap_cuda_slice(dst, dev.clone()))
+/// The block layout of a quantized dtype and what the cuda kernels support for it, as returned by
+/// [`QCudaStorage::dtype_info`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DTypeInfo {
+    pub dtype: GgmlDType,
+    /// Number of weights per block.
+    pub block_size: usize,
+    /// Size of a block in bytes.
+    pub type_size: usize,
+    /// Storage cost of a weight including the block scales, e.g. 4.5 for q4_0.
+    pub bits_per_weight: f32,
+    pub is_k: bool,
+    /// Whether the weights are dequantized by a cuda kernel rather than on the host.
+    pub has_fast_dequantize: bool,
+    /// Whether matmul-vec can quantize the activation to q8_1, otherwise the dmmv kernel or the
+    /// dequantize fallback is used.
+    pub has_q8_1_mmvq: bool,
 }
 
-impl QCudaStorage {
-    pub fn zeros(device: &CudaDevice, el_count: usize, dtype: GgmlDType) -> Result<Self> {
-        let size_in_bytes = ceil_div(el_count, dtype.block_size()) * dtype.type_size();
-        let data = device.alloc_zeros::<u8>(size_in_bytes).w()?;
-        Ok(QCudaStorage {
-            data,
-            device: device.clone(),
+impl DTypeInfo {
+    pub fn new(dtype: GgmlDType) -> Self {
+        let (block_size, type_size) = (dtype.block_size(), dtype.type_size());
+        let has_fast_dequantize = dtype.dequant_kernel_name().is_some();
+        Self {
             dtype,
-        })
+            block_size,
+            type_size,
+            bits_per_weight: (type_size * 8) as f32 / block_size as f32,
+            is_k: dtype.is_k_quant(),
+            has_fast_dequantize,
+            has_q8_1_mmvq: dtype.q81_kernel_name().is_some(),
+        }
     }
+}
 
-    pub fn dtype(&self) -> GgmlDType {
-        self.dtype
-    }
+/// Summary of dequantized weights, see [`QCudaStorage::dequantize_with_stats`]. The NaN values
+/// are only counted, the other fields cover the remaining values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WeightStats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub abs_max: f32,
+    pub nan_count: usize,
+}
 
-    pub fn device(&self) -> &CudaDevice {
-        &self.device
+impl WeightStats {
+    fn from_values(vs: &[f32]) -> Self {
+        let mut stats = Self {
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
+            mean: 0.,
+            abs_max: 0.,
+            nan_count: 0,
+        };
+        let mut sum = 0f64;
+        for &v in vs {
+            if v.is_nan() {
+                stats.nan_count += 1;
+                continue;
+            }
+            stats.min = stats.min.min(v);
+            stats.max = stats.max.max(v);
+            stats.abs_max = stats.abs_max.max(v.abs());
+            sum += v as f64;
+        }
+        stats.mean = (sum / (vs.len() - stats.nan_count) as f64) as f32;
+        stats
     }
+}
 
-    pub fn dequantize(&self, elem_count: usize) -> Result<CudaStorage> {
-        fn deq<T: GgmlType>(buffer: &[u8], n: usize, dst: &mut [f32]) -> Result<()> {
-            let slice = unsafe { std::slice::from_raw_parts(buffer.as_ptr() as *const T, n) };
-            let vec = slice.to_vec();
-            T::to_float(&vec, dst)
-        }
-
-        let fast_kernel = matches!(
-            self.dtype,
-            GgmlDType::Q4_0
-                | GgmlDType::Q4_1
-                | GgmlDType::Q5_0
-                | GgmlDType::Q5_1
-                | GgmlDType::Q8_0
-                | GgmlDType::Q2K
-                | GgmlDType::Q3K
-                | GgmlDType::Q4K
-                | GgmlDType::Q5K
-                | GgmlDType::Q6K
-                | GgmlDType::Q8K
-        );
-        if fast_kernel {
-            return dequantize(&self.data, self.dtype, elem_count, self.device());
-        }
-        // Run the dequantization on cpu.
-
-        let buffer = self.device.dtoh_sync_copy(&self.data).w()?;
-        let mut out = vec![0.0; elem_count];
-        let block_len = elem_count / self.dtype.block_size();
-        match self.dtype {
-            GgmlDType::F32 => deq::<f32>(&buffer, block_len, &mut out)?,
-            GgmlDType::F16 => deq::<half::f16>(&buffer, block_len, &mut out)?,
-            GgmlDType::Q4_0 => deq::<crate::quantized::BlockQ4_0>(&buffer, block_len, &mut out)?,
-            GgmlDType::Q4_1 => deq::<crate::quantized::BlockQ4_1>(&buffer, block_len, &mut out)?,
-            GgmlDType::Q5_0 => deq::<crate::quantized::BlockQ5_0>(&buffer, block_len, &mut out)?,
-            GgmlDType::Q5_1 => deq::<crate::quantized::BlockQ5_1>(&buffer, block_len, &mut out)?,
-            GgmlDType::Q8_0 => deq::<crate::quantized::BlockQ8_0>(&buffer, block_len, &mut out)?,
-            GgmlDType::Q8_1 => deq::<crate::quantized::BlockQ8_1>(&buffer, block_len, &mut out)?,
-            GgmlDType::Q2K => deq::<crate::quantized::BlockQ2K>(&buffer, block_len, &mut out)?,
-            GgmlDType::Q3K => deq::<crate::quantized::BlockQ3K>(&buffer, block_len, &mut out)?,
-            GgmlDType::Q4K => deq::<crate::quantized::BlockQ4K>(&buffer, block_len, &mut out)?,
-            GgmlDType::Q5K => deq::<crate::quantized::BlockQ5K>(&buffer, block_len, &mut out)?,
-            GgmlDType::Q6K => deq::<crate::quantized::BlockQ6K>(&buffer, block_len, &mut out)?,
-            GgmlDType::Q8K => deq::<crate::quantized::BlockQ8K>(&buffer, block_len, &mut out)?,
-        }
-
-        self.device
-            .storage_from_cpu_storage(&crate::CpuStorage::F32(out))
+/// Rounding used when quantizing the activations to q8_1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Q8_1Rounding {
+    /// Round to nearest with ties away from zero, as done by llama.cpp.
+    #[default]
+    Nearest,
+    TowardZero,
+    /// Rounds up with a probability equal to the fractional part, the random numbers are derived
+    /// from `seed` and the value position so results are reproducible for a given seed.
+    Stochastic {
+        seed: u64,
+    },
+}
+
+impl QuantCudaConfig {
+    /// The configuration used until one is set with [`QuantCudaConfig::set_default`] or
+    /// [`QuantCudaConfig::set_for_device`].
+    pub const DEFAULT: Self = Self {
+        force_dmmv: false,
+        dequantize_matmul_f16: false,
+        dequantize_matmul_tf32: false,
+        experimental_q4_activation: false,
+        q8_1_overflow_threshold: None,
+        mmv_y: GGML_CUDA_MMV_Y,
+        q8_1_rounding: Q8_1Rounding::Nearest,
+        q8_1_integer_fast_path: false,
+        quantize_q8_1_block_size: CUDA_QUANTIZE_BLOCK_SIZE,
+        dequantize_memory_headroom: None,
+        dequantize_matmul_n_chunk: None,
+        mmvq_nwarps: MMVQ_NWARPS,
+        mmvq_nwarps_k_quants: None,
+        dequantize_ftz: false,
+        auto_upload_activations: false,
+        mmv_defaults: MatMulVecDefaults::ALWAYS_Q8_1,
+    };
+}
+
+impl Default for QuantCudaConfig {
+    fn default() -> Self {
+        Self::DEFAULT
     }
+}
 
-    pub fn quantize(&mut self, src: &CudaStorage) -> Result<()> {
-        // Run the quantization on cpu.
-        let src = match &src.slice {
-            crate::cuda_backend::CudaStorageSlice::F32(data) => {
-                self.device.dtoh_sync_copy(data).w()?
-            }
-            _ => crate::bail!("only f32 can be quantized"),
+/// The matmul path [`QCudaStorage::fwd`] takes for a given activation shape, as returned by
+/// [`QCudaStorage::explain_matmul`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatMulPlan {
+    /// A single vector input handled by a matmul-vec kernel. When `overflow_threshold` is set,
+    /// the dmmv kernel is used instead if the activation max-abs exceeds it at run time.
+    Vec {
+        kernel: MatMulVecKernel,
+        overflow_threshold: Option<f32>,
+    },
+    /// `batch` vector inputs, each handled by a matmul-vec kernel.
+    BatchedVec {
+        batch: usize,
+        kernel: MatMulVecKernel,
+        overflow_threshold: Option<f32>,
+    },
+    /// The weights are dequantized and multiplied with a gemm, in f16 if `f16` is set. When
+    /// `chunk_rows` is set, the weights are dequantized by chunks of that many rows as there is
+    /// not enough free device memory to materialize them.
+    Dequantize {
+        f16: bool,
+        chunk_rows: Option<usize>,
+    },
+    /// The `rows` activation rows are quantized to 4 bits and multiplied with the q4_0 weights,
+    /// see [`QuantCudaConfig::experimental_q4_activation`].
+    Q4Activation { rows: usize },
+}
+
+/// The kernel used by the matmul-vec path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatMulVecKernel {
+    /// Dequantizes the weights on the fly and uses the f32 activation.
+    Dmmv,
+    /// Quantizes the activation to q8_1.
+    Q8_1,
+    /// Quantizes the activation to 4 bits, see [`QuantCudaConfig::experimental_q4_activation`].
+    Q4Activation,
+}
+
+/// The device memory used by a quantized matmul, as returned by
+/// [`QCudaStorage::dequantize_matmul_plan`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MatMulFootprint {
+    /// The path taken by [`QCudaStorage::fwd`].
+    pub plan: MatMulPlan,
+    /// Bytes of the buffers only alive during the call, i.e. the quantized activations or the
+    /// dequantized weights, or chunk of weights, and the f16 copies of the dense path.
+    pub transient_bytes: usize,
+    /// Bytes of the f32 output.
+    pub output_bytes: usize,
+    /// Whether the transient and output buffers fit in the free device memory, minus the
+    /// `dequantize_memory_headroom` when set.
+    pub fits: bool,
+}
+
+/// Per dtype table of the matmul-vec kernel picked when neither [`QuantCudaConfig::force_dmmv`]
+/// nor [`QCudaStorage::autotune`] decides, each dtype uses dmmv up to a number of columns and
+/// q8_1 above it. Dtypes without an entry always use q8_1.
+///
+/// No thresholds are shipped, the default uses q8_1 for every dtype. The
+/// `qmatmul_vec_dmmv_vs_q8_1` benchmark in `candle-core/benches` times both kernels for each dtype
+/// and can be used to pick the entries for a given device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatMulVecDefaults {
+    // Largest ncols using dmmv, indexed by `MatMulVecDefaults::index`.
+    dmmv_max_ncols: [usize; 10],
+}
+
+impl MatMulVecDefaults {
+    /// The q8_1 kernels are used for every dtype, this is the default and what tuned weights
+    /// start from.
+    pub const ALWAYS_Q8_1: Self = Self {
+        dmmv_max_ncols: [0; 10],
+    };
+
+    fn index(dtype: GgmlDType) -> Option<usize> {
+        let index = match dtype {
+            GgmlDType::Q4_0 => 0,
+            GgmlDType::Q4_1 => 1,
+            GgmlDType::Q5_0 => 2,
+            GgmlDType::Q5_1 => 3,
+            GgmlDType::Q8_0 => 4,
+            GgmlDType::Q2K => 5,
+            GgmlDType::Q3K => 6,
+            GgmlDType::Q4K => 7,
+            GgmlDType::Q5K => 8,
+            GgmlDType::Q6K => 9,
+            _ => return None,
         };
-        let src_len = src.len();
-        let src = crate::Storage::Cpu(crate::CpuStorage::F32(src));
-        let mut qcpu_storage = crate::Device::Cpu.qzeros(src_len, self.dtype)?;
-        qcpu_storage.quantize(&src)?;
-        let data = qcpu_storage.data()?;
-        let data = self.device.htod_sync_copy(data.as_ref()).w()?;
-        self.data = data;
-        Ok(())
+        Some(index)
     }
 
-    pub fn storage_size_in_bytes(&self) -> usize {
-        self.data.len()
+    /// The largest number of columns for which `dtype` uses dmmv, 0 when it always uses q8_1.
+    pub fn dmmv_max_ncols(&self, dtype: GgmlDType) -> usize {
+        Self::index(dtype).map_or(0, |i| self.dmmv_max_ncols[i])
     }
 
-    pub fn fwd(
-        &self,
-        self_shape: &crate::Shape,
-        storage: &CudaStorage,
-        layout: &crate::Layout,
-    ) -> Result<(CudaStorage, crate::Shape)> {
-        if matches!(layout.shape().dims(), [1, 1, _] | [1, _]) {
-            self.dequantize_matmul_vec(self_shape, storage, layout)
-        } else {
-            self.dequantize_matmul(self_shape, storage, layout)
+    /// Overrides the entry of `dtype`, this fails for dtypes without both kernels.
+    pub fn with_dmmv_max_ncols(mut self, dtype: GgmlDType, max_ncols: usize) -> Result<Self> {
+        match Self::index(dtype) {
+            Some(i) => self.dmmv_max_ncols[i] = max_ncols,
+            None => crate::bail!("no matmul-vec default for {dtype:?}"),
         }
+        Ok(self)
+    }
+
+    /// Whether matmul-vec uses dmmv for `ncols` columns of `dtype` weights.
+    pub fn prefers_dmmv(&self, dtype: GgmlDType, ncols: usize) -> bool {
+        ncols <= self.dmmv_max_ncols(dtype)
     }
 }
 
-impl QCudaStorage {
-    fn dequantize_matmul_vec(
-        &self,
-        self_shape: &crate::Shape,
-        rhs: &CudaStorage,
-        rhs_l: &crate::Layout,
-    ) -> Result<(CudaStorage, crate::Shape)> {
-        let (nrows, ncols) = self_shape.dims2()?;
-        let rhs = rhs.as_cuda_slice::<f32>()?;
-        let rhs = match rhs_l.contiguous_offsets() {
-            Some((o1, o2)) => rhs.slice(o1..o2),
-            None => Err(crate::Error::RequiresContiguous { op: "dmmv" }.bt())?,
-        };
-        let (with_batch, k) = match rhs_l.shape().dims() {
-            [1, 1, k] => (true, k),
-            [1, k] => (false, k),
-            _ => crate::bail!("unexpected rhs shape in dmmv {:?}", rhs_l.shape()),
-        };
-        if ncols != *k {
-            crate::bail!("mismatch on matmul dim {self_shape:?} {:?}", rhs_l.shape())
-        }
+/// Matmul-vec kernel parameters picked for a storage by [`QCudaStorage::autotune`]. These take
+/// precedence over the configuration of the device when the storage runs a matmul.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunedKernels {
+    /// Use the dmmv kernel rather than quantizing the activation to q8_1.
+    pub force_dmmv: bool,
+    pub mmv_y: usize,
+    pub mmvq_nwarps: usize,
+}
 
-        let out = if FORCE_DMMV.load(std::sync::atomic::Ordering::Relaxed) {
-            dequantize_mul_mat_vec(&self.data, &rhs, self.dtype, ncols, nrows, self.device())?
-        } else {
-            mul_mat_vec_via_q8_1(&self.data, &rhs, self.dtype, ncols, nrows, self.device())?
-        };
-        let out_shape = if with_batch {
-            vec![1, 1, nrows]
-        } else {
-            vec![1, nrows]
-        };
-        Ok((out, out_shape.into()))
+impl TunedKernels {
+    fn apply(&self, config: QuantCudaConfig) -> QuantCudaConfig {
+        QuantCudaConfig {
+            force_dmmv: self.force_dmmv,
+            mmv_y: self.mmv_y,
+            mmvq_nwarps: self.mmvq_nwarps,
+            mmvq_nwarps_k_quants: None,
+            mmv_defaults: MatMulVecDefaults::ALWAYS_Q8_1,
+            ..config
+        }
     }
+}
 
-    fn dequantize_matmul(
-        &self,
-        self_shape: &crate::Shape,
-        storage: &CudaStorage,
-        layout: &crate::Layout,
-    ) -> Result<(CudaStorage, crate::Shape)> {
-        use crate::backend::BackendStorage;
-        let (n, k) = self_shape.dims2()?;
-        let (b, m, k2) = match layout.shape().dims() {
-            &[b, m, k2] => (b, m, k2),
-            &[m, k2] => (1, m, k2),
-            s => crate::bail!("unexpected shape for input {s:?}"),
-        };
-        if k2 != k {
-            crate::bail!("mismatch on matmul dim {self_shape:?} {:?}", layout.shape())
-        }
+thread_local! {
+    // The tuned kernels of the storage running a matmul on the current thread, and its device.
+    static TUNED: std::cell::Cell<Option<(crate::cuda_backend::DeviceId, TunedKernels)>> =
+        const { std::cell::Cell::new(None) };
+}
 
-        let data_f32 = self.dequantize(n * k)?;
-        let rhs_l = crate::Layout::new((k, n).into(), vec![1, k], 0).broadcast_as((b, k, n))?;
-        let out = storage.matmul(&data_f32, (b, m, n, k), layout, &rhs_l)?;
-        let mut out_shape = layout.shape().dims().to_vec();
-        out_shape.pop();
-        out_shape.push(n);
-        Ok((out, out_shape.into()))
+// Makes `QuantCudaConfig::for_device` apply the tuned kernels of a storage until dropped, the
+// previous ones are restored on drop.
+struct TunedScope {
+    previous: Option<(crate::cuda_backend::DeviceId, TunedKernels)>,
+}
+
+impl TunedScope {
+    fn enter(dev: &CudaDevice, tuned: Option<TunedKernels>) -> Self {
+        let previous = TUNED.with(|t| t.replace(tuned.map(|tuned| (dev.id(), tuned))));
+        Self { previous }
     }
 }
 
-pub fn load_quantized<T: super::GgmlType + Send + Sync + 'static>(
-    device: &CudaDevice,
-    data: &[T],
-) -> Result<super::QStorage> {
-    let data = unsafe {
-        std::slice::from_raw_parts(data.as_ptr() as *const u8, core::mem::size_of_val(data))
-    };
-    let data = device.htod_sync_copy(data).w()?;
-    Ok(QStorage::Cuda(QCudaStorage {
-        data,
-        device: device.clone(),
-        dtype: T::DTYPE,
-    }))
+impl Drop for TunedScope {
+    fn drop(&mut self) {
+        TUNED.with(|t| t.set(self.previous))
+    }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+/// Per call choice of the matmul-vec kernel, see [`QCudaStorage::fwd_with_policy`]. This takes
+/// precedence over the configuration of the device and the tuned kernels of the storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QMatMulPolicy {
+    /// Dequantize the weights on the fly and use the f32 activation, as with `force_dmmv`.
+    Dmmv,
+    /// Quantize the activation to q8_1, without the `q8_1_overflow_threshold` fallback to dmmv.
+    Q8_1,
+}
 
-    #[test]
-    fn cuda_quantize_q8_1() -> Result<()> {
-        let dev = CudaDevice::new(0)?;
-        let el = 256;
+impl QMatMulPolicy {
+    fn apply(&self, config: QuantCudaConfig) -> QuantCudaConfig {
+        match self {
+            Self::Dmmv => QuantCudaConfig {
+                force_dmmv: true,
+                ..config
+            },
+            Self::Q8_1 => QuantCudaConfig {
+                force_dmmv: false,
+                experimental_q4_activation: false,
+                q8_1_overflow_threshold: None,
+                mmv_defaults: MatMulVecDefaults::ALWAYS_Q8_1,
+                ..config
+            },
+        }
+    }
+}
+
+thread_local! {
+    // The policy of the `fwd_with_policy` call running on the current thread, and its device.
+    static POLICY: std::cell::Cell<Option<(crate::cuda_backend::DeviceId, QMatMulPolicy)>> =
+        const { std::cell::Cell::new(None) };
+}
+
+// Same as `TunedScope` for the policy of a `fwd_with_policy` call.
+struct PolicyScope {
+    previous: Option<(crate::cuda_backend::DeviceId, QMatMulPolicy)>,
+}
+
+impl PolicyScope {
+    fn enter(dev: &CudaDevice, policy: Option<QMatMulPolicy>) -> Self {
+        let previous = POLICY.with(|p| p.replace(policy.map(|policy| (dev.id(), policy))));
+        Self { previous }
+    }
+}
+
+impl Drop for PolicyScope {
+    fn drop(&mut self) {
+        POLICY.with(|p| p.set(self.previous))
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    // The configuration set by the test running on the current thread, and its device.
+    static CONFIG: std::cell::Cell<Option<(crate::cuda_backend::DeviceId, QuantCudaConfig)>> =
+        const { std::cell::Cell::new(None) };
+}
+
+// Same as `TunedScope` for the configuration of a test, this replaces the configuration set for
+// the device without changing it for the tests running on other threads.
+#[cfg(test)]
+struct ConfigScope {
+    previous: Option<(crate::cuda_backend::DeviceId, QuantCudaConfig)>,
+}
+
+#[cfg(test)]
+impl ConfigScope {
+    fn enter(dev: &CudaDevice, config: QuantCudaConfig) -> Result<Self> {
+        config.validate()?;
+        let previous = CONFIG.with(|c| c.replace(Some((dev.id(), config))));
+        Ok(Self { previous })
+    }
+}
+
+#[cfg(test)]
+impl Drop for ConfigScope {
+    fn drop(&mut self) {
+        CONFIG.with(|c| c.set(self.previous))
+    }
+}
+
+struct QuantCudaConfigs {
+    default: QuantCudaConfig,
+    per_device: Vec<(crate::cuda_backend::DeviceId, QuantCudaConfig)>,
+}
+
+static CONFIGS: std::sync::Mutex<QuantCudaConfigs> = std::sync::Mutex::new(QuantCudaConfigs {
+    default: QuantCudaConfig::DEFAULT,
+    per_device: Vec::new(),
+});
+
+impl QuantCudaConfig {
+    /// The configuration used by the kernels running on `dev`.
+    pub fn for_device(dev: &CudaDevice) -> Self {
+        let config = {
+            let configs = CONFIGS.lock().unwrap();
+            match configs.per_device.iter().find(|(id, _)| *id == dev.id()) {
+                Some((_, c)) => *c,
+                None => configs.default,
+            }
+        };
+        #[cfg(test)]
+        let config = match CONFIG.with(|c| c.get()) {
+            Some((id, test_config)) if id == dev.id() => test_config,
+            _ => config,
+        };
+        let config = match TUNED.with(|t| t.get()) {
+            Some((id, tuned)) if id == dev.id() => tuned.apply(config),
+            _ => config,
+        };
+        match POLICY.with(|p| p.get()) {
+            Some((id, policy)) if id == dev.id() => policy.apply(config),
+            _ => config,
+        }
+    }
+
+    /// Sets the configuration of `dev`, this takes precedence over the default configuration.
+    pub fn set_for_device(dev: &CudaDevice, config: Self) -> Result<()> {
+        config.validate()?;
+        let mut configs = CONFIGS.lock().unwrap();
+        match configs
+            .per_device
+            .iter_mut()
+            .find(|(id, _)| *id == dev.id())
+        {
+            Some((_, c)) => *c = config,
+            None => configs.per_device.push((dev.id(), config)),
+        }
+        Ok(())
+    }
+
+    /// Sets the configuration of the devices that do not have their own configuration.
+    pub fn set_default(config: Self) -> Result<()> {
+        config.validate()?;
+        CONFIGS.lock().unwrap().default = config;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.mmv_y == 0 || self.mmv_y * WARP_SIZE > 1024 {
+            crate::bail!("invalid mmv_y {}", self.mmv_y)
+        }
+        for nwarps in [Some(self.mmvq_nwarps), self.mmvq_nwarps_k_quants]
+            .into_iter()
+            .flatten()
+        {
+            if nwarps == 0 || nwarps > MMVQ_MAX_NWARPS {
+                crate::bail!("invalid mmvq nwarps {nwarps}, expected 1 to {MMVQ_MAX_NWARPS}")
+            }
+        }
+        let block_size = self.quantize_q8_1_block_size;
+        if block_size == 0 || block_size % WARP_SIZE != 0 || block_size > 1024 {
+            crate::bail!("invalid quantize_q8_1 block size {block_size}")
+        }
+        if self.dequantize_matmul_n_chunk == Some(0) {
+            crate::bail!("invalid dequantize matmul n chunk 0")
+        }
+        Ok(())
+    }
+
+    /// The number of warps per block used by the q8_1 matmul-vec kernel for `dtype`.
+    pub fn mmvq_nwarps(&self, dtype: GgmlDType) -> usize {
+        match self.mmvq_nwarps_k_quants {
+            Some(nwarps) if dtype.is_k_quant() => nwarps,
+            _ => self.mmvq_nwarps,
+        }
+    }
+
+    // Applies `f` to the default and the per device configurations, this is used by the global
+    // setters which predate per device configurations.
+    fn update_all<F: Fn(&mut Self)>(f: F) {
+        let mut configs = CONFIGS.lock().unwrap();
+        f(&mut configs.default);
+        for (_, c) in configs.per_device.iter_mut() {
+            f(c)
+        }
+    }
+}
+
+#[deprecated(note = "use QuantCudaConfig::force_dmmv instead")]
+pub fn set_force_dmmv(f: bool) {
+    QuantCudaConfig::update_all(|c| c.force_dmmv = f)
+}
+
+#[deprecated(note = "use QuantCudaConfig::dequantize_matmul_f16 instead")]
+pub fn set_dequantize_matmul_f16(f: bool) {
+    QuantCudaConfig::update_all(|c| c.dequantize_matmul_f16 = f)
+}
+
+#[deprecated(note = "use QuantCudaConfig::experimental_q4_activation instead")]
+pub fn set_experimental_q4_activation(f: bool) {
+    QuantCudaConfig::update_all(|c| c.experimental_q4_activation = f)
+}
+
+#[deprecated(note = "use QuantCudaConfig::q8_1_overflow_threshold instead")]
+pub fn set_q8_1_overflow_threshold(threshold: Option<f32>) {
+    QuantCudaConfig::update_all(|c| c.q8_1_overflow_threshold = threshold)
+}
+
+/// Size in bytes of a block of 32 int4 activations, a f16 scale followed by 16 bytes of nibbles.
+const Q4_ACT_TYPE_SIZE: usize = 18;
+
+pub const WARP_SIZE: usize = 32;
+pub const GGML_CUDA_MMV_X: usize = 32;
+pub const GGML_CUDA_MMV_Y: usize = 1;
+pub const CUDA_QUANTIZE_BLOCK_SIZE: usize = 256;
+pub const MMVQ_NWARPS: usize = 4;
+pub const MMVQ_MAX_NWARPS: usize = 8;
+/// Largest batch of `(b, 1, k)` vectors handled by the matmul-vec kernels rather than
+/// dequantizing the weights for a dense matmul. The q8_1 kernels cover the whole batch in a
+/// single launch, dmmv runs once per vector.
+pub const MAX_BATCHED_VEC: usize = 8;
+
+/// The device properties the quantized kernels depend on, resolved once per device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuantCudaCaps {
+    pub compute_capability: (usize, usize),
+    pub warp_size: usize,
+}
+
+impl QuantCudaCaps {
+    /// Returns the capabilities of `dev`, these are only queried once per device. The kernels are
+    /// compiled for warps of `WARP_SIZE` threads so this fails on devices with another warp size,
+    /// this is checked when a storage is created rather than on each launch.
+    pub fn for_device(dev: &CudaDevice) -> Result<Self> {
+        use cudarc::driver::sys::CUdevice_attribute as A;
+        static CAPS: std::sync::Mutex<Vec<(crate::cuda_backend::DeviceId, QuantCudaCaps)>> =
+            std::sync::Mutex::new(Vec::new());
+
+        let mut caps = CAPS.lock().unwrap();
+        if let Some((_, c)) = caps.iter().find(|(id, _)| *id == dev.id()) {
+            return Ok(*c);
+        }
+        let major = dev
+            .attribute(A::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR)
+            .w()?;
+        let minor = dev
+            .attribute(A::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR)
+            .w()?;
+        let warp_size = dev.attribute(A::CU_DEVICE_ATTRIBUTE_WARP_SIZE).w()? as usize;
+        if warp_size != WARP_SIZE {
+            crate::bail!(
+                "the quantized kernels require a warp size of {WARP_SIZE}, the device uses {warp_size}"
+            )
+        }
+        let c = Self {
+            compute_capability: (major as usize, minor as usize),
+            warp_size,
+        };
+        caps.push((dev.id(), c));
+        Ok(c)
+    }
+}
+
+/// Suffix of the safetensors metadata key storing the ggml dtype of a quantized tensor.
+///
+/// A quantized tensor `name` is stored as a flat `u8` tensor containing the raw ggml blocks. The
+/// file metadata then holds `{name}.ggml_dtype`, the ggml type id as used in gguf files (e.g. `2`
+/// for q4_0), and `{name}.elem_count`, the number of elements once dequantized.
+pub const SAFETENSORS_GGML_DTYPE_SUFFIX: &str = ".ggml_dtype";
+/// Suffix of the safetensors metadata key storing the element count of a quantized tensor.
+pub const SAFETENSORS_ELEM_COUNT_SUFFIX: &str = ".elem_count";
+/// Suffix of the optional `u32` tensor holding the indices of the rows of a quantized tensor kept
+/// in f16, see [`ExceptionRows`].
+pub const SAFETENSORS_EXCEPTION_ROWS_SUFFIX: &str = ".exception_rows";
+/// Suffix of the optional f16 tensor holding the values of the exception rows, with shape
+/// `(num_rows, ncols)`.
+pub const SAFETENSORS_EXCEPTION_VALUES_SUFFIX: &str = ".exception_values";
+
+/// Blocks until all the kernels queued on `device` have completed.
+pub fn sync(device: &CudaDevice) -> Result<()> {
+    device.synchronize()
+}
+
+/// The amount of free memory on `device` in bytes.
+pub fn available_memory(device: &CudaDevice) -> Result<usize> {
+    device.available_memory()
+}
+
+/// Device memory held by quantized weights and by the buffers derived from them, see
+/// [`quant_vram_report`] and [`QCudaStorage::memory_layout_report`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VramReport {
+    /// Bytes of the quantized weights, including the ones resident in a [`LazyQCudaPool`].
+    pub quantized_bytes: usize,
+    /// Bytes of the dense weights kept alive by [`DequantizedWeights`].
+    pub dequantized_bytes: usize,
+    /// Bytes of the activations held by the [`Q81Cache`] of the device on the current thread.
+    pub q8_1_cache_bytes: usize,
+    /// Sum of the budgets of the lazy pools, only set by [`QCudaStorage::memory_layout_report`].
+    pub lazy_pool_capacity_bytes: usize,
+    /// Free device memory as reported by the driver, this accounts for all the allocations of all
+    /// the processes using the device.
+    pub free_bytes: usize,
+    /// Total device memory.
+    pub total_bytes: usize,
+}
+
+impl VramReport {
+    /// The bytes of the weights and caches accounted for in this report.
+    pub fn accounted_bytes(&self) -> usize {
+        self.quantized_bytes + self.dequantized_bytes + self.q8_1_cache_bytes
+    }
+
+    // Fills the fields shared by all the reports of `device`.
+    fn for_device(device: &CudaDevice) -> Result<Self> {
+        device.bind_to_thread().w()?;
+        let (free_bytes, total_bytes) = cudarc::driver::result::mem_get_info().w()?;
+        Ok(Self {
+            quantized_bytes: 0,
+            dequantized_bytes: VramTicket::live_bytes(device, VramKind::Dequantized),
+            q8_1_cache_bytes: Q81Cache::size_in_bytes(device),
+            lazy_pool_capacity_bytes: 0,
+            free_bytes,
+            total_bytes,
+        })
+    }
+}
+
+/// The device memory used by all the live quantized weights of `device` and by the dense weights
+/// and q8_1 activations cached from them. The q8_1 cache is thread local, only the cache of the
+/// current thread is accounted for.
+pub fn quant_vram_report(device: &CudaDevice) -> Result<VramReport> {
+    Ok(VramReport {
+        quantized_bytes: VramTicket::live_bytes(device, VramKind::Quantized),
+        ..VramReport::for_device(device)?
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VramKind {
+    Quantized,
+    Dequantized,
+}
+
+// Live bytes per device and kind, updated by the `VramTicket`s.
+static LIVE_BYTES: std::sync::Mutex<Vec<(crate::cuda_backend::DeviceId, VramKind, usize)>> =
+    std::sync::Mutex::new(Vec::new());
+
+// Accounts for a device buffer in `quant_vram_report` while it is alive.
+#[derive(Debug)]
+struct VramTicket {
+    device_id: crate::cuda_backend::DeviceId,
+    kind: VramKind,
+    bytes: usize,
+}
+
+impl VramTicket {
+    fn new(device: &CudaDevice, kind: VramKind, bytes: usize) -> Self {
+        let device_id = device.id();
+        let mut live = LIVE_BYTES.lock().unwrap();
+        match live
+            .iter_mut()
+            .find(|(id, k, _)| *id == device_id && *k == kind)
+        {
+            Some((_, _, total)) => *total += bytes,
+            None => live.push((device_id, kind, bytes)),
+        }
+        Self {
+            device_id,
+            kind,
+            bytes,
+        }
+    }
+
+    fn live_bytes(device: &CudaDevice, kind: VramKind) -> usize {
+        LIVE_BYTES
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(id, k, _)| *id == device.id() && *k == kind)
+            .map_or(0, |(_, _, total)| *total)
+    }
+}
+
+impl Drop for VramTicket {
+    fn drop(&mut self) {
+        let mut live = LIVE_BYTES.lock().unwrap();
+        if let Some((_, _, total)) = live
+            .iter_mut()
+            .find(|(id, k, _)| *id == self.device_id && *k == self.kind)
+        {
+            *total -= self.bytes
+        }
+    }
+}
+
+#[cfg(feature = "quant-trace")]
+use super::cuda_trace::LaunchScope;
+
+// No-op stand-in for the tracer scope when the `quant-trace` feature is disabled.
+#[cfg(not(feature = "quant-trace"))]
+struct LaunchScope;
+
+#[cfg(not(feature = "quant-trace"))]
+impl LaunchScope {
+    fn end(self, _dev: &CudaDevice) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Starts recording a kernel launch with the [`super::cuda_trace::QuantTracer`] when enabled.
+fn trace_launch(dev: &CudaDevice, name: &str, dtype: GgmlDType) -> Result<LaunchScope> {
+    #[cfg(feature = "quant-trace")]
+    {
+        super::cuda_trace::QuantTracer::begin(dev, name, dtype)
+    }
+    #[cfg(not(feature = "quant-trace"))]
+    {
+        let _ = (dev, name, dtype);
+        Ok(LaunchScope)
+    }
+}
+
+// Launches `func` on the stream of `dev`, or on the capture stream while a
+// [`super::cuda_graph::QuantCudaGraph`] is being captured for `dev` on this thread.
+unsafe fn launch<P>(
+    func: cudarc::driver::CudaFunction,
+    dev: &CudaDevice,
+    cfg: cudarc::driver::LaunchConfig,
+    params: P,
+) -> Result<()>
+where
+    cudarc::driver::CudaFunction: cudarc::driver::LaunchAsync<P>,
+{
+    use cudarc::driver::LaunchAsync;
+
+    match super::cuda_graph::capture_stream(dev) {
+        Some(stream) => func.launch_on_stream(&stream, cfg, params).w(),
+        None => func.launch(cfg, params).w(),
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    // Number of weight dequantizations on the current thread, tests use it to check that a path
+    // does not dequantize.
+    static DEQUANTIZE_CALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn count_dequantize() {
+    #[cfg(test)]
+    DEQUANTIZE_CALLS.with(|c| c.set(c.get() + 1))
+}
+
+// The f32 gemm of `lhs` with `rhs`, same as `CudaStorage::matmul` but using the TF32 compute type
+// when `tf32` is set. The compute type is passed to this gemm only, the math mode of the cublas
+// handle shared by all the users of the device is left untouched.
+fn f32_matmul(
+    lhs: &CudaStorage,
+    rhs: &CudaStorage,
+    (b, m, n, k): (usize, usize, usize, usize),
+    lhs_l: &crate::Layout,
+    rhs_l: &crate::Layout,
+    tf32: bool,
+) -> Result<CudaStorage> {
+    use crate::backend::BackendStorage;
+    use cudarc::cublas::{result, sys};
+    use cudarc::driver::DevicePtrMut;
+
+    if !tf32 {
+        return lhs.matmul(rhs, (b, m, n, k), lhs_l, rhs_l);
+    }
+    let dev = lhs.device();
+    let cfg = crate::cuda_backend::gemm_config(1f32, 0f32, (b, m, n, k), lhs_l, rhs_l)?;
+    let lhs = lhs.as_cuda_slice::<f32>()?.slice(lhs_l.start_offset()..);
+    let rhs = rhs.as_cuda_slice::<f32>()?.slice(rhs_l.start_offset()..);
+    let mut out = unsafe { dev.alloc::<f32>(b * m * n).w()? };
+    let gemm = &cfg.gemm;
+    unsafe {
+        result::gemm_strided_batched_ex(
+            *dev.blas.handle(),
+            gemm.transa,
+            gemm.transb,
+            gemm.m,
+            gemm.n,
+            gemm.k,
+            &gemm.alpha as *const f32 as *const _,
+            *rhs.device_ptr() as *const _,
+            sys::cudaDataType_t::CUDA_R_32F,
+            gemm.lda,
+            cfg.stride_a,
+            *lhs.device_ptr() as *const _,
+            sys::cudaDataType_t::CUDA_R_32F,
+            gemm.ldb,
+            cfg.stride_b,
+            &gemm.beta as *const f32 as *const _,
+            *out.device_ptr_mut() as *mut _,
+            sys::cudaDataType_t::CUDA_R_32F,
+            gemm.ldc,
+            cfg.stride_c,
+            cfg.batch_size,
+            sys::cublasComputeType_t::CUBLAS_COMPUTE_32F_FAST_TF32,
+            sys::cublasGemmAlgo_t::CUBLAS_GEMM_DEFAULT_TENSOR_OP,
+        )
+        .w()?
+    };
+    Ok(CudaStorage::wrap_cuda_slice(out, dev.clone()))
+}
+
+// The (b, m, n, k) dimensions of the product of an activation with `(n, k)` weights.
+fn dense_matmul_dims(
+    self_shape: &crate::Shape,
+    layout: &crate::Layout,
+) -> Result<(usize, usize, usize, usize)> {
+    let (n, k) = self_shape.dims2()?;
+    let (b, m, k2) = match layout.shape().dims() {
+        &[b, m, k2] => (b, m, k2),
+        &[m, k2] => (1, m, k2),
+        s => crate::bail!("unexpected shape for input {s:?}"),
+    };
+    if k2 != k {
+        crate::bail!("mismatch on matmul dim {self_shape:?} {:?}", layout.shape())
+    }
+    Ok((b, m, n, k))
+}
+
+// The (b, k, n) rhs layout of the gemm on the dequantized weights. These are stored as a row
+// major (n, k) matrix, the rhs is a transposed view of it that is shared by all the batch
+// elements (stride 0 on b). Weights stored transposed in the file already form a (k, n) matrix.
+fn dense_rhs_layout(
+    transposed_in_file: bool,
+    (b, n, k): (usize, usize, usize),
+) -> Result<crate::Layout> {
+    let rhs_l = if transposed_in_file {
+        crate::Layout::contiguous((k, n))
+    } else {
+        crate::Layout::new((k, n).into(), vec![1, k], 0)
+    };
+    rhs_l.broadcast_as((b, k, n))
+}
+
+// Multiplies the activation with the dequantized weights `data`, f16 if `f16` is set.
+fn dense_matmul(
+    config: &QuantCudaConfig,
+    data: &CudaStorage,
+    f16: bool,
+    storage: &CudaStorage,
+    layout: &crate::Layout,
+    (b, m, n, k): (usize, usize, usize, usize),
+    rhs_l: &crate::Layout,
+) -> Result<(CudaStorage, crate::Shape)> {
+    use crate::backend::BackendStorage;
+
+    // The activation layout is passed as is to the gemm which takes care of its start offset.
+    let out = if f16 {
+        let lhs_l = crate::Layout::contiguous(layout.shape());
+        let lhs = storage.to_dtype(layout, crate::DType::F16)?;
+        let out = lhs.matmul(data, (b, m, n, k), &lhs_l, rhs_l)?;
+        out.to_dtype(&crate::Layout::contiguous((b, m, n)), crate::DType::F32)?
+    } else {
+        f32_matmul(
+            storage,
+            data,
+            (b, m, n, k),
+            layout,
+            rhs_l,
+            config.dequantize_matmul_tf32,
+        )?
+    };
+    // The gemm always writes a contiguous (b, m, n) output, even for strided activations, so
+    // the output shape only has to swap the last dimension.
+    let mut out_shape = layout.shape().dims().to_vec();
+    out_shape.pop();
+    out_shape.push(n);
+    Ok((out, out_shape.into()))
+}
+
+// Quantizes `elem_count` values to q8_1, the padding up to a multiple of `row_padding` values is
+// filled with zeros.
+fn quantize_q8_1(
+    src: &CudaView<f32>,
+    dst: &mut CudaSlice<u8>,
+    elem_count: usize,
+    row_padding: usize,
+    rounding: Q8_1Rounding,
+    dev: &CudaDevice,
+) -> Result<()> {
+    quantize_q8_1_rows(src, dst, elem_count, 1, row_padding, rounding, dev)
+}
+
+// Quantizes `nrows` contiguous rows of `elem_count` values, each row is padded separately so
+// that the q8_1 rows are stored back to back with `pad(elem_count, row_padding)` values each.
+#[allow(clippy::too_many_arguments)]
+fn quantize_q8_1_rows(
+    src: &CudaView<f32>,
+    dst: &mut CudaSlice<u8>,
+    elem_count: usize,
+    nrows: usize,
+    row_padding: usize,
+    rounding: Q8_1Rounding,
+    dev: &CudaDevice,
+) -> Result<()> {
+    let config = QuantCudaConfig::for_device(dev);
+    let block_size = config.quantize_q8_1_block_size;
+    let kx = elem_count;
+    let kx_padded = pad(kx, row_padding);
+    // kx_padded fits in an i32 so the blocks always fit in the x dimension of the grid.
+    let num_blocks = ceil_div(kx_padded, block_size);
+    let (kx, kx_padded) = (kernel_dim(kx, "kx")?, kernel_dim(kx_padded, "kx_padded")?);
+    if row_padding % GgmlDType::Q8_1.block_size() != 0 {
+        crate::bail!("quantize_q8_1: row padding {row_padding} is not a multiple of the block size")
+    }
+    if dst.len() < nrows * q8_1_buffer_size(elem_count, row_padding) {
+        crate::bail!(
+            "quantize_q8_1: dst size {} too small for {nrows} rows of {elem_count} values",
+            dst.len()
+        )
+    }
+    kernel_dim(nrows, "nrows")?;
+    let (rounding, seed) = match rounding {
+        Q8_1Rounding::Nearest => (0i32, 0u64),
+        Q8_1Rounding::TowardZero => (1, 0),
+        Q8_1Rounding::Stochastic { seed } => (2, seed),
+    };
+    let int_fast_path = config.q8_1_integer_fast_path as i32;
+    // A launch per MAX_GRID_DIM_Y rows, e.g. for the tokens of a long prompt.
+    for row_offset in (0..nrows).step_by(MAX_GRID_DIM_Y) {
+        let func = dev.get_or_load_func("quantize_q8_1", candle_kernels::QUANTIZED)?;
+        let cfg = cudarc::driver::LaunchConfig {
+            grid_dim: (
+                num_blocks as u32,
+                (nrows - row_offset).min(MAX_GRID_DIM_Y) as u32,
+                1,
+            ),
+            block_dim: (block_size as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let params = (
+            src,
+            &mut *dst,
+            kx,
+            kx_padded,
+            rounding,
+            seed,
+            int_fast_path,
+            row_offset as i32,
+        );
+        let scope = trace_launch(dev, "quantize_q8_1", GgmlDType::Q8_1)?;
+        unsafe { launch(func, dev, cfg, params) }?;
+        scope.end(dev)?;
+    }
+    Ok(())
+}
+
+fn max_abs(src: &CudaView<f32>, dev: &CudaDevice) -> Result<f32> {
+    super::cuda_graph::check_not_capturing(dev, "the q8_1 overflow check")?;
+    let num_blocks = ceil_div(src.len(), CUDA_QUANTIZE_BLOCK_SIZE);
+    let func = dev.get_or_load_func("max_abs_f32", candle_kernels::QUANTIZED)?;
+    let dst = dev.alloc_zeros::<f32>(1).w()?;
+    let cfg = cudarc::driver::LaunchConfig {
+        grid_dim: (num_blocks as u32, 1, 1),
+        block_dim: (CUDA_QUANTIZE_BLOCK_SIZE as u32, 1, 1),
+        shared_mem_bytes: 0,
+    };
+    let params = (src, &dst, src.len() as i32);
+    unsafe { launch(func, dev, cfg, params) }?;
+    let dst = dev.dtoh_sync_copy(&dst).w()?;
+    Ok(dst[0])
+}
+
+/// Diagnostic helper returning the q8_1 blocks produced by the matmul-vec activation quantization
+/// of `y`, using the rounding configured for `dev`. This is meant to diff the exact bytes against
+/// other runtimes, only the blocks covering `y` are returned and not the row padding.
+///
+/// Each 36 bytes block holds its f16 scale and the f16 sum of its values followed by the 32 int8
+/// quants. This is the only q8_1 layout, the quantize kernel and all the q8_1 matmul kernels use
+/// f16 scales so there is no scale precision to select or to keep in sync between them.
+pub fn debug_quantize_q8_1(dev: &CudaDevice, y: &[f32]) -> Result<Vec<u8>> {
+    let dtype = GgmlDType::Q8_1;
+    let y_padded = pad(y.len(), MATRIX_ROW_PADDING);
+    let mut y_q8_1 = dev
+        .alloc_zeros::<u8>(y_padded / dtype.block_size() * dtype.type_size())
+        .w()?;
+    let y_dev = dev.htod_sync_copy(y).w()?;
+    let rounding = QuantCudaConfig::for_device(dev).q8_1_rounding;
+    let y_dev = y_dev.slice(..);
+    quantize_q8_1(
+        &y_dev,
+        &mut y_q8_1,
+        y.len(),
+        MATRIX_ROW_PADDING,
+        rounding,
+        dev,
+    )?;
+    let mut bytes = dev.dtoh_sync_copy(&y_q8_1).w()?;
+    bytes.truncate(ceil_div(y.len(), dtype.block_size()) * dtype.type_size());
+    Ok(bytes)
+}
+
+// Copies `src` from `src_dev` to `dst_dev` with a peer copy queued on the stream of `src_dev`.
+fn copy_to_device(
+    src: &CudaSlice<f32>,
+    src_dev: &CudaDevice,
+    dst_dev: &CudaDevice,
+) -> Result<CudaSlice<f32>> {
+    use cudarc::driver::sys;
+
+    let dst = unsafe { dst_dev.alloc::<f32>(src.len()).w()? };
+    // The allocation is ordered on the destination stream and the consumers of the copy run there.
+    stream_wait(dst_dev, src_dev)?;
+    src_dev.bind_to_thread().w()?;
+    unsafe {
+        sys::cuMemcpyPeerAsync(
+            *dst.device_ptr(),
+            *dst_dev.cu_primary_ctx(),
+            *src.device_ptr(),
+            *src_dev.cu_primary_ctx(),
+            std::mem::size_of_val(&0f32) * src.len(),
+            *src_dev.cu_stream(),
+        )
+    }
+    .result()
+    .w()?;
+    stream_wait(src_dev, dst_dev)?;
+    Ok(dst)
+}
+
+// Makes the stream of `waiter` wait for the work currently queued on the stream of `dev`.
+fn stream_wait(dev: &CudaDevice, waiter: &CudaDevice) -> Result<()> {
+    use cudarc::driver::{result, sys};
+
+    dev.bind_to_thread().w()?;
+    let event = result::event::create(sys::CUevent_flags::CU_EVENT_DISABLE_TIMING).w()?;
+    let waited = unsafe { result::event::record(event, *dev.cu_stream()) }.and_then(|()| {
+        waiter.bind_to_thread()?;
+        let flags = sys::CUevent_wait_flags::CU_EVENT_WAIT_DEFAULT;
+        unsafe { result::stream::wait_event(*waiter.cu_stream(), event, flags) }
+    });
+    // The event is only released once the wait completes.
+    let _ = unsafe { result::event::destroy(event) };
+    waited.w()
+}
+
+fn quantize_q8_0(
+    src: &CudaView<f32>,
+    dst: &mut CudaSlice<u8>,
+    elem_count: usize,
+    dev: &CudaDevice,
+) -> Result<()> {
+    if elem_count % GgmlDType::Q8_0.block_size() != 0 {
+        crate::bail!("q8_0 quantization requires a multiple of 32 elements, got {elem_count}")
+    }
+    let num_blocks = ceil_div(elem_count, CUDA_QUANTIZE_BLOCK_SIZE);
+    let func = dev.get_or_load_func("quantize_q8_0", candle_kernels::QUANTIZED)?;
+    let cfg = cudarc::driver::LaunchConfig {
+        grid_dim: (num_blocks as u32, 1, 1),
+        block_dim: (CUDA_QUANTIZE_BLOCK_SIZE as u32, 1, 1),
+        shared_mem_bytes: 0,
+    };
+    let params = (src, dst, elem_count as i32);
+    let scope = trace_launch(dev, "quantize_q8_0", GgmlDType::Q8_0)?;
+    unsafe { launch(func, dev, cfg, params) }?;
+    scope.end(dev)?;
+    Ok(())
+}
+
+// Queries a pointer attribute whose value has type `T`, e.g. `u64` for the buffer id.
+fn pointer_attribute<T: Default>(
+    ptr: u64,
+    attribute: cudarc::driver::sys::CUpointer_attribute,
+) -> Result<T> {
+    let mut value = T::default();
+    unsafe {
+        cudarc::driver::sys::cuPointerGetAttribute(
+            &mut value as *mut T as *mut std::ffi::c_void,
+            attribute,
+            ptr,
+        )
+    }
+    .result()
+    .w()?;
+    Ok(value)
+}
+
+// Checks that `data` has been allocated on `dev`, launching a kernel of `dev` on a buffer of
+// another device would fail with an opaque illegal address error or read garbage. Buffers can only
+// be on another device when several are visible so the check is skipped otherwise, the device
+// count being queried once.
+fn check_same_device<S: DevicePtr<u8>>(data: &S, dev: &CudaDevice, op: &'static str) -> Result<()> {
+    use cudarc::driver::sys;
+
+    static MULTI_DEVICE: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    let multi_device = MULTI_DEVICE.get_or_init(|| {
+        let mut count: std::os::raw::c_int = 0;
+        let res = unsafe { sys::cuDeviceGetCount(&mut count) }.result();
+        // Keep checking if the count is unknown.
+        res.is_err() || count > 1
+    });
+    let ptr = *data.device_ptr();
+    if !multi_device || ptr == 0 {
+        return Ok(());
+    }
+    let ordinal: std::os::raw::c_int = pointer_attribute(
+        ptr,
+        sys::CUpointer_attribute::CU_POINTER_ATTRIBUTE_DEVICE_ORDINAL,
+    )?;
+    if ordinal as usize == dev.ordinal() {
+        return Ok(());
+    }
+    Err(crate::Error::DeviceMismatchBinaryOp {
+        lhs: crate::DeviceLocation::Cuda {
+            gpu_id: ordinal.max(0) as usize,
+        },
+        rhs: dev.location(),
+        op,
+    }
+    .bt())
+}
+
+fn dequantize<T: CudaDType + cudarc::driver::DeviceRepr + crate::WithDType>(
+    data: &CudaView<u8>,
+    dtype: GgmlDType,
+    elem_count: usize,
+    dev: &CudaDevice,
+) -> Result<CudaStorage> {
+    let f16_output = match T::DTYPE {
+        crate::DType::F32 => false,
+        crate::DType::F16 => true,
+        out_dtype => crate::bail!("unsupported output dtype for dequantize {out_dtype:?}"),
+    };
+    let dst = unsafe { dev.alloc::<T>(elem_count).w()? };
+    let launch = dequantize_launch(dtype, elem_count, f16_output)?;
+    launch_dequantize(data, dtype, launch, &dst, dev)?;
+    Ok(CudaStorage::wrap_cuda_slice(dst, dev.clone()))
+}
+
+// Same as `dequantize::<f32>` with the subnormal values flushed to zero, keeping their sign, as
+// they are written.
+fn dequantize_ftz(
+    data: &CudaView<u8>,
+    dtype: GgmlDType,
+    elem_count: usize,
+    dev: &CudaDevice,
+) -> Result<CudaStorage> {
+    let dst = unsafe { dev.alloc::<f32>(elem_count).w()? };
+    launch_dequantize(
+        data,
+        dtype,
+        dequantize_ftz_launch(dtype, elem_count)?,
+        &dst,
+        dev,
+    )?;
+    Ok(CudaStorage::wrap_cuda_slice(dst, dev.clone()))
+}
+
+// The cpu counterpart of the flush-to-zero kernels.
+fn flush_subnormals(xs: &mut [f32]) {
+    for v in xs.iter_mut().filter(|v| v.is_subnormal()) {
+        *v = 0f32.copysign(*v)
+    }
+}
+
+// Launches a dequantize kernel taking the blocks, the output buffer `dst` and the number of
+// blocks of 32 values if any, e.g. the plain or the flush-to-zero variants.
+fn launch_dequantize<D: cudarc::driver::DeviceRepr>(
+    data: &CudaView<u8>,
+    dtype: GgmlDType,
+    launch: DequantizeLaunch,
+    dst: D,
+    dev: &CudaDevice,
+) -> Result<()> {
+    check_same_device(data, dev, "dequantize")?;
+    let DequantizeLaunch {
+        kernel_name,
+        block_dim,
+        num_blocks,
+        nb32,
+    } = launch;
+    let func = dev.get_or_load_func(&kernel_name, candle_kernels::QUANTIZED)?;
+    // See e.g.
+    // https://github.com/ggerganov/llama.cpp/blob/cbbd1efa06f8c09f9dff58ff9d9af509cc4c152b/ggml-cuda.cu#L7270
+    let cfg = cudarc::driver::LaunchConfig {
+        grid_dim: (num_blocks as u32, 1, 1),
+        block_dim: (block_dim as u32, 1, 1),
+        shared_mem_bytes: 0,
+    };
+
+    if let Some(nb32) = nb32 {
+        let params = (data, dst, nb32);
+        let scope = trace_launch(dev, &kernel_name, dtype)?;
+        unsafe { launch(func, dev, cfg, params) }?;
+        scope.end(dev)?;
+    } else {
+        let params = (data, dst);
+        let scope = trace_launch(dev, &kernel_name, dtype)?;
+        unsafe { launch(func, dev, cfg, params) }?;
+        scope.end(dev)?;
+    }
+    Ok(())
+}
+
+/// Dequantizes `elem_count` values of type `dtype` from a view of a larger device buffer, e.g. one
+/// tensor of a packed buffer holding several of them, without copying the blocks out first. The
+/// view has to start on a block boundary of the tensor.
+pub fn dequantize_view(
+    data: &CudaView<u8>,
+    dtype: GgmlDType,
+    elem_count: usize,
+    dev: &CudaDevice,
+) -> Result<CudaStorage> {
+    let (block_size, type_size) = (dtype.block_size(), dtype.type_size());
+    if elem_count % block_size != 0 {
+        crate::bail!("dequantize_view: {elem_count} is not a multiple of the {dtype:?} block size")
+    }
+    let len = elem_count / block_size * type_size;
+    if data.len() < len {
+        crate::bail!(
+            "dequantize_view: {elem_count} {dtype:?} values require {len} bytes but the view only has {}",
+            data.len()
+        )
+    }
+    // The kernels read the block scales as half or half2 values.
+    let align = if type_size % 4 == 0 { 4 } else { 2 };
+    if (*data.device_ptr() as usize) % align != 0 {
+        crate::bail!("dequantize_view: the view is not aligned on {align} bytes for {dtype:?}")
+    }
+    // The kernels only read the blocks covering elem_count, the rest of the view is untouched.
+    if DTypeInfo::new(dtype).has_fast_dequantize {
+        return dequantize::<f32>(data, dtype, elem_count, dev);
+    }
+    let buffer = dev.dtoh_sync_copy(&data.slice(..len)).w()?;
+    let out = dequantize_on_cpu(&buffer, dtype, elem_count)?;
+    dev.storage_from_cpu_storage(&crate::CpuStorage::F32(out))
+}
+
+// The kernels read the multi-byte block fields in little-endian order, as stored in ggml files.
+// Blocks crossing between the host block types and the device are converted on big-endian hosts,
+// this is a no-op on little-endian ones. The conversion is its own inverse.
+fn convert_host_blocks(dtype: GgmlDType, data: &[u8]) -> Result<std::borrow::Cow<'_, [u8]>> {
+    #[cfg(target_endian = "big")]
+    {
+        let mut data = data.to_vec();
+        dtype.swap_block_byte_order(&mut data)?;
+        Ok(std::borrow::Cow::Owned(data))
+    }
+    #[cfg(not(target_endian = "big"))]
+    {
+        let _ = dtype;
+        Ok(std::borrow::Cow::Borrowed(data))
+    }
+}
+
+fn dequantize_on_cpu(buffer: &[u8], dtype: GgmlDType, elem_count: usize) -> Result<Vec<f32>> {
+    let buffer = convert_host_blocks(dtype, buffer)?;
+    let buffer = buffer.as_ref();
+    fn deq<T: GgmlType>(buffer: &[u8], n: usize, dst: &mut [f32]) -> Result<()> {
+        let slice = unsafe { std::slice::from_raw_parts(buffer.as_ptr() as *const T, n) };
+        let vec = slice.to_vec();
+        T::to_float(&vec, dst)
+    }
+
+    let mut out = vec![0.0; elem_count];
+    let block_len = elem_count / dtype.block_size();
+    match dtype {
+        GgmlDType::F32 => deq::<f32>(buffer, block_len, &mut out)?,
+        GgmlDType::F16 => deq::<half::f16>(buffer, block_len, &mut out)?,
+        GgmlDType::BF16 => deq::<half::bf16>(buffer, block_len, &mut out)?,
+        GgmlDType::Q4_0 => deq::<crate::quantized::BlockQ4_0>(buffer, block_len, &mut out)?,
+        GgmlDType::Q4_1 => deq::<crate::quantized::BlockQ4_1>(buffer, block_len, &mut out)?,
+        GgmlDType::Q5_0 => deq::<crate::quantized::BlockQ5_0>(buffer, block_len, &mut out)?,
+        GgmlDType::Q5_1 => deq::<crate::quantized::BlockQ5_1>(buffer, block_len, &mut out)?,
+        GgmlDType::Q8_0 => deq::<crate::quantized::BlockQ8_0>(buffer, block_len, &mut out)?,
+        GgmlDType::Q8_1 => deq::<crate::quantized::BlockQ8_1>(buffer, block_len, &mut out)?,
+        GgmlDType::Q2K => deq::<crate::quantized::BlockQ2K>(buffer, block_len, &mut out)?,
+        GgmlDType::Q3K => deq::<crate::quantized::BlockQ3K>(buffer, block_len, &mut out)?,
+        GgmlDType::Q4K => deq::<crate::quantized::BlockQ4K>(buffer, block_len, &mut out)?,
+        GgmlDType::Q5K => deq::<crate::quantized::BlockQ5K>(buffer, block_len, &mut out)?,
+        GgmlDType::Q6K => deq::<crate::quantized::BlockQ6K>(buffer, block_len, &mut out)?,
+        GgmlDType::Q8K => deq::<crate::quantized::BlockQ8K>(buffer, block_len, &mut out)?,
+    }
+    Ok(out)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn dequantize_mul_mat_vec(
+    data: &CudaSlice<u8>,
+    y: &CudaView<f32>,
+    bias: Option<&CudaView<f32>>,
+    valid_rows: Option<usize>,
+    dtype: GgmlDType,
+    ncols: usize,
+    nrows: usize,
+    dev: &CudaDevice,
+) -> Result<CudaStorage> {
+    check_matmul_data(data.len(), dtype, ncols, nrows)?;
+    check_same_device(data, dev, "dequantize-mul-mat-vec")?;
+    if ncols % dtype.block_size() != 0 {
+        crate::bail!(
+            "the dmmv kernels require rows of whole {dtype:?} blocks, ncols {ncols}, use the q8_1 kernels"
+        )
+    }
+    if y.len() != ncols {
+        crate::bail!("unexpected y size {}, ncols {ncols} {nrows}", y.len())
+    }
+    // The bias is optional, a null pointer is passed to the kernel when there is none.
+    let bias = match bias {
+        Some(bias) if bias.len() != nrows => {
+            crate::bail!("unexpected bias size {}, nrows {nrows}", bias.len())
+        }
+        Some(bias) => *bias.device_ptr(),
+        None => 0,
+    };
+    let kernel_name = dmmv_kernel_name(dtype)?;
+    let (mmv_y, block_num_y) = dmmv_grid(dtype, nrows, QuantCudaConfig::for_device(dev).mmv_y);
+    let (ncols_i32, nrows_i32) = (kernel_dim(ncols, "ncols")?, kernel_dim(nrows, "nrows")?);
+    let valid_rows = valid_rows.map_or(nrows_i32, |v| v.min(nrows) as i32);
+    let func = dev.get_or_load_func(kernel_name, candle_kernels::QUANTIZED)?;
+    let dst = unsafe { dev.alloc::<f32>(nrows).w()? };
+    let cfg = cudarc::driver::LaunchConfig {
+        grid_dim: (block_num_y as u32, 1, 1),
+        block_dim: (WARP_SIZE as u32, mmv_y as u32, 1),
+        shared_mem_bytes: 0,
+    };
+
+    let params = (data, y, &dst, ncols_i32, nrows_i32, bias, valid_rows);
+    let scope = trace_launch(dev, kernel_name, dtype)?;
+    unsafe { launch(func, dev, cfg, params) }?;
+    scope.end(dev)?;
+    Ok(CudaStorage::wrap_cuda_slice(dst, dev.clone()))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn mul_mat_vec_via_q8_1(
+    data: &CudaSlice<u8>,
+    y: &CudaView<f32>,
+    bias: Option<&CudaView<f32>>,
+    valid_rows: Option<usize>,
+    dtype: GgmlDType,
+    ncols: usize,
+    nrows: usize,
+    dev: &CudaDevice,
+) -> Result<CudaStorage> {
+    if y.len() != ncols {
+        crate::bail!("unexpected y size {}, ncols {ncols} {nrows}", y.len())
+    }
+    // The bias is optional, a null pointer is passed to the kernel when there is none.
+    let bias = match bias {
+        Some(bias) if bias.len() != nrows => {
+            crate::bail!("unexpected bias size {}, nrows {nrows}", bias.len())
+        }
+        Some(bias) => *bias.device_ptr(),
+        None => 0,
+    };
+    // Start by quantizing y, unless it has already been quantized during this pass.
+    let row_padding = q8_1_row_padding(dtype);
+    let rounding = QuantCudaConfig::for_device(dev).q8_1_rounding;
+    let key = match Q81Cache::generation(dev) {
+        Some(generation) => Some(Q81CacheKey {
+            generation,
+            ptr: *y.device_ptr(),
+            buffer_id: pointer_attribute::<u64>(
+                *y.device_ptr(),
+                cudarc::driver::sys::CUpointer_attribute::CU_POINTER_ATTRIBUTE_BUFFER_ID,
+            )?,
+            len: ncols,
+            row_padding,
+            rounding,
+        }),
+        None => None,
+    };
+    let y_q8_1 = match key.and_then(|key| Q81Cache::lookup(dev, key)) {
+        Some(y_q8_1) => y_q8_1,
+        None => {
+            let size = q8_1_buffer_size(ncols, row_padding);
+            let mut y_q8_1 = unsafe { dev.alloc::<u8>(size).w()? };
+            quantize_q8_1(y, &mut y_q8_1, ncols, row_padding, rounding, dev)?;
+            let y_q8_1 = std::sync::Arc::new(y_q8_1);
+            if let Some(key) = key {
+                Q81Cache::insert(dev, key, &y_q8_1);
+            }
+            y_q8_1
+        }
+    };
+    let out = mul_mat_vec_q8_1(data, &y_q8_1, bias, valid_rows, dtype, ncols, nrows, dev);
+    super::cuda_graph::release_scratch(dev, y_q8_1);
+    out
+}
+
+/// Opt-in cache of the q8_1 quantized activations of the matmul-vec path, so that an activation
+/// multiplied by multiple weights, e.g. the q, k and v projections, is only quantized once.
+///
+/// Entries are keyed by the cache generation, the device pointer, the cuda buffer id and the length
+/// of the activation as well as by the q8_1 rounding. The buffer id is unique to an allocation so a
+/// freed buffer whose address gets reused does not hit the cache, but the cache cannot tell when
+/// the content of a live buffer changes. It is enabled on the current thread for the lifetime of
+/// the guard returned by [`Q81Cache::enable`] and [`Q81Cache::invalidate`], which starts a new
+/// generation, has to be called whenever the activations may have been overwritten in place,
+/// typically between layers. At most [`Q81Cache::MAX_ENTRIES`] activations are kept, the oldest
+/// one is dropped first.
+pub struct Q81Cache;
+
+/// Keeps the [`Q81Cache`] of a device enabled, the cached activations are freed on drop.
+#[must_use]
+pub struct Q81CacheGuard {
+    device_id: crate::cuda_backend::DeviceId,
+    // The cache is thread local so the guard has to be dropped on the thread that created it.
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+/// Counters of a [`Q81Cache`] since it was enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Q81CacheStats {
+    /// Number of activations quantized to q8_1, i.e. of quantize kernel launches.
+    pub quantized: usize,
+    /// Number of matmul-vec calls that reused a cached activation.
+    pub hits: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Q81CacheKey {
+    // The generation of the cache when the activation was quantized, see `Q81Cache::invalidate`.
+    generation: u64,
+    ptr: u64,
+    // The `CU_POINTER_ATTRIBUTE_BUFFER_ID` of the allocation holding the activation.
+    buffer_id: u64,
+    len: usize,
+    row_padding: usize,
+    rounding: Q8_1Rounding,
+}
+
+struct Q81CacheState {
+    device_id: crate::cuda_backend::DeviceId,
+    version: u64,
+    entries: Vec<(Q81CacheKey, std::sync::Arc<CudaSlice<u8>>)>,
+    stats: Q81CacheStats,
+}
+
+thread_local! {
+    static Q8_1_CACHES: std::cell::RefCell<Vec<Q81CacheState>> =
+        const { std::cell::RefCell::new(Vec::new()) };
+}
+
+impl Q81Cache {
+    /// The number of activations kept by the cache of a device.
+    pub const MAX_ENTRIES: usize = 16;
+
+    /// Enables the cache for `dev` until the returned guard is dropped.
+    pub fn enable(dev: &CudaDevice) -> Result<Q81CacheGuard> {
+        Q8_1_CACHES.with_borrow_mut(|caches| {
+            if caches.iter().any(|c| c.device_id == dev.id()) {
+                crate::bail!("the q8_1 activation cache is already enabled for this device")
+            }
+            caches.push(Q81CacheState {
+                device_id: dev.id(),
+                version: 0,
+                entries: vec![],
+                stats: Q81CacheStats::default(),
+            });
+            Ok(Q81CacheGuard {
+                device_id: dev.id(),
+                _not_send: std::marker::PhantomData,
+            })
+        })
+    }
+
+    /// Starts a new generation of the cache of `dev`, the activations cached so far are dropped
+    /// and never hit again. This returns the new generation and is a no-op when the cache is not
+    /// enabled.
+    pub fn invalidate(dev: &CudaDevice) -> u64 {
+        Q8_1_CACHES.with_borrow_mut(|caches| {
+            match caches.iter_mut().find(|c| c.device_id == dev.id()) {
+                Some(c) => {
+                    c.entries.clear();
+                    c.version += 1;
+                    c.version
+                }
+                None => 0,
+            }
+        })
+    }
+
+    /// The counters of the cache of `dev`, `None` when it is not enabled.
+    pub fn stats(dev: &CudaDevice) -> Option<Q81CacheStats> {
+        Q8_1_CACHES.with_borrow(|caches| {
+            caches
+                .iter()
+                .find(|c| c.device_id == dev.id())
+                .map(|c| c.stats)
+        })
+    }
+
+    /// The bytes of the activations cached for `dev` on the current thread.
+    pub fn size_in_bytes(dev: &CudaDevice) -> usize {
+        Q8_1_CACHES.with_borrow(|caches| {
+            caches
+                .iter()
+                .find(|c| c.device_id == dev.id())
+                .map_or(0, |c| c.entries.iter().map(|(_, y)| y.len()).sum())
+        })
+    }
+
+    // The current generation of the cache of `dev`, `None` when it is not enabled.
+    fn generation(dev: &CudaDevice) -> Option<u64> {
+        Q8_1_CACHES.with_borrow(|caches| {
+            caches
+                .iter()
+                .find(|c| c.device_id == dev.id())
+                .map(|c| c.version)
+        })
+    }
+
+    fn lookup(dev: &CudaDevice, key: Q81CacheKey) -> Option<std::sync::Arc<CudaSlice<u8>>> {
+        Q8_1_CACHES.with_borrow_mut(|caches| {
+            let cache = caches.iter_mut().find(|c| c.device_id == dev.id())?;
+            let entry = cache.entries.iter().find(|(k, _)| *k == key)?.1.clone();
+            cache.stats.hits += 1;
+            Some(entry)
+        })
+    }
+
+    fn insert(dev: &CudaDevice, key: Q81CacheKey, y_q8_1: &std::sync::Arc<CudaSlice<u8>>) {
+        Q8_1_CACHES.with_borrow_mut(|caches| {
+            if let Some(cache) = caches.iter_mut().find(|c| c.device_id == dev.id()) {
+                cache.entries.retain(|(k, _)| k.generation == cache.version);
+                if cache.entries.len() >= Self::MAX_ENTRIES {
+                    cache.entries.remove(0);
+                }
+                cache.entries.push((key, y_q8_1.clone()));
+                cache.stats.quantized += 1;
+            }
+        })
+    }
+}
+
+impl Drop for Q81CacheGuard {
+    fn drop(&mut self) {
+        Q8_1_CACHES.with_borrow_mut(|caches| caches.retain(|c| c.device_id != self.device_id))
+    }
+}
+
+// Runs the matmul-vec kernel on an activation already quantized to q8_1 and padded to
+// MATRIX_ROW_PADDING, `bias` is a device pointer to `nrows` values or null. The rows from
+// `valid_rows` on are set to zero.
+#[allow(clippy::too_many_arguments)]
+fn mul_mat_vec_q8_1(
+    data: &CudaSlice<u8>,
+    y_q8_1: &CudaSlice<u8>,
+    bias: u64,
+    valid_rows: Option<usize>,
+    dtype: GgmlDType,
+    ncols: usize,
+    nrows: usize,
+    dev: &CudaDevice,
+) -> Result<CudaStorage> {
+    mul_mat_vec_q8_1_batched(data, y_q8_1, 1, bias, valid_rows, dtype, ncols, nrows, dev)
+}
+
+// Same as `mul_mat_vec_q8_1` for `ncols_y` activations quantized with `quantize_q8_1_rows`, in
+// a single launch. The output is a row major `(ncols_y, nrows)` matrix.
+#[allow(clippy::too_many_arguments)]
+fn mul_mat_vec_q8_1_batched(
+    data: &CudaSlice<u8>,
+    y_q8_1: &CudaSlice<u8>,
+    ncols_y: usize,
+    bias: u64,
+    valid_rows: Option<usize>,
+    dtype: GgmlDType,
+    ncols: usize,
+    nrows: usize,
+    dev: &CudaDevice,
+) -> Result<CudaStorage> {
+    let (ncols_i32, nrows_i32) = (kernel_dim(ncols, "ncols")?, kernel_dim(nrows, "nrows")?);
+    let valid_rows = valid_rows.map_or(nrows_i32, |v| v.min(nrows) as i32);
+    check_matmul_data(data.len(), dtype, ncols, nrows)?;
+    check_same_device(data, dev, "mul-mat-vec-q8-1")?;
+    // Values per q8_1 activation row, the rows after the first one start past the padding.
+    let row_padding = q8_1_row_padding(dtype);
+    if y_q8_1.len() < ncols_y * q8_1_buffer_size(ncols, row_padding) {
+        crate::bail!(
+            "unexpected q8_1 activation size {} for {ncols_y} rows of {ncols} values",
+            y_q8_1.len()
+        )
+    }
+    let nrows_y = kernel_dim(pad(ncols, row_padding), "nrows_y")?;
+    let (kernel_name, rows_per_block) = mmvq_batched_kernel(dtype, ncols_y)?;
+    let func = dev.get_or_load_func(&kernel_name, candle_kernels::QUANTIZED)?;
+    let nwarps = QuantCudaConfig::for_device(dev).mmvq_nwarps(dtype);
+    let dst = unsafe { dev.alloc::<f32>(ncols_y * nrows).w()? };
+    let cfg = cudarc::driver::LaunchConfig {
+        grid_dim: (ceil_div(nrows, rows_per_block) as u32, 1, 1),
+        block_dim: (WARP_SIZE as u32, nwarps as u32, 1),
+        shared_mem_bytes: 0,
+    };
+
+    let params = (
+        data, y_q8_1, &dst, /* ncols_x */ ncols_i32, /* nrows_x */ nrows_i32,
+        /* nrows_y */ nrows_y, /* nrows_dst */ nrows_i32, bias, valid_rows,
+    );
+    let scope = trace_launch(dev, &kernel_name, dtype)?;
+    unsafe { launch(func, dev, cfg, params) }?;
+    scope.end(dev)?;
+    Ok(CudaStorage::wrap_cuda_slice(dst, dev.clone()))
+}
+
+fn quantize_q4_activation(
+    src: &CudaView<f32>,
+    dst: &mut CudaSlice<u8>,
+    elem_count: usize,
+    dev: &CudaDevice,
+) -> Result<()> {
+    let num_blocks = ceil_div(elem_count, CUDA_QUANTIZE_BLOCK_SIZE);
+    let func = dev.get_or_load_func("quantize_q4_act", candle_kernels::QUANTIZED)?;
+    let cfg = cudarc::driver::LaunchConfig {
+        grid_dim: (num_blocks as u32, 1, 1),
+        block_dim: (CUDA_QUANTIZE_BLOCK_SIZE as u32, 1, 1),
+        shared_mem_bytes: 0,
+    };
+    let params = (src, dst, kernel_dim(elem_count, "elem_count")?);
+    let scope = trace_launch(dev, "quantize_q4_act", GgmlDType::Q4_0)?;
+    unsafe { launch(func, dev, cfg, params) }?;
+    scope.end(dev)?;
+    Ok(())
+}
+
+// Multiplies the q4_0 weights with `ny` contiguous activation rows of `ncols` values, both
+// quantized to int4. The output is a row major `(ny, nrows)` matrix.
+fn mul_mat_via_q4_act(
+    data: &CudaSlice<u8>,
+    y: &CudaView<f32>,
+    dtype: GgmlDType,
+    ncols: usize,
+    nrows: usize,
+    ny: usize,
+    dev: &CudaDevice,
+) -> Result<CudaStorage> {
+    if dtype != GgmlDType::Q4_0 {
+        crate::bail!("unsupported dtype for int4 activations {dtype:?}")
+    }
+    check_matmul_data(data.len(), dtype, ncols, nrows)?;
+    check_same_device(data, dev, "mul-mat-q4-act")?;
+    if y.len() != ny * ncols || ncols % dtype.block_size() != 0 {
+        crate::bail!("unexpected y size {}, ncols {ncols} {nrows}", y.len())
+    }
+    let (ncols_i32, nrows_i32) = (kernel_dim(ncols, "ncols")?, kernel_dim(nrows, "nrows")?);
+    let y_size_in_bytes = ny * ncols / dtype.block_size() * Q4_ACT_TYPE_SIZE;
+    let mut y_q4 = unsafe { dev.alloc::<u8>(y_size_in_bytes).w()? };
+    quantize_q4_activation(y, &mut y_q4, ny * ncols, dev)?;
+
+    let func = dev.get_or_load_func("mul_mat_vec_q4_0_q4_act_cuda", candle_kernels::QUANTIZED)?;
+    let dst = unsafe { dev.alloc::<f32>(ny * nrows).w()? };
+    if nrows * ny > MAX_GRID_DIM_X {
+        crate::bail!("{ny} activation rows of {nrows} outputs exceed the cuda grid limits")
+    }
+    let cfg = cudarc::driver::LaunchConfig {
+        grid_dim: ((nrows * ny) as u32, 1, 1),
+        block_dim: (WARP_SIZE as u32, 1, 1),
+        shared_mem_bytes: 0,
+    };
+    let params = (data, &y_q4, &dst, ncols_i32, nrows_i32);
+    let scope = trace_launch(dev, "mul_mat_vec_q4_0_q4_act_cuda", GgmlDType::Q4_0)?;
+    unsafe { launch(func, dev, cfg, params) }?;
+    scope.end(dev)?;
+    super::cuda_graph::release_scratch(dev, y_q4);
+    Ok(CudaStorage::wrap_cuda_slice(dst, dev.clone()))
+}
+
+// The exception rows of the concatenation of `storages` along rows of `ncols` values, the rows
+// of each storage being shifted by the rows of the storages before it.
+fn cat_exception_rows(storages: &[&QCudaStorage], ncols: usize) -> Result<Option<ExceptionRows>> {
+    if storages.iter().all(|s| s.exceptions.is_none()) {
+        return Ok(None);
+    }
+    let (mut rows, mut values) = (vec![], vec![]);
+    let mut first_row = 0usize;
+    for storage in storages.iter() {
+        if let Some(exceptions) = &storage.exceptions {
+            if exceptions.ncols != ncols {
+                crate::bail!(
+                    "cat_rows: ncols {ncols} does not match the {} values of the exception rows",
+                    exceptions.ncols
+                )
+            }
+            let offset = match u32::try_from(first_row) {
+                Ok(offset) => offset,
+                Err(_) => crate::bail!("cat_rows: too many rows for exception rows {first_row}"),
+            };
+            rows.extend(exceptions.rows()?.iter().map(|r| r + offset));
+            values.extend(exceptions.values()?);
+        }
+        first_row += storage.elem_count() / ncols;
+    }
+    let exceptions = ExceptionRows::new(&storages[0].device, &rows, &values, ncols)?;
+    Ok(Some(exceptions))
+}
+
+/// Returns the activation data, with a hint on how to fix things when it is not f32.
+fn f32_activation(storage: &CudaStorage) -> Result<&CudaSlice<f32>> {
+    use crate::backend::BackendStorage;
+
+    match storage.as_cuda_slice::<f32>() {
+        Ok(slice) => Ok(slice),
+        Err(_) => crate::bail!(
+            "quantized matmul expects f32 activations, got {:?}; cast them to f32 first",
+            storage.dtype()
+        ),
+    }
+}
+
+impl QCudaStorage {
+    pub fn zeros(device: &CudaDevice, el_count: usize, dtype: GgmlDType) -> Result<Self> {
+        // The kernel launches rely on the warp size being checked when the storages are created.
+        QuantCudaCaps::for_device(device)?;
+        let size_in_bytes = ceil_div(el_count, dtype.block_size()) * dtype.type_size();
+        let data = device.alloc_zeros::<u8>(size_in_bytes).w()?;
+        Ok(QCudaStorage {
+            _vram: std::sync::Arc::new(VramTicket::new(device, VramKind::Quantized, data.len())),
+            data: std::sync::Arc::new(data),
+            device: device.clone(),
+            dtype,
+            embedding_layout: false,
+            shape: None,
+            transposed_in_file: false,
+            tuned: None,
+            exceptions: None,
+        })
+    }
+
+    pub fn dtype(&self) -> GgmlDType {
+        self.dtype
+    }
+
+    pub fn device(&self) -> &CudaDevice {
+        &self.device
+    }
+
+    /// Blocks until all the kernels queued on the device have completed. Kernels are launched
+    /// asynchronously so benchmarks should call this before reading the clock.
+    pub fn sync(&self) -> Result<()> {
+        sync(&self.device)
+    }
+
+    fn check_standard_layout(&self, op: &str) -> Result<()> {
+        if self.embedding_layout {
+            crate::bail!("{op} is not supported on storages using the embedding layout")
+        }
+        Ok(())
+    }
+
+    // For the ops that do not substitute the exception rows, which would otherwise return the
+    // quantized values of these rows.
+    fn check_no_exception_rows(&self, op: &str) -> Result<()> {
+        if self.exceptions.is_some() {
+            crate::bail!("{op} is not supported on weights with exception rows")
+        }
+        Ok(())
+    }
+
+    fn check_not_transposed(&self, op: &str) -> Result<()> {
+        if self.transposed_in_file {
+            crate::bail!(
+                "{op} requires {:?} blocks along the input dimension but the weights are stored transposed, use fwd which dequantizes them or requantize the transposed weights",
+                self.dtype
+            )
+        }
+        Ok(())
+    }
+
+    /// Whether the weights of a `(n, k)` linear layer are stored as `k` rows of `n` values, see
+    /// [`QCudaStorage::set_transposed_in_file`].
+    pub fn is_transposed_in_file(&self) -> bool {
+        self.transposed_in_file
+    }
+
+    /// Marks the weights as stored transposed, i.e. as `(in, out)` rather than the usual `(out, in)`
+    /// gguf layout, as done by some converters. The shape passed to [`QCudaStorage::fwd`] is still
+    /// `(out, in)`. The quantization blocks then run along the output dimension so the matmul-vec
+    /// kernels cannot be used, [`QCudaStorage::fwd`] dequantizes the weights for all the input
+    /// shapes, which avoids transposing them on the host at load time.
+    pub fn set_transposed_in_file(&mut self, transposed: bool) {
+        self.transposed_in_file = transposed
+    }
+
+    /// The shape declared for these weights if any, this is recorded when building a `QTensor`.
+    pub fn shape(&self) -> Option<&crate::Shape> {
+        self.shape.as_ref()
+    }
+
+    /// Records the shape of the weights, it has to match the number of elements.
+    pub fn set_shape<S: Into<crate::Shape>>(&mut self, shape: S) -> Result<()> {
+        let shape = shape.into();
+        if shape.elem_count() != self.elem_count() {
+            crate::bail!(
+                "shape {shape:?} does not match {} elements",
+                self.elem_count()
+            )
+        }
+        self.shape = Some(shape);
+        Ok(())
+    }
+
+    /// Returns a clone of the weights with `shape` as their recorded shape, e.g. to fold
+    /// dimensions. The clone shares the device buffer, see [`QCudaStorage::shares_data_with`], so
+    /// the new shape has to keep the number of elements and its last dimension has to be a
+    /// multiple of the block size, so that each row still starts on a block boundary. Use
+    /// [`QCudaStorage::set_shape`] to change the shape of this storage in place.
+    pub fn reshape<S: Into<crate::Shape>>(&self, shape: S) -> Result<QCudaStorage> {
+        let shape = shape.into();
+        if self.transposed_in_file {
+            crate::bail!("reshape is not supported for weights stored transposed in the file")
+        }
+        let row_len = shape.dims().last().copied().unwrap_or(1);
+        let block_size = self.dtype.block_size();
+        if row_len % block_size != 0 {
+            crate::bail!(
+                "reshape to {shape:?} would split {:?} blocks of {block_size} elements",
+                self.dtype
+            )
+        }
+        if self.embedding_layout {
+            let current_row_len = self.shape.as_ref().and_then(|s| s.dims().last().copied());
+            if current_row_len != Some(row_len) {
+                crate::bail!(
+                    "reshape to {shape:?} changes the row length of a storage using the embedding layout"
+                )
+            }
+        }
+        let mut storage = self.clone();
+        storage.set_shape(shape)?;
+        Ok(storage)
+    }
+
+    // Used by `QMatMul::forward` and `quantized_matmul` to accept cpu activations.
+    pub(crate) fn auto_upload_activations(&self) -> bool {
+        QuantCudaConfig::for_device(&self.device).auto_upload_activations
+    }
+
+    // Used by `QTensor::new`, shapes that do not match the storage are not recorded.
+    pub(crate) fn record_shape(&mut self, shape: &crate::Shape) {
+        if shape.elem_count() == self.elem_count() {
+            self.shape = Some(shape.clone())
+        }
+    }
+
+    /// Dequantizes the weights to a f32 tensor using their recorded shape, see
+    /// [`QCudaStorage::set_shape`], or as a flat tensor when no shape has been recorded.
+    pub fn dequantize_tensor(&self) -> Result<crate::Tensor> {
+        let shape = match &self.shape {
+            Some(shape) => shape.clone(),
+            None => crate::Shape::from(self.elem_count()),
+        };
+        let storage = self.dequantize(shape.elem_count())?;
+        let none = crate::op::BackpropOp::none();
+        crate::tensor::from_storage(crate::Storage::Cuda(storage), shape, none, false)
+    }
+
+    /// Dequantizes the weights to a `shape` tensor of type `dtype`, which has to be f32, f16 or
+    /// bf16. The f16 values are written by the dequantize kernels, bf16 ones are converted from
+    /// f32.
+    pub fn to_tensor(&self, shape: &crate::Shape, dtype: crate::DType) -> Result<crate::Tensor> {
+        use crate::backend::BackendStorage;
+
+        let elem_count = shape.elem_count();
+        if elem_count != self.elem_count() {
+            crate::bail!(
+                "to_tensor: shape {shape:?} does not match {} elements",
+                self.elem_count()
+            )
+        }
+        let storage = match dtype {
+            crate::DType::F32 => self.dequantize(elem_count)?,
+            crate::DType::F16 => self.dequantize_f16(elem_count)?,
+            crate::DType::BF16 => self
+                .dequantize(elem_count)?
+                .to_dtype(&crate::Layout::contiguous(shape), crate::DType::BF16)?,
+            dtype => crate::bail!("to_tensor: unsupported dtype {dtype:?}"),
+        };
+        let none = crate::op::BackpropOp::none();
+        crate::tensor::from_storage(crate::Storage::Cuda(storage), shape.clone(), none, false)
+    }
+
+    /// Whether the storage uses the row gather optimized layout, see
+    /// [`QCudaStorage::to_embedding_layout`].
+    pub fn is_embedding_layout(&self) -> bool {
+        self.embedding_layout
+    }
+
+    /// Reorders q4_0 `(nrows, ncols)` weights so that each row holds all its block scales followed
+    /// by all its quants. This makes [`QCudaStorage::gather_rows`] faster for token embeddings but
+    /// the resulting storage can only be used for gathers.
+    pub fn to_embedding_layout(&self, ncols: usize) -> Result<QCudaStorage> {
+        self.check_standard_layout("to_embedding_layout")?;
+        if self.dtype != GgmlDType::Q4_0 {
+            crate::bail!(
+                "embedding layout is only supported for q4_0, got {:?}",
+                self.dtype
+            )
+        }
+        let (block_size, type_size) = (self.dtype.block_size(), self.dtype.type_size());
+        if ncols == 0 || ncols % block_size != 0 || self.elem_count() % ncols != 0 {
+            crate::bail!(
+                "unexpected ncols {ncols} for {} elements",
+                self.elem_count()
+            )
+        }
+        let nblocks = self.data.len() / type_size;
+        let dev = self.device();
+        let func = dev.get_or_load_func("to_embedding_layout_q4_0", candle_kernels::QUANTIZED)?;
+        let data = unsafe { dev.alloc::<u8>(self.data.len()).w()? };
+        let cfg = cudarc::driver::LaunchConfig {
+            grid_dim: (ceil_div(nblocks, CUDA_QUANTIZE_BLOCK_SIZE) as u32, 1, 1),
+            block_dim: (CUDA_QUANTIZE_BLOCK_SIZE as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let params = (
+            &*self.data,
+            &data,
+            kernel_dim(ncols / block_size, "blocks_per_row")?,
+            kernel_dim(nblocks, "nblocks")?,
+        );
+        unsafe { launch(func, dev, cfg, params) }?;
+        Ok(QCudaStorage {
+            _vram: std::sync::Arc::new(VramTicket::new(
+                &self.device,
+                VramKind::Quantized,
+                data.len(),
+            )),
+            data: std::sync::Arc::new(data),
+            dtype: self.dtype,
+            device: self.device.clone(),
+            embedding_layout: true,
+            shape: self.shape.clone(),
+            transposed_in_file: self.transposed_in_file,
+            tuned: None,
+            exceptions: None,
+        })
+    }
+
+    /// Dequantizes the rows `ids` of q4_0 `(nrows, ncols)` weights into a `(ids.len(), ncols)` f32
+    /// storage, both the standard and the embedding layouts are supported. The rows of the ids
+    /// that are out of range are zeros.
+    pub fn gather_rows(&self, ids: &CudaView<u32>, ncols: usize) -> Result<CudaStorage> {
+        if self.dtype != GgmlDType::Q4_0 {
+            crate::bail!(
+                "gather_rows is only supported for q4_0, got {:?}",
+                self.dtype
+            )
+        }
+        if ncols == 0 || ncols % self.dtype.block_size() != 0 {
+            crate::bail!("unexpected ncols {ncols} for {:?}", self.dtype)
+        }
+        let nrows = self.elem_count() / ncols;
+        let dev = self.device();
+        if ids.len() == 0 {
+            let dst = dev.alloc_zeros::<f32>(0).w()?;
+            return Ok(CudaStorage::wrap_cuda_slice(dst, dev.clone()));
+        }
+        let func = dev.get_or_load_func("gather_rows_q4_0", candle_kernels::QUANTIZED)?;
+        let dst = unsafe { dev.alloc::<f32>(ids.len() * ncols).w()? };
+        let cfg = cudarc::driver::LaunchConfig {
+            grid_dim: (ids.len() as u32, 1, 1),
+            block_dim: (CUDA_DEQUANTIZE_BLOCK_SIZE as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let params = (
+            &*self.data,
+            ids,
+            &dst,
+            ncols as i32,
+            nrows as i32,
+            self.embedding_layout as i32,
+        );
+        unsafe { launch(func, dev, cfg, params) }?;
+        Ok(CudaStorage::wrap_cuda_slice(dst, dev.clone()))
+    }
+
+    fn has_fast_dequantize_kernel(&self) -> bool {
+        self.dtype_info().has_fast_dequantize
+    }
+
+    /// The per-dtype facts of this storage, see [`DTypeInfo`].
+    pub fn dtype_info(&self) -> DTypeInfo {
+        DTypeInfo::new(self.dtype)
+    }
+
+    pub fn dequantize(&self, elem_count: usize) -> Result<CudaStorage> {
+        self.check_standard_layout("dequantize")?;
+        count_dequantize();
+        let fast_kernel = self.has_fast_dequantize_kernel();
+        let ftz = QuantCudaConfig::for_device(self.device()).dequantize_ftz;
+        let out = if fast_kernel && ftz {
+            dequantize_ftz(&self.data.slice(..), self.dtype, elem_count, self.device())?
+        } else if fast_kernel {
+            dequantize::<f32>(&self.data.slice(..), self.dtype, elem_count, self.device())?
+        } else {
+            // Run the dequantization on cpu.
+            super::cuda_graph::check_not_capturing(self.device(), "dequantizing on cpu")?;
+            let buffer = self.device.dtoh_sync_copy(&*self.data).w()?;
+            let mut out = dequantize_on_cpu(&buffer, self.dtype, elem_count)?;
+            if ftz {
+                flush_subnormals(&mut out)
+            }
+            self.device
+                .storage_from_cpu_storage(&crate::CpuStorage::F32(out))?
+        };
+        // The f16 exception rows have no subnormal f32 values.
+        self.substitute_exception_rows(out.as_cuda_slice::<f32>()?, 0, elem_count, "f32")?;
+        Ok(out)
+    }
+
+    /// The rows kept in f16, if any.
+    pub fn exception_rows(&self) -> Option<&ExceptionRows> {
+        self.exceptions.as_deref()
+    }
+
+    /// Sets the rows kept in f16, replacing the previous ones. The weights are seen as rows of
+    /// `exceptions.ncols()` values which must all exist. These stay attached to the clones of the
+    /// storage and are dropped when new weights are quantized into it.
+    pub fn set_exception_rows(&mut self, exceptions: Option<ExceptionRows>) -> Result<()> {
+        if let Some(exceptions) = &exceptions {
+            self.check_standard_layout("set_exception_rows")?;
+            self.check_not_transposed("set_exception_rows")?;
+            if exceptions.device.id() != self.device.id() {
+                crate::bail!("the exception rows are not on the device of the weights")
+            }
+            let elem_count = self.elem_count();
+            let ncols = exceptions.ncols;
+            if elem_count % ncols != 0 {
+                crate::bail!("{elem_count} weights cannot be split in rows of {ncols} values")
+            }
+            if let Some(&last) = exceptions.rows()?.last() {
+                if last as usize >= elem_count / ncols {
+                    crate::bail!(
+                        "exception row {last} out of range for {} rows",
+                        elem_count / ncols
+                    )
+                }
+            }
+        }
+        self.exceptions = exceptions.map(std::sync::Arc::new);
+        Ok(())
+    }
+
+    // Overwrites the exception rows within the first `elem_count` values of `dst`, dequantized
+    // weights of type `suffix` starting at row `first_row`.
+    fn substitute_exception_rows<D: cudarc::driver::DeviceRepr>(
+        &self,
+        dst: D,
+        first_row: usize,
+        elem_count: usize,
+        suffix: &str,
+    ) -> Result<()> {
+        let exceptions = match &self.exceptions {
+            Some(exceptions) if exceptions.num_rows > 0 => exceptions,
+            _ => return Ok(()),
+        };
+        let dev = self.device();
+        let kernel_name = format!("substitute_exception_rows_{suffix}");
+        let func = dev.get_or_load_func(&kernel_name, candle_kernels::QUANTIZED)?;
+        let num_values = exceptions.num_rows * exceptions.ncols;
+        let cfg = cudarc::driver::LaunchConfig {
+            grid_dim: (
+                ceil_div(num_values, CUDA_DEQUANTIZE_BLOCK_SIZE) as u32,
+                1,
+                1,
+            ),
+            block_dim: (CUDA_DEQUANTIZE_BLOCK_SIZE as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let params = (
+            &exceptions.values,
+            &exceptions.rows,
+            kernel_dim(exceptions.num_rows, "exception rows")?,
+            kernel_dim(exceptions.ncols, "ncols")?,
+            dst,
+            kernel_dim(first_row, "first_row")?,
+            kernel_dim(elem_count, "elem_count")?,
+        );
+        unsafe { launch(func, dev, cfg, params) }?;
+        Ok(())
+    }
+
+    // Recomputes the output columns of the exception rows in `out`, the row major `(.., nrows)`
+    // output of `fwd` for the activation `storage`.
+    fn exception_rows_matmul(
+        &self,
+        out: &CudaStorage,
+        nrows: usize,
+        storage: &CudaStorage,
+        layout: &crate::Layout,
+    ) -> Result<()> {
+        let exceptions = match &self.exceptions {
+            Some(exceptions) if exceptions.num_rows > 0 => exceptions,
+            _ => return Ok(()),
+        };
+        let ncols = exceptions.ncols;
+        let x = match layout.contiguous_offsets() {
+            Some((start, end)) => f32_activation(storage)?.slice(start..end),
+            None => crate::bail!("exception rows require a contiguous f32 activation"),
+        };
+        if layout.dims().last() != Some(&ncols) || exceptions.num_rows > u16::MAX as usize {
+            crate::bail!(
+                "unexpected activation shape {:?} for {} exception rows of {ncols} values",
+                layout.dims(),
+                exceptions.num_rows
+            )
+        }
+        let ny = x.len() / ncols;
+        let dev = self.device();
+        let func = dev.get_or_load_func("exception_rows_matmul", candle_kernels::QUANTIZED)?;
+        // The activation rows go along x, the grid y dimension is limited to 65535 blocks.
+        let cfg = cudarc::driver::LaunchConfig {
+            grid_dim: (
+                kernel_dim(ny, "exception rows ny")? as u32,
+                exceptions.num_rows as u32,
+                1,
+            ),
+            block_dim: (WARP_SIZE as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let params = (
+            &exceptions.values,
+            &exceptions.rows,
+            kernel_dim(ncols, "ncols")?,
+            &x,
+            out.as_cuda_slice::<f32>()?,
+            kernel_dim(nrows, "nrows")?,
+        );
+        unsafe { launch(func, dev, cfg, params) }?;
+        Ok(())
+    }
+
+    /// Same as [`QCudaStorage::dequantize`] but the values are clamped to `[min, max]`, e.g. to
+    /// work around a few extreme weights in aggressively quantized models. Infinite values are
+    /// clamped to the nearest bound. NaN values have no nearest bound, they are left as is and
+    /// counted in the returned single-value buffer. The count stays on the device so this does not
+    /// synchronize, callers check it when they need to. The clamp happens in the dequantize
+    /// kernel, the exception rows written afterwards keep their f16 values.
+    pub fn dequantize_clamped(
+        &self,
+        elem_count: usize,
+        min: f32,
+        max: f32,
+    ) -> Result<(CudaStorage, CudaSlice<u32>)> {
+        // This also rejects NaN bounds.
+        if !(min <= max) {
+            crate::bail!("invalid clamp range [{min}, {max}]")
+        }
+        self.check_standard_layout("dequantize_clamped")?;
+        count_dequantize();
+        let dev = self.device();
+        if !self.has_fast_dequantize_kernel() {
+            let buffer = self.device.dtoh_sync_copy(&*self.data).w()?;
+            let mut out = dequantize_on_cpu(&buffer, self.dtype, elem_count)?;
+            let mut nan_count = 0u32;
+            for v in out.iter_mut() {
+                if v.is_nan() {
+                    nan_count += 1
+                } else {
+                    *v = v.clamp(min, max)
+                }
+            }
+            let out = dev.storage_from_cpu_storage(&crate::CpuStorage::F32(out))?;
+            self.substitute_exception_rows(out.as_cuda_slice::<f32>()?, 0, elem_count, "f32")?;
+            return Ok((out, dev.htod_sync_copy(&[nan_count]).w()?));
+        }
+        let DequantizeLaunch {
+            kernel_name,
+            block_dim,
+            num_blocks,
+            nb32,
+        } = dequantize_clamped_launch(self.dtype, elem_count)?;
+        let func = dev.get_or_load_func(&kernel_name, candle_kernels::QUANTIZED)?;
+        let dst = unsafe { dev.alloc::<f32>(elem_count).w()? };
+        let nan_count = dev.alloc_zeros::<u32>(1).w()?;
+        let cfg = cudarc::driver::LaunchConfig {
+            grid_dim: (num_blocks as u32, 1, 1),
+            block_dim: (block_dim as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let scope = trace_launch(dev, &kernel_name, self.dtype)?;
+        if let Some(nb32) = nb32 {
+            let params = (&*self.data, &dst, nb32, min, max, &nan_count);
+            unsafe { launch(func, dev, cfg, params) }?;
+        } else {
+            let params = (&*self.data, &dst, min, max, &nan_count);
+            unsafe { launch(func, dev, cfg, params) }?;
+        }
+        scope.end(dev)?;
+        self.substitute_exception_rows(&dst, 0, elem_count, "f32")?;
+        Ok((CudaStorage::wrap_cuda_slice(dst, dev.clone()), nan_count))
+    }
+
+    /// Dequantizes the blocks `[block_start, block_end)` only. For weights with rows of `ncols`
+    /// elements, the range has to start and end on a row boundary so that the result is a
+    /// contiguous `(rows, ncols)` f32 storage.
+    pub fn dequantize_range(
+        &self,
+        block_start: usize,
+        block_end: usize,
+        ncols: usize,
+    ) -> Result<CudaStorage> {
+        self.check_standard_layout("dequantize_range")?;
+        count_dequantize();
+        let (block_size, type_size) = (self.dtype.block_size(), self.dtype.type_size());
+        let num_blocks = self.data.len() / type_size;
+        if block_start > block_end || block_end > num_blocks {
+            crate::bail!("invalid block range {block_start}..{block_end} for {num_blocks} blocks")
+        }
+        if ncols == 0 || ncols % block_size != 0 {
+            crate::bail!("ncols {ncols} is not a multiple of the block size {block_size}")
+        }
+        let blocks_per_row = ncols / block_size;
+        if block_start % blocks_per_row != 0 || block_end % blocks_per_row != 0 {
+            crate::bail!(
+                "block range {block_start}..{block_end} is not aligned on rows of {blocks_per_row} blocks"
+            )
+        }
+        if let Some(exceptions) = &self.exceptions {
+            if exceptions.ncols != ncols {
+                crate::bail!(
+                    "dequantize_range: ncols {ncols} does not match the {} values of the exception rows",
+                    exceptions.ncols
+                )
+            }
+        }
+        let elem_count = (block_end - block_start) * block_size;
+        let data = self
+            .data
+            .slice(block_start * type_size..block_end * type_size);
+        // Flushes like `dequantize` so that the chunked matmuls match the unchunked ones.
+        let ftz = QuantCudaConfig::for_device(self.device()).dequantize_ftz;
+        let out = if self.has_fast_dequantize_kernel() && ftz {
+            dequantize_ftz(&data, self.dtype, elem_count, self.device())?
+        } else if self.has_fast_dequantize_kernel() {
+            dequantize::<f32>(&data, self.dtype, elem_count, self.device())?
+        } else {
+            let buffer = self.device.dtoh_sync_copy(&data).w()?;
+            let mut out = dequantize_on_cpu(&buffer, self.dtype, elem_count)?;
+            if ftz {
+                flush_subnormals(&mut out)
+            }
+            self.device
+                .storage_from_cpu_storage(&crate::CpuStorage::F32(out))?
+        };
+        let first_row = block_start / blocks_per_row;
+        self.substitute_exception_rows(out.as_cuda_slice::<f32>()?, first_row, elem_count, "f32")?;
+        Ok(out)
+    }
+
+    /// Dequantizes the weights and returns them on the host. The fast dequantization kernels are
+    /// used when available, see [`QCudaStorage::dequantize_to_pinned`] to copy the result to a
+    /// reusable pinned buffer instead.
+    pub fn dequantize_to_host(&self, elem_count: usize) -> Result<Vec<f32>> {
+        self.check_standard_layout("dequantize_to_host")?;
+        super::cuda_graph::check_not_capturing(self.device(), "dequantize_to_host")?;
+        if !self.has_fast_dequantize_kernel() && self.exceptions.is_none() {
+            let buffer = self.device.dtoh_sync_copy(&*self.data).w()?;
+            return dequantize_on_cpu(&buffer, self.dtype, elem_count);
+        }
+        let out = self.dequantize(elem_count)?;
+        self.device.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()
+    }
+
+    /// Same as [`QCudaStorage::dequantize_to_host`] but the values are copied to the first
+    /// `elem_count` elements of `host`. The page-locked buffer avoids the staging copy of pageable
+    /// memory, it is meant to be reused across calls, e.g. when exporting all the weights.
+    pub fn dequantize_to_pinned(
+        &self,
+        elem_count: usize,
+        host: &mut PinnedHostBuffer<f32>,
+    ) -> Result<()> {
+        self.check_standard_layout("dequantize_to_pinned")?;
+        if host.len() < elem_count {
+            crate::bail!(
+                "pinned buffer of {} elements too small for {elem_count} values",
+                host.len()
+            )
+        }
+        let host = &mut host.as_mut_slice()[..elem_count];
+        if !self.has_fast_dequantize_kernel() && self.exceptions.is_none() {
+            let buffer = self.device.dtoh_sync_copy(&*self.data).w()?;
+            host.copy_from_slice(&dequantize_on_cpu(&buffer, self.dtype, elem_count)?);
+            return Ok(());
+        }
+        let out = self.dequantize(elem_count)?;
+        let out = out.as_cuda_slice::<f32>()?;
+        self.device.synchronize()?;
+        unsafe { cudarc::driver::result::memcpy_dtoh_sync(host, *out.device_ptr()) }.w()?;
+        Ok(())
+    }
+
+    pub fn dequantize_f16(&self, elem_count: usize) -> Result<CudaStorage> {
+        use crate::backend::BackendStorage;
+
+        self.check_standard_layout("dequantize_f16")?;
+        count_dequantize();
+        if self.has_fast_dequantize_kernel() {
+            let out = dequantize::<half::f16>(
+                &self.data.slice(..),
+                self.dtype,
+                elem_count,
+                self.device(),
+            )?;
+            self.substitute_exception_rows(
+                out.as_cuda_slice::<half::f16>()?,
+                0,
+                elem_count,
+                "f16",
+            )?;
+            return Ok(out);
+        }
+        self.dequantize(elem_count)?
+            .to_dtype(&crate::Layout::contiguous(elem_count), crate::DType::F16)
+    }
+
+    /// Same as [`QCudaStorage::dequantize_f16`] going through the disk cache of
+    /// [`DequantCache`] when a cache directory is set: the f16 values are copied from the entry
+    /// matching the checksum of the weights if there is one, otherwise they are dequantized and
+    /// written to a new entry. A failure to write the entry does not change the result, the error
+    /// is kept for [`DequantCache::take_error`].
+    pub fn dequantize_cached(&self, elem_count: usize) -> Result<CudaStorage> {
+        self.check_standard_layout("dequantize_cached")?;
+        // The checksum only covers the quantized blocks, not the exception rows.
+        let dir = match DequantCache::cache_dir() {
+            Some(dir) if elem_count > 0 && self.exceptions.is_none() => dir,
+            _ => return self.dequantize_f16(elem_count),
+        };
+        let path = DequantCache::entry_path(&dir, self.checksum()?, self.dtype, elem_count);
+        if let Some(out) = DequantCache::load(&path, self.device(), elem_count)? {
+            return Ok(CudaStorage::wrap_cuda_slice(out, self.device.clone()));
+        }
+        let out = self.dequantize_f16(elem_count)?;
+        if let Err(err) = DequantCache::store(&path, self.device(), out.as_cuda_slice()?) {
+            DequantCache::set_error(err.with_path(&path))
+        }
+        Ok(out)
+    }
+
+    /// Dequantizes the first `elem_count` values into `dst[offset..offset + elem_count]`, the rest
+    /// of `dst` is left untouched. This lets several tensors, e.g. the shards of a fused weight, be
+    /// dequantized into a single buffer without an allocation per piece nor a final concat.
+    /// `elem_count` has to be a multiple of the block size.
+    pub fn dequantize_into_at(
+        &self,
+        dst: &mut CudaSlice<f32>,
+        offset: usize,
+        elem_count: usize,
+    ) -> Result<()> {
+        self.check_standard_layout("dequantize_into_at")?;
+        let block_size = self.dtype.block_size();
+        if elem_count % block_size != 0 || elem_count > self.elem_count() {
+            crate::bail!(
+                "dequantize_into_at: unexpected elem_count {elem_count} for {} {:?} values",
+                self.elem_count(),
+                self.dtype
+            )
+        }
+        let end = match offset.checked_add(elem_count) {
+            Some(end) if end <= dst.len() => end,
+            _ => crate::bail!(
+                "dequantize_into_at: {elem_count} values at offset {offset} are out of bounds for a buffer of {}",
+                dst.len()
+            ),
+        };
+        count_dequantize();
+        let dev = self.device();
+        let mut dst = dst.slice_mut(offset..end);
+        if self.has_fast_dequantize_kernel() {
+            let launch = dequantize_launch(self.dtype, elem_count, false)?;
+            launch_dequantize(&self.data.slice(..), self.dtype, launch, &mut dst, dev)?;
+        } else {
+            super::cuda_graph::check_not_capturing(dev, "dequantizing on cpu")?;
+            let buffer = self.device.dtoh_sync_copy(&*self.data).w()?;
+            let out = dequantize_on_cpu(&buffer, self.dtype, elem_count)?;
+            dev.htod_sync_copy_into(&out, &mut dst).w()?;
+        }
+        self.substitute_exception_rows(&mut dst, 0, elem_count, "f32")
+    }
+
+    /// Dequantizes `(nrows, ncols)` weights into a column-major f32 buffer, i.e. the row-major
+    /// `(ncols, nrows)` transpose, as expected by cuBLAS and other Fortran-order consumers. The
+    /// dequantize kernels write each value at its transposed position directly so there is no
+    /// separate transpose pass.
+    pub fn dequantize_colmajor(
+        &self,
+        elem_count: usize,
+        nrows: usize,
+        ncols: usize,
+    ) -> Result<CudaStorage> {
+        self.check_standard_layout("dequantize_colmajor")?;
+        self.check_no_exception_rows("dequantize_colmajor")?;
+        if nrows * ncols != elem_count {
+            crate::bail!(
+                "dequantize_colmajor: ({nrows}, {ncols}) does not hold {elem_count} elements"
+            )
+        }
+        count_dequantize();
+        let dev = self.device();
+        if !self.has_fast_dequantize_kernel() {
+            let buffer = self.device.dtoh_sync_copy(&*self.data).w()?;
+            let out = dequantize_on_cpu(&buffer, self.dtype, elem_count)?;
+            let mut transposed = vec![0f32; elem_count];
+            for (i, v) in out.iter().enumerate() {
+                transposed[(i % ncols) * nrows + i / ncols] = *v
+            }
+            return dev.storage_from_cpu_storage(&crate::CpuStorage::F32(transposed));
+        }
+        let (nrows_i, ncols_i) = (kernel_dim(nrows, "nrows")?, kernel_dim(ncols, "ncols")?);
+        let DequantizeLaunch {
+            kernel_name,
+            block_dim,
+            num_blocks,
+            nb32,
+        } = dequantize_colmajor_launch(self.dtype, elem_count)?;
+        let func = dev.get_or_load_func(&kernel_name, candle_kernels::QUANTIZED)?;
+        let dst = unsafe { dev.alloc::<f32>(elem_count).w()? };
+        let cfg = cudarc::driver::LaunchConfig {
+            grid_dim: (num_blocks as u32, 1, 1),
+            block_dim: (block_dim as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let scope = trace_launch(dev, &kernel_name, self.dtype)?;
+        if let Some(nb32) = nb32 {
+            let params = (&*self.data, &dst, nb32, nrows_i, ncols_i);
+            unsafe { launch(func, dev, cfg, params) }?;
+        } else {
+            let params = (&*self.data, &dst, nrows_i, ncols_i);
+            unsafe { launch(func, dev, cfg, params) }?;
+        }
+        scope.end(dev)?;
+        Ok(CudaStorage::wrap_cuda_slice(dst, dev.clone()))
+    }
+
+    /// Dequantizes the weights to f32 like [`QCudaStorage::dequantize`] and computes their
+    /// [`WeightStats`] in the same pass, the kernel accumulates per cuda block partial stats that
+    /// are reduced on the host.
+    pub fn dequantize_with_stats(&self, elem_count: usize) -> Result<(CudaStorage, WeightStats)> {
+        self.check_standard_layout("dequantize_with_stats")?;
+        self.check_no_exception_rows("dequantize_with_stats")?;
+        if elem_count == 0 {
+            crate::bail!("dequantize_with_stats: no elements to compute stats on")
+        }
+        count_dequantize();
+        let dev = self.device();
+        if !self.has_fast_dequantize_kernel() {
+            let buffer = self.device.dtoh_sync_copy(&*self.data).w()?;
+            let out = dequantize_on_cpu(&buffer, self.dtype, elem_count)?;
+            let stats = WeightStats::from_values(&out);
+            let out = dev.storage_from_cpu_storage(&crate::CpuStorage::F32(out))?;
+            return Ok((out, stats));
+        }
+        let DequantizeLaunch {
+            kernel_name,
+            block_dim,
+            num_blocks,
+            nb32,
+        } = dequantize_stats_launch(self.dtype, elem_count)?;
+        let func = dev.get_or_load_func(&kernel_name, candle_kernels::QUANTIZED)?;
+        let dst = unsafe { dev.alloc::<f32>(elem_count).w()? };
+        // Every cuda block writes its five partial stats.
+        let partials = unsafe { dev.alloc::<f32>(5 * num_blocks).w()? };
+        let cfg = cudarc::driver::LaunchConfig {
+            grid_dim: (num_blocks as u32, 1, 1),
+            block_dim: (block_dim as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let scope = trace_launch(dev, &kernel_name, self.dtype)?;
+        if let Some(nb32) = nb32 {
+            let params = (&*self.data, &dst, nb32, &partials);
+            unsafe { launch(func, dev, cfg, params) }?;
+        } else {
+            let params = (&*self.data, &dst, &partials);
+            unsafe { launch(func, dev, cfg, params) }?;
+        }
+        scope.end(dev)?;
+        let partials = dev.dtoh_sync_copy(&partials).w()?;
+        let mut stats = WeightStats {
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
+            mean: 0.,
+            abs_max: 0.,
+            nan_count: 0,
+        };
+        let mut sum = 0f64;
+        for p in partials.chunks_exact(5) {
+            stats.min = stats.min.min(p[0]);
+            stats.max = stats.max.max(p[1]);
+            sum += p[2] as f64;
+            stats.abs_max = stats.abs_max.max(p[3]);
+            stats.nan_count += p[4] as usize;
+        }
+        stats.mean = (sum / (elem_count - stats.nan_count) as f64) as f32;
+        Ok((CudaStorage::wrap_cuda_slice(dst, dev.clone()), stats))
+    }
+
+    /// The energy of each row of the `(nrows, ncols)` weights, i.e. the sum of the squares of its
+    /// dequantized values, as `nrows` f32 values. The dequantize kernel accumulates the energies
+    /// directly rather than writing the weights so no dense copy is allocated. Rows with a low
+    /// energy are candidates for pruning or a coarser quantization.
+    pub fn dequantize_row_energy(&self, nrows: usize, ncols: usize) -> Result<CudaStorage> {
+        self.check_standard_layout("dequantize_row_energy")?;
+        self.check_no_exception_rows("dequantize_row_energy")?;
+        let elem_count = nrows * ncols;
+        if elem_count == 0 || elem_count != self.elem_count() {
+            crate::bail!(
+                "dequantize_row_energy: ({nrows}, {ncols}) does not match {} elements",
+                self.elem_count()
+            )
+        }
+        count_dequantize();
+        let dev = self.device();
+        if !self.has_fast_dequantize_kernel() {
+            let buffer = self.device.dtoh_sync_copy(&*self.data).w()?;
+            let out = dequantize_on_cpu(&buffer, self.dtype, elem_count)?;
+            let energy: Vec<f32> = out
+                .chunks_exact(ncols)
+                .map(|row| row.iter().map(|v| v * v).sum())
+                .collect();
+            return dev.storage_from_cpu_storage(&crate::CpuStorage::F32(energy));
+        }
+        let ncols_i = kernel_dim(ncols, "ncols")?;
+        let DequantizeLaunch {
+            kernel_name,
+            block_dim,
+            num_blocks,
+            nb32,
+        } = dequantize_energy_launch(self.dtype, elem_count)?;
+        let func = dev.get_or_load_func(&kernel_name, candle_kernels::QUANTIZED)?;
+        let energy = dev.alloc_zeros::<f32>(nrows).w()?;
+        let cfg = cudarc::driver::LaunchConfig {
+            grid_dim: (num_blocks as u32, 1, 1),
+            block_dim: (block_dim as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let scope = trace_launch(dev, &kernel_name, self.dtype)?;
+        if let Some(nb32) = nb32 {
+            let params = (&*self.data, &energy, nb32, ncols_i);
+            unsafe { launch(func, dev, cfg, params) }?;
+        } else {
+            let params = (&*self.data, &energy, ncols_i);
+            unsafe { launch(func, dev, cfg, params) }?;
+        }
+        scope.end(dev)?;
+        Ok(CudaStorage::wrap_cuda_slice(energy, dev.clone()))
+    }
+
+    /// Extracts the scales of the quantized blocks as f32 values, without dequantizing the
+    /// weights. The legacy quants have a scale per block so this returns `num_blocks` values. The
+    /// k-quants have a scale per sub-block, 16 for q2k, q3k and q6k and 8 for q4k and q5k, which
+    /// is returned multiplied by the super-block scale in the order of the block scale fields,
+    /// i.e. `num_blocks * scales_per_block` values. The mins of the blocks are not included.
+    pub fn dequantize_scales(&self) -> Result<CudaStorage> {
+        self.check_standard_layout("dequantize_scales")?;
+        let (kernel_name, scales_per_block) = dequantize_scales_kernel(self.dtype)?;
+        let num_blocks = self.data.len() / self.dtype.type_size();
+        let n_scales = num_blocks * scales_per_block;
+        let dev = self.device();
+        if n_scales == 0 {
+            let dst = dev.alloc_zeros::<f32>(0).w()?;
+            return Ok(CudaStorage::wrap_cuda_slice(dst, dev.clone()));
+        }
+        let func = dev.get_or_load_func(kernel_name, candle_kernels::QUANTIZED)?;
+        let dst = unsafe { dev.alloc::<f32>(n_scales).w()? };
+        let cfg = cudarc::driver::LaunchConfig {
+            grid_dim: (ceil_div(n_scales, CUDA_DEQUANTIZE_BLOCK_SIZE) as u32, 1, 1),
+            block_dim: (CUDA_DEQUANTIZE_BLOCK_SIZE as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let params = (&*self.data, &dst, kernel_dim(n_scales, "n_scales")?);
+        let scope = trace_launch(dev, kernel_name, self.dtype)?;
+        unsafe { launch(func, dev, cfg, params) }?;
+        scope.end(dev)?;
+        Ok(CudaStorage::wrap_cuda_slice(dst, dev.clone()))
+    }
+
+    /// Dequantizes each storage to f32 on its own, returning one result per storage in the same
+    /// order so that bulk conversion tools can report the tensors that failed, e.g. with an
+    /// unsupported dtype or layout, and carry on with the others. The device is synchronized after
+    /// each tensor so that kernel errors are attributed to the tensor that caused them.
+    pub fn dequantize_batch(storages: &[&QCudaStorage]) -> Vec<Result<CudaStorage>> {
+        storages
+            .iter()
+            .map(|storage| {
+                let out = storage.dequantize(storage.elem_count())?;
+                storage.device.synchronize()?;
+                Ok(out)
+            })
+            .collect()
+    }
+
+    /// Dequantizes `n` quantized `(nrows, ncols)` weights, e.g. the q/k/v projections, into a
+    /// single f32 buffer laid out according to `mode`. The weights can have different dtypes but
+    /// must all have the same shape and live on the same device.
+    pub fn dequantize_interleaved(
+        storages: &[&QCudaStorage],
+        ncols: usize,
+        mode: InterleaveMode,
+    ) -> Result<CudaStorage> {
+        use crate::backend::BackendStorage;
+
+        let first = match storages.first() {
+            Some(v) => v,
+            None => crate::bail!("dequantize_interleaved requires at least one storage"),
+        };
+        let elem_count = first.elem_count();
+        if ncols == 0 || elem_count % ncols != 0 {
+            crate::bail!(
+                "dequantize_interleaved: unexpected ncols {ncols} for {elem_count} elements"
+            )
+        }
+        for storage in storages.iter() {
+            if storage.elem_count() != elem_count {
+                crate::bail!(
+                    "dequantize_interleaved: element count mismatch {elem_count} {}",
+                    storage.elem_count()
+                )
+            }
+            if storage.device.id() != first.device.id() {
+                crate::bail!("dequantize_interleaved: all storages must be on the same device")
+            }
+        }
+        let (n, nrows) = (storages.len(), elem_count / ncols);
+        let dst = unsafe { first.device.alloc::<f32>(n * elem_count).w()? };
+        let mut dst = CudaStorage::wrap_cuda_slice(dst, first.device.clone());
+        for (i, storage) in storages.iter().enumerate() {
+            let src = storage.dequantize(elem_count)?;
+            let (dst_s, dst_o) = match mode {
+                InterleaveMode::Stacked => (ncols, i * elem_count),
+                InterleaveMode::RowInterleaved => (n * ncols, i * ncols),
+            };
+            src.copy2d(&mut dst, nrows, ncols, ncols, dst_s, 0, dst_o)?;
+        }
+        Ok(dst)
+    }
+
+    /// Concatenates quantized `(rows_i, ncols)` weights along the row dimension, e.g. to build a
+    /// fused qkv projection. As a storage does not track its shape, `ncols` is provided by the
+    /// caller and each storage must hold a whole number of rows. Rows are made of full blocks so
+    /// this is a lossless byte concatenation. The exception rows of the storages are kept, shifted
+    /// by the rows of the storages before them.
+    pub fn cat_rows(storages: &[&QCudaStorage], ncols: usize) -> Result<QCudaStorage> {
+        let (first, rest) = match storages.split_first() {
+            Some(v) => v,
+            None => crate::bail!("cat_rows requires at least one storage"),
+        };
+        let dtype = first.dtype;
+        let device = first.device.clone();
+        if ncols == 0 || ncols % dtype.block_size() != 0 {
+            crate::bail!("cat_rows: ncols {ncols} is not a multiple of the {dtype:?} block size")
+        }
+        for storage in rest.iter() {
+            if storage.dtype != dtype {
+                crate::bail!("cat_rows: dtype mismatch {dtype:?} {:?}", storage.dtype)
+            }
+            if storage.device.id() != device.id() {
+                crate::bail!("cat_rows: all storages must be on the same device")
+            }
+        }
+        for storage in storages.iter() {
+            storage.check_standard_layout("cat_rows")?;
+            if storage.transposed_in_file {
+                crate::bail!("cat_rows: weights stored transposed in the file are not supported")
+            }
+            if storage.elem_count() % ncols != 0 {
+                crate::bail!(
+                    "cat_rows: {} elements is not a whole number of rows of {ncols}",
+                    storage.elem_count()
+                )
+            }
+        }
+        let exceptions = cat_exception_rows(storages, ncols)?;
+        let size_in_bytes = storages.iter().map(|s| s.data.len()).sum();
+        let mut data = unsafe { device.alloc::<u8>(size_in_bytes).w()? };
+        let mut offset = 0;
+        for storage in storages.iter() {
+            let len = storage.data.len();
+            let mut dst = data.slice_mut(offset..offset + len);
+            device.dtod_copy(&*storage.data, &mut dst).w()?;
+            offset += len;
+        }
+        Ok(QCudaStorage {
+            _vram: std::sync::Arc::new(VramTicket::new(&device, VramKind::Quantized, data.len())),
+            data: std::sync::Arc::new(data),
+            dtype,
+            device,
+            embedding_layout: false,
+            shape: None,
+            transposed_in_file: false,
+            tuned: None,
+            exceptions: exceptions.map(std::sync::Arc::new),
+        })
+    }
+
+    pub fn quantize(&mut self, src: &CudaStorage) -> Result<()> {
+        self.quantize_on_host(src, |storage, src| storage.from_float(src))
+    }
+
+    /// Same as [`QCudaStorage::quantize`] with the quantization errors weighted per column by the
+    /// importance matrix `imatrix`, `src` is made of rows of `imatrix.len()` values. This runs on
+    /// cpu and is only supported for q4_0, q5_0 and q8_0.
+    pub fn quantize_with_imatrix(&mut self, src: &CudaStorage, imatrix: &[f32]) -> Result<()> {
+        self.quantize_on_host(src, |storage, src| {
+            if imatrix.is_empty() || src.len() % imatrix.len() != 0 {
+                crate::bail!(
+                    "{} values do not make whole rows of the {} values of the importance matrix",
+                    src.len(),
+                    imatrix.len()
+                )
+            }
+            storage.from_float_imatrix(src, imatrix, imatrix.len())
+        })
+    }
+
+    // Runs the quantization of `src` on cpu with `quantize`, which fills the cpu blocks of the
+    // storage dtype, and replaces the weights with the result.
+    fn quantize_on_host(
+        &mut self,
+        src: &CudaStorage,
+        quantize: impl FnOnce(&mut dyn super::QuantizedType, &[f32]) -> Result<()>,
+    ) -> Result<()> {
+        let src = match &src.slice {
+            crate::cuda_backend::CudaStorageSlice::F32(data) => {
+                self.device.dtoh_sync_copy(data).w()?
+            }
+            _ => crate::bail!("only f32 can be quantized"),
+        };
+        let mut storage = self.dtype.cpu_zeros(src.len());
+        quantize(storage.as_mut(), &src)?;
+        let qcpu_storage = QStorage::Cpu(storage);
+        let data = qcpu_storage.data()?;
+        let data = convert_host_blocks(self.dtype, data.as_ref())?;
+        let data = self.device.htod_sync_copy(data.as_ref()).w()?;
+        self._vram = std::sync::Arc::new(VramTicket::new(
+            &self.device,
+            VramKind::Quantized,
+            data.len(),
+        ));
+        self.data = std::sync::Arc::new(data);
+        self.embedding_layout = false;
+        self.exceptions = None;
+        Ok(())
+    }
+
+    /// Same as [`QCudaStorage::quantize`] but the result is then dequantized and compared to
+    /// `src`, this requires an extra dequantize pass and host copies.
+    pub fn quantize_with_report(&mut self, src: &CudaStorage) -> Result<QuantReport> {
+        self.quantize(src)?;
+        let src = match &src.slice {
+            crate::cuda_backend::CudaStorageSlice::F32(data) => {
+                self.device.dtoh_sync_copy(data).w()?
+            }
+            _ => crate::bail!("only f32 can be quantized"),
+        };
+        let dequantized = self.dequantize_to_host(src.len())?;
+        Ok(QuantReport::new(&src, &dequantized))
+    }
+
+    pub fn storage_size_in_bytes(&self) -> usize {
+        self.data.len()
+    }
+
+    // The buffer of this storage for in place updates. A buffer shared with clones is copied
+    // first so that the clones are left untouched.
+    fn data_mut(&mut self) -> Result<&mut CudaSlice<u8>> {
+        if std::sync::Arc::get_mut(&mut self.data).is_none() {
+            let data = self.data.try_clone().w()?;
+            self._vram = std::sync::Arc::new(VramTicket::new(
+                &self.device,
+                VramKind::Quantized,
+                data.len(),
+            ));
+            self.data = std::sync::Arc::new(data);
+        }
+        match std::sync::Arc::get_mut(&mut self.data) {
+            Some(data) => Ok(data),
+            None => crate::bail!("the weights buffer is still shared after being copied"),
+        }
+    }
+
+    /// Whether `self` and `other` use the same device buffer. Cloning a storage shares its
+    /// buffer rather than copying it, e.g. for the embedding and output projection of a model
+    /// with tied weights. [`QCudaStorage::quantize`] and [`QCudaStorage::scale_in_place`] give the
+    /// storage a buffer of its own, the clones keep the previous weights.
+    pub fn shares_data_with(&self, other: &QCudaStorage) -> bool {
+        std::sync::Arc::ptr_eq(&self.data, &other.data)
+    }
+
+    /// A clone of the storage labelled as `dtype`, sharing the same bytes. This is meant to recover
+    /// from weights loaded with the wrong dtype and only relabels them: the bytes are neither
+    /// converted nor checked to be valid `dtype` blocks, use [`QCudaStorage::dequantize`] and
+    /// [`QCudaStorage::quantize`] to actually convert weights. This fails unless
+    /// - the data is made of whole `dtype` blocks,
+    /// - the storage uses the standard layout, the embedding layout depends on the block structure,
+    /// - the element count stays the same when the storage records a shape or has exception rows.
+    ///
+    /// The kernel parameters picked by `autotune` are specific to a dtype and are not kept.
+    pub fn clone_dtype_as(&self, dtype: GgmlDType) -> Result<Self> {
+        self.check_standard_layout("clone_dtype_as")?;
+        let elem_count = data_elem_count(self.data.len(), dtype)?;
+        if let Some(shape) = &self.shape {
+            if shape.elem_count() != elem_count {
+                crate::bail!(
+                    "cannot relabel {:?} weights of shape {shape:?} as {dtype:?}, {elem_count} elements",
+                    self.dtype
+                )
+            }
+        }
+        if self.exceptions.is_some() && self.elem_count() != elem_count {
+            crate::bail!(
+                "cannot relabel {:?} weights with exception rows as {dtype:?}, {elem_count} elements",
+                self.dtype
+            )
+        }
+        Ok(Self {
+            dtype,
+            tuned: None,
+            ..self.clone()
+        })
+    }
+
+    /// The device address of the weights.
+    pub fn device_ptr(&self) -> u64 {
+        *self.data.device_ptr()
+    }
+
+    /// Prefetches the weights to their device on `stream`, the device stream when `None`, so that
+    /// they are resident when used, e.g. for the next layer while the current one runs. This only
+    /// applies to the managed weights of [`load_quantized_managed`] on devices with concurrent
+    /// managed access, as with unified memory, and returns whether a prefetch was issued. This is
+    /// a no-op for the other weights, which always reside on the device.
+    pub fn prefetch(&self, stream: Option<&cudarc::driver::CudaStream>) -> Result<bool> {
+        use cudarc::driver::sys;
+        use sys::CUdevice_attribute as A;
+
+        let dev = self.device();
+        if self.data.len() == 0
+            || dev
+                .attribute(A::CU_DEVICE_ATTRIBUTE_CONCURRENT_MANAGED_ACCESS)
+                .w()?
+                == 0
+        {
+            return Ok(false);
+        }
+        dev.bind_to_thread().w()?;
+        let ptr = *self.data.device_ptr();
+        let mut is_managed: std::os::raw::c_uint = 0;
+        unsafe {
+            sys::cuPointerGetAttribute(
+                &mut is_managed as *mut std::os::raw::c_uint as *mut std::ffi::c_void,
+                sys::CUpointer_attribute::CU_POINTER_ATTRIBUTE_IS_MANAGED,
+                ptr,
+            )
+        }
+        .result()
+        .w()?;
+        if is_managed == 0 {
+            return Ok(false);
+        }
+        let stream = match stream {
+            Some(stream) => stream.stream,
+            None => *dev.cu_stream(),
+        };
+        let mut cu_device: sys::CUdevice = 0;
+        unsafe { sys::cuDeviceGet(&mut cu_device, dev.ordinal() as std::os::raw::c_int) }
+            .result()
+            .w()?;
+        unsafe { sys::cuMemPrefetchAsync(ptr, self.data.len(), cu_device, stream) }
+            .result()
+            .w()?;
+        Ok(true)
+    }
+
+    /// The device memory used by `storages` and by the weights resident in `pools`, all on
+    /// `device`, together with the device wide cache usage of [`quant_vram_report`]. Use
+    /// [`quant_vram_report`] to account for all the quantized weights of the device instead.
+    pub fn memory_layout_report(
+        device: &CudaDevice,
+        storages: &[&QCudaStorage],
+        pools: &[&LazyQCudaPool],
+    ) -> Result<VramReport> {
+        let same_device = storages.iter().all(|s| s.device.id() == device.id())
+            && pools.iter().all(|p| p.device().id() == device.id());
+        if !same_device {
+            crate::bail!("memory_layout_report expects storages and pools on {device:?}")
+        }
+        // The clones of a storage share its buffer, which is only counted once.
+        let mut storage_bytes = 0;
+        for (i, storage) in storages.iter().enumerate() {
+            if !storages[..i].iter().any(|s| s.shares_data_with(storage)) {
+                storage_bytes += storage.storage_size_in_bytes()
+            }
+        }
+        let pool_bytes: usize = pools.iter().map(|p| p.used_in_bytes()).sum();
+        Ok(VramReport {
+            quantized_bytes: storage_bytes + pool_bytes,
+            lazy_pool_capacity_bytes: pools.iter().map(|p| p.capacity_in_bytes()).sum(),
+            ..VramReport::for_device(device)?
+        })
+    }
+
+    /// The number of elements held by this storage once dequantized.
+    pub fn elem_count(&self) -> usize {
+        let info = self.dtype_info();
+        self.data.len() / info.type_size * info.block_size
+    }
+
+    /// Multiplies the weights by `factor` in place by only rescaling the f16 block scales, the
+    /// quantized integers are left untouched. This is supported for the dtypes where all the block
+    /// scales and mins are f16 values applied multiplicatively: f16, q4_0, q4_1, q5_0, q5_1, q8_0,
+    /// q8_1, q2_k, q3_k, q4_k, q5_k and q6_k. The only loss comes from rounding the scales to f16.
+    pub fn scale_in_place(&mut self, factor: f32) -> Result<()> {
+        self.check_standard_layout("scale_in_place")?;
+        if self.exceptions.is_some() {
+            crate::bail!("scale_in_place is not supported with exception rows")
+        }
+        let type_size = self.dtype.type_size();
+        // Byte offset of the f16 scales within a block and their number.
+        let (offset, count) = match self.dtype {
+            GgmlDType::F16 | GgmlDType::Q4_0 | GgmlDType::Q5_0 | GgmlDType::Q8_0 => (0, 1),
+            GgmlDType::Q4_1 | GgmlDType::Q5_1 | GgmlDType::Q8_1 => (0, 2),
+            GgmlDType::Q4K | GgmlDType::Q5K => (0, 2),
+            GgmlDType::Q2K => (type_size - 4, 2),
+            GgmlDType::Q3K | GgmlDType::Q6K => (type_size - 2, 1),
+            GgmlDType::F32 | GgmlDType::BF16 | GgmlDType::Q8K => {
+                crate::bail!("scale_in_place is not supported for {:?}", self.dtype)
+            }
+        };
+        let num_blocks = self.data.len() / type_size;
+        let dev = self.device.clone();
+        let func = dev.get_or_load_func("scale_half_fields", candle_kernels::QUANTIZED)?;
+        let cfg = cudarc::driver::LaunchConfig {
+            grid_dim: (ceil_div(num_blocks, CUDA_QUANTIZE_BLOCK_SIZE) as u32, 1, 1),
+            block_dim: (CUDA_QUANTIZE_BLOCK_SIZE as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let params = (
+            &*self.data_mut()?,
+            num_blocks as i32,
+            type_size as i32,
+            offset as i32,
+            count as i32,
+            factor,
+        );
+        unsafe { launch(func, &dev, cfg, params) }?;
+        Ok(())
+    }
+
+    /// Sanity checks of freshly loaded weights, failing on the first problem found:
+    /// - the data is made of whole `dtype` blocks and is aligned for the kernels,
+    /// - the recorded shape and `expected_shape`, when set, match the element count and each other,
+    /// - the quantization blocks do not straddle rows, i.e. the dimension the blocks run along is
+    ///   a multiple of the block size,
+    /// - with `scan_non_finite`, the dequantized values are all finite. This dequantizes the whole
+    ///   tensor so it is opt-in.
+    pub fn validate(
+        &self,
+        expected_shape: Option<&crate::Shape>,
+        scan_non_finite: bool,
+    ) -> Result<()> {
+        let info = self.dtype_info();
+        let elem_count = data_elem_count(self.data.len(), self.dtype)?;
+        let align = if info.type_size % 4 == 0 { 4 } else { 2 };
+        if (*self.data.device_ptr() as usize) % align != 0 {
+            crate::bail!("the {:?} data is not aligned on {align} bytes", self.dtype)
+        }
+        if let (Some(shape), Some(expected)) = (self.shape.as_ref(), expected_shape) {
+            if shape != expected {
+                crate::bail!("recorded shape {shape:?} does not match expected {expected:?}")
+            }
+        }
+        if let Some(shape) = expected_shape.or(self.shape.as_ref()) {
+            if shape.elem_count() != elem_count {
+                crate::bail!("shape {shape:?} does not match {elem_count} elements")
+            }
+            // The blocks run along the input dimension, or the output one when transposed.
+            let dims = shape.dims();
+            let block_dim = if self.transposed_in_file {
+                dims.len().checked_sub(2).map(|i| dims[i])
+            } else {
+                dims.last().copied()
+            };
+            if let Some(d) = block_dim {
+                if d % info.block_size != 0 {
+                    crate::bail!(
+                        "shape {shape:?} splits {:?} blocks of {} elements across rows",
+                        self.dtype,
+                        info.block_size
+                    )
+                }
+            }
+        }
+        if scan_non_finite {
+            let (_, stats) = self.dequantize_with_stats(elem_count)?;
+            // NaNs propagate to the sum, infinities to the max-abs.
+            if !stats.mean.is_finite() || !stats.abs_max.is_finite() {
+                crate::bail!(
+                    "the dequantized {:?} weights hold non-finite values",
+                    self.dtype
+                )
+            }
+        }
+        Ok(())
+    }
+
+    /// A 64 bits hash of the quantized bytes computed on device, only the result is copied back
+    /// to the host. Identical bytes always produce the same checksum so this can be used to check
+    /// that weights were transferred correctly or have not been corrupted in device memory.
+    pub fn checksum(&self) -> Result<u64> {
+        let dev = self.device();
+        let num_words = ceil_div(self.data.len(), 8);
+        let num_blocks = ceil_div(num_words, CUDA_QUANTIZE_BLOCK_SIZE);
+        let func = dev.get_or_load_func("checksum_u8", candle_kernels::QUANTIZED)?;
+        let dst = dev.alloc_zeros::<u64>(1).w()?;
+        let cfg = cudarc::driver::LaunchConfig {
+            grid_dim: (num_blocks as u32, 1, 1),
+            block_dim: (CUDA_QUANTIZE_BLOCK_SIZE as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let params = (&*self.data, &dst, self.data.len());
+        unsafe { launch(func, dev, cfg, params) }?;
+        let dst = dev.dtoh_sync_copy(&dst).w()?;
+        Ok(dst[0])
+    }
+
+    /// Serializes the raw quantized blocks as a safetensors buffer holding a single `u8` tensor
+    /// called `name`, see [`SAFETENSORS_GGML_DTYPE_SUFFIX`] for the metadata convention.
+    pub fn to_safetensors_bytes(&self, name: &str) -> Result<Vec<u8>> {
+        use safetensors::tensor::TensorView;
+
+        self.check_standard_layout("to_safetensors_bytes")?;
+        let data = self.data_to_host()?;
+        let view = TensorView::new(safetensors::Dtype::U8, vec![data.len()], &data)?;
+        let mut views = vec![(name.to_string(), view)];
+        let exceptions = match &self.exceptions {
+            Some(e) => {
+                let rows: Vec<u8> = e.rows()?.iter().flat_map(|v| v.to_le_bytes()).collect();
+                let values: Vec<u8> = e.values()?.iter().flat_map(|v| v.to_le_bytes()).collect();
+                Some((e.num_rows, e.ncols, rows, values))
+            }
+            None => None,
+        };
+        if let Some((num_rows, ncols, rows, values)) = &exceptions {
+            views.push((
+                format!("{name}{SAFETENSORS_EXCEPTION_ROWS_SUFFIX}"),
+                TensorView::new(safetensors::Dtype::U32, vec![*num_rows], rows)?,
+            ));
+            views.push((
+                format!("{name}{SAFETENSORS_EXCEPTION_VALUES_SUFFIX}"),
+                TensorView::new(safetensors::Dtype::F16, vec![*num_rows, *ncols], values)?,
+            ));
+        }
+        let metadata: std::collections::HashMap<String, String> = [
+            (
+                format!("{name}{SAFETENSORS_GGML_DTYPE_SUFFIX}"),
+                self.dtype.to_u32().to_string(),
+            ),
+            (
+                format!("{name}{SAFETENSORS_ELEM_COUNT_SUFFIX}"),
+                self.elem_count().to_string(),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        Ok(safetensors::tensor::serialize(views, &Some(metadata))?)
+    }
+
+    // The raw ggml blocks, copied to the host.
+    pub(crate) fn data_to_host(&self) -> Result<Vec<u8>> {
+        self.device.dtoh_sync_copy(&*self.data).w()
+    }
+
+    /// Loads the quantized tensor `name` from a safetensors buffer produced by
+    /// [`QCudaStorage::to_safetensors_bytes`].
+    pub fn from_safetensors_bytes(device: &CudaDevice, name: &str, data: &[u8]) -> Result<Self> {
+        let (_, metadata) = safetensors::SafeTensors::read_metadata(data)?;
+        let metadata = match metadata.metadata() {
+            Some(metadata) => metadata,
+            None => crate::bail!("missing safetensors metadata for quantized tensor {name}"),
+        };
+        let get = |suffix: &str| -> Result<usize> {
+            let key = format!("{name}{suffix}");
+            match metadata.get(&key) {
+                None => crate::bail!("missing safetensors metadata {key}"),
+                Some(v) => v
+                    .parse::<usize>()
+                    .map_err(|_| crate::Error::Msg(format!("invalid metadata {key}: {v}")).bt()),
+            }
+        };
+        let dtype = GgmlDType::from_u32(get(SAFETENSORS_GGML_DTYPE_SUFFIX)? as u32)?;
+        let elem_count = get(SAFETENSORS_ELEM_COUNT_SUFFIX)?;
+        let st = safetensors::SafeTensors::deserialize(data)?;
+        let view = st.tensor(name)?;
+        if view.dtype() != safetensors::Dtype::U8 {
+            crate::bail!(
+                "quantized tensor {name} is not stored as u8 {:?}",
+                view.dtype()
+            )
+        }
+        let size_in_bytes = ceil_div(elem_count, dtype.block_size()) * dtype.type_size();
+        if view.data().len() != size_in_bytes {
+            crate::bail!(
+                "unexpected size for quantized tensor {name}, {} bytes for {elem_count} {dtype:?}",
+                view.data().len()
+            )
+        }
+        let mut storage = load_quantized_bytes(device, dtype, view.data())?;
+        let rows = st.tensor(&format!("{name}{SAFETENSORS_EXCEPTION_ROWS_SUFFIX}"));
+        let values = st.tensor(&format!("{name}{SAFETENSORS_EXCEPTION_VALUES_SUFFIX}"));
+        match (rows, values) {
+            (Err(_), Err(_)) => {}
+            (Ok(rows), Ok(values)) => {
+                let ncols = match (rows.dtype(), values.dtype(), values.shape()) {
+                    (safetensors::Dtype::U32, safetensors::Dtype::F16, &[_, ncols]) => ncols,
+                    (r, v, shape) => crate::bail!(
+                        "unexpected exception rows for {name}, rows {r:?}, values {v:?} {shape:?}"
+                    ),
+                };
+                let rows: Vec<u32> = rows
+                    .data()
+                    .chunks_exact(4)
+                    .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect();
+                let values: Vec<half::f16> = values
+                    .data()
+                    .chunks_exact(2)
+                    .map(|b| half::f16::from_le_bytes([b[0], b[1]]))
+                    .collect();
+                let exceptions = ExceptionRows::new(device, &rows, &values, ncols)?;
+                storage.set_exception_rows(Some(exceptions))?
+            }
+            _ => crate::bail!("incomplete exception rows for quantized tensor {name}"),
+        }
+        Ok(storage)
+    }
+
+    /// Computes `xs @ w.t()` where `w` has shape `self_shape`. Whatever the layout of the
+    /// activation, the output is a new contiguous row major storage of shape `(.., m, n)`, i.e.
+    /// the activation shape with its last dimension replaced by `n`.
+    ///
+    /// The weights are a single 2d matrix shared by all the batch elements of a `(b, m, k)`
+    /// activation. For attention scores, this handles a quantized `(seq, dim)` K of a single
+    /// head, but not per head K matrices: a `(heads, seq, dim)` K needs a different matrix for
+    /// each batch element, see [`QCudaStorage::qk_matmul`].
+    pub fn fwd(
+        &self,
+        self_shape: &crate::Shape,
+        storage: &CudaStorage,
+        layout: &crate::Layout,
+    ) -> Result<(CudaStorage, crate::Shape)> {
+        self.fwd_with_policy(self_shape, storage, layout, None)
+    }
+
+    /// Same as [`QCudaStorage::fwd`] with the matmul-vec kernel picked by `policy` for this call
+    /// only, `None` uses the configuration of the device. The other threads using the device are
+    /// not affected. The dense path for larger activations does not depend on the policy.
+    pub fn fwd_with_policy(
+        &self,
+        self_shape: &crate::Shape,
+        storage: &CudaStorage,
+        layout: &crate::Layout,
+        policy: Option<QMatMulPolicy>,
+    ) -> Result<(CudaStorage, crate::Shape)> {
+        let _tuned = TunedScope::enter(self.device(), self.tuned);
+        let _policy = PolicyScope::enter(self.device(), policy);
+        let result = match layout.shape().dims() {
+            _ if self.transposed_in_file => self.dequantize_matmul(self_shape, storage, layout),
+            [1, 1, _] | [1, _] => {
+                self.dequantize_matmul_vec(self_shape, storage, layout, None, None)
+            }
+            // Beam search or parallel sampling decode, each sequence is a single vector.
+            &[b, 1, _] if (1..=MAX_BATCHED_VEC).contains(&b) && layout.is_contiguous() => {
+                self.dequantize_matmul_batched_vec(self_shape, storage, layout)
+            }
+            _ => self.dequantize_matmul(self_shape, storage, layout),
+        };
+        let result = match result {
+            Ok((out, out_shape)) if self.exceptions.is_some() => self
+                .exception_rows_matmul(&out, self_shape.dims2()?.0, storage, layout)
+                .map(|()| (out, out_shape)),
+            result => result,
+        };
+        QuantRecorder::on_fwd(self, self_shape, storage, layout, &result);
+        result
+    }
+
+    /// Times the matmul-vec kernels available for this dtype on a single `(1, k)` activation, as
+    /// used for decoding, and keeps the fastest parameters for the following matmuls of this
+    /// storage, see [`TunedKernels`]. This is meant to be called once after loading the weights.
+    pub fn autotune(&mut self, self_shape: &crate::Shape) -> Result<TunedKernels> {
+        // Timed runs per candidate, after a warmup run which also loads the kernel.
+        const ITERS: usize = 10;
+
+        self.check_standard_layout("autotune")?;
+        self.check_not_transposed("autotune")?;
+        let (_n, k) = self_shape.dims2()?;
+        let dev = self.device.clone();
+        let xs = dev.ones_impl(&(1, k).into(), crate::DType::F32)?;
+        let layout = crate::Layout::contiguous((1, k));
+        let config = QuantCudaConfig::for_device(&dev);
+        let mut candidates = vec![];
+        if self.dtype.q81_kernel_name().is_some() {
+            for nwarps in [1, 2, 4, 8] {
+                candidates.push(TunedKernels {
+                    force_dmmv: false,
+                    mmv_y: config.mmv_y,
+                    mmvq_nwarps: nwarps,
+                })
+            }
+        }
+        if self.dtype.dmmv_kernel_name().is_some() {
+            for mmv_y in [1, 2, 4, 8] {
+                candidates.push(TunedKernels {
+                    force_dmmv: true,
+                    mmv_y,
+                    mmvq_nwarps: config.mmvq_nwarps(self.dtype),
+                })
+            }
+        }
+        // The parameters in use before tuning are restored when no candidate gets picked.
+        let previous = self.tuned;
+        let mut best: Option<(std::time::Duration, TunedKernels)> = None;
+        for candidate in candidates {
+            self.tuned = Some(candidate);
+            let elapsed = (|| -> Result<std::time::Duration> {
+                self.fwd(self_shape, &xs, &layout)?;
+                dev.synchronize()?;
+                let start = std::time::Instant::now();
+                for _ in 0..ITERS {
+                    self.fwd(self_shape, &xs, &layout)?;
+                }
+                dev.synchronize()?;
+                Ok(start.elapsed())
+            })();
+            let elapsed = match elapsed {
+                Ok(elapsed) => elapsed,
+                Err(err) => {
+                    self.tuned = previous;
+                    return Err(err);
+                }
+            };
+            let faster = match best {
+                Some((b, _)) => elapsed < b,
+                None => true,
+            };
+            if faster {
+                best = Some((elapsed, candidate))
+            }
+        }
+        match best {
+            Some((_, tuned)) => {
+                self.tuned = Some(tuned);
+                Ok(tuned)
+            }
+            None => {
+                self.tuned = previous;
+                crate::bail!("no matmul-vec kernel to tune for {:?}", self.dtype)
+            }
+        }
+    }
+
+    /// The kernel parameters picked by [`QCudaStorage::autotune`], if any.
+    pub fn tuned_kernels(&self) -> Option<TunedKernels> {
+        self.tuned
+    }
+
+    /// Sets or clears the kernel parameters used by the matmuls of this storage, e.g. to reuse the
+    /// result of [`QCudaStorage::autotune`] for storages with the same dtype and shape.
+    pub fn set_tuned_kernels(&mut self, tuned: Option<TunedKernels>) -> Result<()> {
+        if let Some(tuned) = tuned {
+            tuned.apply(QuantCudaConfig::default()).validate()?
+        }
+        self.tuned = tuned;
+        Ok(())
+    }
+
+    /// Computes the attention scores `q @ k.t()` for each head, where this storage holds a
+    /// quantized K of shape `k_shape = (kv_heads, seq, dim)` and `q` is a f32 `(heads, q_len, dim)`
+    /// activation. The output is a contiguous `(heads, q_len, seq)` f32 storage. With grouped query
+    /// attention, `heads` is a multiple of `kv_heads` and the consecutive query heads of a group
+    /// share their K head, `q` has to be contiguous in this case.
+    ///
+    /// K is dequantized and the heads are multiplied with a single batched gemm, so this does not
+    /// save compute over a dequantized K cache, only memory. There is no matmul-vec path for the
+    /// `q_len == 1` decoding case yet.
+    pub fn qk_matmul(
+        &self,
+        k_shape: &crate::Shape,
+        q: &CudaStorage,
+        q_layout: &crate::Layout,
+    ) -> Result<(CudaStorage, crate::Shape)> {
+        self.check_standard_layout("qk_matmul")?;
+        self.check_not_transposed("qk_matmul")?;
+        f32_activation(q)?;
+        let (kv_heads, seq, dim) = k_shape.dims3()?;
+        let (heads, q_len, q_dim) = q_layout.shape().dims3()?;
+        if q_dim != dim {
+            crate::bail!(
+                "qk_matmul: mismatch on dim {k_shape:?} {:?}",
+                q_layout.shape()
+            )
+        }
+        if kv_heads == 0 || heads % kv_heads != 0 {
+            crate::bail!(
+                "qk_matmul: {heads} query heads cannot be grouped over {kv_heads} kv heads"
+            )
+        }
+        if dim % self.dtype.block_size() != 0 {
+            crate::bail!(
+                "qk_matmul: dim {dim} is not a multiple of the {:?} block size",
+                self.dtype
+            )
+        }
+        let group = heads / kv_heads;
+        let q_layout = if group == 1 {
+            q_layout.clone()
+        } else if q_layout.is_contiguous() {
+            // The query heads of a group are consecutive so they form a single matrix.
+            crate::Layout::contiguous_with_offset(
+                (kv_heads, group * q_len, dim),
+                q_layout.start_offset(),
+            )
+        } else {
+            crate::bail!("qk_matmul: grouped query heads require a contiguous q")
+        };
+        let m = group * q_len;
+        let config = QuantCudaConfig::for_device(self.device());
+        let f16 = config.dequantize_matmul_f16;
+        let elem_count = kv_heads * seq * dim;
+        if elem_count != self.elem_count() {
+            crate::bail!(
+                "qk_matmul: {k_shape:?} does not match the {} elements of K",
+                self.elem_count()
+            )
+        }
+        let k = if f16 {
+            self.dequantize_f16(elem_count)?
+        } else {
+            self.dequantize(elem_count)?
+        };
+        // Each (seq, dim) head of K is used transposed as the (dim, seq) rhs of its batch element.
+        let rhs_l = crate::Layout::new((kv_heads, dim, seq).into(), vec![seq * dim, 1, dim], 0);
+        let (out, _) = dense_matmul(
+            &config,
+            &k,
+            f16,
+            q,
+            &q_layout,
+            (kv_heads, m, seq, dim),
+            &rhs_l,
+        )?;
+        Ok((out, (heads, q_len, seq).into()))
+    }
+
+    /// Returns the path [`QCudaStorage::fwd`] would take for an activation with layout `layout`
+    /// and the current configuration of the device, without running anything.
+    pub fn explain_matmul(
+        &self,
+        self_shape: &crate::Shape,
+        layout: &crate::Layout,
+    ) -> Result<MatMulPlan> {
+        let (n, k) = self_shape.dims2()?;
+        let k2 = match layout.shape().dims().last() {
+            Some(k2) => *k2,
+            None => crate::bail!("unexpected shape for input {:?}", layout.shape()),
+        };
+        if k2 != k {
+            crate::bail!("mismatch on matmul dim {self_shape:?} {:?}", layout.shape())
+        }
+        let config = {
+            let _tuned = TunedScope::enter(self.device(), self.tuned);
+            QuantCudaConfig::for_device(self.device())
+        };
+        let kernel = self.matmul_vec_kernel(&config, k, false);
+        let overflow_threshold = config.q8_1_overflow_threshold;
+        let plan = match layout.shape().dims() {
+            _ if self.transposed_in_file => MatMulPlan::Dequantize {
+                f16: config.dequantize_matmul_f16,
+                chunk_rows: None,
+            },
+            [1, 1, _] | [1, _] => MatMulPlan::Vec {
+                kernel,
+                overflow_threshold,
+            },
+            &[b, 1, _] if (1..=MAX_BATCHED_VEC).contains(&b) && layout.is_contiguous() => {
+                MatMulPlan::BatchedVec {
+                    batch: b,
+                    kernel,
+                    overflow_threshold,
+                }
+            }
+            dims if self.use_q4_activation_matmul(&config, layout) => MatMulPlan::Q4Activation {
+                rows: dims[..dims.len() - 1].iter().product(),
+            },
+            _ => MatMulPlan::Dequantize {
+                f16: config.dequantize_matmul_f16,
+                chunk_rows: self.dequantize_chunk_rows(&config, n, k)?,
+            },
+        };
+        Ok(plan)
+    }
+
+    /// The device memory [`QCudaStorage::fwd`] needs for an activation with layout `layout` and
+    /// the path it takes, without running anything. The dense fallback materializes the
+    /// dequantized weights, `n * k * 4` bytes in f32, this lets a scheduler pick another batching
+    /// or reject the call before running out of memory.
+    pub fn dequantize_matmul_plan(
+        &self,
+        self_shape: &crate::Shape,
+        layout: &crate::Layout,
+    ) -> Result<MatMulFootprint> {
+        let plan = self.explain_matmul(self_shape, layout)?;
+        let (n, k) = self_shape.dims2()?;
+        let dims = layout.shape().dims();
+        let rows: usize = dims[..dims.len() - 1].iter().product();
+        let f32_size = std::mem::size_of::<f32>();
+        let q4_act_size = |rows: usize| rows * k / self.dtype.block_size() * Q4_ACT_TYPE_SIZE;
+        let vec_size = |kernel| match kernel {
+            MatMulVecKernel::Dmmv => 0,
+            MatMulVecKernel::Q8_1 => q8_1_buffer_size(k, q8_1_row_padding(self.dtype)),
+            MatMulVecKernel::Q4Activation => q4_act_size(1),
+        };
+        let transient_bytes = match plan {
+            MatMulPlan::Vec { kernel, .. } => vec_size(kernel),
+            MatMulPlan::BatchedVec { batch, kernel, .. } => batch * vec_size(kernel),
+            MatMulPlan::Q4Activation { rows } => q4_act_size(rows),
+            // A chunk of f32 weights and the gemm output for these columns.
+            MatMulPlan::Dequantize {
+                chunk_rows: Some(chunk),
+                ..
+            } => (chunk * k + rows * chunk) * f32_size,
+            // The f16 weights, activation and output.
+            MatMulPlan::Dequantize {
+                f16: true,
+                chunk_rows: None,
+            } => (n * k + rows * k + rows * n) * 2,
+            MatMulPlan::Dequantize {
+                f16: false,
+                chunk_rows: None,
+            } => n * k * f32_size,
+        };
+        let output_bytes = rows * n * f32_size;
+        let headroom = QuantCudaConfig::for_device(self.device())
+            .dequantize_memory_headroom
+            .unwrap_or(0);
+        let free = available_memory(self.device())?;
+        Ok(MatMulFootprint {
+            plan,
+            transient_bytes,
+            output_bytes,
+            fits: transient_bytes + output_bytes + headroom <= free,
+        })
+    }
+
+    /// Matmul-vec variant of [`QCudaStorage::fwd`] that returns its output quantized as q8_0, so
+    /// that it can be fed to the next quantized layer without an f32 round trip. The q8_0 scales
+    /// are computed per block of 32 values of the output row, so `nrows` has to be a multiple of
+    /// 32.
+    pub fn fwd_q8_0(
+        &self,
+        self_shape: &crate::Shape,
+        storage: &CudaStorage,
+        layout: &crate::Layout,
+    ) -> Result<(QCudaStorage, crate::Shape)> {
+        if !matches!(layout.shape().dims(), [1, 1, _] | [1, _]) {
+            crate::bail!(
+                "q8_0 output requires a single vector input {:?}",
+                layout.shape()
+            )
+        }
+        let (out, out_shape) =
+            self.dequantize_matmul_vec(self_shape, storage, layout, None, None)?;
+        let nrows = out_shape.elem_count();
+        self.exception_rows_matmul(&out, nrows, storage, layout)?;
+        let dtype = GgmlDType::Q8_0;
+        let mut out_q = QCudaStorage::zeros(self.device(), nrows, dtype)?;
+        let out = out.as_cuda_slice::<f32>()?;
+        quantize_q8_0(&out.slice(..), out_q.data_mut()?, nrows, self.device())?;
+        Ok((out_q, out_shape))
+    }
+
+    /// Matmul-vec with an activation that is already quantized as q8_0, e.g. the output of
+    /// [`QCudaStorage::fwd_q8_0`] for the previous layer. The q8_0 blocks are converted to the
+    /// q8_1 layout used by the kernels, avoiding a dequantize/quantize round trip. The output is
+    /// a f32 `(1, nrows)` storage.
+    pub fn fwd_with_q8_0_activation(
+        &self,
+        self_shape: &crate::Shape,
+        activation: &QCudaStorage,
+    ) -> Result<(CudaStorage, crate::Shape)> {
+        self.check_standard_layout("matmul")?;
+        self.check_not_transposed("matmul-vec")?;
+        activation.check_standard_layout("matmul")?;
+        if activation.dtype != GgmlDType::Q8_0 {
+            crate::bail!("expected a q8_0 activation, got {:?}", activation.dtype)
+        }
+        let (nrows, ncols) = self_shape.dims2()?;
+        if activation.elem_count() != ncols {
+            crate::bail!(
+                "mismatch on matmul dim {self_shape:?} {}",
+                activation.elem_count()
+            )
+        }
+        let dev = self.device();
+        let num_blocks = ncols / GgmlDType::Q8_0.block_size();
+        // The padding blocks have to be zeros.
+        let size = q8_1_buffer_size(ncols, q8_1_row_padding(self.dtype));
+        let y_q8_1 = dev.alloc_zeros::<u8>(size).w()?;
+        let func = dev.get_or_load_func("q8_0_to_q8_1", candle_kernels::QUANTIZED)?;
+        let cfg = cudarc::driver::LaunchConfig {
+            grid_dim: (num_blocks as u32, 1, 1),
+            block_dim: (WARP_SIZE as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let params = (&*activation.data, &y_q8_1, num_blocks as i32);
+        unsafe { launch(func, dev, cfg, params) }?;
+        let out = mul_mat_vec_q8_1(&self.data, &y_q8_1, 0, None, self.dtype, ncols, nrows, dev)?;
+        super::cuda_graph::release_scratch(dev, y_q8_1);
+        if self.exceptions.is_some() {
+            // The exception rows are f16 so they are recomputed from the f32 activation.
+            let x = activation.dequantize(ncols)?;
+            self.exception_rows_matmul(&out, nrows, &x, &crate::Layout::contiguous((1, ncols)))?;
+        }
+        Ok((out, (1, nrows).into()))
+    }
+
+    /// Same as [`QCudaStorage::fwd`] followed by adding `bias`, a f32 vector with one value per
+    /// output row. On the vector path the bias add is fused in the matmul kernel.
+    pub fn fwd_with_bias(
+        &self,
+        self_shape: &crate::Shape,
+        storage: &CudaStorage,
+        layout: &crate::Layout,
+        bias: &CudaStorage,
+        bias_l: &crate::Layout,
+    ) -> Result<(CudaStorage, crate::Shape)> {
+        use crate::backend::BackendStorage;
+
+        let (nrows, _) = self_shape.dims2()?;
+        if bias_l.dims() != [nrows] {
+            crate::bail!("unexpected bias shape {:?}, nrows {nrows}", bias_l.shape())
+        }
+        let vector =
+            matches!(layout.shape().dims(), [1, 1, _] | [1, _]) && !self.transposed_in_file;
+        // The exception rows are recomputed without the bias, which is then added separately.
+        if vector && self.exceptions.is_none() {
+            let b = bias.as_cuda_slice::<f32>()?;
+            let b = match bias_l.contiguous_offsets() {
+                Some((o1, o2)) => b.slice(o1..o2),
+                None => Err(crate::Error::RequiresContiguous { op: "dmmv-bias" }.bt())?,
+            };
+            return self.dequantize_matmul_vec(self_shape, storage, layout, Some(&b), None);
+        }
+        let (out, out_shape) = if vector {
+            self.dequantize_matmul_vec(self_shape, storage, layout, None, None)?
+        } else {
+            self.dequantize_matmul(self_shape, storage, layout)?
+        };
+        self.exception_rows_matmul(&out, nrows, storage, layout)?;
+        let bias_l = bias_l.broadcast_as(&out_shape)?;
+        let out_l = crate::Layout::contiguous(&out_shape);
+        let out = out.binary_impl::<crate::op::Add>(bias, &out_l, &bias_l)?;
+        Ok((out, out_shape))
+    }
+
+    /// Same as [`QCudaStorage::fwd`] on a `(1, k)` or `(1, 1, k)` vector, with the output rows
+    /// from `valid_rows` on set to zero by the matmul kernel, e.g. for the padded positions of a
+    /// dynamic-length batch. Other activation shapes are not supported.
+    pub fn fwd_vec_masked(
+        &self,
+        self_shape: &crate::Shape,
+        storage: &CudaStorage,
+        layout: &crate::Layout,
+        valid_rows: usize,
+    ) -> Result<(CudaStorage, crate::Shape)> {
+        if !matches!(layout.shape().dims(), [1, 1, _] | [1, _]) {
+            crate::bail!(
+                "fwd_vec_masked requires a (1, k) or (1, 1, k) activation, got {:?}",
+                layout.shape()
+            )
+        }
+        // Recomputing the exception rows would overwrite the masked outputs.
+        self.check_no_exception_rows("fwd_vec_masked")?;
+        let _tuned = TunedScope::enter(self.device(), self.tuned);
+        self.dequantize_matmul_vec(self_shape, storage, layout, None, Some(valid_rows))
+    }
+
+    /// Same as [`QCudaStorage::fwd`] with the output placed on `out_device`, e.g. the gpu running
+    /// the downstream ops. The matmul runs on the device of the weights and its output is then
+    /// copied peer to peer, the streams of both devices are ordered with events so the host does
+    /// not wait. This is the same as `fwd` when `out_device` is the device of the weights.
+    pub fn fwd_on_device(
+        &self,
+        self_shape: &crate::Shape,
+        storage: &CudaStorage,
+        layout: &crate::Layout,
+        out_device: &CudaDevice,
+    ) -> Result<(CudaStorage, crate::Shape)> {
+        let (out, out_shape) = self.fwd(self_shape, storage, layout)?;
+        if out_device.id() == self.device.id() {
+            return Ok((out, out_shape));
+        }
+        let out = copy_to_device(out.as_cuda_slice::<f32>()?, self.device(), out_device)?;
+        Ok((
+            CudaStorage::wrap_cuda_slice(out, out_device.clone()),
+            out_shape,
+        ))
+    }
+}
+
+impl QCudaStorage {
+    fn dequantize_matmul_vec(
+        &self,
+        self_shape: &crate::Shape,
+        rhs: &CudaStorage,
+        rhs_l: &crate::Layout,
+        bias: Option<&CudaView<f32>>,
+        valid_rows: Option<usize>,
+    ) -> Result<(CudaStorage, crate::Shape)> {
+        self.check_standard_layout("matmul")?;
+        self.check_not_transposed("matmul-vec")?;
+        let (nrows, ncols) = self_shape.dims2()?;
+        let rhs = f32_activation(rhs)?;
+        // Size one dimensions are ignored by the contiguity check whatever their stride, so a
+        // (1, k) or (1, 1, k) vector with a start offset is sliced directly. Only a strided or
+        // broadcast last dimension requires a copy.
+        let rhs = match rhs_l.contiguous_offsets() {
+            Some((o1, o2)) => rhs.slice(o1..o2),
+            None => Err(crate::Error::RequiresContiguous { op: "dmmv" }.bt())?,
+        };
+        let (with_batch, k) = match rhs_l.shape().dims() {
+            [1, 1, k] => (true, k),
+            [1, k] => (false, k),
+            _ => crate::bail!("unexpected rhs shape in dmmv {:?}", rhs_l.shape()),
+        };
+        if ncols != *k {
+            crate::bail!("mismatch on matmul dim {self_shape:?} {:?}", rhs_l.shape())
+        }
+
+        let dev = self.device();
+        let config = QuantCudaConfig::for_device(dev);
+        let q8_1_overflow = match config.q8_1_overflow_threshold {
+            Some(threshold) => max_abs(&rhs, dev)? > threshold,
+            None => false,
+        };
+        let with_epilogue = bias.is_some() || valid_rows.is_some();
+        let partial_block = ncols % self.dtype.block_size() != 0;
+        let kernel = match self.matmul_vec_kernel(&config, ncols, with_epilogue) {
+            MatMulVecKernel::Q8_1 | MatMulVecKernel::Q4Activation
+                if q8_1_overflow && !partial_block =>
+            {
+                MatMulVecKernel::Dmmv
+            }
+            kernel => kernel,
+        };
+        let out = match kernel {
+            MatMulVecKernel::Dmmv => dequantize_mul_mat_vec(
+                &self.data, &rhs, bias, valid_rows, self.dtype, ncols, nrows, dev,
+            )?,
+            MatMulVecKernel::Q4Activation => {
+                mul_mat_via_q4_act(&self.data, &rhs, self.dtype, ncols, nrows, 1, dev)?
+            }
+            MatMulVecKernel::Q8_1 => mul_mat_vec_via_q8_1(
+                &self.data, &rhs, bias, valid_rows, self.dtype, ncols, nrows, dev,
+            )?,
+        };
+        let out_shape = if with_batch {
+            vec![1, 1, nrows]
+        } else {
+            vec![1, nrows]
+        };
+        Ok((out, out_shape.into()))
+    }
+
+    // Runs the matmul-vec kernels on the vectors of a contiguous (b, 1, k) input. With the q8_1
+    // kernel, the b vectors are quantized and multiplied by a single launch each, otherwise the
+    // kernels run once per vector.
+    fn dequantize_matmul_batched_vec(
+        &self,
+        self_shape: &crate::Shape,
+        storage: &CudaStorage,
+        layout: &crate::Layout,
+    ) -> Result<(CudaStorage, crate::Shape)> {
+        let (b, _, k) = layout.shape().dims3()?;
+        let (nrows, ncols) = self_shape.dims2()?;
+        let dev = self.device();
+        let config = QuantCudaConfig::for_device(dev);
+        // The overflow check picks the kernel per vector.
+        let single_launch = self.matmul_vec_kernel(&config, k, false) == MatMulVecKernel::Q8_1
+            && config.q8_1_overflow_threshold.is_none();
+        if single_launch {
+            self.check_standard_layout("matmul")?;
+            self.check_not_transposed("matmul-vec")?;
+            if ncols != k {
+                crate::bail!("mismatch on matmul dim {self_shape:?} {:?}", layout.shape())
+            }
+            let rhs = f32_activation(storage)?;
+            let rhs = rhs.slice(layout.start_offset()..layout.start_offset() + b * k);
+            let row_padding = q8_1_row_padding(self.dtype);
+            let mut y_q8_1 = unsafe { dev.alloc::<u8>(b * q8_1_buffer_size(k, row_padding)).w()? };
+            let rounding = config.q8_1_rounding;
+            quantize_q8_1_rows(&rhs, &mut y_q8_1, k, b, row_padding, rounding, dev)?;
+            let dst = mul_mat_vec_q8_1_batched(
+                &self.data, &y_q8_1, b, 0, None, self.dtype, ncols, nrows, dev,
+            )?;
+            super::cuda_graph::release_scratch(dev, y_q8_1);
+            return Ok((dst, (b, 1, nrows).into()));
+        }
+        let mut dst = unsafe { dev.alloc::<f32>(b * nrows).w()? };
+        for i in 0..b {
+            let offset = layout.start_offset() + i * k;
+            let vec_l = crate::Layout::contiguous_with_offset((1, k), offset);
+            let (out, _) = self.dequantize_matmul_vec(self_shape, storage, &vec_l, None, None)?;
+            let mut dst = dst.slice_mut(i * nrows..(i + 1) * nrows);
+            dev.dtod_copy(out.as_cuda_slice::<f32>()?, &mut dst).w()?;
+        }
+        let dst = CudaStorage::wrap_cuda_slice(dst, dev.clone());
+        Ok((dst, (b, 1, nrows).into()))
+    }
+
+    fn dequantize_matmul(
+        &self,
+        self_shape: &crate::Shape,
+        storage: &CudaStorage,
+        layout: &crate::Layout,
+    ) -> Result<(CudaStorage, crate::Shape)> {
+        self.check_standard_layout("matmul")?;
+        f32_activation(storage)?;
+        let (b, m, n, k) = dense_matmul_dims(self_shape, layout)?;
+        if k % self.dtype.block_size() != 0 {
+            crate::bail!(
+                "rows ending with a partial {:?} block, k {k}, are only supported by the matmul-vec kernels",
+                self.dtype
+            )
+        }
+        if b * m == 0 {
+            // Empty batches, e.g. from dynamic batching, do not need the weights.
+            let out = self.device().alloc_zeros::<f32>(0).w()?;
+            let mut out_shape = layout.shape().dims().to_vec();
+            out_shape.pop();
+            out_shape.push(n);
+            return Ok((
+                CudaStorage::wrap_cuda_slice(out, self.device().clone()),
+                out_shape.into(),
+            ));
+        }
+
+        let config = QuantCudaConfig::for_device(self.device());
+        if self.use_q4_activation_matmul(&config, layout) {
+            let rhs = f32_activation(storage)?;
+            let rhs = rhs.slice(layout.start_offset()..layout.start_offset() + b * m * k);
+            let dev = self.device();
+            let out = mul_mat_via_q4_act(&self.data, &rhs, self.dtype, k, n, b * m, dev)?;
+            let mut out_shape = layout.shape().dims().to_vec();
+            out_shape.pop();
+            out_shape.push(n);
+            return Ok((out, out_shape.into()));
+        }
+        // The chunks are slices of the (n, k) rows, transposed weights are dequantized at once.
+        let chunk_rows = if self.transposed_in_file {
+            None
+        } else {
+            self.dequantize_chunk_rows(&config, n, k)?
+        };
+        if let Some(rows) = chunk_rows {
+            let out = self.dequantize_matmul_chunked(storage, layout, (b, m, n, k), rows)?;
+            let mut out_shape = layout.shape().dims().to_vec();
+            out_shape.pop();
+            out_shape.push(n);
+            return Ok((out, out_shape.into()));
+        }
+        let rhs_l = dense_rhs_layout(self.transposed_in_file, (b, n, k))?;
+        self.dequantize_matmul_full(&config, storage, layout, (b, m, n, k), &rhs_l)
+    }
+
+    // Dequantizes all the weights at once and runs a single gemm against `rhs_l`.
+    fn dequantize_matmul_full(
+        &self,
+        config: &QuantCudaConfig,
+        storage: &CudaStorage,
+        layout: &crate::Layout,
+        (b, m, n, k): (usize, usize, usize, usize),
+        rhs_l: &crate::Layout,
+    ) -> Result<(CudaStorage, crate::Shape)> {
+        let data = if config.dequantize_matmul_f16 {
+            self.dequantize_f16(n * k)?
+        } else {
+            self.dequantize(n * k)?
+        };
+        let f16 = config.dequantize_matmul_f16;
+        dense_matmul(config, &data, f16, storage, layout, (b, m, n, k), rhs_l)
+    }
+
+    /// Dequantizes the `self_shape` weights once so that they can be multiplied with multiple
+    /// activations, e.g. the chunks of a long prefill, see [`DequantizedWeights`]. The weights are
+    /// dequantized to f16 when `dequantize_matmul_f16` is set in the device config.
+    pub fn dequantize_for_matmul(&self, self_shape: &crate::Shape) -> Result<DequantizedWeights> {
+        self.check_standard_layout("dequantize_for_matmul")?;
+        let (n, k) = self_shape.dims2()?;
+        if n * k != self.elem_count() {
+            crate::bail!(
+                "shape {self_shape:?} does not match {} elements",
+                self.elem_count()
+            )
+        }
+        let f16 = QuantCudaConfig::for_device(self.device()).dequantize_matmul_f16;
+        let data = if f16 {
+            self.dequantize_f16(n * k)?
+        } else {
+            self.dequantize(n * k)?
+        };
+        let bytes = n * k * if f16 { 2 } else { 4 };
+        Ok(DequantizedWeights {
+            _vram: VramTicket::new(self.device(), VramKind::Dequantized, bytes),
+            data,
+            shape: self_shape.clone(),
+            dtype: self.dtype,
+            f16,
+            transposed_in_file: self.transposed_in_file,
+        })
+    }
+
+    // The kernel picked by `dequantize_matmul_vec` before the activation overflow check.
+    // The int4 activation kernel has no bias add nor row masking epilogue.
+    fn matmul_vec_kernel(
+        &self,
+        config: &QuantCudaConfig,
+        ncols: usize,
+        with_epilogue: bool,
+    ) -> MatMulVecKernel {
+        // Only the q8_1 kernels handle rows ending with a partial block.
+        if ncols % self.dtype.block_size() != 0 {
+            return MatMulVecKernel::Q8_1;
+        }
+        if config.force_dmmv || config.mmv_defaults.prefers_dmmv(self.dtype, ncols) {
+            MatMulVecKernel::Dmmv
+        } else if config.experimental_q4_activation
+            && self.dtype == GgmlDType::Q4_0
+            && !with_epilogue
+        {
+            MatMulVecKernel::Q4Activation
+        } else {
+            MatMulVecKernel::Q8_1
+        }
+    }
+
+    // Whether `dequantize_matmul` quantizes the activation to int4 rather than dequantizing the
+    // weights.
+    fn use_q4_activation_matmul(&self, config: &QuantCudaConfig, layout: &crate::Layout) -> bool {
+        config.experimental_q4_activation
+            && self.dtype == GgmlDType::Q4_0
+            && !self.transposed_in_file
+            && layout.is_contiguous()
+    }
+
+    // The number of weight rows to dequantize at a time in `dequantize_matmul`, `None` when the
+    // whole (n, k) weights fit in memory and no slab size is configured.
+    fn dequantize_chunk_rows(
+        &self,
+        config: &QuantCudaConfig,
+        n: usize,
+        k: usize,
+    ) -> Result<Option<usize>> {
+        let n_chunk = config.dequantize_matmul_n_chunk.filter(|&rows| rows < n);
+        let headroom = match config.dequantize_memory_headroom {
+            None => return Ok(n_chunk),
+            Some(headroom) => headroom,
+        };
+        let free = available_memory(self.device())?;
+        let size_in_bytes = n * k * std::mem::size_of::<f32>();
+        if size_in_bytes.saturating_add(headroom) <= free {
+            return Ok(n_chunk);
+        }
+        // Use half of what is left for each chunk, the other half holds its output.
+        let chunk_in_bytes = free.saturating_sub(headroom) / 2;
+        let rows = (chunk_in_bytes / (k * std::mem::size_of::<f32>())).max(1);
+        Ok(Some(n_chunk.map_or(rows, |n_chunk| n_chunk.min(rows))))
+    }
+
+    // Same as the dense path of `dequantize_matmul` but only `rows` rows of the weights are
+    // dequantized at a time, each chunk producing a slice of the output columns.
+    fn dequantize_matmul_chunked(
+        &self,
+        storage: &CudaStorage,
+        layout: &crate::Layout,
+        (b, m, n, k): (usize, usize, usize, usize),
+        rows: usize,
+    ) -> Result<CudaStorage> {
+        use crate::backend::BackendStorage;
+
+        let dev = self.device();
+        let tf32 = QuantCudaConfig::for_device(dev).dequantize_matmul_tf32;
+        let blocks_per_row = k / self.dtype.block_size();
+        let dst = unsafe { dev.alloc::<f32>(b * m * n).w()? };
+        let mut dst = CudaStorage::wrap_cuda_slice(dst, dev.clone());
+        let mut row_start = 0;
+        while row_start < n {
+            let row_end = usize::min(row_start + rows, n);
+            let n_chunk = row_end - row_start;
+            let (block_start, block_end) = (row_start * blocks_per_row, row_end * blocks_per_row);
+            let data_f32 = self.dequantize_range(block_start, block_end, k)?;
+            let rhs_l = crate::Layout::new((k, n_chunk).into(), vec![1, k], 0)
+                .broadcast_as((b, k, n_chunk))?;
+            let out = f32_matmul(storage, &data_f32, (b, m, n_chunk, k), layout, &rhs_l, tf32)?;
+            out.copy2d(&mut dst, b * m, n_chunk, n_chunk, n, 0, row_start)?;
+            row_start = row_end;
+        }
+        Ok(dst)
+    }
+}
+
+/// Uploads host blocks to the device, these are converted to the little-endian layout used by
+/// the kernels on big-endian hosts.
+pub fn load_quantized<T: super::GgmlType + Send + Sync + 'static>(
+    device: &CudaDevice,
+    data: &[T],
+) -> Result<super::QStorage> {
+    let data = unsafe {
+        std::slice::from_raw_parts(data.as_ptr() as *const u8, core::mem::size_of_val(data))
+    };
+    QuantCudaCaps::for_device(device)?;
+    let data = convert_host_blocks(T::DTYPE, data)?;
+    let data = device.htod_sync_copy(data.as_ref()).w()?;
+    Ok(QStorage::Cuda(QCudaStorage {
+        _vram: std::sync::Arc::new(VramTicket::new(device, VramKind::Quantized, data.len())),
+        data: std::sync::Arc::new(data),
+        device: device.clone(),
+        dtype: T::DTYPE,
+        embedding_layout: false,
+        shape: None,
+        transposed_in_file: false,
+        tuned: None,
+        exceptions: None,
+    }))
+}
+
+/// Issues a prefetch of the weights of the experts selected by `expert_ids`, e.g. the top experts
+/// of the router logits, so that they get resident while the routing completes. Each expert is
+/// prefetched once whatever the number of tokens routed to it. This returns the number of experts
+/// that have been prefetched, see [`QCudaStorage::prefetch`] for when this is a no-op.
+pub fn prefetch_experts(
+    experts: &[&QCudaStorage],
+    expert_ids: &[u32],
+    stream: Option<&cudarc::driver::CudaStream>,
+) -> Result<usize> {
+    let mut selected = vec![false; experts.len()];
+    for &expert_id in expert_ids.iter() {
+        match selected.get_mut(expert_id as usize) {
+            Some(selected) => *selected = true,
+            None => crate::bail!("expert id {expert_id} out of range {}", experts.len()),
+        }
+    }
+    let mut prefetched = 0;
+    for (expert, _) in experts.iter().zip(selected).filter(|(_, s)| *s) {
+        if expert.prefetch(stream)? {
+            prefetched += 1
+        }
+    }
+    Ok(prefetched)
+}
+
+/// Mixture of experts matmul-vec: `tokens` is a contiguous `(n_tokens, ncols)` matrix and
+/// `expert_ids` holds `top_k` expert indexes per token. Row `i` of the `(n_tokens * top_k, nrows)`
+/// output is the product of token `i / top_k` with the expert `expert_ids[i]`, each expert being a
+/// `(nrows, ncols)` quantized weight. The experts have to share the same dtype.
+///
+/// The tokens are quantized to q8_1 once and each expert is a single launch over the rows routed
+/// to it, the expert ids stay on the device. Rows with an out of range expert id are set to zero.
+pub fn mul_mat_vec_moe(
+    experts: &[QCudaStorage],
+    tokens: &CudaView<f32>,
+    expert_ids: &CudaSlice<u32>,
+    ncols: usize,
+    nrows: usize,
+    dev: &CudaDevice,
+) -> Result<CudaStorage> {
+    if ncols == 0 || tokens.len() % ncols != 0 {
+        crate::bail!("unexpected tokens size {}, ncols {ncols}", tokens.len())
+    }
+    let n_tokens = tokens.len() / ncols;
+    if n_tokens == 0 || expert_ids.len() % n_tokens != 0 {
+        crate::bail!(
+            "unexpected expert ids size {} for {n_tokens} tokens",
+            expert_ids.len()
+        )
+    }
+    let top_k = expert_ids.len() / n_tokens;
+    let dtype = match experts.first() {
+        Some(expert) => expert.dtype,
+        None => crate::bail!("mul_mat_vec_moe requires at least one expert"),
+    };
+    for expert in experts.iter() {
+        if expert.dtype != dtype {
+            crate::bail!("mixed expert dtypes {dtype:?} and {:?}", expert.dtype)
+        }
+        check_matmul_data(expert.data.len(), dtype, ncols, nrows)?;
+        check_same_device(&expert.data, dev, "mul-mat-vec-moe")?;
+    }
+    let (ncols_i32, nrows_i32) = (kernel_dim(ncols, "ncols")?, kernel_dim(nrows, "nrows")?);
+    let top_k_i32 = kernel_dim(top_k, "top_k")?;
+    let n_out = expert_ids.len();
+    kernel_dim(n_out, "expert ids")?;
+
+    let row_padding = q8_1_row_padding(dtype);
+    let nrows_y = kernel_dim(pad(ncols, row_padding), "nrows_y")?;
+    let mut y_q8_1 = unsafe {
+        dev.alloc::<u8>(n_tokens * q8_1_buffer_size(ncols, row_padding))
+            .w()?
+    };
+    let rounding = QuantCudaConfig::for_device(dev).q8_1_rounding;
+    quantize_q8_1_rows(
+        tokens,
+        &mut y_q8_1,
+        ncols,
+        n_tokens,
+        row_padding,
+        rounding,
+        dev,
+    )?;
+
+    let kernel_name = mmvq_moe_kernel(dtype)?;
+    let nwarps = QuantCudaConfig::for_device(dev).mmvq_nwarps(dtype);
+    let dst = dev.alloc_zeros::<f32>(n_out * nrows).w()?;
+    for (expert_id, expert) in experts.iter().enumerate() {
+        // The output rows go on the y dimension of the grid, split when they exceed its limit.
+        for row_offset in (0..n_out).step_by(MAX_GRID_DIM_Y) {
+            let func = dev.get_or_load_func(&kernel_name, candle_kernels::QUANTIZED)?;
+            let cfg = cudarc::driver::LaunchConfig {
+                grid_dim: (
+                    nrows as u32,
+                    (n_out - row_offset).min(MAX_GRID_DIM_Y) as u32,
+                    1,
+                ),
+                block_dim: (WARP_SIZE as u32, nwarps as u32, 1),
+                shared_mem_bytes: 0,
+            };
+            let params = (
+                &expert.data,
+                &y_q8_1,
+                &dst,
+                ncols_i32,
+                nrows_i32,
+                nrows_y,
+                expert_ids,
+                expert_id as u32,
+                top_k_i32,
+                row_offset as i32,
+            );
+            let scope = trace_launch(dev, &kernel_name, dtype)?;
+            unsafe { launch(func, dev, cfg, params) }?;
+            scope.end(dev)?;
+        }
+    }
+    super::cuda_graph::release_scratch(dev, y_q8_1);
+    Ok(CudaStorage::wrap_cuda_slice(dst, dev.clone()))
+}
+
+/// Uploads raw ggml blocks of type `dtype` to the device. The blocks are expected in the
+/// little-endian layout of ggml files on all hosts, see [`load_quantized`] for host blocks.
+pub fn load_quantized_bytes(
+    device: &CudaDevice,
+    dtype: GgmlDType,
+    data: &[u8],
+) -> Result<QCudaStorage> {
+    if data.len() % dtype.type_size() != 0 {
+        crate::bail!(
+            "quantized data size {} is not a multiple of the {dtype:?} type size {}",
+            data.len(),
+            dtype.type_size()
+        )
+    }
+    QuantCudaCaps::for_device(device)?;
+    let data = device.htod_sync_copy(data).w()?;
+    Ok(QCudaStorage {
+        _vram: std::sync::Arc::new(VramTicket::new(device, VramKind::Quantized, data.len())),
+        data: std::sync::Arc::new(data),
+        device: device.clone(),
+        dtype,
+        embedding_layout: false,
+        shape: None,
+        transposed_in_file: false,
+        tuned: None,
+        exceptions: None,
+    })
+}
+
+impl QCudaStorage {
+    // Wraps quantized data that is already on the device, e.g. written there by the GDS loader.
+    #[cfg(feature = "gds")]
+    pub(crate) fn from_device_data(
+        device: &CudaDevice,
+        dtype: GgmlDType,
+        data: CudaSlice<u8>,
+    ) -> Result<Self> {
+        if data.len() % dtype.type_size() != 0 {
+            crate::bail!(
+                "quantized data size {} is not a multiple of the {dtype:?} type size {}",
+                data.len(),
+                dtype.type_size()
+            )
+        }
+        QuantCudaCaps::for_device(device)?;
+        Ok(QCudaStorage {
+            _vram: std::sync::Arc::new(VramTicket::new(device, VramKind::Quantized, data.len())),
+            data: std::sync::Arc::new(data),
+            device: device.clone(),
+            dtype,
+            embedding_layout: false,
+            shape: None,
+            transposed_in_file: false,
+            tuned: None,
+            exceptions: None,
+        })
+    }
+}
+
+/// Location of the data of a tensor in one of the files of a split model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardLocation {
+    pub path: std::path::PathBuf,
+    /// Absolute byte offset of the tensor data in the file.
+    pub offset: u64,
+    pub len: usize,
+}
+
+/// Reads the tensor `name` from the shard returned by `resolve` and uploads it to the device.
+pub fn load_quantized_from_shards<F>(
+    device: &CudaDevice,
+    dtype: GgmlDType,
+    name: &str,
+    resolve: F,
+) -> Result<QCudaStorage>
+where
+    F: Fn(&str) -> Result<ShardLocation>,
+{
+    use std::io::{Read, Seek};
+
+    let location = resolve(name)?;
+    let mut file = std::fs::File::open(&location.path)?;
+    let mut data = vec![0u8; location.len];
+    file.seek(std::io::SeekFrom::Start(location.offset))?;
+    file.read_exact(&mut data)?;
+    load_quantized_bytes(device, dtype, &data)
+}
+
+/// Index of the tensors of a gguf model split over multiple files, e.g.
+/// `model-00001-of-00005.gguf`, each file having its own header and data section.
+#[derive(Debug)]
+pub struct GgufShards {
+    tensors: std::collections::HashMap<String, (ShardLocation, GgmlDType, crate::Shape)>,
+}
+
+impl GgufShards {
+    /// Reads the header of each shard. A tensor name present in multiple shards is an error.
+    pub fn open<P: AsRef<std::path::Path>>(paths: &[P]) -> Result<Self> {
+        let mut tensors: std::collections::HashMap<String, (ShardLocation, _, _)> =
+            std::collections::HashMap::new();
+        for path in paths.iter() {
+            let path = path.as_ref();
+            let mut file = std::fs::File::open(path)?;
+            let content = super::gguf_file::Content::read(&mut file)?;
+            for (name, info) in content.tensor_infos.into_iter() {
+                let elem_count = info.shape.elem_count();
+                let len = elem_count / info.ggml_dtype.block_size() * info.ggml_dtype.type_size();
+                let location = ShardLocation {
+                    path: path.to_path_buf(),
+                    offset: content.tensor_data_offset + info.offset,
+                    len,
+                };
+                if let Some((prev, _, _)) = tensors.get(&name) {
+                    crate::bail!(
+                        "tensor {name} is present in both {:?} and {path:?}",
+                        prev.path
+                    )
+                }
+                tensors.insert(name, (location, info.ggml_dtype, info.shape));
+            }
+        }
+        Ok(Self { tensors })
+    }
+
+    pub fn tensor_names(&self) -> impl Iterator<Item = &str> {
+        self.tensors.keys().map(|k| k.as_str())
+    }
+
+    pub fn resolve(&self, name: &str) -> Result<ShardLocation> {
+        match self.tensors.get(name) {
+            Some((location, _, _)) => Ok(location.clone()),
+            None => crate::bail!("cannot find tensor info for {name}"),
+        }
+    }
+
+    /// Loads the tensor `name` from its shard, the returned storage records the tensor shape.
+    pub fn load(&self, device: &CudaDevice, name: &str) -> Result<QCudaStorage> {
+        let (dtype, shape) = match self.tensors.get(name) {
+            Some((_, dtype, shape)) => (*dtype, shape),
+            None => crate::bail!("cannot find tensor info for {name}"),
+        };
+        let mut storage = load_quantized_from_shards(device, dtype, name, |n| self.resolve(n))?;
+        storage.set_shape(shape.clone())?;
+        Ok(storage)
+    }
+
+    /// Same as [`GgufShards::load`] but the data is transferred with GPU Direct Storage when
+    /// available, see [`super::cuda_gds`].
+    #[cfg(feature = "gds")]
+    pub fn load_direct(&self, device: &CudaDevice, name: &str) -> Result<QCudaStorage> {
+        let (location, dtype, shape) = match self.tensors.get(name) {
+            Some((location, dtype, shape)) => (location, *dtype, shape),
+            None => crate::bail!("cannot find tensor info for {name}"),
+        };
+        let mut storage = super::cuda_gds::load_quantized_direct(device, dtype, location)?;
+        storage.set_shape(shape.clone())?;
+        Ok(storage)
+    }
+
+    /// Loads the linear weights `name` written as `[in, out]` by their converter rather than the
+    /// usual `[out, in]`, without transposing them on the host. The returned storage records the
+    /// `(out, in)` shape expected by [`QCudaStorage::fwd`], see
+    /// [`QCudaStorage::set_transposed_in_file`].
+    pub fn load_transposed(&self, device: &CudaDevice, name: &str) -> Result<QCudaStorage> {
+        let (dtype, shape) = match self.tensors.get(name) {
+            Some((_, dtype, shape)) => (*dtype, shape),
+            None => crate::bail!("cannot find tensor info for {name}"),
+        };
+        let (k, n) = match shape.dims2() {
+            Ok(dims) => dims,
+            Err(_) => crate::bail!("{name}: transposed weights must be 2d, got {shape:?}"),
+        };
+        let mut storage = load_quantized_from_shards(device, dtype, name, |t| self.resolve(t))?;
+        storage.set_shape((n, k))?;
+        storage.set_transposed_in_file(true);
+        Ok(storage)
+    }
+}
+
+/// Default group size of [`QCudaGroupStorage`], as used by most GPTQ/AWQ exports.
+pub const INT4_GROUP_SIZE: usize = 128;
+
+/// Symmetric int4 weights of shape `(nrows, ncols)` with one f16 scale per group of `group_size`
+/// consecutive values of a row, as produced by GPTQ/AWQ-style exports. These do not map onto a
+/// ggml block type so they use their own storage. The quants are stored row major with two values
+/// per byte, the first one in the low nibble, and a weight is `(q - 8) * scale`.
+#[derive(Clone, Debug)]
+pub struct QCudaGroupStorage {
+    qweight: CudaSlice<u8>,
+    scales: CudaSlice<half::f16>,
+    nrows: usize,
+    ncols: usize,
+    group_size: usize,
+    device: CudaDevice,
+}
+
+impl QCudaGroupStorage {
+    /// Uploads the packed quants and the `(nrows, ncols / group_size)` scales.
+    pub fn new(
+        device: &CudaDevice,
+        qweight: &[u8],
+        scales: &[half::f16],
+        (nrows, ncols): (usize, usize),
+        group_size: usize,
+    ) -> Result<Self> {
+        if group_size == 0 || group_size % 2 != 0 || ncols % group_size != 0 {
+            crate::bail!("group size {group_size} has to be even and divide ncols {ncols}")
+        }
+        if qweight.len() != nrows * ncols / 2 {
+            crate::bail!(
+                "unexpected qweight size {}, ({nrows}, {ncols})",
+                qweight.len()
+            )
+        }
+        if scales.len() != nrows * ncols / group_size {
+            crate::bail!(
+                "unexpected scales size {}, ({nrows}, {ncols})",
+                scales.len()
+            )
+        }
+        let qweight = device.htod_sync_copy(qweight).w()?;
+        let scales = device.htod_sync_copy(scales).w()?;
+        Ok(Self {
+            qweight,
+            scales,
+            nrows,
+            ncols,
+            group_size,
+            device: device.clone(),
+        })
+    }
+
+    pub fn device(&self) -> &CudaDevice {
+        &self.device
+    }
+
+    pub fn group_size(&self) -> usize {
+        self.group_size
+    }
+
+    pub fn dims(&self) -> (usize, usize) {
+        (self.nrows, self.ncols)
+    }
+
+    pub fn storage_size_in_bytes(&self) -> usize {
+        self.qweight.len() + self.scales.len() * 2
+    }
+
+    /// Dequantizes the weights to a row major `(nrows, ncols)` f32 storage.
+    pub fn dequantize(&self) -> Result<CudaStorage> {
+        let dev = &self.device;
+        let elem_count = self.nrows * self.ncols;
+        let func = dev.get_or_load_func("dequantize_int4_grouped", candle_kernels::QUANTIZED)?;
+        let dst = unsafe { dev.alloc::<f32>(elem_count).w()? };
+        // Each thread handles the two values of a byte.
+        let num_blocks = ceil_div(elem_count / 2, CUDA_DEQUANTIZE_BLOCK_SIZE);
+        let cfg = cudarc::driver::LaunchConfig {
+            grid_dim: (num_blocks as u32, 1, 1),
+            block_dim: (CUDA_DEQUANTIZE_BLOCK_SIZE as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let params = (
+            &self.qweight,
+            &self.scales,
+            &dst,
+            self.group_size as i32,
+            elem_count as i32,
+        );
+        unsafe { launch(func, dev, cfg, params) }?;
+        Ok(CudaStorage::wrap_cuda_slice(dst, dev.clone()))
+    }
+
+    /// Computes `xs @ w.t()` for f32 activations `xs` of shape `(b, m, ncols)` or `(m, ncols)` by
+    /// dequantizing the weights, there is no dedicated matmul kernel for now.
+    pub fn fwd(
+        &self,
+        storage: &CudaStorage,
+        layout: &crate::Layout,
+    ) -> Result<(CudaStorage, crate::Shape)> {
+        use crate::backend::BackendStorage;
+
+        let (n, k) = (self.nrows, self.ncols);
+        f32_activation(storage)?;
+        let (b, m, k2) = match layout.shape().dims() {
+            &[b, m, k2] => (b, m, k2),
+            &[m, k2] => (1, m, k2),
+            s => crate::bail!("unexpected shape for input {s:?}"),
+        };
+        if k2 != k {
+            crate::bail!("mismatch on matmul dim ({n}, {k}) {:?}", layout.shape())
+        }
+        let rhs_l = crate::Layout::new((k, n).into(), vec![1, k], 0).broadcast_as((b, k, n))?;
+        let data_f32 = self.dequantize()?;
+        let out = storage.matmul(&data_f32, (b, m, n, k), layout, &rhs_l)?;
+        let mut out_shape = layout.shape().dims().to_vec();
+        out_shape.pop();
+        out_shape.push(n);
+        Ok((out, out_shape.into()))
+    }
+}
+
+/// Page-locked host memory, device transfers from or to such a buffer avoid the staging copy that
+/// pageable memory requires.
+pub struct PinnedHostBuffer<T> {
+    ptr: *mut T,
+    len: usize,
+}
+
+// The buffer owns its allocation, it is only freed on drop.
+unsafe impl<T: Send> Send for PinnedHostBuffer<T> {}
+unsafe impl<T: Sync> Sync for PinnedHostBuffer<T> {}
+
+impl<T: cudarc::driver::DeviceRepr + cudarc::driver::ValidAsZeroBits> PinnedHostBuffer<T> {
+    /// Allocates `len` zeroed elements of pinned memory.
+    pub fn zeros(dev: &CudaDevice, len: usize) -> Result<Self> {
+        if len == 0 {
+            let ptr = std::ptr::NonNull::dangling().as_ptr();
+            return Ok(Self { ptr, len });
+        }
+        dev.bind_to_thread().w()?;
+        let mut ptr = std::ptr::null_mut();
+        let size_in_bytes = len * std::mem::size_of::<T>();
+        unsafe {
+            cudarc::driver::sys::cuMemHostAlloc(&mut ptr, size_in_bytes, 0)
+                .result()
+                .w()?;
+            // The slices of the buffer must never expose uninitialized memory.
+            std::ptr::write_bytes(ptr as *mut u8, 0, size_in_bytes);
+        }
+        Ok(Self {
+            ptr: ptr as *mut T,
+            len,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl<T> Drop for PinnedHostBuffer<T> {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe {
+                let _ = cudarc::driver::sys::cuMemFreeHost(self.ptr as *mut std::ffi::c_void);
+            }
+        }
+    }
+}
+
+/// Sums the f32 partial outputs of a tensor parallel matmul, each device having computed the
+/// product with its column shard of the weights. The partials can live on different devices, they
+/// are staged through host memory and the sum is returned on `device`.
+pub fn all_reduce_sum_host(partials: &[&CudaStorage], device: &CudaDevice) -> Result<CudaStorage> {
+    use crate::backend::BackendStorage;
+
+    let mut sum: Option<Vec<f32>> = None;
+    for partial in partials.iter() {
+        let partial = partial
+            .device()
+            .dtoh_sync_copy(partial.as_cuda_slice::<f32>()?)
+            .w()?;
+        match sum.as_mut() {
+            None => sum = Some(partial),
+            Some(sum) => {
+                if sum.len() != partial.len() {
+                    crate::bail!("all_reduce: size mismatch {} {}", sum.len(), partial.len())
+                }
+                sum.iter_mut()
+                    .zip(partial.iter())
+                    .for_each(|(s, p)| *s += p)
+            }
+        }
+    }
+    let sum = match sum {
+        Some(sum) => sum,
+        None => crate::bail!("all_reduce requires at least one partial output"),
+    };
+    let sum = device.htod_sync_copy(&sum).w()?;
+    Ok(CudaStorage::wrap_cuda_slice(sum, device.clone()))
+}
+
+/// Sums the f32 partial output of this rank with the ones of the other ranks of `comm` using
+/// NCCL, every rank gets the full sum on its own device.
+#[cfg(feature = "nccl")]
+pub fn all_reduce_sum_nccl(
+    partial: &CudaStorage,
+    comm: &cudarc::nccl::safe::Comm,
+) -> Result<CudaStorage> {
+    use crate::backend::BackendStorage;
+    use cudarc::nccl::safe::ReduceOp;
+
+    let dev = partial.device().clone();
+    let partial = partial.as_cuda_slice::<f32>()?;
+    let mut dst = unsafe { dev.alloc::<f32>(partial.len()).w()? };
+    comm.all_reduce(partial, &mut dst, &ReduceOp::Sum)
+        .map_err(|e| crate::Error::Msg(format!("nccl all_reduce failed: {e:?}")).bt())?;
+    Ok(CudaStorage::wrap_cuda_slice(dst, dev))
+}
+
+/// Uploads quantized weights from pinned memory. `data` has to be made of full `dtype` blocks.
+pub fn load_quantized_pinned(
+    device: &CudaDevice,
+    dtype: GgmlDType,
+    data: &PinnedHostBuffer<u8>,
+) -> Result<QCudaStorage> {
+    let mut dst = alloc_for_load(device, dtype, data.len())?;
+    htod_async(device, data.as_slice(), &mut dst, 0)?;
+    device.synchronize()?;
+    Ok(QCudaStorage {
+        _vram: std::sync::Arc::new(VramTicket::new(device, VramKind::Quantized, dst.len())),
+        data: std::sync::Arc::new(dst),
+        dtype,
+        device: device.clone(),
+        embedding_layout: false,
+        shape: None,
+        transposed_in_file: false,
+        tuned: None,
+        exceptions: None,
+    })
+}
+
+/// Copies quantized weights to managed memory, which the driver migrates between the host and the
+/// device on demand so that the weights of a model can exceed the device memory. Use
+/// [`QCudaStorage::prefetch`] to migrate them ahead of their use. `data` has to be made of full
+/// `dtype` blocks and this fails on devices without managed memory.
+pub fn load_quantized_managed(
+    device: &CudaDevice,
+    dtype: GgmlDType,
+    data: &[u8],
+) -> Result<QCudaStorage> {
+    use cudarc::driver::sys;
+
+    if data.is_empty() || data.len() % dtype.type_size() != 0 {
+        crate::bail!(
+            "quantized data size {} is not a positive multiple of the {dtype:?} type size {}",
+            data.len(),
+            dtype.type_size()
+        )
+    }
+    QuantCudaCaps::for_device(device)?;
+    let managed = device
+        .attribute(sys::CUdevice_attribute::CU_DEVICE_ATTRIBUTE_MANAGED_MEMORY)
+        .w()?;
+    if managed == 0 {
+        crate::bail!("{device:?} does not support managed memory")
+    }
+    device.bind_to_thread().w()?;
+    let mut ptr: sys::CUdeviceptr = 0;
+    let flags = sys::CUmemAttach_flags::CU_MEM_ATTACH_GLOBAL as std::os::raw::c_uint;
+    unsafe { sys::cuMemAllocManaged(&mut ptr, data.len(), flags) }
+        .result()
+        .w()?;
+    // Without concurrent managed access the host can only access managed memory while the device
+    // is idle.
+    device.synchronize()?;
+    // SAFETY: Managed memory is accessible from the host and the slice takes ownership of the
+    // allocation.
+    let dst = unsafe {
+        std::ptr::copy_nonoverlapping(data.as_ptr(), ptr as *mut u8, data.len());
+        device.upgrade_device_ptr::<u8>(ptr, data.len())
+    };
+    Ok(QCudaStorage {
+        _vram: std::sync::Arc::new(VramTicket::new(device, VramKind::Quantized, dst.len())),
+        data: std::sync::Arc::new(dst),
+        dtype,
+        device: device.clone(),
+        embedding_layout: false,
+        shape: None,
+        transposed_in_file: false,
+        tuned: None,
+        exceptions: None,
+    })
+}
+
+/// Uploads quantized weights, e.g. read from a mmaped file, through a reusable pinned staging
+/// buffer. The buffer is split in two halves so that filling one half overlaps with the transfer
+/// of the other, the transfers themselves avoid the extra staging copy of pageable memory.
+pub struct PinnedStagingLoader {
+    device: CudaDevice,
+    staging: PinnedHostBuffer<u8>,
+}
+
+impl PinnedStagingLoader {
+    pub fn new(device: &CudaDevice, staging_size_in_bytes: usize) -> Result<Self> {
+        if staging_size_in_bytes < 2 {
+            crate::bail!("staging buffer too small {staging_size_in_bytes}")
+        }
+        let staging = PinnedHostBuffer::zeros(device, staging_size_in_bytes)?;
+        Ok(Self {
+            device: device.clone(),
+            staging,
+        })
+    }
+
+    pub fn load(&mut self, dtype: GgmlDType, data: &[u8]) -> Result<QCudaStorage> {
+        let half_len = self.staging.len() / 2;
+        let (lo, hi) = self.staging.as_mut_slice().split_at_mut(half_len);
+        let mut halves = [lo, &mut hi[..half_len]];
+        let device = self.device.clone();
+        let mut dst = alloc_for_load(&device, dtype, data.len())?;
+        for (i, chunk) in data.chunks(half_len).enumerate() {
+            let staging = &mut halves[i % 2][..chunk.len()];
+            staging.copy_from_slice(chunk);
+            // Wait for the transfer of the previous chunk so that the transfer launched two
+            // chunks ago, which used the half that is about to be refilled, has completed.
+            device.synchronize()?;
+            let offset = i * half_len;
+            htod_async(&device, staging, &mut dst, offset)?;
+        }
+        device.synchronize()?;
+        Ok(QCudaStorage {
+            _vram: std::sync::Arc::new(VramTicket::new(&device, VramKind::Quantized, dst.len())),
+            data: std::sync::Arc::new(dst),
+            dtype,
+            device,
+            embedding_layout: false,
+            shape: None,
+            transposed_in_file: false,
+            tuned: None,
+            exceptions: None,
+        })
+    }
+}
+
+fn alloc_for_load(device: &CudaDevice, dtype: GgmlDType, len: usize) -> Result<CudaSlice<u8>> {
+    if len % dtype.type_size() != 0 {
+        crate::bail!(
+            "quantized data size {len} is not a multiple of the {dtype:?} type size {}",
+            dtype.type_size()
+        )
+    }
+    QuantCudaCaps::for_device(device)?;
+    Ok(unsafe { device.alloc::<u8>(len).w()? })
+}
+
+// `src` must be pinned memory that is not modified until the stream has been synchronized.
+fn htod_async(
+    device: &CudaDevice,
+    src: &[u8],
+    dst: &mut CudaSlice<u8>,
+    offset: usize,
+) -> Result<()> {
+    use cudarc::driver::DevicePtrMut;
+
+    let mut dst = dst.slice_mut(offset..offset + src.len());
+    unsafe {
+        cudarc::driver::result::memcpy_htod_async(*dst.device_ptr_mut(), src, *device.cu_stream())
+    }
+    .w()?;
+    Ok(())
+}
+
+/// A device memory budget shared by [`LazyQCudaStorage`] instances, the least recently used
+/// weights get evicted when uploading a new one would exceed the budget. Pinned weights, see
+/// [`LazyQCudaStorage::pin`], are never evicted. Dropping a lazy storage evicts its weights.
+#[derive(Clone)]
+pub struct LazyQCudaPool {
+    device: CudaDevice,
+    inner: std::sync::Arc<std::sync::Mutex<LazyQCudaPoolInner>>,
+}
+
+struct LazyQCudaPoolInner {
+    capacity_in_bytes: usize,
+    used_in_bytes: usize,
+    next_id: usize,
+    // Resident weights, the most recently used one is last.
+    entries: Vec<(usize, std::sync::Arc<QCudaStorage>)>,
+    // Ids of the resident weights that cannot be evicted.
+    pinned: Vec<usize>,
+}
+
+impl LazyQCudaPool {
+    pub fn new(device: &CudaDevice, capacity_in_bytes: usize) -> Self {
+        let inner = LazyQCudaPoolInner {
+            capacity_in_bytes,
+            used_in_bytes: 0,
+            next_id: 0,
+            entries: vec![],
+            pinned: vec![],
+        };
+        Self {
+            device: device.clone(),
+            inner: std::sync::Arc::new(std::sync::Mutex::new(inner)),
+        }
+    }
+
+    pub fn device(&self) -> &CudaDevice {
+        &self.device
+    }
+
+    pub fn capacity_in_bytes(&self) -> usize {
+        self.inner.lock().unwrap().capacity_in_bytes
+    }
+
+    /// The number of bytes currently used by resident weights.
+    pub fn used_in_bytes(&self) -> usize {
+        self.inner.lock().unwrap().used_in_bytes
+    }
+
+    /// The number of bytes used by pinned weights, these are not available to the other ones.
+    pub fn pinned_in_bytes(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .entries
+            .iter()
+            .filter(|(i, _)| inner.pinned.contains(i))
+            .map(|(_, s)| s.storage_size_in_bytes())
+            .sum()
+    }
+
+    fn is_resident(&self, id: usize) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.entries.iter().any(|(i, _)| *i == id)
+    }
+
+    fn is_pinned(&self, id: usize) -> bool {
+        self.inner.lock().unwrap().pinned.contains(&id)
+    }
+
+    fn unpin(&self, id: usize) {
+        self.inner.lock().unwrap().pinned.retain(|i| *i != id)
+    }
+
+    // Removes the weights `id` from the pool, they are freed once the last user drops them.
+    fn evict(&self, id: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.pinned.retain(|i| *i != id);
+        if let Some(pos) = inner.entries.iter().position(|(i, _)| *i == id) {
+            let (_, evicted) = inner.entries.remove(pos);
+            inner.used_in_bytes -= evicted.storage_size_in_bytes();
+        }
+    }
+
+    // Returns the resident weights `id`, uploading them if needed. With `pin` set, the weights
+    // are also pinned while holding the lock so that they cannot be evicted in between. The room
+    // for an upload is reserved under the lock but the copy itself runs without holding it.
+    fn get_or_upload(
+        &self,
+        id: usize,
+        dtype: GgmlDType,
+        data: &[u8],
+        pin: bool,
+    ) -> Result<std::sync::Arc<QCudaStorage>> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(storage) = inner.touch(id, pin) {
+                return Ok(storage);
+            }
+            let pinned_in_bytes: usize = inner
+                .entries
+                .iter()
+                .filter(|(i, _)| inner.pinned.contains(i))
+                .map(|(_, s)| s.storage_size_in_bytes())
+                .sum();
+            if pinned_in_bytes + data.len() > inner.capacity_in_bytes {
+                crate::bail!(
+                    "quantized weight of {} bytes exceeds the pool capacity {} with {pinned_in_bytes} bytes pinned",
+                    data.len(),
+                    inner.capacity_in_bytes
+                )
+            }
+            // Evicted weights are only freed once the last user drops them. The pinned weights
+            // are skipped, the check above ensures that evicting the others makes enough room
+            // unless other uploads are in flight.
+            while inner.used_in_bytes + data.len() > inner.capacity_in_bytes {
+                let pos = match inner
+                    .entries
+                    .iter()
+                    .position(|(i, _)| !inner.pinned.contains(i))
+                {
+                    Some(pos) => pos,
+                    None => crate::bail!("no evictable weights in the pool"),
+                };
+                let (_, evicted) = inner.entries.remove(pos);
+                inner.used_in_bytes -= evicted.storage_size_in_bytes();
+            }
+            inner.used_in_bytes += data.len();
+        }
+        let storage = load_quantized_bytes(&self.device, dtype, data);
+        let mut inner = self.inner.lock().unwrap();
+        let storage = match storage {
+            Ok(storage) => std::sync::Arc::new(storage),
+            Err(err) => {
+                inner.used_in_bytes -= data.len();
+                return Err(err);
+            }
+        };
+        // Another thread may have uploaded the same weights in the meantime, keep its copy.
+        if let Some(existing) = inner.touch(id, pin) {
+            inner.used_in_bytes -= data.len();
+            return Ok(existing);
+        }
+        inner.entries.push((id, storage.clone()));
+        if pin {
+            inner.pinned.push(id)
+        }
+        Ok(storage)
+    }
+}
+
+impl LazyQCudaPoolInner {
+    // Marks the weights `id` as the most recently used ones and returns them if resident.
+    fn touch(&mut self, id: usize, pin: bool) -> Option<std::sync::Arc<QCudaStorage>> {
+        let pos = self.entries.iter().position(|(i, _)| *i == id)?;
+        let entry = self.entries.remove(pos);
+        let storage = entry.1.clone();
+        self.entries.push(entry);
+        if pin && !self.pinned.contains(&id) {
+            self.pinned.push(id)
+        }
+        Some(storage)
+    }
+}
+
+/// Quantized weights that stay on the host, e.g. in a memory mapped gguf file, and are only
+/// uploaded to the device when used.
+pub struct LazyQCudaStorage {
+    id: usize,
+    dtype: GgmlDType,
+    host_data: std::sync::Arc<dyn AsRef<[u8]> + Send + Sync>,
+    range: std::ops::Range<usize>,
+    pool: LazyQCudaPool,
+}
+
+impl LazyQCudaStorage {
+    /// Creates a lazy storage for the ggml blocks in `host_data[range]`.
+    pub fn new(
+        pool: &LazyQCudaPool,
+        dtype: GgmlDType,
+        host_data: std::sync::Arc<dyn AsRef<[u8]> + Send + Sync>,
+        range: std::ops::Range<usize>,
+    ) -> Result<Self> {
+        let len = (*host_data).as_ref().len();
+        if range.start > range.end || range.end > len {
+            crate::bail!("invalid range {range:?} for host data of size {len}")
+        }
+        if range.len() % dtype.type_size() != 0 {
+            crate::bail!(
+                "quantized data size {} is not a multiple of the {dtype:?} type size {}",
+                range.len(),
+                dtype.type_size()
+            )
+        }
+        let id = {
+            let mut inner = pool.inner.lock().unwrap();
+            inner.next_id += 1;
+            inner.next_id
+        };
+        Ok(Self {
+            id,
+            dtype,
+            host_data,
+            range,
+            pool: pool.clone(),
+        })
+    }
+
+    pub fn dtype(&self) -> GgmlDType {
+        self.dtype
+    }
+
+    pub fn device(&self) -> &CudaDevice {
+        self.pool.device()
+    }
+
+    pub fn storage_size_in_bytes(&self) -> usize {
+        self.range.len()
+    }
+
+    /// Whether the weights are currently uploaded to the device.
+    pub fn is_resident(&self) -> bool {
+        self.pool.is_resident(self.id)
+    }
+
+    /// Returns the device storage, uploading it if needed.
+    pub fn resident(&self) -> Result<std::sync::Arc<QCudaStorage>> {
+        let data = &(*self.host_data).as_ref()[self.range.clone()];
+        self.pool.get_or_upload(self.id, self.dtype, data, false)
+    }
+
+    /// Uploads the weights if needed and keeps them resident until [`LazyQCudaStorage::unpin`] is
+    /// called or this is dropped, e.g. for the attention weights while the feed-forward ones are
+    /// paged in and out. This fails when the pinned weights would not fit in the pool.
+    pub fn pin(&self) -> Result<()> {
+        let data = &(*self.host_data).as_ref()[self.range.clone()];
+        self.pool.get_or_upload(self.id, self.dtype, data, true)?;
+        Ok(())
+    }
+
+    /// Lets the weights be evicted again, they stay resident until then.
+    pub fn unpin(&self) {
+        self.pool.unpin(self.id)
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pool.is_pinned(self.id)
+    }
+
+    pub fn dequantize(&self, elem_count: usize) -> Result<CudaStorage> {
+        self.resident()?.dequantize(elem_count)
+    }
+
+    pub fn fwd(
+        &self,
+        self_shape: &crate::Shape,
+        storage: &CudaStorage,
+        layout: &crate::Layout,
+    ) -> Result<(CudaStorage, crate::Shape)> {
+        self.resident()?.fwd(self_shape, storage, layout)
+    }
+}
+
+impl Drop for LazyQCudaStorage {
+    fn drop(&mut self) {
+        self.pool.evict(self.id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Reference result for `xs @ y` where `xs` has shape `(nrows, y.len())`, computed on the host
+    /// by dequantizing the weights and running a plain f32 dot product per row.
+    fn cpu_reference_mmv(xs: &QCudaStorage, y: &[f32], nrows: usize) -> Result<Vec<f32>> {
+        let ncols = y.len();
+        let buffer = xs.device.dtoh_sync_copy(&*xs.data).w()?;
+        let weights = dequantize_on_cpu(&buffer, xs.dtype, ncols * nrows)?;
+        let out = weights
+            .chunks(ncols)
+            .map(|row| row.iter().zip(y.iter()).map(|(w, y)| w * y).sum())
+            .collect();
+        Ok(out)
+    }
+
+    fn assert_close(vs: &[f32], expected: &[f32], tolerance: f32) {
+        assert_eq!(vs.len(), expected.len());
+        for (v, e) in vs.iter().zip(expected.iter()) {
+            let err = (v - e).abs() / e.abs().max(1.0);
+            assert!(err <= tolerance, "{v} vs {e}, error {err} > {tolerance}");
+        }
+    }
+
+    /// Activation of `ncols` ones, multiplying the weights by it gives their row sums.
+    fn ones_activation(ncols: usize) -> Vec<f32> {
+        vec![1.0; ncols]
+    }
+
+    /// Activation selecting the column `col` of the weights.
+    fn unit_activation(ncols: usize, col: usize) -> Vec<f32> {
+        let mut vs = vec![0.0; ncols];
+        vs[col] = 1.0;
+        vs
+    }
+
+    fn assert_rows_close(what: &str, out: &[f32], expected: &[f32], tolerance: f32) {
+        assert_eq!(out.len(), expected.len(), "{what}");
+        for (row, (v, e)) in out.iter().zip(expected.iter()).enumerate() {
+            let err = (v - e).abs() / e.abs().max(1.0);
+            assert!(
+                err <= tolerance,
+                "{what}, row {row}: {v} vs {e}, error {err}"
+            );
+        }
+    }
+
+    /// Checks a matmul-vec output of `xs`, with shape `(out.len(), ncols)`, computed with
+    /// [`ones_activation`] against the row sums of the dequantized weights.
+    fn assert_row_sums(xs: &QCudaStorage, out: &[f32], ncols: usize, tolerance: f32) -> Result<()> {
+        let buffer = xs.device.dtoh_sync_copy(&*xs.data).w()?;
+        let weights = dequantize_on_cpu(&buffer, xs.dtype, ncols * out.len())?;
+        let expected: Vec<f32> = weights.chunks(ncols).map(|row| row.iter().sum()).collect();
+        assert_rows_close(
+            &format!("{:?} row sums", xs.dtype),
+            out,
+            &expected,
+            tolerance,
+        );
+        Ok(())
+    }
+
+    /// Checks a matmul-vec output of `xs` computed with [`unit_activation`] for `col` against this
+    /// column of the dequantized weights.
+    fn assert_column(
+        xs: &QCudaStorage,
+        out: &[f32],
+        ncols: usize,
+        col: usize,
+        tolerance: f32,
+    ) -> Result<()> {
+        let buffer = xs.device.dtoh_sync_copy(&*xs.data).w()?;
+        let weights = dequantize_on_cpu(&buffer, xs.dtype, ncols * out.len())?;
+        let expected: Vec<f32> = weights.chunks(ncols).map(|row| row[col]).collect();
+        let what = format!("{:?} column {col}", xs.dtype);
+        assert_rows_close(&what, out, &expected, tolerance);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_quantize_q8_1() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let el = 256;
+        let el_padded = pad(el, MATRIX_ROW_PADDING);
+        let y_size_in_bytes =
+            el_padded * GgmlDType::Q8_1.type_size() / GgmlDType::Q8_1.block_size();
+        let mut y_q8_1 = unsafe { dev.alloc::<u8>(y_size_in_bytes).w()? };
+        let vs: Vec<f32> = (0..el).map(|v| v as f32).collect();
+        let y = dev.htod_sync_copy(&vs).w()?;
+        quantize_q8_1(
+            &y.slice(..),
+            &mut y_q8_1,
+            el,
+            MATRIX_ROW_PADDING,
+            Q8_1Rounding::Nearest,
+            &dev,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_quantize_q8_1_rounding() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let el = 32;
         let el_padded = pad(el, MATRIX_ROW_PADDING);
         let y_size_in_bytes =
             el_padded * GgmlDType::Q8_1.type_size() / GgmlDType::Q8_1.block_size();
-        let mut y_q8_1 = unsafe { dev.alloc::<u8>(y_size_in_bytes).w()? };
-        let vs: Vec<f32> = (0..el).map(|v| v as f32).collect();
+        // The max-abs of 127 gives a scale of 1 so the quants are the rounded values.
+        let mut vs = vec![127., 2.6, -2.6, 1.5, -1.5, 0.2];
+        vs.resize(el, 0.);
+        let y = dev.htod_sync_copy(&vs).w()?;
+        let quantize = |rounding| -> Result<Vec<i8>> {
+            let mut y_q8_1 = dev.alloc_zeros::<u8>(y_size_in_bytes).w()?;
+            quantize_q8_1(
+                &y.slice(..),
+                &mut y_q8_1,
+                el,
+                MATRIX_ROW_PADDING,
+                rounding,
+                &dev,
+            )?;
+            let bytes = dev.dtoh_sync_copy(&y_q8_1).w()?;
+            // Skip the half2 scale and sum of the first block.
+            Ok(bytes[4..4 + 6].iter().map(|&b| b as i8).collect())
+        };
+        assert_eq!(quantize(Q8_1Rounding::Nearest)?, [127, 3, -3, 2, -2, 0]);
+        assert_eq!(quantize(Q8_1Rounding::TowardZero)?, [127, 2, -2, 1, -1, 0]);
+        let stochastic = quantize(Q8_1Rounding::Stochastic { seed: 42 })?;
+        assert_eq!(stochastic, quantize(Q8_1Rounding::Stochastic { seed: 42 })?);
+        for (q, v) in stochastic.iter().zip(vs.iter()) {
+            assert!(*q as f32 == v.floor() || *q as f32 == v.ceil(), "{q} {v}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_quantize_q8_1_block_size() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let el = 3000;
+        let vs: Vec<f32> = (0..el).map(|v| (v as f32 * 0.37).sin()).collect();
+        let y = dev.htod_sync_copy(&vs).w()?;
+        let quantize = |block_size| -> Result<Vec<u8>> {
+            let config = QuantCudaConfig {
+                quantize_q8_1_block_size: block_size,
+                ..Default::default()
+            };
+            let _scope = ConfigScope::enter(&dev, config)?;
+            let size = q8_1_buffer_size(el, MATRIX_ROW_PADDING);
+            let mut y_q8_1 = dev.alloc_zeros::<u8>(size).w()?;
+            let y = y.slice(..);
+            quantize_q8_1(
+                &y,
+                &mut y_q8_1,
+                el,
+                MATRIX_ROW_PADDING,
+                Q8_1Rounding::Nearest,
+                &dev,
+            )?;
+            dev.dtoh_sync_copy(&y_q8_1).w()
+        };
+        let expected = quantize(CUDA_QUANTIZE_BLOCK_SIZE)?;
+        for block_size in [32, 96, 1024] {
+            assert_eq!(quantize(block_size)?, expected, "{block_size}");
+        }
+        for block_size in [0, 48, 2048] {
+            let config = QuantCudaConfig {
+                quantize_q8_1_block_size: block_size,
+                ..Default::default()
+            };
+            assert!(QuantCudaConfig::set_for_device(&dev, config).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_quantize_q8_1_integer_fast_path() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let el = 64;
+        let el_padded = pad(el, MATRIX_ROW_PADDING);
+        let y_size_in_bytes =
+            el_padded * GgmlDType::Q8_1.type_size() / GgmlDType::Q8_1.block_size();
+        // The first block only holds small integers, the second one has a fractional value.
+        let mut vs: Vec<f32> = (0..32).map(|i| (i % 7) as f32 - 3.).collect();
+        vs.extend((0..32).map(|i| if i == 5 { 0.5 } else { i as f32 }));
+        let y = dev.htod_sync_copy(&vs).w()?;
+        let quantize = |fast_path| -> Result<Vec<u8>> {
+            let config = QuantCudaConfig {
+                q8_1_integer_fast_path: fast_path,
+                ..Default::default()
+            };
+            let _scope = ConfigScope::enter(&dev, config)?;
+            let mut y_q8_1 = dev.alloc_zeros::<u8>(y_size_in_bytes).w()?;
+            quantize_q8_1(
+                &y.slice(..),
+                &mut y_q8_1,
+                el,
+                MATRIX_ROW_PADDING,
+                Q8_1Rounding::Nearest,
+                &dev,
+            )?;
+            dev.dtoh_sync_copy(&y_q8_1).w()
+        };
+        let slow = quantize(false)?;
+        let fast = quantize(true)?;
+
+        // A q8_1 block is a half2 scale and sum followed by the 32 quants.
+        let block = |bytes: &[u8], i: usize| bytes[36 * i..36 * (i + 1)].to_vec();
+        let d = half::f16::from_le_bytes([fast[0], fast[1]]);
+        let s = half::f16::from_le_bytes([fast[2], fast[3]]);
+        assert_eq!(d.to_f32(), 1.);
+        assert_eq!(s.to_f32(), vs[..32].iter().sum::<f32>());
+        let qs: Vec<f32> = fast[4..36].iter().map(|&b| b as i8 as f32).collect();
+        assert_eq!(qs, vs[..32]);
+        assert_ne!(block(&fast, 0), block(&slow, 0));
+        assert_eq!(block(&fast, 1), block(&slow, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_debug_quantize_q8_1() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let vs: Vec<f32> = (0..40).map(|v| v as f32 - 20.).collect();
+        let bytes = debug_quantize_q8_1(&dev, &vs)?;
+        assert_eq!(bytes.len(), 2 * GgmlDType::Q8_1.type_size());
+        // First block: a half2 with the scale and the sum followed by the 32 quants.
+        let d = half::f16::from_le_bytes([bytes[0], bytes[1]]).to_f32();
+        let sum = half::f16::from_le_bytes([bytes[2], bytes[3]]).to_f32();
+        assert!((d - 20. / 127.).abs() < 1e-4, "{d}");
+        assert_eq!(sum, vs[..32].iter().sum::<f32>());
+        assert_eq!(bytes[4] as i8, -127);
+        assert_eq!(bytes[4 + 20] as i8, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_q8_1() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let ncols = 256;
+        let vs: Vec<f32> = (0..ncols).map(|v| v as f32).collect();
+        let y = dev.htod_sync_copy(&vs).w()?;
+        let mut xs = QCudaStorage::zeros(&dev, ncols, GgmlDType::Q4_0)?;
+        xs.quantize(&CudaStorage::wrap_cuda_slice(y.clone(), dev.clone()))?;
+        let cuda_storage = mul_mat_vec_via_q8_1(
+            &xs.data,
+            &y.slice(..),
+            /* bias */ None,
+            None,
+            /* dtype */ GgmlDType::Q4_0,
+            /* ncols */ ncols,
+            /* nrows */ 1,
+            &dev,
+        )?;
+        let vs = cuda_storage.as_cuda_slice::<f32>()?;
+        let vs = dev.dtoh_sync_copy(&vs.slice(..)).unwrap();
+        assert_eq!(vs.len(), 1);
+        // for n = 255, n.(n+1).(2n+1) / 6 = 5559680
+        // Q8 means 1/256 precision.
+        assert_eq!(vs[0], 5561664.5);
+
+        let cuda_storage = dequantize_mul_mat_vec(
+            &xs.data,
+            &y.slice(..),
+            /* bias */ None,
+            None,
+            /* dtype */ GgmlDType::Q4_0,
+            /* ncols */ ncols,
+            /* nrows */ 1,
+            &dev,
+        )?;
+        let vs = cuda_storage.as_cuda_slice::<f32>()?;
+        let vs = dev.dtoh_sync_copy(&vs.slice(..)).unwrap();
+        assert_eq!(vs.len(), 1);
+        assert_eq!(vs[0], 5561851.0);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_matches_cpu_reference() -> Result<()> {
+        use rand::{Rng, SeedableRng};
+
+        let dev = CudaDevice::new(0)?;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let (ncols, nrows) = (512, 8);
+        let ws: Vec<f32> = (0..ncols * nrows)
+            .map(|_| rng.gen_range(-1.0..1.0))
+            .collect();
+        let vs: Vec<f32> = (0..ncols).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let ws = dev.htod_sync_copy(&ws).w()?;
+        let y = dev.htod_sync_copy(&vs).w()?;
+        for dtype in [
+            GgmlDType::Q4_0,
+            GgmlDType::Q4_1,
+            GgmlDType::Q5_0,
+            GgmlDType::Q5_1,
+            GgmlDType::Q8_0,
+            GgmlDType::Q2K,
+            GgmlDType::Q3K,
+            GgmlDType::Q4K,
+            GgmlDType::Q5K,
+            GgmlDType::Q6K,
+        ] {
+            let mut xs = QCudaStorage::zeros(&dev, ncols * nrows, dtype)?;
+            xs.quantize(&CudaStorage::wrap_cuda_slice(ws.clone(), dev.clone()))?;
+            let expected = cpu_reference_mmv(&xs, &vs, nrows)?;
+            let out = mul_mat_vec_via_q8_1(
+                &xs.data,
+                &y.slice(..),
+                None,
+                None,
+                dtype,
+                ncols,
+                nrows,
+                &dev,
+            )?;
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            assert_close(&out, &expected, 0.05);
+            let out = dequantize_mul_mat_vec(
+                &xs.data,
+                &y.slice(..),
+                None,
+                None,
+                dtype,
+                ncols,
+                nrows,
+                &dev,
+            )?;
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            assert_close(&out, &expected, 1e-3);
+        }
+        Ok(())
+    }
+
+    // Matmul-vec vectors computed from a port of the llama.cpp reference dequantization, written
+    // by `tests/quantized_golden.py`.
+    #[test]
+    fn cuda_golden_vectors() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let f32s = |b: &[u8]| -> Vec<f32> {
+            b.chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect()
+        };
+        for name in [
+            "q4_0", "q4_1", "q5_0", "q5_1", "q8_0", "q2k", "q3k", "q4k", "q5k", "q6k",
+        ] {
+            let path = format!("tests/quantized_golden/mmv_{name}.bin");
+            let bytes =
+                std::fs::read(&path).map_err(|err| crate::Error::from(err).with_path(&path))?;
+            let header: Vec<usize> = bytes[..12]
+                .chunks_exact(4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+                .collect();
+            let dtype = GgmlDType::from_u32(header[0] as u32)?;
+            let (nrows, ncols) = (header[1], header[2]);
+            let w_end = 12 + nrows * ncols / dtype.block_size() * dtype.type_size();
+            let x_end = w_end + 4 * ncols;
+            let w = load_quantized_bytes(&dev, dtype, &bytes[12..w_end])?;
+            let x = f32s(&bytes[w_end..x_end]);
+            let expected = f32s(&bytes[x_end..]);
+            assert_eq!(expected.len(), nrows, "{path}");
+            let y = dev.htod_sync_copy(&x).w()?;
+            let y = y.slice(..);
+            let out = mul_mat_vec_via_q8_1(&w.data, &y, None, None, dtype, ncols, nrows, &dev)?;
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            assert_close(&out, &expected, 0.05);
+            let out = dequantize_mul_mat_vec(&w.data, &y, None, None, dtype, ncols, nrows, &dev)?;
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            assert_close(&out, &expected, 1e-3);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_ones_and_unit_activations() -> Result<()> {
+        use rand::{Rng, SeedableRng};
+
+        let dev = CudaDevice::new(0)?;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(4242);
+        let (ncols, nrows) = (512, 8);
+        let ws: Vec<f32> = (0..ncols * nrows)
+            .map(|_| rng.gen_range(-1.0..1.0))
+            .collect();
+        let ws = dev.htod_sync_copy(&ws).w()?;
+        let ones = dev.htod_sync_copy(&ones_activation(ncols)).w()?;
+        for dtype in [
+            GgmlDType::Q4_0,
+            GgmlDType::Q4_1,
+            GgmlDType::Q5_0,
+            GgmlDType::Q5_1,
+            GgmlDType::Q8_0,
+            GgmlDType::Q2K,
+            GgmlDType::Q3K,
+            GgmlDType::Q4K,
+            GgmlDType::Q5K,
+            GgmlDType::Q6K,
+        ] {
+            let mut xs = QCudaStorage::zeros(&dev, ncols * nrows, dtype)?;
+            xs.quantize(&CudaStorage::wrap_cuda_slice(ws.clone(), dev.clone()))?;
+            // Ones and unit vectors quantize to q8_1 with a single 127 quant per block, the only
+            // error comes from the f16 scale and the float accumulation.
+            let y = ones.slice(..);
+            let out = mul_mat_vec_via_q8_1(&xs.data, &y, None, None, dtype, ncols, nrows, &dev)?;
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            assert_row_sums(&xs, &out, ncols, 1e-3)?;
+            let out = dequantize_mul_mat_vec(&xs.data, &y, None, None, dtype, ncols, nrows, &dev)?;
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            assert_row_sums(&xs, &out, ncols, 1e-3)?;
+            for col in [0, 31, 257, ncols - 1] {
+                let y = dev.htod_sync_copy(&unit_activation(ncols, col)).w()?;
+                let y = y.slice(..);
+                let out =
+                    mul_mat_vec_via_q8_1(&xs.data, &y, None, None, dtype, ncols, nrows, &dev)?;
+                let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+                assert_column(&xs, &out, ncols, col, 1e-3)?;
+                let out =
+                    dequantize_mul_mat_vec(&xs.data, &y, None, None, dtype, ncols, nrows, &dev)?;
+                let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+                assert_column(&xs, &out, ncols, col, 1e-5)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_fused_bias() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (ncols, nrows) = (256, 4);
+        let ws: Vec<f32> = (0..ncols * nrows).map(|v| (v % 7) as f32 - 3.).collect();
+        let vs: Vec<f32> = (0..ncols).map(|v| v as f32 / ncols as f32).collect();
+        let bs: Vec<f32> = (0..nrows).map(|v| v as f32 * 10.).collect();
+        let ws = dev.htod_sync_copy(&ws).w()?;
+        let y = dev.htod_sync_copy(&vs).w()?;
+        let bias = dev.htod_sync_copy(&bs).w()?;
+        let mut xs = QCudaStorage::zeros(&dev, ncols * nrows, GgmlDType::Q8_0)?;
+        xs.quantize(&CudaStorage::wrap_cuda_slice(ws, dev.clone()))?;
+        let y = y.slice(..);
+        let dtype = GgmlDType::Q8_0;
+        for force_dmmv in [false, true] {
+            let (no_bias, with_bias) = if force_dmmv {
+                (
+                    dequantize_mul_mat_vec(&xs.data, &y, None, None, dtype, ncols, nrows, &dev)?,
+                    dequantize_mul_mat_vec(
+                        &xs.data,
+                        &y,
+                        Some(&bias.slice(..)),
+                        None,
+                        dtype,
+                        ncols,
+                        nrows,
+                        &dev,
+                    )?,
+                )
+            } else {
+                (
+                    mul_mat_vec_via_q8_1(&xs.data, &y, None, None, dtype, ncols, nrows, &dev)?,
+                    mul_mat_vec_via_q8_1(
+                        &xs.data,
+                        &y,
+                        Some(&bias.slice(..)),
+                        None,
+                        dtype,
+                        ncols,
+                        nrows,
+                        &dev,
+                    )?,
+                )
+            };
+            let no_bias = dev.dtoh_sync_copy(no_bias.as_cuda_slice::<f32>()?).w()?;
+            let with_bias = dev.dtoh_sync_copy(with_bias.as_cuda_slice::<f32>()?).w()?;
+            let expected: Vec<f32> = no_bias.iter().zip(bs.iter()).map(|(v, b)| v + b).collect();
+            assert_close(&with_bias, &expected, 1e-5);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_valid_rows() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (ncols, nrows, valid_rows) = (256, 8, 3);
+        let ws: Vec<f32> = (0..ncols * nrows).map(|v| (v % 5) as f32 - 2.).collect();
+        let vs: Vec<f32> = (0..ncols).map(|v| v as f32 / ncols as f32).collect();
+        let ws = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ws).w()?, dev.clone());
+        let y = dev.htod_sync_copy(&vs).w()?;
+        let mut xs = QCudaStorage::zeros(&dev, ncols * nrows, GgmlDType::Q4K)?;
+        xs.quantize(&ws)?;
+        let y = y.slice(..);
+        let (data, dtype) = (&xs.data, xs.dtype);
+        for force_dmmv in [false, true] {
+            let (full, masked) = if force_dmmv {
+                (
+                    dequantize_mul_mat_vec(data, &y, None, None, dtype, ncols, nrows, &dev)?,
+                    dequantize_mul_mat_vec(
+                        data,
+                        &y,
+                        None,
+                        Some(valid_rows),
+                        dtype,
+                        ncols,
+                        nrows,
+                        &dev,
+                    )?,
+                )
+            } else {
+                (
+                    mul_mat_vec_via_q8_1(data, &y, None, None, dtype, ncols, nrows, &dev)?,
+                    mul_mat_vec_via_q8_1(
+                        data,
+                        &y,
+                        None,
+                        Some(valid_rows),
+                        dtype,
+                        ncols,
+                        nrows,
+                        &dev,
+                    )?,
+                )
+            };
+            let full = dev.dtoh_sync_copy(full.as_cuda_slice::<f32>()?).w()?;
+            let masked = dev.dtoh_sync_copy(masked.as_cuda_slice::<f32>()?).w()?;
+            assert_eq!(masked[..valid_rows], full[..valid_rows]);
+            assert!(masked[valid_rows..].iter().all(|v| *v == 0.));
+        }
+
+        let self_shape = crate::Shape::from((nrows, ncols));
+        let rhs = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&vs).w()?, dev.clone());
+        let (full, _) = xs.fwd(&self_shape, &rhs, &crate::Layout::contiguous((1, ncols)))?;
+        let layout = crate::Layout::contiguous((1, ncols));
+        let (out, shape) = xs.fwd_vec_masked(&self_shape, &rhs, &layout, nrows + 1)?;
+        assert_eq!(shape.dims(), [1, nrows]);
+        assert_eq!(
+            dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?,
+            dev.dtoh_sync_copy(full.as_cuda_slice::<f32>()?).w()?
+        );
+        let layout = crate::Layout::contiguous((2, ncols / 2));
+        assert!(xs.fwd_vec_masked(&self_shape, &rhs, &layout, 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_lazy_storage_eviction() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let dtype = GgmlDType::Q8_0;
+        let size_in_bytes = 256 / dtype.block_size() * dtype.type_size();
+        let pool = LazyQCudaPool::new(&dev, size_in_bytes);
+        let host_data: std::sync::Arc<dyn AsRef<[u8]> + Send + Sync> =
+            std::sync::Arc::new(vec![0u8; 2 * size_in_bytes]);
+        let w1 = LazyQCudaStorage::new(&pool, dtype, host_data.clone(), 0..size_in_bytes)?;
+        let w2 = LazyQCudaStorage::new(&pool, dtype, host_data, size_in_bytes..2 * size_in_bytes)?;
+        assert!(!w1.is_resident());
+        w1.dequantize(256)?;
+        assert!(w1.is_resident());
+        w2.dequantize(256)?;
+        assert!(w2.is_resident());
+        assert!(!w1.is_resident());
+        assert_eq!(pool.used_in_bytes(), size_in_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_lazy_storage_pinning() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let dtype = GgmlDType::Q8_0;
+        let size_in_bytes = 256 / dtype.block_size() * dtype.type_size();
+        let pool = LazyQCudaPool::new(&dev, 2 * size_in_bytes);
+        let host_data: std::sync::Arc<dyn AsRef<[u8]> + Send + Sync> =
+            std::sync::Arc::new(vec![0u8; 3 * size_in_bytes]);
+        let ws = (0..3)
+            .map(|i| {
+                let range = i * size_in_bytes..(i + 1) * size_in_bytes;
+                LazyQCudaStorage::new(&pool, dtype, host_data.clone(), range)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        ws[0].pin()?;
+        assert!(ws[0].is_resident() && ws[0].is_pinned());
+        // The unpinned weights share the remaining room, the pinned one stays resident even
+        // though it is the least recently used.
+        ws[1].dequantize(256)?;
+        ws[2].dequantize(256)?;
+        assert!(ws[0].is_resident() && !ws[1].is_resident() && ws[2].is_resident());
+        assert_eq!(pool.pinned_in_bytes(), size_in_bytes);
+
+        // Pinning a second weight fills the pool, the third one cannot be uploaded anymore.
+        ws[1].pin()?;
+        assert!(!ws[2].is_resident());
+        assert!(ws[2].dequantize(256).is_err());
+        assert!(ws[2].pin().is_err());
+        assert!(!ws[2].is_pinned());
+
+        ws[0].unpin();
+        ws[2].dequantize(256)?;
+        assert!(!ws[0].is_resident() && ws[1].is_resident() && ws[2].is_resident());
+        // Dropping a pinned weight unpins it and frees its room in the pool.
+        let mut ws = ws;
+        let w2 = ws.pop().unwrap();
+        drop(ws.pop());
+        assert!(w2.is_resident());
+        assert_eq!(pool.pinned_in_bytes(), 0);
+        assert_eq!(pool.used_in_bytes(), size_in_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_matmul_batched() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (b, m, n, k) = (2, 3, 5, 7);
+        let ws: Vec<f32> = (0..n * k).map(|v| (v % 11) as f32 - 5.).collect();
+        let xs: Vec<f32> = (0..b * m * k).map(|v| (v % 5) as f32 - 2.).collect();
+        let mut w = QCudaStorage::zeros(&dev, n * k, GgmlDType::F32)?;
+        let ws_dev = dev.htod_sync_copy(&ws).w()?;
+        w.quantize(&CudaStorage::wrap_cuda_slice(ws_dev, dev.clone()))?;
+        let x = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        let x_l = crate::Layout::contiguous((b, m, k));
+        let (out, out_shape) = w.fwd(&(n, k).into(), &x, &x_l)?;
+        assert_eq!(out_shape.dims(), [b, m, n]);
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        let mut expected = vec![0f32; b * m * n];
+        for bi in 0..b {
+            for mi in 0..m {
+                for ni in 0..n {
+                    expected[(bi * m + mi) * n + ni] = (0..k)
+                        .map(|ki| xs[(bi * m + mi) * k + ki] * ws[ni * k + ki])
+                        .sum();
+                }
+            }
+        }
+        assert_eq!(out, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_moe() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (ncols, nrows, n_experts) = (256, 4, 3);
+        let mut experts = vec![];
+        for e in 0..n_experts {
+            let ws: Vec<f32> = (0..ncols * nrows).map(|v| ((v + e) % 5) as f32).collect();
+            let ws = dev.htod_sync_copy(&ws).w()?;
+            let mut xs = QCudaStorage::zeros(&dev, ncols * nrows, GgmlDType::Q8_0)?;
+            xs.quantize(&CudaStorage::wrap_cuda_slice(ws, dev.clone()))?;
+            experts.push(xs)
+        }
+        let tokens: Vec<f32> = (0..2 * ncols).map(|v| (v % 3) as f32).collect();
+        let tokens = dev.htod_sync_copy(&tokens).w()?;
+        // Two tokens, each routed to two experts.
+        let expert_ids = dev.htod_sync_copy(&[2u32, 0, 1, 2]).w()?;
+        let out = mul_mat_vec_moe(&experts, &tokens.slice(..), &expert_ids, ncols, nrows, &dev)?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        for (row, (expert, token)) in [(2, 0), (0, 0), (1, 1), (2, 1)].into_iter().enumerate() {
+            let y = tokens.slice(token * ncols..(token + 1) * ncols);
+            let xs = &experts[expert];
+            let expected =
+                mul_mat_vec_via_q8_1(&xs.data, &y, None, None, xs.dtype, ncols, nrows, &dev)?;
+            let expected = dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?;
+            assert_eq!(out[row * nrows..(row + 1) * nrows], expected);
+        }
+        // The rows routed to an unknown expert are zero.
+        let expert_ids = dev.htod_sync_copy(&[2u32, 7, 1, 2]).w()?;
+        let out = mul_mat_vec_moe(&experts, &tokens.slice(..), &expert_ids, ncols, nrows, &dev)?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        assert!(out[nrows..2 * nrows].iter().all(|v| *v == 0.));
+        experts.push(QCudaStorage::zeros(&dev, ncols * nrows, GgmlDType::Q4_0)?);
+        assert!(
+            mul_mat_vec_moe(&experts, &tokens.slice(..), &expert_ids, ncols, nrows, &dev).is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_prefetch_experts() -> Result<()> {
+        use cudarc::driver::sys::CUdevice_attribute as A;
+
+        let dev = CudaDevice::new(0)?;
+        let experts = (0..3)
+            .map(|_| QCudaStorage::zeros(&dev, 1024, GgmlDType::Q4K))
+            .collect::<Result<Vec<_>>>()?;
+        let experts: Vec<_> = experts.iter().collect();
+        // The weights allocated on the device are not managed, nothing gets prefetched.
+        assert_eq!(prefetch_experts(&experts, &[2, 0, 2], None)?, 0);
+        assert_eq!(prefetch_experts(&experts, &[], None)?, 0);
+        assert!(prefetch_experts(&experts, &[1, 3], None).is_err());
+        let concurrent = dev
+            .attribute(A::CU_DEVICE_ATTRIBUTE_CONCURRENT_MANAGED_ACCESS)
+            .w()?;
+        if dev.attribute(A::CU_DEVICE_ATTRIBUTE_MANAGED_MEMORY).w()? == 0 || concurrent == 0 {
+            return Ok(());
+        }
+        let managed = experts
+            .iter()
+            .map(|e| load_quantized_managed(&dev, GgmlDType::Q4K, &e.data_to_host()?))
+            .collect::<Result<Vec<_>>>()?;
+        let managed: Vec<_> = managed.iter().collect();
+        // Each selected expert is prefetched once.
+        assert_eq!(prefetch_experts(&managed, &[2, 0, 2], None)?, 2);
+        assert_eq!(prefetch_experts(&managed, &[1], None)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_q8_0_output() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (ncols, nrows) = (256, 64);
+        let ws: Vec<f32> = (0..ncols * nrows)
+            .map(|v| ((v % 9) as f32 - 4.) / 4.)
+            .collect();
+        let vs: Vec<f32> = (0..ncols).map(|v| v as f32 / ncols as f32).collect();
+        let ws = dev.htod_sync_copy(&ws).w()?;
+        let mut xs = QCudaStorage::zeros(&dev, ncols * nrows, GgmlDType::Q4_0)?;
+        xs.quantize(&CudaStorage::wrap_cuda_slice(ws, dev.clone()))?;
+        let y = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&vs).w()?, dev.clone());
+        let y_l = crate::Layout::contiguous((1, ncols));
+        let self_shape = (nrows, ncols).into();
+        let (out, _) = xs.fwd(&self_shape, &y, &y_l)?;
+        let expected = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        let (out_q, out_shape) = xs.fwd_q8_0(&self_shape, &y, &y_l)?;
+        assert_eq!(out_shape.dims(), [1, nrows]);
+        assert_eq!(out_q.dtype(), GgmlDType::Q8_0);
+        let out = out_q.dequantize(nrows)?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        assert_close(&out, &expected, 0.01);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_matmul_offset() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (n, k, offset) = (8, 64, 13);
+        let ws: Vec<f32> = (0..n * k).map(|v| (v % 7) as f32 - 3.).collect();
+        let ws = dev.htod_sync_copy(&ws).w()?;
+        let mut w = QCudaStorage::zeros(&dev, n * k, GgmlDType::Q8_0)?;
+        w.quantize(&CudaStorage::wrap_cuda_slice(ws, dev.clone()))?;
+        let self_shape = (n, k).into();
+        // Covers both the vector path (m = 1) and the gemm path (m = 3).
+        for m in [1, 3] {
+            let xs: Vec<f32> = (0..m * k).map(|v| (v % 5) as f32 - 2.).collect();
+            let padded: Vec<f32> = vec![42f32; offset].into_iter().chain(xs.clone()).collect();
+            let x = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+            let x_l = crate::Layout::contiguous((m, k));
+            let (expected, _) = w.fwd(&self_shape, &x, &x_l)?;
+            let expected = dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?;
+            let x = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&padded).w()?, dev.clone());
+            let x_l = crate::Layout::contiguous_with_offset((m, k), offset);
+            let (out, out_shape) = w.fwd(&self_shape, &x, &x_l)?;
+            assert_eq!(out_shape.dims(), [m, n]);
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            assert_eq!(out, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_q4_activation() -> Result<()> {
+        use rand::{Rng, SeedableRng};
+
+        let dev = CudaDevice::new(0)?;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(299792458);
+        let (ncols, nrows) = (1024, 16);
+        let ws: Vec<f32> = (0..ncols * nrows)
+            .map(|_| rng.gen_range(-1.0..1.0))
+            .collect();
+        let vs: Vec<f32> = (0..ncols).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let ws = dev.htod_sync_copy(&ws).w()?;
+        let y = dev.htod_sync_copy(&vs).w()?;
+        let mut xs = QCudaStorage::zeros(&dev, ncols * nrows, GgmlDType::Q4_0)?;
+        xs.quantize(&CudaStorage::wrap_cuda_slice(ws, dev.clone()))?;
+        let expected = cpu_reference_mmv(&xs, &vs, nrows)?;
+        let out = mul_mat_via_q4_act(&xs.data, &y.slice(..), xs.dtype, ncols, nrows, 1, &dev)?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        // Int4 activations are lossy, only check that the result is in the right ballpark.
+        let err: f32 = out
+            .iter()
+            .zip(expected.iter())
+            .map(|(a, b)| (a - b).abs())
+            .sum();
+        let norm: f32 = expected.iter().map(|v| v.abs()).sum();
+        assert!(err / norm < 0.2, "relative error {}", err / norm);
+
+        // More activation rows than the grid y limit, with a small weight to keep them cheap.
+        let (ncols, nrows, ny) = (32, 2, MAX_GRID_DIM_Y + 3);
+        let ws = dev.htod_sync_copy(&vs[..ncols * nrows]).w()?;
+        let mut xs = QCudaStorage::zeros(&dev, ncols * nrows, GgmlDType::Q4_0)?;
+        xs.quantize(&CudaStorage::wrap_cuda_slice(ws, dev.clone()))?;
+        let y = &vs[..ncols];
+        let ys = dev.htod_sync_copy(&y.repeat(ny)).w()?;
+        let out = mul_mat_via_q4_act(
+            &xs.data,
+            &ys.slice(..ncols),
+            xs.dtype,
+            ncols,
+            nrows,
+            1,
+            &dev,
+        )?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        let outs = mul_mat_via_q4_act(&xs.data, &ys.slice(..), xs.dtype, ncols, nrows, ny, &dev)?;
+        let outs = dev.dtoh_sync_copy(outs.as_cuda_slice::<f32>()?).w()?;
+        assert_eq!(outs[..nrows], out);
+        assert_eq!(outs[(ny - 1) * nrows..], out);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_quantize_q8_1_rows_beyond_grid_limit() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        // More rows than the grid y limit, e.g. the tokens of a long prompt for the moe kernels.
+        let (ncols, nrows) = (32, MAX_GRID_DIM_Y + 3);
+        let vs: Vec<f32> = (0..ncols * nrows).map(|i| (i % 11) as f32 - 5.3).collect();
+        let src = dev.htod_sync_copy(&vs).w()?;
+        let row_size = q8_1_buffer_size(ncols, ncols);
+        let mut all = dev.alloc_zeros::<u8>(nrows * row_size).w()?;
+        let rounding = Q8_1Rounding::Nearest;
+        quantize_q8_1_rows(
+            &src.slice(..),
+            &mut all,
+            ncols,
+            nrows,
+            ncols,
+            rounding,
+            &dev,
+        )?;
+        let all = dev.dtoh_sync_copy(&all).w()?;
+        for row in [0, MAX_GRID_DIM_Y - 1, MAX_GRID_DIM_Y, nrows - 1] {
+            let mut one = dev.alloc_zeros::<u8>(row_size).w()?;
+            let src = src.slice(row * ncols..(row + 1) * ncols);
+            quantize_q8_1(&src, &mut one, ncols, ncols, rounding, &dev)?;
+            let one = dev.dtoh_sync_copy(&one).w()?;
+            assert_eq!(all[row * row_size..(row + 1) * row_size], one, "{row}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_matmul_rows_of_different_magnitudes() -> Result<()> {
+        use rand::{Rng, SeedableRng};
+
+        let dev = CudaDevice::new(0)?;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1618);
+        let (n, k) = (16, 512);
+        let scales = [1e-3f32, 1., 1e3, 1e-2];
+        let ws: Vec<f32> = (0..n * k).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let mut xs = vec![];
+        for scale in scales {
+            xs.extend((0..k).map(|_| scale * rng.gen_range(-1.0f32..1.0)))
+        }
+        let mut w = QCudaStorage::zeros(&dev, n * k, GgmlDType::Q4_0)?;
+        w.quantize(&CudaStorage::wrap_cuda_slice(
+            dev.htod_sync_copy(&ws).w()?,
+            dev.clone(),
+        ))?;
+        let x = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        let self_shape = crate::Shape::from((n, k));
+        let expected: Vec<Vec<f32>> = xs
+            .chunks(k)
+            .map(|x| cpu_reference_mmv(&w, x, n))
+            .collect::<Result<_>>()?;
+        // The activations are quantized with one scale per block of each row, a single scale for
+        // the whole matrix would flush the small rows to zero.
+        let check_rows = |out: &CudaStorage, tolerance: f32| -> Result<()> {
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            for (i, (out, expected)) in out.chunks(n).zip(expected.iter()).enumerate() {
+                let err: f32 = out.iter().zip(expected).map(|(a, b)| (a - b).abs()).sum();
+                let norm: f32 = expected.iter().map(|v| v.abs()).sum();
+                let rel_err = err / norm;
+                assert!(
+                    rel_err < tolerance,
+                    "row {i} scale {}: {rel_err}",
+                    scales[i]
+                );
+            }
+            Ok(())
+        };
+
+        // Batched vectors, each quantized to q8_1 on its own.
+        let x_l = crate::Layout::contiguous((scales.len(), 1, k));
+        let plan = w.explain_matmul(&self_shape, &x_l)?;
+        assert!(matches!(plan, MatMulPlan::BatchedVec { .. }), "{plan:?}");
+        let (out, _) = w.fwd(&self_shape, &x, &x_l)?;
+        check_rows(&out, 0.05)?;
+
+        // A single matrix with its activations quantized to int4.
+        let x_l = crate::Layout::contiguous((1, scales.len(), k));
+        let config = QuantCudaConfig {
+            experimental_q4_activation: true,
+            ..QuantCudaConfig::default()
+        };
+        let scope = ConfigScope::enter(&dev, config)?;
+        let plan = w.explain_matmul(&self_shape, &x_l);
+        let res = w.fwd(&self_shape, &x, &x_l);
+        drop(scope);
+        assert_eq!(plan?, MatMulPlan::Q4Activation { rows: scales.len() });
+        check_rows(&res?.0, 0.3)?;
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_matmul_q4_activation() -> Result<()> {
+        use rand::{Rng, SeedableRng};
+
+        let dev = CudaDevice::new(0)?;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(299792458);
+        let (b, m, n, k) = (2, 5, 16, 1024);
+        let ws: Vec<f32> = (0..n * k).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let xs: Vec<f32> = (0..b * m * k).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let mut w = QCudaStorage::zeros(&dev, n * k, GgmlDType::Q4_0)?;
+        w.quantize(&CudaStorage::wrap_cuda_slice(
+            dev.htod_sync_copy(&ws).w()?,
+            dev.clone(),
+        ))?;
+        let x = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        let x_l = crate::Layout::contiguous((b, m, k));
+        let self_shape = crate::Shape::from((n, k));
+        let mut expected = vec![];
+        for x in xs.chunks(k) {
+            expected.extend(cpu_reference_mmv(&w, x, n)?)
+        }
+        let rel_error = |out: &CudaStorage| -> Result<f32> {
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            let err: f32 = out
+                .iter()
+                .zip(expected.iter())
+                .map(|(a, b)| (a - b).abs())
+                .sum();
+            let norm: f32 = expected.iter().map(|v| v.abs()).sum();
+            Ok(err / norm)
+        };
+        let (out, _) = w.fwd(&self_shape, &x, &x_l)?;
+        let f32_err = rel_error(&out)?;
+
+        let config = QuantCudaConfig {
+            experimental_q4_activation: true,
+            ..QuantCudaConfig::default()
+        };
+        let scope = ConfigScope::enter(&dev, config)?;
+        let plan = w.explain_matmul(&self_shape, &x_l);
+        let res = w.fwd(&self_shape, &x, &x_l);
+        drop(scope);
+        assert_eq!(plan?, MatMulPlan::Q4Activation { rows: b * m });
+        let (out, out_shape) = res?;
+        assert_eq!(out_shape.dims(), &[b, m, n]);
+        let q4_err = rel_error(&out)?;
+        // Quantizing the activations to int4 adds an error of the same order as the q4_0 weight
+        // quantization, the dense path only has the float rounding error.
+        assert!(f32_err < 1e-4, "{f32_err}");
+        assert!(q4_err < 0.2, "{q4_err}");
+        assert!(q4_err > f32_err, "{q4_err} <= {f32_err}");
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_ftz() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        // bf16 shares the f32 exponent range so its weights can be subnormal f32 values.
+        let values = [1e-39f32, -1e-39, f32::MIN_POSITIVE, 1.0, -0.5, 0.0];
+        let bytes: Vec<u8> = values
+            .iter()
+            .flat_map(|v| half::bf16::from_f32(*v).to_le_bytes())
+            .collect();
+        let xs = load_quantized_bytes(&dev, GgmlDType::BF16, &bytes)?;
+        let dequantized = |xs: &QCudaStorage| -> Result<Vec<f32>> {
+            let out = xs.dequantize(values.len())?;
+            dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()
+        };
+        // Off by default, the subnormal values are kept.
+        let out = dequantized(&xs)?;
+        assert!(out[0].is_subnormal() && out[1].is_subnormal());
+        let config = QuantCudaConfig {
+            dequantize_ftz: true,
+            ..QuantCudaConfig::default()
+        };
+        let scope = ConfigScope::enter(&dev, config)?;
+        let out = dequantized(&xs);
+        // The chunked matmuls dequantize the weights by ranges of rows.
+        let range = xs.dequantize_range(0, values.len(), values.len());
+        drop(scope);
+        let out = out?;
+        let range = dev.dtoh_sync_copy(range?.as_cuda_slice::<f32>()?).w()?;
+        let bits = |xs: &[f32]| xs.iter().map(|v| v.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&range), bits(&out));
+        assert_eq!(out[0].to_bits(), 0f32.to_bits());
+        assert_eq!(out[1].to_bits(), (-0f32).to_bits());
+        let normal: Vec<f32> = values[2..]
+            .iter()
+            .map(|v| half::bf16::from_f32(*v).to_f32())
+            .collect();
+        assert_eq!(out[2..], normal);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_clamped() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let xs: Vec<f32> = (0..256).map(|i| (i as f32 - 128.) / 32.).collect();
+        let mut w = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q8_0)?;
+        w.quantize(&CudaStorage::wrap_cuda_slice(
+            dev.htod_sync_copy(&xs).w()?,
+            dev.clone(),
+        ))?;
+        let expected = w.dequantize(xs.len())?;
+        let expected = dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?;
+        let expected: Vec<f32> = expected.iter().map(|v| v.clamp(-1.5, 0.5)).collect();
+        let (out, nan_count) = w.dequantize_clamped(xs.len(), -1.5, 0.5)?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        assert_eq!(out, expected);
+        assert_eq!(dev.dtoh_sync_copy(&nan_count).w()?, [0]);
+
+        let err = w.dequantize_clamped(xs.len(), 1., -1.).unwrap_err();
+        assert!(err.to_string().contains("invalid clamp range"), "{err}");
+        let err = w.dequantize_clamped(xs.len(), f32::NAN, 1.).unwrap_err();
+        assert!(err.to_string().contains("invalid clamp range"), "{err}");
+
+        // Infinite values go to the nearest bound, NaN values are left as is and counted, both
+        // in the fused kernel of bf16 and on the cpu path of f16.
+        let mut xs = vec![1f32; 64];
+        xs[3] = f32::INFINITY;
+        xs[5] = f32::NEG_INFINITY;
+        xs[7] = f32::NAN;
+        xs[9] = f32::NAN;
+        let bf16: Vec<u8> = xs
+            .iter()
+            .flat_map(|v| half::bf16::from_f32(*v).to_le_bytes())
+            .collect();
+        let f16: Vec<u8> = xs
+            .iter()
+            .flat_map(|v| half::f16::from_f32(*v).to_le_bytes())
+            .collect();
+        for (dtype, bytes) in [(GgmlDType::BF16, bf16), (GgmlDType::F16, f16)] {
+            let w = load_quantized_bytes(&dev, dtype, &bytes)?;
+            let (out, nan_count) = w.dequantize_clamped(xs.len(), -2., 2.)?;
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            assert_eq!((out[3], out[5], out[4]), (2., -2., 1.), "{dtype:?}");
+            assert!(out[7].is_nan() && out[9].is_nan(), "{dtype:?}");
+            assert_eq!(dev.dtoh_sync_copy(&nan_count).w()?, [2], "{dtype:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_reshape() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let xs: Vec<f32> = (0..128).map(|i| (i as f32 - 64.) / 16.).collect();
+        let mut w = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q4_0)?;
+        w.quantize(&CudaStorage::wrap_cuda_slice(
+            dev.htod_sync_copy(&xs).w()?,
+            dev.clone(),
+        ))?;
+        w.set_shape((2, 64))?;
+        let r = w.reshape((4, 32))?;
+        assert_eq!(r.shape().map(|s| s.dims()), Some(&[4, 32][..]));
+        assert_eq!(w.shape().map(|s| s.dims()), Some(&[2, 64][..]));
+        let expected = w.dequantize_tensor()?.flatten_all()?.to_vec1::<f32>()?;
+        let got = r.dequantize_tensor()?;
+        assert_eq!(got.dims(), &[4, 32]);
+        assert_eq!(got.flatten_all()?.to_vec1::<f32>()?, expected);
+
+        let err = w.reshape((8, 16)).unwrap_err().to_string();
+        assert!(err.contains("would split Q4_0 blocks"), "{err}");
+        let err = w.reshape((3, 32)).unwrap_err().to_string();
+        assert!(err.contains("does not match 128 elements"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_matmul_empty_batch() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (n, k) = (64, 4096);
+        let w = QCudaStorage::zeros(&dev, n * k, GgmlDType::Q4_0)?;
+        let x = CudaStorage::wrap_cuda_slice(dev.alloc_zeros::<f32>(k).w()?, dev.clone());
+        let calls = DEQUANTIZE_CALLS.with(|c| c.get());
+        for dims in [&[0, 4, k][..], &[0, 1, k], &[2, 0, k], &[0, k]] {
+            let x_l = crate::Layout::contiguous(dims);
+            let (out, out_shape) = w.fwd(&(n, k).into(), &x, &x_l)?;
+            let mut expected = dims.to_vec();
+            *expected.last_mut().unwrap() = n;
+            assert_eq!(out_shape.dims(), expected);
+            assert_eq!(out.as_cuda_slice::<f32>()?.len(), 0);
+        }
+        assert_eq!(DEQUANTIZE_CALLS.with(|c| c.get()), calls);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_for_matmul() -> Result<()> {
+        use rand::{Rng, SeedableRng};
+
+        let dev = CudaDevice::new(0)?;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(2718);
+        let (chunk, n, k) = (5, 32, 256);
+        let ws: Vec<f32> = (0..n * k).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let xs: Vec<f32> = (0..3 * chunk * k)
+            .map(|_| rng.gen_range(-1.0..1.0))
+            .collect();
+        let mut w = QCudaStorage::zeros(&dev, n * k, GgmlDType::Q4K)?;
+        w.quantize(&CudaStorage::wrap_cuda_slice(
+            dev.htod_sync_copy(&ws).w()?,
+            dev.clone(),
+        ))?;
+        let x = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        let self_shape = crate::Shape::from((n, k));
+        let dense = w.dequantize_for_matmul(&self_shape)?;
+        assert!(!dense.is_f16());
+        assert_eq!(dense.shape(), &self_shape);
+        // Prefill by chunks of `chunk` tokens, only the first call dequantizes.
+        for i in 0..3 {
+            let x_l = crate::Layout::contiguous_with_offset((1, chunk, k), i * chunk * k);
+            let (expected, expected_shape) = w.fwd(&self_shape, &x, &x_l)?;
+            let (out, out_shape) = dense.fwd(&x, &x_l)?;
+            assert_eq!(out_shape, expected_shape);
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            let expected = dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?;
+            assert_eq!(out, expected);
+        }
+        let x_l = crate::Layout::contiguous((chunk, k + 32));
+        assert!(dense.fwd(&x, &x_l).is_err());
+        assert!(w.dequantize_for_matmul(&(n, 2 * k).into()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_vram_report() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (n, k) = (32, 256);
+        let w1 = QCudaStorage::zeros(&dev, n * k, GgmlDType::Q4_0)?;
+        let w2 = QCudaStorage::zeros(&dev, n * k, GgmlDType::Q8_0)?;
+        let bytes = w1.storage_size_in_bytes() + w2.storage_size_in_bytes();
+        let report = quant_vram_report(&dev)?;
+        assert_eq!(report.quantized_bytes, bytes);
+        assert_eq!(report.dequantized_bytes, 0);
+        assert!(report.free_bytes <= report.total_bytes);
+        {
+            // A clone shares the weights.
+            let _w3 = w2.clone();
+            assert_eq!(quant_vram_report(&dev)?.quantized_bytes, bytes);
+        }
+        let dense = w1.dequantize_for_matmul(&(n, k).into())?;
+        let dense_bytes = n * k * if dense.is_f16() { 2 } else { 4 };
+        let report = quant_vram_report(&dev)?;
+        assert_eq!(report.quantized_bytes, bytes);
+        assert_eq!(report.dequantized_bytes, dense_bytes);
+        assert_eq!(report.accounted_bytes(), bytes + dense_bytes);
+        drop(dense);
+        assert_eq!(quant_vram_report(&dev)?.dequantized_bytes, 0);
+
+        let pool = LazyQCudaPool::new(&dev, 1 << 20);
+        let report = QCudaStorage::memory_layout_report(&dev, &[&w1], &[&pool])?;
+        assert_eq!(report.quantized_bytes, w1.storage_size_in_bytes());
+        assert_eq!(report.lazy_pool_capacity_bytes, 1 << 20);
+        // A reshaped clone shares the buffer of `w1` and is only counted once.
+        let w1_clone = w1.reshape((2 * n, k / 2))?;
+        let report = QCudaStorage::memory_layout_report(&dev, &[&w1, &w1_clone], &[])?;
+        assert_eq!(report.quantized_bytes, w1.storage_size_in_bytes());
+        drop(w1_clone);
+        let other = CudaDevice::new(0)?;
+        assert!(QCudaStorage::memory_layout_report(&other, &[&w1], &[]).is_err());
+        drop((w1, w2));
+        assert_eq!(quant_vram_report(&dev)?.quantized_bytes, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_clone_shares_data() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let xs: Vec<f32> = (0..1024).map(|i| (i as f32 * 0.1).sin()).collect();
+        let src = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        let mut embedding = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q8_0)?;
+        embedding.quantize(&src)?;
+        let mut output = embedding.clone();
+        assert!(output.shares_data_with(&embedding));
+        assert_eq!(output.device_ptr(), embedding.device_ptr());
+        assert_eq!(
+            quant_vram_report(&dev)?.quantized_bytes,
+            embedding.storage_size_in_bytes()
+        );
+        // Quantizing again only replaces the buffer of the storage being quantized.
+        output.quantize(&src)?;
+        assert!(!output.shares_data_with(&embedding));
+        assert_ne!(output.device_ptr(), embedding.device_ptr());
+        assert_eq!(output.data_to_host()?, embedding.data_to_host()?);
+        let mut scaled = embedding.clone();
+        scaled.scale_in_place(2.0)?;
+        assert!(!scaled.shares_data_with(&embedding));
+        assert_ne!(scaled.data_to_host()?, embedding.data_to_host()?);
+        assert_eq!(output.data_to_host()?, embedding.data_to_host()?);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_clone_dtype_as() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        // 9 q8_0 blocks of 34 bytes, i.e. 153 f16 values.
+        let q8_0 = QCudaStorage::zeros(&dev, 32 * 9, GgmlDType::Q8_0)?;
+        let f16 = q8_0.clone_dtype_as(GgmlDType::F16)?;
+        assert_eq!(f16.dtype(), GgmlDType::F16);
+        assert_eq!(f16.elem_count(), 34 * 9 / 2);
+        assert!(f16.shares_data_with(&q8_0));
+        assert!(q8_0.clone_dtype_as(GgmlDType::Q4K).is_err());
+        // The recorded shape has to stay valid.
+        let mut shaped = QCudaStorage::zeros(&dev, 64, GgmlDType::Q4_0)?;
+        shaped.set_shape((2, 32))?;
+        assert!(shaped.clone_dtype_as(GgmlDType::F16).is_err());
+        assert_eq!(
+            shaped.clone_dtype_as(GgmlDType::Q4_0)?.shape(),
+            shaped.shape()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_prefetch() -> Result<()> {
+        use cudarc::driver::sys::CUdevice_attribute as A;
+
+        let dev = CudaDevice::new(0)?;
+        let vs: Vec<f32> = (0..1024).map(|v| (v % 13) as f32 - 6.).collect();
+        let mut qs = QCudaStorage::zeros(&dev, vs.len(), GgmlDType::Q4_0)?;
+        qs.quantize(&CudaStorage::wrap_cuda_slice(
+            dev.htod_sync_copy(&vs).w()?,
+            dev.clone(),
+        ))?;
+        // The weights allocated on the device are not managed, prefetching them does nothing.
+        assert!(!qs.prefetch(None)?);
+        let stream = dev.fork_default_stream().w()?;
+        assert!(!qs.prefetch(Some(&stream))?);
+        if dev.attribute(A::CU_DEVICE_ATTRIBUTE_MANAGED_MEMORY).w()? == 0 {
+            return Ok(());
+        }
+        let managed = load_quantized_managed(&dev, GgmlDType::Q4_0, &qs.data_to_host()?)?;
+        let concurrent = dev
+            .attribute(A::CU_DEVICE_ATTRIBUTE_CONCURRENT_MANAGED_ACCESS)
+            .w()?
+            != 0;
+        assert_eq!(managed.prefetch(None)?, concurrent);
+        assert_eq!(managed.prefetch(Some(&stream))?, concurrent);
+        dev.wait_for(&stream).w()?;
+        let expected = qs.dequantize_to_host(vs.len())?;
+        assert_eq!(managed.dequantize_to_host(vs.len())?, expected);
+        assert!(load_quantized_managed(&dev, GgmlDType::Q4_0, &[0; 17]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_matmul_tf32() -> Result<()> {
+        use rand::{Rng, SeedableRng};
+
+        let dev = CudaDevice::new(0)?;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1729);
+        let (b, m, n, k) = (2, 7, 64, 512);
+        let ws: Vec<f32> = (0..n * k).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let xs: Vec<f32> = (0..b * m * k).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let mut w = QCudaStorage::zeros(&dev, n * k, GgmlDType::Q8_0)?;
+        w.quantize(&CudaStorage::wrap_cuda_slice(
+            dev.htod_sync_copy(&ws).w()?,
+            dev.clone(),
+        ))?;
+        let x = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        let x_l = crate::Layout::contiguous((b, m, k));
+        let self_shape = crate::Shape::from((n, k));
+        let (out, _) = w.fwd(&self_shape, &x, &x_l)?;
+        let expected = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+
+        let config = QuantCudaConfig {
+            dequantize_matmul_tf32: true,
+            ..QuantCudaConfig::default()
+        };
+        let scope = ConfigScope::enter(&dev, config)?;
+        let res = w.fwd(&self_shape, &x, &x_l);
+        drop(scope);
+        let (out, out_shape) = res?;
+        assert_eq!(out_shape.dims(), &[b, m, n]);
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        let err: f32 = out
+            .iter()
+            .zip(expected.iter())
+            .map(|(a, b)| (a - b).abs())
+            .sum();
+        let norm: f32 = expected.iter().map(|v| v.abs()).sum();
+        // The results are identical on pre-Ampere devices, TF32 rounds the inputs to 10 bits of
+        // mantissa otherwise.
+        assert!(err / norm < 1e-2, "{}", err / norm);
+
+        // The default math mode is restored after the gemm.
+        let (out, _) = w.fwd(&self_shape, &x, &x_l)?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        assert_eq!(out, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_matmul_transposed_in_file() -> Result<()> {
+        use rand::{Rng, SeedableRng};
+
+        let dev = CudaDevice::new(0)?;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let (m, n, k) = (3, 64, 96);
+        // The file stores k rows of n values, the q8_0 blocks run along n.
+        let ws: Vec<f32> = (0..k * n).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let xs: Vec<f32> = (0..m * k).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let mut w = QCudaStorage::zeros(&dev, n * k, GgmlDType::Q8_0)?;
+        w.quantize(&CudaStorage::wrap_cuda_slice(
+            dev.htod_sync_copy(&ws).w()?,
+            dev.clone(),
+        ))?;
+        w.set_transposed_in_file(true);
+        assert!(w.is_transposed_in_file());
+        let wd = w.dequantize(n * k)?;
+        let wd = dev.dtoh_sync_copy(wd.as_cuda_slice::<f32>()?).w()?;
+        let x = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        let self_shape = crate::Shape::from((n, k));
+        for rows in [1, m] {
+            let x_l = crate::Layout::contiguous((rows, k));
+            assert!(matches!(
+                w.explain_matmul(&self_shape, &x_l)?,
+                MatMulPlan::Dequantize { .. }
+            ));
+            let (out, out_shape) = w.fwd(&self_shape, &x, &x_l)?;
+            assert_eq!(out_shape.dims(), &[rows, n]);
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            for r in 0..rows {
+                for j in 0..n {
+                    let e: f32 = (0..k).map(|i| xs[r * k + i] * wd[i * n + j]).sum();
+                    assert!((out[r * n + j] - e).abs() < 1e-3, "{r} {j}")
+                }
+            }
+        }
+        let x_l = crate::Layout::contiguous((1, k));
+        let err = w.fwd_q8_0(&self_shape, &x, &x_l).unwrap_err().to_string();
+        assert!(err.contains("stored transposed"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_matmul_non_f32_activation() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (n, k) = (4, 256);
+        let w = QCudaStorage::zeros(&dev, n * k, GgmlDType::Q4_0)?;
+        let xs = vec![half::f16::ONE; k];
+        let x = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        let x_l = crate::Layout::contiguous((1, k));
+        let err = w.fwd(&(n, k).into(), &x, &x_l).unwrap_err().to_string();
+        assert!(err.contains("expects f32 activations, got F16"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_to_host() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let el = 512;
+        let vs: Vec<f32> = (0..el).map(|v| v as f32 / el as f32).collect();
+        let y = dev.htod_sync_copy(&vs).w()?;
+        for dtype in [GgmlDType::Q4K, GgmlDType::F16] {
+            let mut xs = QCudaStorage::zeros(&dev, el, dtype)?;
+            xs.quantize(&CudaStorage::wrap_cuda_slice(y.clone(), dev.clone()))?;
+            let expected = xs.dequantize(el)?;
+            let expected = dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?;
+            assert_eq!(xs.dequantize_to_host(el)?, expected);
+            let mut host = PinnedHostBuffer::<f32>::zeros(&dev, el + 3)?;
+            assert!(host.as_slice().iter().all(|v| *v == 0.));
+            xs.dequantize_to_pinned(el, &mut host)?;
+            assert_eq!(host.as_slice()[..el], expected);
+            assert!(xs.dequantize_to_pinned(el + 4, &mut host).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_cat_rows() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let ncols = 256;
+        let mut parts = vec![];
+        let mut expected = vec![];
+        for rows in [2, 1, 3] {
+            let vs: Vec<f32> = (0..rows * ncols).map(|v| (v + rows) as f32 / 64.).collect();
+            let y = dev.htod_sync_copy(&vs).w()?;
+            let mut xs = QCudaStorage::zeros(&dev, rows * ncols, GgmlDType::Q4K)?;
+            xs.quantize(&CudaStorage::wrap_cuda_slice(y, dev.clone()))?;
+            expected.extend(xs.dequantize_to_host(rows * ncols)?);
+            parts.push(xs)
+        }
+        let parts: Vec<&QCudaStorage> = parts.iter().collect();
+        let fused = QCudaStorage::cat_rows(&parts, ncols)?;
+        assert_eq!(fused.elem_count(), 6 * ncols);
+        assert_eq!(fused.dequantize_to_host(6 * ncols)?, expected);
+
+        let q8 = QCudaStorage::zeros(&dev, ncols, GgmlDType::Q8_0)?;
+        assert!(QCudaStorage::cat_rows(&[parts[0], &q8], ncols).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_gather_rows_embedding_layout() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (10, 128);
+        let vs: Vec<f32> = (0..nrows * ncols).map(|v| (v % 13) as f32 - 6.).collect();
+        let y = dev.htod_sync_copy(&vs).w()?;
+        let mut xs = QCudaStorage::zeros(&dev, nrows * ncols, GgmlDType::Q4_0)?;
+        xs.quantize(&CudaStorage::wrap_cuda_slice(y, dev.clone()))?;
+        let all = xs.dequantize_to_host(nrows * ncols)?;
+        // The out of range ids, including the ones that do not fit in an i32, give rows of zeros.
+        let ids = [7u32, 0, 7, 3, nrows as u32, u32::MAX];
+        let expected: Vec<f32> = ids
+            .iter()
+            .flat_map(|&i| match i as usize {
+                i if i < nrows => all[i * ncols..(i + 1) * ncols].to_vec(),
+                _ => vec![0.; ncols],
+            })
+            .collect();
+        let ids = dev.htod_sync_copy(&ids).w()?;
+        let emb = xs.to_embedding_layout(ncols)?;
+        assert!(emb.is_embedding_layout());
+        assert!(emb.dequantize(nrows * ncols).is_err());
+        for storage in [&xs, &emb] {
+            let out = storage.gather_rows(&ids.slice(..), ncols)?;
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            assert_eq!(out, expected);
+        }
+        let no_ids = dev.alloc_zeros::<u32>(0).w()?;
+        let out = xs.gather_rows(&no_ids.slice(..), ncols)?;
+        assert_eq!(out.as_cuda_slice::<f32>()?.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_q8_1_overflow_fallback() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let ncols = 256;
+        // A single large outlier per block, q8_1 rounds the small values of the block to zero.
+        let y: Vec<f32> = (0..ncols)
+            .map(|i| {
+                if i % 32 == 0 {
+                    1e4
+                } else {
+                    0.5 + (i % 7) as f32
+                }
+            })
+            .collect();
+        let xs: Vec<f32> = (0..4 * ncols).map(|i| (i % 5) as f32 - 2.).collect();
+        let mut qs = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q8_0)?;
+        let xs_dev = dev.htod_sync_copy(&xs).w()?;
+        qs.quantize(&CudaStorage::wrap_cuda_slice(xs_dev, dev.clone()))?;
+        let rhs = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&y).w()?, dev.clone());
+        let rhs_l = crate::Layout::contiguous((1, ncols));
+        let shape = crate::Shape::from((4, ncols));
+
+        let config = QuantCudaConfig {
+            force_dmmv: true,
+            ..Default::default()
+        };
+        let scope = ConfigScope::enter(&dev, config)?;
+        let (dmmv, _) = qs.fwd(&shape, &rhs, &rhs_l)?;
+        let dmmv = dev.dtoh_sync_copy(dmmv.as_cuda_slice::<f32>()?).w()?;
+        drop(scope);
+
+        let config = QuantCudaConfig {
+            q8_1_overflow_threshold: Some(100.),
+            ..Default::default()
+        };
+        let scope = ConfigScope::enter(&dev, config)?;
+        let (out, _) = qs.fwd(&shape, &rhs, &rhs_l)?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        drop(scope);
+        assert_eq!(out, dmmv);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_checksum() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let el = 1000 * 32;
+        let vs: Vec<f32> = (0..el).map(|v| (v % 17) as f32 - 8.).collect();
+        let y = dev.htod_sync_copy(&vs).w()?;
+        let mut xs = QCudaStorage::zeros(&dev, el, GgmlDType::Q8_0)?;
+        xs.quantize(&CudaStorage::wrap_cuda_slice(y, dev.clone()))?;
+        let bytes = dev.dtoh_sync_copy(&*xs.data).w()?;
+        let checksum = xs.checksum()?;
+        assert_eq!(xs.clone().checksum()?, checksum);
+        let reloaded = load_quantized_bytes(&dev, GgmlDType::Q8_0, &bytes)?;
+        assert_eq!(reloaded.checksum()?, checksum);
+        let mut corrupted = bytes.clone();
+        corrupted[12345] ^= 1;
+        let corrupted = load_quantized_bytes(&dev, GgmlDType::Q8_0, &corrupted)?;
+        assert_ne!(corrupted.checksum()?, checksum);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_range() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (8, 512);
+        let vs: Vec<f32> = (0..nrows * ncols).map(|v| (v % 29) as f32 / 7.).collect();
+        let y = dev.htod_sync_copy(&vs).w()?;
+        let mut xs = QCudaStorage::zeros(&dev, nrows * ncols, GgmlDType::Q4K)?;
+        xs.quantize(&CudaStorage::wrap_cuda_slice(y, dev.clone()))?;
+        let all = xs.dequantize_to_host(nrows * ncols)?;
+        // Rows 3 to 5, with two q4_k blocks per row.
+        let out = xs.dequantize_range(6, 12, ncols)?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        assert_eq!(out, &all[3 * ncols..6 * ncols]);
+        assert!(xs.dequantize_range(1, 4, ncols).is_err());
+        assert!(xs.dequantize_range(6, 18, ncols).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_config_per_device() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let config = QuantCudaConfig {
+            mmv_y: 2,
+            ..Default::default()
+        };
+        assert!(
+            QuantCudaConfig::set_for_device(&dev, QuantCudaConfig { mmv_y: 0, ..config }).is_err()
+        );
+        QuantCudaConfig::set_for_device(&dev, config)?;
+        assert_eq!(QuantCudaConfig::for_device(&dev), config);
+        QuantCudaConfig::set_for_device(&dev, QuantCudaConfig::default())?;
+        // The mmv_y rows per block must not change the dmmv result, including when the number of
+        // rows is not a multiple of mmv_y and the last block has rows past the end.
+        let (ncols, nrows) = (256, 5);
+        let y: Vec<f32> = (0..ncols).map(|i| (i % 9) as f32 - 4.).collect();
+        let xs: Vec<f32> = (0..nrows * ncols).map(|i| (i % 11) as f32 / 3.).collect();
+        let xs_dev = dev.htod_sync_copy(&xs).w()?;
+        let y_dev = dev.htod_sync_copy(&y).w()?;
+        for dtype in [
+            GgmlDType::Q4_0,
+            GgmlDType::Q2K,
+            GgmlDType::Q3K,
+            GgmlDType::Q4K,
+            GgmlDType::Q6K,
+        ] {
+            let mut qs = QCudaStorage::zeros(&dev, xs.len(), dtype)?;
+            qs.quantize(&CudaStorage::wrap_cuda_slice(xs_dev.clone(), dev.clone()))?;
+            let expected = cpu_reference_mmv(&qs, &y, nrows)?;
+            for mmv_y in [2, 4] {
+                let config = QuantCudaConfig {
+                    mmv_y,
+                    ..Default::default()
+                };
+                let scope = ConfigScope::enter(&dev, config)?;
+                let out = dequantize_mul_mat_vec(
+                    &qs.data,
+                    &y_dev.slice(..),
+                    None,
+                    None,
+                    qs.dtype,
+                    ncols,
+                    nrows,
+                    &dev,
+                );
+                drop(scope);
+                let out = dev.dtoh_sync_copy(out?.as_cuda_slice::<f32>()?).w()?;
+                assert_close(&out, &expected, 1e-4);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_interleaved() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (3, 64);
+        let mut storages = vec![];
+        let mut expected = vec![];
+        for (i, dtype) in [GgmlDType::Q8_0, GgmlDType::Q4_0, GgmlDType::F16]
+            .iter()
+            .enumerate()
+        {
+            let vs: Vec<f32> = (0..nrows * ncols).map(|v| (v % 5 + i) as f32).collect();
+            let y = dev.htod_sync_copy(&vs).w()?;
+            let mut xs = QCudaStorage::zeros(&dev, nrows * ncols, *dtype)?;
+            xs.quantize(&CudaStorage::wrap_cuda_slice(y, dev.clone()))?;
+            expected.push(xs.dequantize_to_host(nrows * ncols)?);
+            storages.push(xs);
+        }
+        let storages: Vec<&QCudaStorage> = storages.iter().collect();
+
+        let out = QCudaStorage::dequantize_interleaved(&storages, ncols, InterleaveMode::Stacked)?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        assert_eq!(out, expected.concat());
+
+        let mode = InterleaveMode::RowInterleaved;
+        let out = QCudaStorage::dequantize_interleaved(&storages, ncols, mode)?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        for (row, chunk) in out.chunks(3 * ncols).enumerate() {
+            for (i, vs) in chunk.chunks(ncols).enumerate() {
+                assert_eq!(vs, &expected[i][row * ncols..(row + 1) * ncols]);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_truncated_data() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (4, 64);
+        let dtype = GgmlDType::Q4_0;
+        let size_in_bytes = nrows * ncols / dtype.block_size() * dtype.type_size();
+        let data = dev.alloc_zeros::<u8>(size_in_bytes - 1).w()?;
+        let y = dev.alloc_zeros::<f32>(ncols).w()?;
+        let err = mul_mat_vec_via_q8_1(&data, &y.slice(..), None, None, dtype, ncols, nrows, &dev)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("not a multiple of the Q4_0 type size"),
+            "{err}"
+        );
+        let err =
+            dequantize_mul_mat_vec(&data, &y.slice(..), None, None, dtype, ncols, nrows, &dev)
+                .unwrap_err()
+                .to_string();
+        assert!(
+            err.contains("not a multiple of the Q4_0 type size"),
+            "{err}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_int4_grouped() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols, group_size) = (3, 256, INT4_GROUP_SIZE);
+        let qweight: Vec<u8> = (0..nrows * ncols / 2)
+            .map(|i| (i * 37 % 256) as u8)
+            .collect();
+        let scales: Vec<half::f16> = (0..nrows * ncols / group_size)
+            .map(|i| half::f16::from_f32(0.25 * (i + 1) as f32))
+            .collect();
+        let expected: Vec<f32> = (0..nrows * ncols)
+            .map(|i| {
+                let q = qweight[i / 2];
+                let q = if i % 2 == 0 { q & 0xF } else { q >> 4 };
+                (q as f32 - 8.) * scales[i / group_size].to_f32()
+            })
+            .collect();
+        let ws = QCudaGroupStorage::new(&dev, &qweight, &scales, (nrows, ncols), group_size)?;
+        let out = ws.dequantize()?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        assert_eq!(out, expected);
+
+        let xs: Vec<f32> = (0..2 * ncols).map(|i| (i % 3) as f32 - 1.).collect();
+        let xs_dev = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        let (out, shape) = ws.fwd(&xs_dev, &crate::Layout::contiguous((2, ncols)))?;
+        assert_eq!(shape.dims(), &[2, nrows]);
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        let expected: Vec<f32> = xs
+            .chunks(ncols)
+            .flat_map(|x| {
+                expected
+                    .chunks(ncols)
+                    .map(|w| x.iter().zip(w.iter()).map(|(x, w)| x * w).sum::<f32>())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_close(&out, &expected, 1e-5);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_pinned_staging_load() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let dtype = GgmlDType::Q8_0;
+        let data: Vec<u8> = (0..1000 * dtype.type_size())
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let expected = load_quantized_bytes(&dev, dtype, &data)?.checksum()?;
+        // A staging buffer that does not divide the data size.
+        let mut loader = PinnedStagingLoader::new(&dev, 1234)?;
+        let storage = loader.load(dtype, &data)?;
+        assert_eq!(dev.dtoh_sync_copy(&*storage.data).w()?, data);
+        assert_eq!(storage.checksum()?, expected);
+        assert!(loader.load(dtype, &data[1..]).is_err());
+
+        let mut pinned = PinnedHostBuffer::zeros(&dev, data.len())?;
+        pinned.as_mut_slice().copy_from_slice(&data);
+        let storage = load_quantized_pinned(&dev, dtype, &pinned)?;
+        assert_eq!(storage.checksum()?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_batched_vec() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (b, nrows, ncols) = (4, 16, 256);
+        let xs: Vec<f32> = (0..nrows * ncols)
+            .map(|i| (i % 13) as f32 / 4. - 1.)
+            .collect();
+        let mut qs = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q4K)?;
+        let xs_dev = dev.htod_sync_copy(&xs).w()?;
+        qs.quantize(&CudaStorage::wrap_cuda_slice(xs_dev, dev.clone()))?;
+        let ys: Vec<f32> = (0..b * ncols).map(|i| (i % 7) as f32 - 3.).collect();
+        let ys_dev = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ys).w()?, dev.clone());
+        let self_shape = crate::Shape::from((nrows, ncols));
+        let (out, shape) = qs.fwd(
+            &self_shape,
+            &ys_dev,
+            &crate::Layout::contiguous((b, 1, ncols)),
+        )?;
+        assert_eq!(shape.dims(), &[b, 1, nrows]);
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        for (i, y) in ys.chunks(ncols).enumerate() {
+            let vec_l = crate::Layout::contiguous_with_offset((1, ncols), i * ncols);
+            let (expected, _) = qs.fwd(&self_shape, &ys_dev, &vec_l)?;
+            let expected = dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?;
+            assert_eq!(&out[i * nrows..(i + 1) * nrows], expected);
+            assert_close(&expected, &cpu_reference_mmv(&qs, y, nrows)?, 0.05);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_batched_single_launch() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        // An odd number of rows leaves the last cuda block of the batched kernels with one row.
+        let (nrows, ncols) = (15, 512);
+        let ws: Vec<f32> = (0..nrows * ncols)
+            .map(|i| (i % 19) as f32 / 9. - 1.)
+            .collect();
+        let ws = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ws).w()?, dev.clone());
+        let self_shape = crate::Shape::from((nrows, ncols));
+        for dtype in [
+            GgmlDType::Q4_0,
+            GgmlDType::Q8_0,
+            GgmlDType::Q4K,
+            GgmlDType::Q6K,
+        ] {
+            let mut qs = QCudaStorage::zeros(&dev, nrows * ncols, dtype)?;
+            qs.quantize(&ws)?;
+            for b in 1..=MAX_BATCHED_VEC {
+                let ys: Vec<f32> = (0..b * ncols).map(|i| (i % 11) as f32 / 5. - 1.).collect();
+                let ys = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ys).w()?, dev.clone());
+                let layout = crate::Layout::contiguous((b, 1, ncols));
+                let (out, _) = qs.fwd(&self_shape, &ys, &layout)?;
+                let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+                for i in 0..b {
+                    let vec_l = crate::Layout::contiguous_with_offset((1, ncols), i * ncols);
+                    let (expected, _) = qs.fwd(&self_shape, &ys, &vec_l)?;
+                    let expected = dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?;
+                    assert_eq!(&out[i * nrows..(i + 1) * nrows], expected, "{dtype:?} {b}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_matmul_chunked() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        assert!(available_memory(&dev)? > 0);
+        let (b, m, n, k) = (2, 3, 40, 256);
+        let ws: Vec<f32> = (0..n * k).map(|i| (i % 17) as f32 / 8. - 1.).collect();
+        let mut qs = QCudaStorage::zeros(&dev, ws.len(), GgmlDType::Q4K)?;
+        let ws_dev = dev.htod_sync_copy(&ws).w()?;
+        qs.quantize(&CudaStorage::wrap_cuda_slice(ws_dev, dev.clone()))?;
+        let xs: Vec<f32> = (0..b * m * k).map(|i| (i % 5) as f32 - 2.).collect();
+        let xs = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        let layout = crate::Layout::contiguous((b, m, k));
+        let self_shape = crate::Shape::from((n, k));
+        let (expected, _) = qs.fwd(&self_shape, &xs, &layout)?;
+        let expected = dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?;
+        // 40 rows in chunks of 16 rows, the last chunk being partial.
+        let out = qs.dequantize_matmul_chunked(&xs, &layout, (b, m, n, k), 16)?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        assert_close(&out, &expected, 1e-5);
+        // A headroom larger than the device memory forces the chunked path.
+        let config = QuantCudaConfig {
+            dequantize_memory_headroom: Some(usize::MAX / 2),
+            ..Default::default()
+        };
+        let scope = ConfigScope::enter(&dev, config)?;
+        let out = qs.fwd(&self_shape, &xs, &layout);
+        drop(scope);
+        let (out, shape) = out?;
+        assert_eq!(shape.dims(), &[b, m, n]);
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        assert_close(&out, &expected, 1e-5);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_matmul_n_chunk() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (b, m, n, k) = (2, 5, 72, 512);
+        let ws: Vec<f32> = (0..n * k).map(|i| (i % 23) as f32 / 11. - 1.).collect();
+        let mut qs = QCudaStorage::zeros(&dev, ws.len(), GgmlDType::Q6K)?;
+        let ws_dev = dev.htod_sync_copy(&ws).w()?;
+        qs.quantize(&CudaStorage::wrap_cuda_slice(ws_dev, dev.clone()))?;
+        let xs: Vec<f32> = (0..b * m * k).map(|i| (i % 9) as f32 / 4. - 1.).collect();
+        let xs = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        let layout = crate::Layout::contiguous((b, m, k));
+        let self_shape = crate::Shape::from((n, k));
+        let (expected, _) = qs.fwd(&self_shape, &xs, &layout)?;
+        let expected = dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?;
+        // Slabs dividing n, not dividing it, and larger than n which runs a single gemm.
+        for n_chunk in [8, 30, 1000] {
+            let config = QuantCudaConfig {
+                dequantize_matmul_n_chunk: Some(n_chunk),
+                ..Default::default()
+            };
+            let scope = ConfigScope::enter(&dev, config)?;
+            let plan = qs.explain_matmul(&self_shape, &layout);
+            let out = qs.fwd(&self_shape, &xs, &layout);
+            drop(scope);
+            let chunk_rows = (n_chunk < n).then_some(n_chunk);
+            assert!(
+                matches!(plan?, MatMulPlan::Dequantize { chunk_rows: c, .. } if c == chunk_rows),
+                "{n_chunk}"
+            );
+            let (out, shape) = out?;
+            assert_eq!(shape.dims(), &[b, m, n]);
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            assert_close(&out, &expected, 1e-5);
+        }
+        let config = QuantCudaConfig {
+            dequantize_matmul_n_chunk: Some(0),
+            ..Default::default()
+        };
+        assert!(QuantCudaConfig::set_for_device(&dev, config).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_matmul_transposed_input() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (m, n, k) = (5, 8, 256);
+        let ws: Vec<f32> = (0..n * k).map(|i| (i % 11) as f32 / 4. - 1.).collect();
+        let mut qs = QCudaStorage::zeros(&dev, ws.len(), GgmlDType::Q8_0)?;
+        let ws_dev = dev.htod_sync_copy(&ws).w()?;
+        qs.quantize(&CudaStorage::wrap_cuda_slice(ws_dev, dev.clone()))?;
+        let self_shape = crate::Shape::from((n, k));
+        // The same (m, k) activation, once row major and once as a transposed (k, m) buffer.
+        let xs: Vec<f32> = (0..m * k).map(|i| (i % 7) as f32 - 3.).collect();
+        let xs_t: Vec<f32> = (0..k * m).map(|i| xs[(i % m) * k + i / m]).collect();
+        let xs = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        let xs_t = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs_t).w()?, dev.clone());
+        let (expected, _) = qs.fwd(&self_shape, &xs, &crate::Layout::contiguous((m, k)))?;
+        let expected = dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?;
+        let layout_t = crate::Layout::new((m, k).into(), vec![1, m], 0);
+        let (out, shape) = qs.fwd(&self_shape, &xs_t, &layout_t)?;
+        assert_eq!(shape.dims(), &[m, n]);
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        assert_eq!(out.len(), m * n);
+        assert_close(&out, &expected, 1e-5);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_all_reduce_sum_host() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let a: Vec<f32> = (0..100).map(|v| v as f32).collect();
+        let b: Vec<f32> = (0..100).map(|v| 0.5 - v as f32).collect();
+        let a = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&a).w()?, dev.clone());
+        let b = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&b).w()?, dev.clone());
+        let sum = all_reduce_sum_host(&[&a, &b], &dev)?;
+        let sum = dev.dtoh_sync_copy(sum.as_cuda_slice::<f32>()?).w()?;
+        assert_eq!(sum, vec![0.5; 100]);
+        assert!(all_reduce_sum_host(&[], &dev).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_validate() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (4, 256);
+        let ws: Vec<f32> = (0..nrows * ncols)
+            .map(|i| ((i as f32) * 0.2).sin())
+            .collect();
+        let ws = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ws).w()?, dev.clone());
+        let mut xs = QCudaStorage::zeros(&dev, nrows * ncols, GgmlDType::Q8_0)?;
+        xs.quantize(&ws)?;
+        let shape = crate::Shape::from((nrows, ncols));
+        xs.validate(None, true)?;
+        xs.validate(Some(&shape), true)?;
+        assert!(xs
+            .validate(Some(&(nrows, ncols + 32).into()), false)
+            .is_err());
+        // Rows of 16 values would split the 32 values blocks.
+        assert!(xs.validate(Some(&(64, 16).into()), false).is_err());
+        xs.set_shape(shape.clone())?;
+        assert!(xs
+            .validate(Some(&(2 * nrows, ncols / 2).into()), false)
+            .is_err());
+
+        // A scale set to a f16 infinity, the block then dequantizes to non-finite values.
+        let mut bytes = dev.dtoh_sync_copy(&*xs.data).w()?;
+        bytes[..2].copy_from_slice(&half::f16::INFINITY.to_le_bytes());
+        let corrupted = load_quantized_bytes(&dev, GgmlDType::Q8_0, &bytes)?;
+        corrupted.validate(Some(&shape), false)?;
+        assert!(corrupted.validate(Some(&shape), true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_fwd_on_device() -> Result<()> {
+        use crate::backend::BackendStorage;
+
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (16, 256);
+        let ws: Vec<f32> = (0..nrows * ncols)
+            .map(|i| ((i as f32) * 0.2).sin())
+            .collect();
+        let ws = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ws).w()?, dev.clone());
+        let mut xs = QCudaStorage::zeros(&dev, nrows * ncols, GgmlDType::Q8_0)?;
+        xs.quantize(&ws)?;
+        let vs: Vec<f32> = (0..2 * ncols).map(|i| (i as f32 * 0.3).cos()).collect();
+        let x = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&vs).w()?, dev.clone());
+        let self_shape = crate::Shape::from((nrows, ncols));
+        let layout = crate::Layout::contiguous((2, ncols));
+        let (expected, _) = xs.fwd(&self_shape, &x, &layout)?;
+        let expected = dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?;
+        let (out, shape) = xs.fwd_on_device(&self_shape, &x, &layout, &dev)?;
+        assert_eq!(shape.dims(), [2, nrows]);
+        assert_eq!(out.device().id(), dev.id());
+        assert_eq!(
+            dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?,
+            expected
+        );
+        // The peer copy needs a second gpu.
+        if let Ok(dev1) = CudaDevice::new(1) {
+            let (out, _) = xs.fwd_on_device(&self_shape, &x, &layout, &dev1)?;
+            assert_eq!(out.device().id(), dev1.id());
+            assert_eq!(
+                dev1.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?,
+                expected
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_device_mismatch() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let data = dev.alloc_zeros::<u8>(34 * 8).w()?;
+        check_same_device(&data, &dev, "test")?;
+        // A buffer of another device needs a second gpu.
+        if let Ok(dev1) = CudaDevice::new(1) {
+            let data1 = dev1.alloc_zeros::<u8>(34 * 8).w()?;
+            let err = dequantize::<f32>(&data1.slice(..), GgmlDType::Q8_0, 256, &dev).unwrap_err();
+            assert!(
+                err.to_string().contains("device mismatch in dequantize"),
+                "{err}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_auto_upload_activations() -> Result<()> {
+        use crate::quantized::{QMatMul, QTensor};
+        use crate::Module;
+
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (16, 256);
+        let ws: Vec<f32> = (0..nrows * ncols)
+            .map(|i| ((i as f32) * 0.2).sin())
+            .collect();
+        let ws = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ws).w()?, dev.clone());
+        let mut xs = QCudaStorage::zeros(&dev, nrows * ncols, GgmlDType::Q8_0)?;
+        xs.quantize(&ws)?;
+        let mm = QMatMul::from_qtensor(QTensor::new(QStorage::Cuda(xs), (nrows, ncols))?)?;
+        let x = crate::Tensor::arange(0f32, 2. * ncols as f32, &crate::Device::Cpu)?
+            .reshape((2, ncols))?;
+        let expected = mm.forward(&x.to_device(&crate::Device::Cuda(dev.clone()))?)?;
+        // Off by default, the cpu activation is rejected.
+        assert!(mm.forward(&x).is_err());
+        let config = QuantCudaConfig {
+            auto_upload_activations: true,
+            ..QuantCudaConfig::default()
+        };
+        let scope = ConfigScope::enter(&dev, config)?;
+        let out = mm.forward(&x);
+        drop(scope);
+        let out = out?;
+        assert!(out.device().is_cuda());
+        assert_eq!(out.to_vec2::<f32>()?, expected.to_vec2::<f32>()?);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_record_replay() -> Result<()> {
+        use crate::quantized::cuda_record::{QuantRecord, QuantRecorder};
+
+        let dev = CudaDevice::new(0)?;
+        // A shape not used by the other tests, which may record in the same directory.
+        let (nrows, ncols) = (24, 768);
+        let ws: Vec<f32> = (0..nrows * ncols)
+            .map(|i| ((i as f32) * 0.3).sin())
+            .collect();
+        let ws = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ws).w()?, dev.clone());
+        let mut xs = QCudaStorage::zeros(&dev, nrows * ncols, GgmlDType::Q4K)?;
+        xs.quantize(&ws)?;
+        let self_shape = crate::Shape::from((nrows, ncols));
+        let vs: Vec<f32> = (0..4 * ncols).map(|i| (i as f32 * 0.7).cos()).collect();
+        let x = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&vs).w()?, dev.clone());
+        // The record keeps the whole activation buffer and the offset into it.
+        let layout = crate::Layout::contiguous_with_offset((1, ncols), 3 * ncols);
+        let bad_layout = crate::Layout::contiguous((2, 2 * ncols));
+
+        let dir = std::env::temp_dir().join(format!("candle-record-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        QuantRecorder::set_record_dir(Some(dir.clone()));
+        QuantRecorder::arm();
+        let expected = xs.fwd(&self_shape, &x, &layout);
+        let failed = xs.fwd(&self_shape, &x, &bad_layout);
+        QuantRecorder::set_record_dir(None);
+        assert!(QuantRecorder::take_error().is_none());
+        let (expected, _) = expected?;
+        let expected = dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?;
+        assert!(failed.is_err());
+        let mut records = vec![];
+        for entry in std::fs::read_dir(&dir)? {
+            let record = QuantRecord::load(&dev, entry?.path())?;
+            if record.weight_shape == self_shape {
+                records.push(record)
+            }
+        }
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(records.len(), 2);
+        let (ok, err): (Vec<_>, Vec<_>) = records.into_iter().partition(|r| r.error.is_none());
+        assert_eq!(ok[0].layout, layout);
+        let (out, shape) = ok[0].replay()?;
+        assert_eq!(shape.dims(), [1, nrows]);
+        assert_eq!(
+            dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?,
+            expected
+        );
+        assert_eq!(err[0].layout, bad_layout);
+        assert!(err[0].replay().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_quantization_rel_error() -> Result<()> {
+        use rand::{Rng, SeedableRng};
+
+        let dev = CudaDevice::new(0)?;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let el = 256 * 16;
+        let ws: Vec<f32> = (0..el).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let norm = ws.iter().map(|w| w * w).sum::<f32>().sqrt();
+        let ws_dev = dev.htod_sync_copy(&ws).w()?;
+        for dtype in [
+            GgmlDType::F16,
+            GgmlDType::Q4_0,
+            GgmlDType::Q4_1,
+            GgmlDType::Q5_0,
+            GgmlDType::Q5_1,
+            GgmlDType::Q8_0,
+            GgmlDType::Q2K,
+            GgmlDType::Q3K,
+            GgmlDType::Q4K,
+            GgmlDType::Q5K,
+            GgmlDType::Q6K,
+        ] {
+            let mut xs = QCudaStorage::zeros(&dev, el, dtype)?;
+            xs.quantize(&CudaStorage::wrap_cuda_slice(ws_dev.clone(), dev.clone()))?;
+            let out = xs.dequantize_to_host(el)?;
+            let err = ws.iter().zip(out.iter()).map(|(w, o)| (w - o) * (w - o));
+            let rel_err = err.sum::<f32>().sqrt() / norm;
+            assert!(rel_err <= dtype.expected_rel_error(), "{dtype:?} {rel_err}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_scale_in_place() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let el = 1024;
+        let vs: Vec<f32> = (0..el).map(|v| (v % 37) as f32 / 9. - 2.).collect();
+        let y = dev.htod_sync_copy(&vs).w()?;
+        for dtype in [
+            GgmlDType::Q4_0,
+            GgmlDType::Q8_0,
+            GgmlDType::Q2K,
+            GgmlDType::Q4K,
+        ] {
+            let mut xs = QCudaStorage::zeros(&dev, el, dtype)?;
+            xs.quantize(&CudaStorage::wrap_cuda_slice(y.clone(), dev.clone()))?;
+            let before = xs.dequantize_to_host(el)?;
+            // Halving the f16 scales is exact.
+            xs.scale_in_place(0.5)?;
+            let after = xs.dequantize_to_host(el)?;
+            let expected: Vec<f32> = before.iter().map(|v| v * 0.5).collect();
+            assert_eq!(after, expected, "{dtype:?}");
+        }
+        let mut xs = QCudaStorage::zeros(&dev, el, GgmlDType::Q8K)?;
+        assert!(xs.scale_in_place(0.5).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_offset_and_strided_vec() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (4, 256);
+        let ws: Vec<f32> = (0..nrows * ncols).map(|i| (i % 9) as f32 - 4.).collect();
+        let mut qs = QCudaStorage::zeros(&dev, ws.len(), GgmlDType::Q8_0)?;
+        let ws_dev = dev.htod_sync_copy(&ws).w()?;
+        qs.quantize(&CudaStorage::wrap_cuda_slice(ws_dev, dev.clone()))?;
+        let self_shape = crate::Shape::from((nrows, ncols));
+        // Two vectors stored back to back, the second one is used through an offset.
+        let ys: Vec<f32> = (0..2 * ncols).map(|i| (i % 5) as f32 - 2.).collect();
+        let ys_dev = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ys).w()?, dev.clone());
+        let y2 = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ys[ncols..]).w()?, dev.clone());
+        let (expected, _) = qs.fwd(&self_shape, &y2, &crate::Layout::contiguous((1, 1, ncols)))?;
+        let expected = dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?;
+        // Offset and an arbitrary stride on the size one dimensions, e.g. from a narrow.
+        let layout = crate::Layout::new((1, 1, ncols).into(), vec![7, 3, 1], ncols);
+        let (out, _) = qs.fwd(&self_shape, &ys_dev, &layout)?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        assert_eq!(out, expected);
+        // Every other element, this requires a copy.
+        let layout = crate::Layout::new((1, ncols).into(), vec![2 * ncols, 2], 0);
+        assert!(qs.fwd(&self_shape, &ys_dev, &layout).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_tensor() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let el = 2 * 256;
+        let vs: Vec<f32> = (0..el).map(|v| v as f32 / el as f32).collect();
+        let y = dev.htod_sync_copy(&vs).w()?;
+        let mut xs = QCudaStorage::zeros(&dev, el, GgmlDType::Q8_0)?;
+        xs.quantize(&CudaStorage::wrap_cuda_slice(y, dev.clone()))?;
+        assert_eq!(xs.dequantize_tensor()?.dims(), &[el]);
+        assert!(xs.set_shape((3, 256)).is_err());
+        let expected = xs.dequantize_to_host(el)?;
+        let qtensor = crate::quantized::QTensor::new(QStorage::Cuda(xs), (2, 256))?;
+        let xs = match &qtensor.storage {
+            QStorage::Cuda(xs) => xs,
+            _ => unreachable!(),
+        };
+        let t = xs.dequantize_tensor()?;
+        assert_eq!(t.dims(), &[2, 256]);
+        assert_eq!(t.flatten_all()?.to_vec1::<f32>()?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_to_tensor() -> Result<()> {
+        use crate::DType;
+
+        let dev = CudaDevice::new(0)?;
+        let el = 4 * 256;
+        let vs: Vec<f32> = (0..el).map(|v| (v as f32 * 0.37).sin()).collect();
         let y = dev.htod_sync_copy(&vs).w()?;
-        quantize_q8_1(&y.slice(..), &mut y_q8_1, el, &dev)?;
+        let mut xs = QCudaStorage::zeros(&dev, el, GgmlDType::Q4K)?;
+        xs.quantize(&CudaStorage::wrap_cuda_slice(y, dev.clone()))?;
+        let expected = xs.dequantize_to_host(el)?;
+        let shape = crate::Shape::from((2, 2, 256));
+        for dtype in [DType::F32, DType::F16, DType::BF16] {
+            let t = xs.to_tensor(&shape, dtype)?;
+            assert_eq!(t.dims(), &[2, 2, 256]);
+            assert_eq!(t.dtype(), dtype);
+            assert!(t.device().is_cuda());
+            let values = t.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()?;
+            let tolerance = if dtype == DType::F32 { 0. } else { 1e-2 };
+            for (v, e) in values.iter().zip(expected.iter()) {
+                assert!(
+                    (v - e).abs() <= tolerance * e.abs().max(1.),
+                    "{dtype:?} {v} {e}"
+                );
+            }
+        }
+        assert!(xs.to_tensor(&(3, 256).into(), DType::F32).is_err());
+        assert!(xs.to_tensor(&shape, DType::U8).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmvq_nwarps() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (6, 1024);
+        let xs: Vec<f32> = (0..nrows * ncols)
+            .map(|i| (i % 23) as f32 / 11. - 1.)
+            .collect();
+        let y: Vec<f32> = (0..ncols).map(|i| (i % 7) as f32 / 3. - 1.).collect();
+        let xs_dev = dev.htod_sync_copy(&xs).w()?;
+        let y_dev = dev.htod_sync_copy(&y).w()?;
+        for dtype in [GgmlDType::Q4_0, GgmlDType::Q4K] {
+            let mut qs = QCudaStorage::zeros(&dev, xs.len(), dtype)?;
+            qs.quantize(&CudaStorage::wrap_cuda_slice(xs_dev.clone(), dev.clone()))?;
+            let mut outs = vec![];
+            for nwarps in [1, 2, 4, 8] {
+                let config = QuantCudaConfig {
+                    mmvq_nwarps: nwarps,
+                    ..Default::default()
+                };
+                let scope = ConfigScope::enter(&dev, config)?;
+                let out = mul_mat_vec_via_q8_1(
+                    &qs.data,
+                    &y_dev.slice(..),
+                    None,
+                    None,
+                    dtype,
+                    ncols,
+                    nrows,
+                    &dev,
+                );
+                drop(scope);
+                outs.push(dev.dtoh_sync_copy(out?.as_cuda_slice::<f32>()?).w()?);
+            }
+            for out in outs[1..].iter() {
+                assert_close(out, &outs[0], 1e-5);
+            }
+        }
+        let config = QuantCudaConfig {
+            mmvq_nwarps_k_quants: Some(16),
+            ..Default::default()
+        };
+        assert!(QuantCudaConfig::set_for_device(&dev, config).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_qk_matmul() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (kv_heads, seq, dim) = (2, 5, 64);
+        let ks: Vec<f32> = (0..kv_heads * seq * dim)
+            .map(|i| ((i as f32) * 0.21).sin())
+            .collect();
+        let mut k = QCudaStorage::zeros(&dev, ks.len(), GgmlDType::Q8_0)?;
+        k.quantize(&CudaStorage::wrap_cuda_slice(
+            dev.htod_sync_copy(&ks).w()?,
+            dev.clone(),
+        ))?;
+        let ks = k.dequantize_to_host(ks.len())?;
+        let k_shape: crate::Shape = (kv_heads, seq, dim).into();
+        for heads in [kv_heads, 2 * kv_heads] {
+            let q_len = 3;
+            let qs: Vec<f32> = (0..heads * q_len * dim)
+                .map(|i| ((i as f32) * 0.37).cos())
+                .collect();
+            let q = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&qs).w()?, dev.clone());
+            let q_layout = crate::Layout::contiguous((heads, q_len, dim));
+            let (out, shape) = k.qk_matmul(&k_shape, &q, &q_layout)?;
+            assert_eq!(shape.dims(), [heads, q_len, seq]);
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            let group = heads / kv_heads;
+            let mut expected = vec![];
+            for h in 0..heads {
+                for i in 0..q_len {
+                    let q_row = &qs[(h * q_len + i) * dim..][..dim];
+                    for j in 0..seq {
+                        let k_row = &ks[((h / group) * seq + j) * dim..][..dim];
+                        expected.push(q_row.iter().zip(k_row).map(|(a, b)| a * b).sum::<f32>());
+                    }
+                }
+            }
+            assert_close(&out, &expected, 1e-4);
+        }
+
+        let q = CudaStorage::wrap_cuda_slice(dev.alloc_zeros::<f32>(3 * dim).w()?, dev.clone());
+        let q_layout = crate::Layout::contiguous((3, 1, dim));
+        assert!(k.qk_matmul(&k_shape, &q, &q_layout).is_err());
+        // A K shape with more elements than the storage would read past its blocks.
+        let q_layout = crate::Layout::contiguous((kv_heads, 1, dim));
+        let q =
+            CudaStorage::wrap_cuda_slice(dev.alloc_zeros::<f32>(kv_heads * dim).w()?, dev.clone());
+        let long_shape: crate::Shape = (kv_heads, 2 * seq, dim).into();
+        assert!(k.qk_matmul(&long_shape, &q, &q_layout).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_autotune() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (64, 1024);
+        let xs: Vec<f32> = (0..nrows * ncols)
+            .map(|i| (i % 23) as f32 / 11. - 1.)
+            .collect();
+        let y: Vec<f32> = (0..ncols).map(|i| (i % 7) as f32 / 3. - 1.).collect();
+        let y = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&y).w()?, dev.clone());
+        let layout = crate::Layout::contiguous((1, ncols));
+        let shape: crate::Shape = (nrows, ncols).into();
+        let mut qs = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q4_0)?;
+        qs.quantize(&CudaStorage::wrap_cuda_slice(
+            dev.htod_sync_copy(&xs).w()?,
+            dev.clone(),
+        ))?;
+        let (expected, _) = qs.fwd(&shape, &y, &layout)?;
+        let expected = dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?;
+
+        let tuned = qs.autotune(&shape)?;
+        assert_eq!(qs.tuned_kernels(), Some(tuned));
+        let kernel = match qs.explain_matmul(&shape, &layout)? {
+            MatMulPlan::Vec { kernel, .. } => kernel,
+            plan => panic!("unexpected plan {plan:?}"),
+        };
+        let expected_kernel = if tuned.force_dmmv {
+            MatMulVecKernel::Dmmv
+        } else {
+            MatMulVecKernel::Q8_1
+        };
+        assert_eq!(kernel, expected_kernel);
+        // A failing tuning run leaves the previous parameters in place.
+        assert!(qs.autotune(&(2 * nrows, ncols).into()).is_err());
+        assert_eq!(qs.tuned_kernels(), Some(tuned));
+        // The device configuration is left untouched.
+        assert_eq!(
+            QuantCudaConfig::for_device(&dev),
+            QuantCudaConfig::default()
+        );
+        let (out, _) = qs.fwd(&shape, &y, &layout)?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        // The tuned kernel may differ from the default one, dmmv does not quantize the activation.
+        assert_close(&out, &expected, 0.05);
+
+        let invalid = TunedKernels { mmv_y: 0, ..tuned };
+        assert!(qs.set_tuned_kernels(Some(invalid)).is_err());
+        qs.set_tuned_kernels(None)?;
+        assert_eq!(qs.tuned_kernels(), None);
+
+        // The sweep runs the k-quant dmmv kernels with every mmv_y, including ones which do not
+        // divide an odd number of rows.
+        let (nrows, ncols) = (37, 1024);
+        let xs: Vec<f32> = (0..nrows * ncols)
+            .map(|i| (i % 23) as f32 / 11. - 1.)
+            .collect();
+        let y: Vec<f32> = (0..ncols).map(|i| (i % 7) as f32 / 3. - 1.).collect();
+        let y_dev = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&y).w()?, dev.clone());
+        let layout = crate::Layout::contiguous((1, ncols));
+        let shape: crate::Shape = (nrows, ncols).into();
+        let mut qs = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q4K)?;
+        qs.quantize(&CudaStorage::wrap_cuda_slice(
+            dev.htod_sync_copy(&xs).w()?,
+            dev.clone(),
+        ))?;
+        let expected = cpu_reference_mmv(&qs, &y, nrows)?;
+        let tuned = qs.autotune(&shape)?;
+        let (out, _) = qs.fwd(&shape, &y_dev, &layout)?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        assert_close(&out, &expected, 0.05);
+        for mmv_y in [1, 2, 4, 8] {
+            let candidate = TunedKernels {
+                force_dmmv: true,
+                mmv_y,
+                ..tuned
+            };
+            qs.set_tuned_kernels(Some(candidate))?;
+            let (out, _) = qs.fwd(&shape, &y_dev, &layout)?;
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            assert_close(&out, &expected, 1e-3);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_defaults() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (16, 512);
+        let xs: Vec<f32> = (0..nrows * ncols)
+            .map(|i| (i % 23) as f32 / 11. - 1.)
+            .collect();
+        let y: Vec<f32> = (0..ncols).map(|i| (i % 7) as f32 / 3. - 1.).collect();
+        let y = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&y).w()?, dev.clone());
+        let layout = crate::Layout::contiguous((1, ncols));
+        let shape: crate::Shape = (nrows, ncols).into();
+        let mut qs = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q4_0)?;
+        qs.quantize(&CudaStorage::wrap_cuda_slice(
+            dev.htod_sync_copy(&xs).w()?,
+            dev.clone(),
+        ))?;
+        let kernel = |qs: &QCudaStorage| match qs.explain_matmul(&shape, &layout) {
+            Ok(MatMulPlan::Vec { kernel, .. }) => kernel,
+            plan => panic!("unexpected plan {plan:?}"),
+        };
+        assert_eq!(kernel(&qs), MatMulVecKernel::Q8_1);
+        let dmmv_config = QuantCudaConfig {
+            force_dmmv: true,
+            ..QuantCudaConfig::default()
+        };
+        let scope = ConfigScope::enter(&dev, dmmv_config)?;
+        let expected = qs.fwd(&shape, &y, &layout);
+        drop(scope);
+
+        let defaults =
+            MatMulVecDefaults::ALWAYS_Q8_1.with_dmmv_max_ncols(GgmlDType::Q4_0, ncols)?;
+        assert!(defaults.prefers_dmmv(GgmlDType::Q4_0, ncols));
+        assert!(!defaults.prefers_dmmv(GgmlDType::Q4_0, ncols + 1));
+        assert!(!defaults.prefers_dmmv(GgmlDType::Q4K, ncols));
+        let config = QuantCudaConfig {
+            mmv_defaults: defaults,
+            ..QuantCudaConfig::default()
+        };
+        let scope = ConfigScope::enter(&dev, config)?;
+        let selected = kernel(&qs);
+        let out = qs.fwd(&shape, &y, &layout);
+        // Tuned kernels take precedence over the table.
+        let tuned = TunedKernels {
+            force_dmmv: false,
+            mmv_y: config.mmv_y,
+            mmvq_nwarps: config.mmvq_nwarps,
+        };
+        qs.set_tuned_kernels(Some(tuned))?;
+        let tuned_kernel = kernel(&qs);
+        drop(scope);
+        assert_eq!(selected, MatMulVecKernel::Dmmv);
+        assert_eq!(tuned_kernel, MatMulVecKernel::Q8_1);
+        let (expected, _) = expected?;
+        let (out, _) = out?;
+        assert_eq!(
+            dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?,
+            dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?
+        );
+        assert!(MatMulVecDefaults::ALWAYS_Q8_1
+            .with_dmmv_max_ncols(GgmlDType::F16, ncols)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_partial_block() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let nrows = 8;
+        for dtype in [GgmlDType::Q4_0, GgmlDType::Q8_0, GgmlDType::Q4K] {
+            let block_size = dtype.block_size();
+            let k = block_size * 3 + 5;
+            let k_padded = block_size * 4;
+            // Each row is stored as whole blocks, the values past k are zero.
+            let ws: Vec<f32> = (0..nrows * k_padded)
+                .map(|i| match i % k_padded {
+                    c if c < k => ((i as f32) * 0.37).sin(),
+                    _ => 0.,
+                })
+                .collect();
+            let ws = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ws).w()?, dev.clone());
+            let mut xs = QCudaStorage::zeros(&dev, nrows * k_padded, dtype)?;
+            xs.quantize(&ws)?;
+            let y: Vec<f32> = (0..k).map(|i| ((i as f32) * 0.11).cos()).collect();
+            let y_padded: Vec<f32> = y
+                .iter()
+                .copied()
+                .chain(std::iter::repeat(0.).take(k_padded - k))
+                .collect();
+            let x = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&y).w()?, dev.clone());
+            let x_padded =
+                CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&y_padded).w()?, dev.clone());
+            let (out, shape) =
+                xs.fwd(&(nrows, k).into(), &x, &crate::Layout::contiguous((1, k)))?;
+            assert_eq!(shape.dims(), [1, nrows]);
+            let (expected, _) = xs.fwd_with_policy(
+                &(nrows, k_padded).into(),
+                &x_padded,
+                &crate::Layout::contiguous((1, k_padded)),
+                Some(QMatMulPolicy::Q8_1),
+            )?;
+            // The zero padded activation quantizes to the same q8_1 blocks.
+            assert_eq!(
+                dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?,
+                dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?,
+                "{dtype:?}"
+            );
+            // The dense path does not support partial blocks.
+            let x = CudaStorage::wrap_cuda_slice(
+                dev.htod_sync_copy(&[y.clone(), y.clone(), y.clone()].concat())
+                    .w()?,
+                dev.clone(),
+            );
+            let layout = crate::Layout::contiguous((3, k));
+            assert!(xs.fwd(&(nrows, k).into(), &x, &layout).is_err());
+        }
         Ok(())
     }
 
     #[test]
-    fn cuda_mmv_q8_1() -> Result<()> {
+    fn cuda_fwd_with_policy() -> Result<()> {
         let dev = CudaDevice::new(0)?;
-        let ncols = 256;
-        let vs: Vec<f32> = (0..ncols).map(|v| v as f32).collect();
-        let y = dev.htod_sync_copy(&vs).w()?;
-        let mut xs = QCudaStorage::zeros(&dev, ncols, GgmlDType::Q4_0)?;
-        xs.quantize(&CudaStorage::wrap_cuda_slice(y.clone(), dev.clone()))?;
-        let cuda_storage = mul_mat_vec_via_q8_1(
-            &xs.data,
-            &y.slice(..),
-            /* dtype */ GgmlDType::Q4_0,
-            /* ncols */ ncols,
-            /* nrows */ 1,
-            &dev,
-        )?;
-        let vs = cuda_storage.as_cuda_slice::<f32>()?;
-        let vs = dev.dtoh_sync_copy(&vs.slice(..)).unwrap();
-        assert_eq!(vs.len(), 1);
-        // for n = 255, n.(n+1).(2n+1) / 6 = 5559680
-        // Q8 means 1/256 precision.
-        assert_eq!(vs[0], 5561664.5);
+        let (nrows, ncols) = (16, 512);
+        let xs: Vec<f32> = (0..nrows * ncols)
+            .map(|i| (i % 23) as f32 / 11. - 1.)
+            .collect();
+        let y: Vec<f32> = (0..ncols).map(|i| (i % 7) as f32 / 3. - 1.).collect();
+        let y = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&y).w()?, dev.clone());
+        let layout = crate::Layout::contiguous((1, ncols));
+        let shape: crate::Shape = (nrows, ncols).into();
+        let mut qs = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q4_0)?;
+        qs.quantize(&CudaStorage::wrap_cuda_slice(
+            dev.htod_sync_copy(&xs).w()?,
+            dev.clone(),
+        ))?;
+        let to_host = |out: Result<(CudaStorage, crate::Shape)>| -> Result<Vec<f32>> {
+            let (out, _) = out?;
+            dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()
+        };
+        let y_slice = y.as_cuda_slice::<f32>()?.slice(..);
+        let dmmv =
+            dequantize_mul_mat_vec(&qs.data, &y_slice, None, None, qs.dtype, ncols, nrows, &dev)?;
+        let dmmv = dev.dtoh_sync_copy(dmmv.as_cuda_slice::<f32>()?).w()?;
+        let q8_1 =
+            mul_mat_vec_via_q8_1(&qs.data, &y_slice, None, None, qs.dtype, ncols, nrows, &dev)?;
+        let q8_1 = dev.dtoh_sync_copy(q8_1.as_cuda_slice::<f32>()?).w()?;
+        assert_ne!(dmmv, q8_1);
 
-        let cuda_storage = dequantize_mul_mat_vec(
-            &xs.data,
-            &y.slice(..),
-            /* dtype */ GgmlDType::Q4_0,
-            /* ncols */ ncols,
-            /* nrows */ 1,
+        let fwd = |policy| qs.fwd_with_policy(&shape, &y, &layout, policy);
+        assert_eq!(to_host(fwd(None))?, q8_1);
+        assert_eq!(to_host(fwd(Some(QMatMulPolicy::Dmmv)))?, dmmv);
+        // The policy takes precedence over the device configuration and is only set for the call.
+        let config = QuantCudaConfig {
+            force_dmmv: true,
+            ..QuantCudaConfig::default()
+        };
+        let scope = ConfigScope::enter(&dev, config)?;
+        let forced_q8_1 = fwd(Some(QMatMulPolicy::Q8_1));
+        let default = fwd(None);
+        let config_after = QuantCudaConfig::for_device(&dev);
+        drop(scope);
+        assert_eq!(to_host(forced_q8_1)?, q8_1);
+        assert_eq!(to_host(default)?, dmmv);
+        assert_eq!(config_after, config);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_mmv_q8_0_activation() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (8, 512);
+        let ws: Vec<f32> = (0..nrows * ncols)
+            .map(|i| (i % 19) as f32 / 9. - 1.)
+            .collect();
+        let mut qs = QCudaStorage::zeros(&dev, ws.len(), GgmlDType::Q4K)?;
+        qs.quantize(&CudaStorage::wrap_cuda_slice(
+            dev.htod_sync_copy(&ws).w()?,
+            dev.clone(),
+        ))?;
+        let y: Vec<f32> = (0..ncols).map(|i| (i % 13) as f32 / 6. - 1.).collect();
+        let mut y_q8_0 = QCudaStorage::zeros(&dev, ncols, GgmlDType::Q8_0)?;
+        y_q8_0.quantize(&CudaStorage::wrap_cuda_slice(
+            dev.htod_sync_copy(&y).w()?,
+            dev.clone(),
+        ))?;
+        let self_shape = crate::Shape::from((nrows, ncols));
+        let (out, shape) = qs.fwd_with_q8_0_activation(&self_shape, &y_q8_0)?;
+        assert_eq!(shape.dims(), &[1, nrows]);
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        // The reference uses the dequantized q8_0 activation.
+        let y_deq = y_q8_0.dequantize_to_host(ncols)?;
+        assert_close(&out, &cpu_reference_mmv(&qs, &y_deq, nrows)?, 1e-3);
+        assert!(qs.fwd_with_q8_0_activation(&self_shape, &qs).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_explain_matmul() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (64, 256);
+        let qs = QCudaStorage::zeros(&dev, nrows * ncols, GgmlDType::Q4_0)?;
+        let self_shape = crate::Shape::from((nrows, ncols));
+        let plan =
+            |dims: &[usize]| qs.explain_matmul(&self_shape, &crate::Layout::contiguous(dims));
+        let q8_1 = MatMulPlan::Vec {
+            kernel: MatMulVecKernel::Q8_1,
+            overflow_threshold: None,
+        };
+        assert_eq!(plan(&[1, ncols])?, q8_1);
+        assert_eq!(plan(&[1, 1, ncols])?, q8_1);
+        assert_eq!(
+            plan(&[4, 1, ncols])?,
+            MatMulPlan::BatchedVec {
+                batch: 4,
+                kernel: MatMulVecKernel::Q8_1,
+                overflow_threshold: None,
+            }
+        );
+        let dense = MatMulPlan::Dequantize {
+            f16: false,
+            chunk_rows: None,
+        };
+        assert_eq!(plan(&[MAX_BATCHED_VEC + 1, 1, ncols])?, dense);
+        assert_eq!(plan(&[2, 7, ncols])?, dense);
+        assert!(plan(&[1, ncols + 1]).is_err());
+
+        let config = QuantCudaConfig {
+            experimental_q4_activation: true,
+            dequantize_matmul_f16: true,
+            ..QuantCudaConfig::default()
+        };
+        let scope = ConfigScope::enter(&dev, config)?;
+        let vec_plan = plan(&[1, ncols]);
+        let dense_plan = plan(&[2, 7, ncols]);
+        drop(scope);
+        assert_eq!(
+            vec_plan?,
+            MatMulPlan::Vec {
+                kernel: MatMulVecKernel::Q4Activation,
+                overflow_threshold: None,
+            }
+        );
+        assert_eq!(
+            dense_plan?,
+            MatMulPlan::Dequantize {
+                f16: true,
+                chunk_rows: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_matmul_plan() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (64, 512);
+        let qs = QCudaStorage::zeros(&dev, nrows * ncols, GgmlDType::Q4_0)?;
+        let self_shape = crate::Shape::from((nrows, ncols));
+        let plan = |dims: &[usize]| {
+            qs.dequantize_matmul_plan(&self_shape, &crate::Layout::contiguous(dims))
+        };
+        let vec = plan(&[1, ncols])?;
+        assert!(matches!(vec.plan, MatMulPlan::Vec { .. }));
+        assert_eq!(
+            vec.transient_bytes,
+            q8_1_buffer_size(ncols, MATRIX_ROW_PADDING)
+        );
+        assert_eq!(vec.output_bytes, nrows * 4);
+        assert!(vec.fits);
+        let batched = plan(&[4, 1, ncols])?;
+        assert_eq!(batched.transient_bytes, 4 * vec.transient_bytes);
+        let dense = plan(&[2, 7, ncols])?;
+        let dense_l = crate::Layout::contiguous((2, 7, ncols));
+        assert_eq!(dense.plan, qs.explain_matmul(&self_shape, &dense_l)?);
+        assert_eq!(dense.transient_bytes, nrows * ncols * 4);
+        assert_eq!(dense.output_bytes, 14 * nrows * 4);
+        assert!(dense.fits);
+
+        // A headroom larger than the device memory leaves no room for anything.
+        let config = QuantCudaConfig {
+            dequantize_memory_headroom: Some(usize::MAX / 2),
+            dequantize_matmul_n_chunk: Some(16),
+            ..QuantCudaConfig::default()
+        };
+        let scope = ConfigScope::enter(&dev, config)?;
+        let chunked = plan(&[2, 7, ncols]);
+        drop(scope);
+        let chunked = chunked?;
+        assert!(!chunked.fits);
+        assert_eq!(
+            chunked.plan,
+            MatMulPlan::Dequantize {
+                f16: false,
+                chunk_rows: Some(1),
+            }
+        );
+        assert_eq!(chunked.transient_bytes, (ncols + 14) * 4);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_load_gguf_shards() -> Result<()> {
+        use crate::quantized::{gguf_file, QTensor};
+
+        let dev = CudaDevice::new(0)?;
+        let cpu = crate::Device::Cpu;
+        let w1 = crate::Tensor::arange(0f32, 512., &cpu)?.reshape((2, 256))?;
+        let w2 = crate::Tensor::arange(0f32, 256., &cpu)?.reshape((8, 32))?;
+        let w1 = QTensor::quantize(&w1, GgmlDType::Q4K)?;
+        let w2 = QTensor::quantize(&w2, GgmlDType::Q8_0)?;
+        let dir = std::env::temp_dir();
+        let pid = std::process::id();
+        let paths = [
+            dir.join(format!("candle-shards-{pid}-00001-of-00002.gguf")),
+            dir.join(format!("candle-shards-{pid}-00002-of-00002.gguf")),
+        ];
+        for (path, (name, w)) in paths.iter().zip([("w1", &w1), ("w2", &w2)]) {
+            let mut file = std::fs::File::create(path)?;
+            gguf_file::write(&mut file, &[], &[(name, w)])?;
+        }
+        let shards = GgufShards::open(&paths);
+        let duplicated = GgufShards::open(&[&paths[0], &paths[0]]);
+        for path in paths.iter() {
+            std::fs::remove_file(path)?;
+        }
+        let shards = shards?;
+        assert!(duplicated.is_err());
+        assert_eq!(shards.resolve("w2")?.path, paths[1]);
+        for (name, w) in [("w1", &w1), ("w2", &w2)] {
+            let storage = shards.load(&dev, name)?;
+            assert_eq!(storage.dtype(), w.dtype());
+            assert_eq!(storage.shape(), Some(w.shape()));
+            let data = dev.dtoh_sync_copy(&*storage.data).w()?;
+            assert_eq!(data, w.data()?.to_vec());
+        }
+        assert!(shards.load(&dev, "w3").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_load_gguf_direct() -> Result<()> {
+        use crate::quantized::{gguf_file, QTensor};
+
+        let dev = CudaDevice::new(0)?;
+        let cpu = crate::Device::Cpu;
+        let w = crate::Tensor::arange(0f32, 4096., &cpu)?.reshape((16, 256))?;
+        let w = QTensor::quantize(&w, GgmlDType::Q4K)?;
+        let path = std::env::temp_dir().join(format!("candle-gds-{}.gguf", std::process::id()));
+        let mut file = std::fs::File::create(&path)?;
+        gguf_file::write(&mut file, &[], &[("w", &w)])?;
+        drop(file);
+        let shards = GgufShards::open(&[&path]);
+        // With GDS unavailable, e.g. on tmpfs, this goes through the host fallback.
+        let storage = shards.and_then(|shards| shards.load_direct(&dev, "w"));
+        std::fs::remove_file(&path)?;
+        let storage = storage?;
+        assert_eq!(storage.dtype(), w.dtype());
+        assert_eq!(storage.shape(), Some(w.shape()));
+        let data = dev.dtoh_sync_copy(&*storage.data).w()?;
+        assert_eq!(data, w.data()?.to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_quantize_with_report() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let xs: Vec<f32> = (0..1024).map(|i| ((i as f32) * 0.37).sin()).collect();
+        let src = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        let mut reports = vec![];
+        for dtype in [GgmlDType::Q8_0, GgmlDType::Q4_0, GgmlDType::Q2K] {
+            let mut qs = QCudaStorage::zeros(&dev, xs.len(), dtype)?;
+            let report = qs.quantize_with_report(&src)?;
+            assert_eq!(report.elem_count, xs.len());
+            assert!(report.max_abs_error > 0.);
+            assert!(report.mse <= (report.max_abs_error as f64).powi(2));
+            assert!(
+                report.rel_rmse() < dtype.expected_rel_error() as f64,
+                "{dtype:?}"
+            );
+            reports.push(report);
+        }
+        // Coarser quants lose more.
+        assert!(reports[0].mse < reports[1].mse);
+        assert!(reports[1].mse < reports[2].mse);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_quantize_with_imatrix() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (rows, cols) = (8, 256);
+        let xs: Vec<f32> = (0..rows * cols)
+            .map(|i| ((i as f32) * 0.37).sin())
+            .collect();
+        let src = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        // Only the first four columns of each block matter.
+        let imatrix: Vec<f32> = (0..cols)
+            .map(|i| if i % 32 < 4 { 1. } else { 1e-3 })
+            .collect();
+        let weighted_error = |ys: &[f32]| {
+            xs.iter()
+                .zip(ys.iter())
+                .enumerate()
+                .map(|(i, (x, y))| imatrix[i % cols] * (x - y) * (x - y))
+                .sum::<f32>()
+        };
+        for dtype in [GgmlDType::Q4_0, GgmlDType::Q5_0, GgmlDType::Q8_0] {
+            let mut plain = QCudaStorage::zeros(&dev, xs.len(), dtype)?;
+            plain.quantize(&src)?;
+            let mut weighted = QCudaStorage::zeros(&dev, xs.len(), dtype)?;
+            weighted.quantize_with_imatrix(&src, &imatrix)?;
+            let plain = plain.dequantize_to_host(xs.len())?;
+            let weighted = weighted.dequantize_to_host(xs.len())?;
+            assert!(
+                weighted_error(&weighted) <= weighted_error(&plain),
+                "{dtype:?} {} {}",
+                weighted_error(&weighted),
+                weighted_error(&plain)
+            );
+        }
+
+        let mut qs = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q4_0)?;
+        let err = qs.quantize_with_imatrix(&src, &imatrix[..100]).unwrap_err();
+        assert!(err.to_string().contains("not divisible by 32"), "{err}");
+        let mut qs = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q4K)?;
+        let err = qs.quantize_with_imatrix(&src, &imatrix).unwrap_err();
+        assert!(err.to_string().contains("not supported for Q4K"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_bf16() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let xs: Vec<half::bf16> = (0..1000)
+            .map(|i| half::bf16::from_f32((i as f32 - 500.) * 0.731))
+            .collect();
+        let bytes = unsafe {
+            std::slice::from_raw_parts(xs.as_ptr() as *const u8, std::mem::size_of_val(&xs[..]))
+        };
+        let qs = load_quantized_bytes(&dev, GgmlDType::BF16, bytes)?;
+        assert_eq!(qs.elem_count(), xs.len());
+        let expected: Vec<f32> = xs.iter().map(|x| x.to_f32()).collect();
+        let out = qs.dequantize(xs.len())?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+        assert_eq!(out, expected);
+        assert_eq!(
+            dequantize_on_cpu(bytes, GgmlDType::BF16, xs.len())?,
+            expected
+        );
+        let out = qs.dequantize_f16(xs.len())?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<half::f16>()?).w()?;
+        for (a, b) in expected.iter().zip(out.iter()) {
+            assert_eq!(half::f16::from_f32(*a), *b);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_view() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let xs: Vec<f32> = (0..512).map(|i| ((i as f32) * 0.29).sin()).collect();
+        let src = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        // Pack a few tensors in a single buffer, each aligned on 32 bytes as in gguf files.
+        let mut packed = vec![];
+        let mut tensors = vec![];
+        for dtype in [
+            GgmlDType::Q4_0,
+            GgmlDType::Q8_0,
+            GgmlDType::F16,
+            GgmlDType::Q4K,
+        ] {
+            let mut qs = QCudaStorage::zeros(&dev, xs.len(), dtype)?;
+            qs.quantize(&src)?;
+            packed.resize(pad(packed.len(), 32), 0u8);
+            tensors.push((dtype, packed.len(), qs.dequantize_to_host(xs.len())?));
+            packed.extend(dev.dtoh_sync_copy(&*qs.data).w()?);
+        }
+        let packed = dev.htod_sync_copy(&packed).w()?;
+        for (dtype, offset, expected) in tensors {
+            let out = dequantize_view(&packed.slice(offset..), dtype, xs.len(), &dev)?;
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            assert_eq!(out, expected, "{dtype:?}");
+        }
+
+        let view = packed.slice(1..);
+        assert!(dequantize_view(&view, GgmlDType::Q8_0, 32, &dev).is_err());
+        let view = packed.slice(..100);
+        assert!(dequantize_view(&view, GgmlDType::Q8_0, 256, &dev).is_err());
+        assert!(dequantize_view(&view, GgmlDType::Q8_0, 48, &dev).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dtype_info() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let qs = QCudaStorage::zeros(&dev, 1024, GgmlDType::Q4_0)?;
+        let info = qs.dtype_info();
+        assert_eq!((info.block_size, info.type_size), (32, 18));
+        assert_eq!(info.bits_per_weight, 4.5);
+        assert!(!info.is_k && info.has_fast_dequantize && info.has_q8_1_mmvq);
+        assert_eq!(qs.elem_count(), 1024);
+
+        let info = DTypeInfo::new(GgmlDType::Q4K);
+        assert_eq!((info.block_size, info.type_size), (256, 144));
+        assert_eq!(info.bits_per_weight, 4.5);
+        assert!(info.is_k);
+        let info = DTypeInfo::new(GgmlDType::F16);
+        assert_eq!(info.bits_per_weight, 16.);
+        assert!(!info.has_fast_dequantize && !info.has_q8_1_mmvq);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_into_at() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let xs: Vec<f32> = (0..1024).map(|i| ((i as f32) * 0.17).sin()).collect();
+        let src = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        let mut q4 = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q4_0)?;
+        q4.quantize(&src)?;
+        let mut q5k = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q5K)?;
+        q5k.quantize(&src)?;
+        let f16 = QCudaStorage::zeros(&dev, 512, GgmlDType::F16)?;
+        // Pieces written back to back after a sentinel prefix, the tail stays untouched.
+        let mut dst = dev
+            .htod_sync_copy(&vec![-7f32; 8 + 2 * 1024 + 512 + 8])
+            .w()?;
+        q4.dequantize_into_at(&mut dst, 8, 1024)?;
+        q5k.dequantize_into_at(&mut dst, 8 + 1024, 1024)?;
+        f16.dequantize_into_at(&mut dst, 8 + 2048, 512)?;
+        let out = dev.dtoh_sync_copy(&dst).w()?;
+        assert!(out[..8]
+            .iter()
+            .chain(out[8 + 2048 + 512..].iter())
+            .all(|v| *v == -7.));
+        assert_eq!(out[8..8 + 1024], q4.dequantize_to_host(1024)?);
+        assert_eq!(out[8 + 1024..8 + 2048], q5k.dequantize_to_host(1024)?);
+        assert!(out[8 + 2048..8 + 2048 + 512].iter().all(|v| *v == 0.));
+        assert!(q4
+            .dequantize_into_at(&mut dst, out.len() - 512, 1024)
+            .is_err());
+        assert!(q4.dequantize_into_at(&mut dst, usize::MAX, 1024).is_err());
+        assert!(q4.dequantize_into_at(&mut dst, 0, 48).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_colmajor() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (6, 512);
+        let xs: Vec<f32> = (0..nrows * ncols)
+            .map(|i| ((i as f32) * 0.13).cos())
+            .collect();
+        let src = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        for dtype in [
+            GgmlDType::Q4_0,
+            GgmlDType::Q5_1,
+            GgmlDType::Q8_0,
+            GgmlDType::Q3K,
+            GgmlDType::Q6K,
+            GgmlDType::F16,
+        ] {
+            let mut qs = QCudaStorage::zeros(&dev, xs.len(), dtype)?;
+            qs.quantize(&src)?;
+            let rowmajor = qs.dequantize_to_host(xs.len())?;
+            let out = qs.dequantize_colmajor(xs.len(), nrows, ncols)?;
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            for r in 0..nrows {
+                for c in 0..ncols {
+                    assert_eq!(out[c * nrows + r], rowmajor[r * ncols + c], "{dtype:?}");
+                }
+            }
+        }
+        let qs = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q4_0)?;
+        assert!(qs.dequantize_colmajor(xs.len(), nrows + 1, ncols).is_err());
+        Ok(())
+    }
+
+    // Uploads the blocks quantizing `xs` and checks the extracted scales against `host`.
+    fn check_dequantize_scales<T: GgmlType + Send + Sync + 'static>(
+        dev: &CudaDevice,
+        xs: &[f32],
+        host: impl Fn(&T) -> Vec<f32>,
+    ) -> Result<()> {
+        let mut blocks = vec![T::zeros(); xs.len() / T::BLCK_SIZE];
+        T::from_float(xs, &mut blocks)?;
+        let storage = match load_quantized(dev, &blocks)? {
+            QStorage::Cuda(storage) => storage,
+            _ => unreachable!(),
+        };
+        let scales = storage.dequantize_scales()?;
+        let scales = dev.dtoh_sync_copy(scales.as_cuda_slice::<f32>()?).w()?;
+        let expected: Vec<f32> = blocks.iter().flat_map(host).collect();
+        assert_eq!(scales, expected, "{:?}", T::DTYPE);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_scales() -> Result<()> {
+        use crate::quantized::k_quants::*;
+        use crate::quantized::utils::get_scale_min_k4;
+
+        let dev = CudaDevice::new(0)?;
+        let xs: Vec<f32> = (0..4 * 256)
+            .map(|i| ((i as f32) * 0.37).sin() * (1. + (i / 64) as f32))
+            .collect();
+        check_dequantize_scales(&dev, &xs, |b: &BlockQ4_0| vec![b.d.to_f32()])?;
+        check_dequantize_scales(&dev, &xs, |b: &BlockQ4_1| vec![b.d.to_f32()])?;
+        check_dequantize_scales(&dev, &xs, |b: &BlockQ5_0| vec![b.d.to_f32()])?;
+        check_dequantize_scales(&dev, &xs, |b: &BlockQ5_1| vec![b.d.to_f32()])?;
+        check_dequantize_scales(&dev, &xs, |b: &BlockQ8_0| vec![b.d.to_f32()])?;
+        check_dequantize_scales(&dev, &xs, |b: &BlockQ8K| vec![b.d])?;
+        check_dequantize_scales(&dev, &xs, |b: &BlockQ2K| {
+            let d = b.d.to_f32();
+            b.scales.iter().map(|s| d * (s & 0xF) as f32).collect()
+        })?;
+        check_dequantize_scales(&dev, &xs, |b: &BlockQ3K| {
+            // The 6 bits scales as unpacked by `BlockQ3K::to_float`.
+            const KMASK1: u32 = 0x03030303;
+            const KMASK2: u32 = 0x0f0f0f0f;
+            let mut aux = [0u32; 4];
+            for (i, a) in aux.iter_mut().take(3).enumerate() {
+                *a = u32::from_le_bytes(b.scales[4 * i..4 * i + 4].try_into().unwrap());
+            }
+            let tmp = aux[2];
+            aux[2] = ((aux[0] >> 4) & KMASK2) | (((tmp >> 4) & KMASK1) << 4);
+            aux[3] = ((aux[1] >> 4) & KMASK2) | (((tmp >> 6) & KMASK1) << 4);
+            aux[0] = (aux[0] & KMASK2) | ((tmp & KMASK1) << 4);
+            aux[1] = (aux[1] & KMASK2) | (((tmp >> 2) & KMASK1) << 4);
+            let d = b.d.to_f32();
+            aux.iter()
+                .flat_map(|a| a.to_le_bytes())
+                .map(|s| d * (s as i8 as f32 - 32.))
+                .collect()
+        })?;
+        check_dequantize_scales(&dev, &xs, |b: &BlockQ4K| {
+            let d = b.d.to_f32();
+            (0..8)
+                .map(|j| d * get_scale_min_k4(j, &b.scales).0 as f32)
+                .collect()
+        })?;
+        check_dequantize_scales(&dev, &xs, |b: &BlockQ5K| {
+            let d = b.d.to_f32();
+            (0..8)
+                .map(|j| d * get_scale_min_k4(j, &b.scales).0 as f32)
+                .collect()
+        })?;
+        check_dequantize_scales(&dev, &xs, |b: &BlockQ6K| {
+            let d = b.d.to_f32();
+            b.scales.iter().map(|s| d * *s as f32).collect()
+        })?;
+        let f16 = QCudaStorage::zeros(&dev, 256, GgmlDType::F16)?;
+        assert!(f16.dequantize_scales().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_with_stats() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let xs: Vec<f32> = (0..4096)
+            .map(|i| ((i as f32) * 0.07).sin() * 3. - 0.5)
+            .collect();
+        let src = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        for dtype in [
+            GgmlDType::Q4_0,
+            GgmlDType::Q5_0,
+            GgmlDType::Q8_0,
+            GgmlDType::Q2K,
+            GgmlDType::Q4K,
+            GgmlDType::Q6K,
+            GgmlDType::F16,
+        ] {
+            let mut qs = QCudaStorage::zeros(&dev, xs.len(), dtype)?;
+            qs.quantize(&src)?;
+            let expected = qs.dequantize_to_host(xs.len())?;
+            let (out, stats) = qs.dequantize_with_stats(xs.len())?;
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            assert_eq!(out, expected, "{dtype:?}");
+            let reference = WeightStats::from_values(&expected);
+            assert_eq!(stats.min, reference.min, "{dtype:?}");
+            assert_eq!(stats.max, reference.max, "{dtype:?}");
+            assert_eq!(stats.abs_max, reference.abs_max, "{dtype:?}");
+            assert!((stats.mean - reference.mean).abs() < 1e-4, "{dtype:?}");
+            assert_eq!(stats.nan_count, 0, "{dtype:?}");
+        }
+
+        // The NaN values are counted and left out of the other stats.
+        let mut xs = xs;
+        for i in [3, 700, 701, 4095] {
+            xs[i] = f32::NAN;
+        }
+        let bytes: Vec<u8> = xs
+            .iter()
+            .flat_map(|v| half::bf16::from_f32(*v).to_le_bytes())
+            .collect();
+        let qs = load_quantized_bytes(&dev, GgmlDType::BF16, &bytes)?;
+        let expected = qs.dequantize_to_host(xs.len())?;
+        let (_, stats) = qs.dequantize_with_stats(xs.len())?;
+        let reference = WeightStats::from_values(&expected);
+        assert_eq!(stats.nan_count, 4);
+        assert_eq!(reference.nan_count, 4);
+        assert_eq!(stats.min, reference.min);
+        assert_eq!(stats.max, reference.max);
+        assert_eq!(stats.abs_max, reference.abs_max);
+        assert!(stats.mean.is_finite());
+        assert!((stats.mean - reference.mean).abs() < 1e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_row_energy() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        // Rows of 384 columns so that the blocks of the k-quants span two rows.
+        let (nrows, ncols) = (16, 384);
+        let xs: Vec<f32> = (0..nrows * ncols)
+            .map(|i| ((i as f32) * 0.07).sin() * (1 + i / ncols) as f32)
+            .collect();
+        let src = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        for dtype in [
+            GgmlDType::Q4_0,
+            GgmlDType::Q5_1,
+            GgmlDType::Q8_0,
+            GgmlDType::Q3K,
+            GgmlDType::Q4K,
+            GgmlDType::Q6K,
+            GgmlDType::F16,
+        ] {
+            let mut qs = QCudaStorage::zeros(&dev, xs.len(), dtype)?;
+            qs.quantize(&src)?;
+            let values = qs.dequantize_to_host(xs.len())?;
+            let energy = qs.dequantize_row_energy(nrows, ncols)?;
+            let energy = dev.dtoh_sync_copy(energy.as_cuda_slice::<f32>()?).w()?;
+            assert_eq!(energy.len(), nrows);
+            for (row, e) in values.chunks_exact(ncols).zip(energy.iter()) {
+                let expected: f32 = row.iter().map(|v| v * v).sum();
+                assert!(
+                    (e - expected).abs() <= expected * 1e-4,
+                    "{dtype:?} {e} {expected}"
+                );
+            }
+        }
+        let qs = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q4_0)?;
+        assert!(qs.dequantize_row_energy(nrows, 2 * ncols).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_q8_1() -> Result<()> {
+        use crate::quantized::k_quants::BlockQ8_1;
+
+        let dev = CudaDevice::new(0)?;
+        let xs: Vec<f32> = (0..1024).map(|i| ((i as f32) * 0.3).sin() * 5.).collect();
+        let mut blocks = vec![BlockQ8_1::zeros(); xs.len() / 32];
+        BlockQ8_1::from_float(&xs, &mut blocks)?;
+        let mut expected = vec![0f32; xs.len()];
+        BlockQ8_1::to_float(&blocks, &mut expected)?;
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                blocks.as_ptr() as *const u8,
+                core::mem::size_of_val(blocks.as_slice()),
+            )
+        };
+        let qs = load_quantized_bytes(&dev, GgmlDType::Q8_1, bytes)?;
+        assert!(qs.has_fast_dequantize_kernel());
+        assert_eq!(qs.dequantize_to_host(xs.len())?, expected);
+        let out = qs.dequantize_f16(xs.len())?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<half::f16>()?).w()?;
+        for (o, e) in out.iter().zip(expected.iter()) {
+            assert_eq!(*o, half::f16::from_f32(*e))
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_batch() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let xs: Vec<f32> = (0..1024).map(|i| ((i as f32) * 0.11).cos()).collect();
+        let src = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&xs).w()?, dev.clone());
+        let mut q4 = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q4_0)?;
+        q4.quantize(&src)?;
+        let mut q6k = QCudaStorage::zeros(&dev, xs.len(), GgmlDType::Q6K)?;
+        q6k.quantize(&src)?;
+        // The embedding layout cannot be dequantized, this must not prevent the other ones.
+        let emb = q4.to_embedding_layout(256)?;
+        let outs = QCudaStorage::dequantize_batch(&[&q4, &emb, &q6k]);
+        assert_eq!(outs.len(), 3);
+        assert!(outs[1].is_err());
+        for (out, qs) in [(&outs[0], &q4), (&outs[2], &q6k)] {
+            let out = match out {
+                Ok(out) => dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?,
+                Err(err) => panic!("unexpected error {err}"),
+            };
+            assert_eq!(out, qs.dequantize_to_host(xs.len())?);
+        }
+        assert!(QCudaStorage::dequantize_batch(&[]).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_graph_replay() -> Result<()> {
+        use crate::quantized::cuda_graph::QuantCudaGraph;
+
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (64, 512);
+        let ws: Vec<f32> = (0..nrows * ncols)
+            .map(|i| ((i as f32) * 0.01).sin())
+            .collect();
+        let ws = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ws).w()?, dev.clone());
+        let mut xs = QCudaStorage::zeros(&dev, nrows * ncols, GgmlDType::Q4K)?;
+        xs.quantize(&ws)?;
+        let self_shape = crate::Shape::from((nrows, ncols));
+        let layout = crate::Layout::contiguous((1, ncols));
+        let input = |step: usize| -> Vec<f32> {
+            (0..ncols)
+                .map(|i| ((i + step) as f32 * 0.1).cos())
+                .collect()
+        };
+        let x = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&input(0)).w()?, dev.clone());
+        let x_ptr = *x.as_cuda_slice::<f32>()?.device_ptr();
+        let (graph, (out, out_shape)) =
+            QuantCudaGraph::capture(&dev, || xs.fwd(&self_shape, &x, &layout))?;
+        assert_eq!(out_shape.dims(), [1, nrows]);
+        assert!(graph.num_nodes()? > 0);
+        for step in 0..3 {
+            let v = input(step);
+            unsafe { cudarc::driver::result::memcpy_htod_sync(x_ptr, &v) }.w()?;
+            graph.replay()?;
+            let replayed = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            let expected = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&v).w()?, dev.clone());
+            let (expected, _) = xs.fwd(&self_shape, &expected, &layout)?;
+            let expected = dev.dtoh_sync_copy(expected.as_cuda_slice::<f32>()?).w()?;
+            assert_eq!(replayed, expected, "{step}");
+        }
+        // Host synchronizations cannot be captured.
+        let err = QuantCudaGraph::capture(&dev, || xs.dequantize_to_host(nrows * ncols));
+        assert!(err.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_q8_1_cache() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (32, 512);
+        let ws: Vec<f32> = (0..nrows * ncols)
+            .map(|i| (i % 23) as f32 / 11. - 1.)
+            .collect();
+        let ws = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ws).w()?, dev.clone());
+        // Three weights sharing the same activation, as for the q, k and v projections.
+        let mut weights = vec![];
+        for dtype in [GgmlDType::Q4_0, GgmlDType::Q8_0, GgmlDType::Q4K] {
+            let mut qs = QCudaStorage::zeros(&dev, nrows * ncols, dtype)?;
+            qs.quantize(&ws)?;
+            weights.push(qs);
+        }
+        let self_shape = crate::Shape::from((nrows, ncols));
+        let y: Vec<f32> = (0..ncols).map(|i| (i % 7) as f32 / 3. - 1.).collect();
+        let y = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&y).w()?, dev.clone());
+        let y_l = crate::Layout::contiguous((1, ncols));
+        let fwd_all = || -> Result<Vec<Vec<f32>>> {
+            let mut outs = vec![];
+            for w in weights.iter() {
+                let (out, _) = w.fwd(&self_shape, &y, &y_l)?;
+                outs.push(dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?);
+            }
+            Ok(outs)
+        };
+        let expected = fwd_all()?;
+        assert_eq!(Q81Cache::stats(&dev), None);
+        {
+            let _guard = Q81Cache::enable(&dev)?;
+            assert!(Q81Cache::enable(&dev).is_err());
+            assert_eq!(fwd_all()?, expected);
+            let stats = Q81Cache::stats(&dev).unwrap();
+            assert_eq!((stats.quantized, stats.hits), (1, 2));
+            assert_eq!(Q81Cache::invalidate(&dev), 1);
+            assert_eq!(fwd_all()?, expected);
+            let stats = Q81Cache::stats(&dev).unwrap();
+            assert_eq!((stats.quantized, stats.hits), (2, 4));
+            // Another rounding quantizes the activation again.
+            let config = QuantCudaConfig::for_device(&dev);
+            let rounding = Q8_1Rounding::TowardZero;
+            let scope = ConfigScope::enter(
+                &dev,
+                QuantCudaConfig {
+                    q8_1_rounding: rounding,
+                    ..config
+                },
+            )?;
+            let outs = fwd_all();
+            drop(scope);
+            outs?;
+            let stats = Q81Cache::stats(&dev).unwrap();
+            assert_eq!((stats.quantized, stats.hits), (3, 6));
+
+            // Only the last `MAX_ENTRIES` activations are kept.
+            assert_eq!(Q81Cache::invalidate(&dev), 2);
+            for _ in 0..Q81Cache::MAX_ENTRIES + 1 {
+                let y = dev.alloc_zeros::<f32>(ncols).w()?;
+                let y = CudaStorage::wrap_cuda_slice(y, dev.clone());
+                weights[0].fwd(&self_shape, &y, &y_l)?;
+            }
+            let size = q8_1_buffer_size(ncols, q8_1_row_padding(GgmlDType::Q4_0));
+            assert_eq!(Q81Cache::size_in_bytes(&dev), Q81Cache::MAX_ENTRIES * size);
+        }
+        assert_eq!(Q81Cache::stats(&dev), None);
+        Ok(())
+    }
+
+    #[cfg(feature = "quant-trace")]
+    #[test]
+    fn cuda_quant_trace() -> Result<()> {
+        use crate::quantized::cuda_trace::QuantTracer;
+
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (32, 256);
+        let qs = QCudaStorage::zeros(&dev, nrows * ncols, GgmlDType::Q4K)?;
+        let y = CudaStorage::wrap_cuda_slice(dev.alloc_zeros::<f32>(ncols).w()?, dev.clone());
+        let y_l = crate::Layout::contiguous((1, ncols));
+        QuantTracer::enable();
+        qs.fwd(&(nrows, ncols).into(), &y, &y_l)?;
+        qs.dequantize(nrows * ncols)?;
+        let num_launches = QuantTracer::num_launches();
+        let trace = QuantTracer::chrome_trace()?;
+        QuantTracer::disable();
+        assert!(num_launches >= 3, "{num_launches}");
+        assert!(trace.starts_with(r#"{"traceEvents":[{"name":"#), "{trace}");
+        assert!(trace.contains(r#""name":"quantize_q8_1""#), "{trace}");
+        assert!(
+            trace.contains(r#""name":"mul_mat_vec_q4_K_q8_1_cuda""#),
+            "{trace}"
+        );
+        assert!(
+            trace.contains(r#""name":"dequantize_block_q4_K","#),
+            "{trace}"
+        );
+        assert!(trace.contains(r#""args":{"dtype":"Q4K"}"#), "{trace}");
+        assert!(!QuantTracer::is_enabled());
+        assert!(QuantTracer::chrome_trace().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_oversized_kernel_dims() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let too_large = i32::MAX as usize + 1;
+        let src = dev.alloc_zeros::<f32>(32).w()?;
+        let mut dst = dev.alloc_zeros::<u8>(36).w()?;
+        let err = quantize_q8_1(
+            &src.slice(..),
+            &mut dst,
+            too_large,
+            MATRIX_ROW_PADDING,
+            Q8_1Rounding::Nearest,
             &dev,
-        )?;
-        let vs = cuda_storage.as_cuda_slice::<f32>()?;
-        let vs = dev.dtoh_sync_copy(&vs.slice(..)).unwrap();
-        assert_eq!(vs.len(), 1);
-        assert_eq!(vs[0], 5561851.0);
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("exceeds the i32 range"), "{err}");
+        let data = dev.alloc_zeros::<u8>(22).w()?;
+        let err = dequantize::<f32>(&data.slice(..), GgmlDType::Q5_0, too_large, &dev)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("exceeds the i32 range"), "{err}");
+        let err = kernel_dim(too_large, "ncols").unwrap_err().to_string();
+        assert!(err.contains("ncols 2147483648"), "{err}");
+        assert_eq!(kernel_dim(i32::MAX as usize, "ncols")?, i32::MAX);
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_f16() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let el = 1024;
+        let vs: Vec<f32> = (0..el).map(|v| v as f32 / el as f32).collect();
+        let y = dev.htod_sync_copy(&vs).w()?;
+        let mut xs = QCudaStorage::zeros(&dev, el, GgmlDType::Q8_0)?;
+        xs.quantize(&CudaStorage::wrap_cuda_slice(y, dev.clone()))?;
+        let f32s = xs.dequantize(el)?;
+        let f32s = dev.dtoh_sync_copy(f32s.as_cuda_slice::<f32>()?).w()?;
+        let f16s = xs.dequantize_f16(el)?;
+        let f16s = dev.dtoh_sync_copy(f16s.as_cuda_slice::<half::f16>()?).w()?;
+        for (a, b) in f32s.iter().zip(f16s.iter()) {
+            assert!((a - b.to_f32()).abs() < 1e-3, "{a} {b}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_dequantize_cached() -> Result<()> {
+        use crate::quantized::cuda_dequant_cache::DequantCache;
+
+        let dev = CudaDevice::new(0)?;
+        let elem_count = 1024;
+        let ws: Vec<f32> = (0..elem_count).map(|i| (i as f32 * 0.1).sin()).collect();
+        let ws = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ws).w()?, dev.clone());
+        let mut xs = QCudaStorage::zeros(&dev, elem_count, GgmlDType::Q4K)?;
+        xs.quantize(&ws)?;
+        let expected = xs.dequantize_f16(elem_count)?;
+        let expected = dev
+            .dtoh_sync_copy(expected.as_cuda_slice::<half::f16>()?)
+            .w()?;
+
+        let dir = std::env::temp_dir().join(format!("candle-dequant-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        DequantCache::set_cache_dir(Some(dir.clone()));
+        let path = DequantCache::entry_path(&dir, xs.checksum()?, xs.dtype(), elem_count);
+        // The first call writes the entry, the second one reads it back.
+        let miss = xs.dequantize_cached(elem_count);
+        let written = std::fs::metadata(&path).map(|m| m.len());
+        let hit = xs.dequantize_cached(elem_count);
+        DequantCache::set_cache_dir(None);
+        std::fs::remove_dir_all(&dir)?;
+        assert!(DequantCache::take_error().is_none());
+        assert_eq!(written?, 2 * elem_count as u64);
+        for out in [miss?, hit?] {
+            assert_eq!(
+                dev.dtoh_sync_copy(out.as_cuda_slice::<half::f16>()?).w()?,
+                expected
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_exception_rows() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (nrows, ncols) = (16, 256);
+        let ws: Vec<f32> = (0..nrows * ncols)
+            .map(|i| ((i as f32) * 0.2).sin())
+            .collect();
+        let ws = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ws).w()?, dev.clone());
+        let mut xs = QCudaStorage::zeros(&dev, nrows * ncols, GgmlDType::Q4_0)?;
+        xs.quantize(&ws)?;
+        let rows = [3u32, 10];
+        let values: Vec<half::f16> = (0..rows.len() * ncols)
+            .map(|i| half::f16::from_f32((i % 7) as f32 - 3.))
+            .collect();
+        assert!(ExceptionRows::new(&dev, &[10, 3], &values, ncols).is_err());
+        let out_of_range = ExceptionRows::new(&dev, &[3, nrows as u32], &values, ncols)?;
+        assert!(xs.set_exception_rows(Some(out_of_range)).is_err());
+        let mut expected = xs.dequantize_to_host(nrows * ncols)?;
+        xs.set_exception_rows(Some(ExceptionRows::new(&dev, &rows, &values, ncols)?))?;
+        for (i, &row) in rows.iter().enumerate() {
+            let row = row as usize * ncols;
+            for (dst, v) in expected[row..row + ncols]
+                .iter_mut()
+                .zip(values[i * ncols..].iter())
+            {
+                *dst = v.to_f32()
+            }
+        }
+        let out = xs.dequantize(nrows * ncols)?;
+        assert_eq!(
+            dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?,
+            expected
+        );
+        let out = xs.dequantize_f16(nrows * ncols)?;
+        let out = dev.dtoh_sync_copy(out.as_cuda_slice::<half::f16>()?).w()?;
+        for &row in rows.iter() {
+            let row = row as usize * ncols;
+            assert_eq!(
+                out[row..row + ncols]
+                    .iter()
+                    .map(|v| v.to_f32())
+                    .collect::<Vec<_>>(),
+                expected[row..row + ncols]
+            );
+        }
+
+        // Both the matmul-vec and the dense paths substitute the exception rows.
+        let self_shape = crate::Shape::from((nrows, ncols));
+        let y: Vec<f32> = (0..4 * ncols).map(|i| (i as f32 * 0.3).cos()).collect();
+        let x = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&y).w()?, dev.clone());
+        for m in [1, 4] {
+            let (out, _) = xs.fwd(&self_shape, &x, &crate::Layout::contiguous((m, ncols)))?;
+            let out = dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w()?;
+            for (i, y) in y.chunks(ncols).take(m).enumerate() {
+                for &row in rows.iter() {
+                    let row = row as usize;
+                    let w = &expected[row * ncols..(row + 1) * ncols];
+                    let e: f32 = w.iter().zip(y.iter()).map(|(w, y)| w * y).sum();
+                    assert!((out[i * nrows + row] - e).abs() < 1e-3, "{m} {row}");
+                }
+            }
+        }
+
+        // The exception rows are saved along with the quantized blocks.
+        let bytes = xs.to_safetensors_bytes("w")?;
+        let loaded = QCudaStorage::from_safetensors_bytes(&dev, "w", &bytes)?;
+        let exceptions = loaded.exception_rows().expect("exception rows");
+        assert_eq!(exceptions.rows()?, rows);
+        assert_eq!(exceptions.values()?, values);
+        // Quantizing new weights drops them.
+        xs.quantize(&ws)?;
+        assert!(xs.exception_rows().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_exception_rows_entry_points() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        // fwd_q8_0 requires a multiple of 32 rows.
+        let (nrows, ncols) = (32, 256);
+        let ws: Vec<f32> = (0..nrows * ncols)
+            .map(|i| ((i as f32) * 0.2).sin())
+            .collect();
+        let ws = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&ws).w()?, dev.clone());
+        let mut xs = QCudaStorage::zeros(&dev, nrows * ncols, GgmlDType::Q4_0)?;
+        xs.quantize(&ws)?;
+        let rows = [3u32, 10, 31];
+        let values: Vec<half::f16> = (0..rows.len() * ncols)
+            .map(|i| half::f16::from_f32((i % 7) as f32 - 3.))
+            .collect();
+        let mut expected = xs.dequantize_to_host(nrows * ncols)?;
+        for (i, &row) in rows.iter().enumerate() {
+            let row = row as usize * ncols;
+            for (dst, v) in expected[row..row + ncols]
+                .iter_mut()
+                .zip(values[i * ncols..].iter())
+            {
+                *dst = v.to_f32()
+            }
+        }
+        xs.set_exception_rows(Some(ExceptionRows::new(&dev, &rows, &values, ncols)?))?;
+        let dot = |row: u32, y: &[f32]| -> f32 {
+            let row = row as usize * ncols;
+            expected[row..row + ncols]
+                .iter()
+                .zip(y.iter())
+                .map(|(w, y)| w * y)
+                .sum()
+        };
+        let to_host = |out: &CudaStorage| dev.dtoh_sync_copy(out.as_cuda_slice::<f32>()?).w();
+
+        // Dequantizing a range of rows, the rows before it shift the exception rows.
+        let blocks_per_row = ncols / 32;
+        let out = xs.dequantize_range(2 * blocks_per_row, 12 * blocks_per_row, ncols)?;
+        assert_eq!(to_host(&out)?, expected[2 * ncols..12 * ncols]);
+        assert!(xs
+            .dequantize_range(0, 2 * blocks_per_row, 2 * ncols)
+            .is_err());
+        let mut dst = dev.alloc_zeros::<f32>(8 + nrows * ncols).w()?;
+        xs.dequantize_into_at(&mut dst, 8, nrows * ncols)?;
+        assert_eq!(dev.dtoh_sync_copy(&dst).w()?[8..], expected);
+
+        // The concatenation keeps the exception rows of both parts.
+        let cat = QCudaStorage::cat_rows(&[&xs, &xs], ncols)?;
+        let cat_rows: Vec<u32> = rows
+            .iter()
+            .chain(rows.iter().map(|r| r + nrows as u32))
+            .collect();
+        assert_eq!(
+            cat.exception_rows().expect("exception rows").rows()?,
+            cat_rows
+        );
+        let out = cat.dequantize(2 * nrows * ncols)?;
+        assert_eq!(
+            to_host(&out)?,
+            [expected.clone(), expected.clone()].concat()
+        );
+
+        // The ops that cannot substitute the exception rows fail.
+        assert!(xs.dequantize_colmajor(nrows * ncols, nrows, ncols).is_err());
+        assert!(xs.dequantize_with_stats(nrows * ncols).is_err());
+        assert!(xs.dequantize_row_energy(nrows, ncols).is_err());
+
+        let self_shape = crate::Shape::from((nrows, ncols));
+        let y: Vec<f32> = (0..4 * ncols).map(|i| (i as f32 * 0.3).cos()).collect();
+        let x = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&y).w()?, dev.clone());
+        let vector = crate::Layout::contiguous((1, ncols));
+        assert!(xs.fwd_vec_masked(&self_shape, &x, &vector, nrows).is_err());
+
+        // The bias is added to the recomputed exception rows, on the vector and dense paths.
+        let bias: Vec<f32> = (0..nrows).map(|i| i as f32).collect();
+        let bias = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&bias).w()?, dev.clone());
+        let bias_l = crate::Layout::contiguous(nrows);
+        for m in [1, 4] {
+            let layout = crate::Layout::contiguous((m, ncols));
+            let (out, _) = xs.fwd_with_bias(&self_shape, &x, &layout, &bias, &bias_l)?;
+            let out = to_host(&out)?;
+            for (i, y) in y.chunks(ncols).take(m).enumerate() {
+                for &row in rows.iter() {
+                    let e = dot(row, y) + row as f32;
+                    assert!(
+                        (out[i * nrows + row as usize] - e).abs() < 1e-3,
+                        "{m} {row}"
+                    );
+                }
+            }
+        }
+
+        // The q8_0 output rounds the values to a 127th of the largest one of their block.
+        let (out, _) = xs.fwd_q8_0(&self_shape, &x, &vector)?;
+        let out = out.dequantize_to_host(nrows)?;
+        for &row in rows.iter() {
+            let e = dot(row, &y[..ncols]);
+            let tol = out.iter().fold(0f32, |m, v| m.max(v.abs())) / 100.;
+            assert!((out[row as usize] - e).abs() < tol, "{row}");
+        }
+
+        // The exception rows are recomputed from the dequantized q8_0 activation.
+        let mut activation = QCudaStorage::zeros(&dev, ncols, GgmlDType::Q8_0)?;
+        let x1 = CudaStorage::wrap_cuda_slice(dev.htod_sync_copy(&y[..ncols]).w()?, dev.clone());
+        activation.quantize(&x1)?;
+        let a = activation.dequantize_to_host(ncols)?;
+        let (out, _) = xs.fwd_with_q8_0_activation(&self_shape, &activation)?;
+        let out = to_host(&out)?;
+        for &row in rows.iter() {
+            assert!((out[row as usize] - dot(row, &a)).abs() < 1e-3, "{row}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cuda_safetensors_roundtrip() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let el = 512;
+        let vs: Vec<f32> = (0..el).map(|v| v as f32 / el as f32).collect();
+        let y = dev.htod_sync_copy(&vs).w()?;
+        let mut xs = QCudaStorage::zeros(&dev, el, GgmlDType::Q4K)?;
+        xs.quantize(&CudaStorage::wrap_cuda_slice(y, dev.clone()))?;
+        let bytes = xs.to_safetensors_bytes("w")?;
+        let ys = QCudaStorage::from_safetensors_bytes(&dev, "w", &bytes)?;
+        assert_eq!(ys.dtype(), GgmlDType::Q4K);
+        assert_eq!(ys.elem_count(), el);
+        let xs = dev.dtoh_sync_copy(&*xs.data).w()?;
+        let ys = dev.dtoh_sync_copy(&*ys.data).w()?;
+        assert_eq!(xs, ys);
         Ok(())
     }
 }
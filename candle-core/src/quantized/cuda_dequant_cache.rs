@@ -0,0 +1,102 @@
+//! A disk cache of the f16 weights returned by [`QCudaStorage::dequantize_cached`], so that a
+//! process restarting with the same model copies the f16 values from a memory mapped file rather
+//! than dequantizing them again. This is off unless the `QUANT_DEQUANT_CACHE_DIR` environment
+//! variable is set to an existing directory, or a directory is set with
+//! [`DequantCache::set_cache_dir`].
+//!
+//! The entries are named after the [`QCudaStorage::checksum`] of the quantized bytes, their dtype
+//! and the number of values, e.g. `0123456789abcdef-Q4K-4096.f16`. They hold the raw f16 values in
+//! the byte order of the host and take twice the number of values in bytes. Nothing is ever
+//! evicted, the directory can be cleared at any time.
+//!
+//! [`QCudaStorage::dequantize_cached`]: super::cuda::QCudaStorage::dequantize_cached
+//! [`QCudaStorage::checksum`]: super::cuda::QCudaStorage::checksum
+use super::GgmlDType;
+use crate::cuda_backend::WrapErr;
+use crate::{CudaDevice, Result};
+use cudarc::driver::{CudaSlice, DevicePtr};
+use half::f16;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The environment variable holding the cache directory.
+pub const DEQUANT_CACHE_DIR_ENV: &str = "QUANT_DEQUANT_CACHE_DIR";
+
+// `None` until the first lookup, which reads the environment variable.
+static CACHE_DIR: std::sync::Mutex<Option<Option<PathBuf>>> = std::sync::Mutex::new(None);
+// The error of the last entry that could not be written.
+static LAST_ERROR: std::sync::Mutex<Option<crate::Error>> = std::sync::Mutex::new(None);
+
+/// The disk cache of the dequantized f16 weights, see the module documentation.
+pub struct DequantCache;
+
+impl DequantCache {
+    /// The cache directory, initialized from `QUANT_DEQUANT_CACHE_DIR`.
+    pub fn cache_dir() -> Option<PathBuf> {
+        let mut dir = CACHE_DIR.lock().unwrap();
+        dir.get_or_insert_with(|| std::env::var_os(DEQUANT_CACHE_DIR_ENV).map(PathBuf::from))
+            .clone()
+    }
+
+    /// Sets the cache directory, overriding `QUANT_DEQUANT_CACHE_DIR`. `None` disables the cache.
+    pub fn set_cache_dir(dir: Option<PathBuf>) {
+        *CACHE_DIR.lock().unwrap() = Some(dir)
+    }
+
+    /// Takes the error of the last entry that could not be written, if any. A failed write does
+    /// not change the result of [`QCudaStorage::dequantize_cached`] so these errors are only
+    /// reported here.
+    ///
+    /// [`QCudaStorage::dequantize_cached`]: super::cuda::QCudaStorage::dequantize_cached
+    pub fn take_error() -> Option<crate::Error> {
+        LAST_ERROR.lock().unwrap().take()
+    }
+
+    pub(crate) fn set_error(err: crate::Error) {
+        *LAST_ERROR.lock().unwrap() = Some(err)
+    }
+
+    /// The path of the entry for weights with the given checksum, dtype and number of values.
+    pub fn entry_path(dir: &Path, checksum: u64, dtype: GgmlDType, elem_count: usize) -> PathBuf {
+        dir.join(format!("{checksum:016x}-{dtype:?}-{elem_count}.f16"))
+    }
+
+    // Copies the entry at `path` to a new device buffer. A missing entry or one without the
+    // expected size, e.g. a partial write of an older version, is a cache miss.
+    pub(crate) fn load(
+        path: &Path,
+        dev: &CudaDevice,
+        elem_count: usize,
+    ) -> Result<Option<CudaSlice<f16>>> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if file.metadata()?.len() != (elem_count * std::mem::size_of::<f16>()) as u64 {
+            return Ok(None);
+        }
+        let mmap = unsafe { memmap2::MmapOptions::new().map(&file)? };
+        let dst = unsafe { dev.alloc::<f16>(elem_count).w()? };
+        // The allocation is ordered on the device stream, the copy below is not.
+        dev.synchronize()?;
+        unsafe { cudarc::driver::result::memcpy_htod_sync(*dst.device_ptr(), &mmap[..]) }.w()?;
+        Ok(Some(dst))
+    }
+
+    // Writes the entry at `path`. The values go to a temporary file first which is then renamed,
+    // so concurrent processes never see a partial entry.
+    pub(crate) fn store(path: &Path, dev: &CudaDevice, values: &CudaSlice<f16>) -> Result<()> {
+        let values = dev.dtoh_sync_copy(values).w()?;
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+        for v in values.iter() {
+            file.write_all(&v.to_ne_bytes())?;
+        }
+        file.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
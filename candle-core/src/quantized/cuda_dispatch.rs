@@ -0,0 +1,450 @@
+//! Launch parameters of the quantized cuda kernels: kernel names, grid sizes and dimension checks.
+//! This does not depend on cudarc so that it can be tested on machines without a gpu.
+use super::GgmlDType;
+use crate::Result;
+
+pub const CUDA_DEQUANTIZE_BLOCK_SIZE: usize = 256;
+pub const MATRIX_ROW_PADDING: usize = 512;
+pub(crate) const MAX_GRID_DIM_X: usize = (1 << 31) - 1;
+pub(crate) const MAX_GRID_DIM_Y: usize = 65535;
+
+pub(crate) fn ceil_div(p: usize, q: usize) -> usize {
+    (p + q - 1) / q
+}
+
+pub(crate) fn pad(p: usize, q: usize) -> usize {
+    ceil_div(p, q) * q
+}
+
+// The kernels take their dimensions as i32, check them rather than silently wrapping around.
+pub(crate) fn kernel_dim(v: usize, name: &str) -> Result<i32> {
+    match i32::try_from(v) {
+        Ok(v) => Ok(v),
+        Err(_) => crate::bail!("{name} {v} exceeds the i32 range supported by the cuda kernels"),
+    }
+}
+
+/// Number of elements held by `len` bytes of quantized data, which has to be made of full blocks.
+pub(crate) fn data_elem_count(len: usize, dtype: GgmlDType) -> Result<usize> {
+    if len % dtype.type_size() != 0 {
+        crate::bail!(
+            "quantized data size {len} is not a multiple of the {dtype:?} type size {}, truncated tensor?",
+            dtype.type_size()
+        )
+    }
+    Ok(len / dtype.type_size() * dtype.block_size())
+}
+
+/// Checks that `len` bytes of `dtype` weights hold at least `nrows` rows of `ncols` elements,
+/// each row being made of whole blocks with the last one possibly partially used.
+pub(crate) fn check_matmul_data(
+    len: usize,
+    dtype: GgmlDType,
+    ncols: usize,
+    nrows: usize,
+) -> Result<()> {
+    let data_elems = data_elem_count(len, dtype)?;
+    if data_elems < pad(ncols, dtype.block_size()) * nrows {
+        crate::bail!("unexpected data size {}, ncols {ncols} {nrows}", data_elems)
+    }
+    Ok(())
+}
+
+/// Padding of the activation rows quantized to q8_1 for a matmul-vec with `dtype` weights. The
+/// kernels only read the q8_1 blocks lined up with whole weight blocks so the rows are padded to
+/// the weight block size, and at least to a q8_1 block as a warp quantizes one block.
+/// [`MATRIX_ROW_PADDING`] is a bound on this for all the dtypes.
+pub(crate) fn q8_1_row_padding(dtype: GgmlDType) -> usize {
+    dtype.block_size().max(GgmlDType::Q8_1.block_size())
+}
+
+/// Size in bytes of the q8_1 buffer holding an activation of `ncols` values, with the rows padded
+/// to `row_padding` values.
+pub(crate) fn q8_1_buffer_size(ncols: usize, row_padding: usize) -> usize {
+    let dtype = GgmlDType::Q8_1;
+    pad(ncols, row_padding) * dtype.type_size() / dtype.block_size()
+}
+
+/// Launch parameters of the dequantize kernel for `elem_count` values of `dtype`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DequantizeLaunch {
+    pub kernel_name: String,
+    pub block_dim: usize,
+    pub num_blocks: usize,
+    /// The size argument of the kernel, `None` for the k-quants kernels which have none.
+    pub nb32: Option<i32>,
+}
+
+pub(crate) fn dequantize_launch(
+    dtype: GgmlDType,
+    elem_count: usize,
+    f16_output: bool,
+) -> Result<DequantizeLaunch> {
+    let nb = (elem_count + 255) / 256;
+    let kernel_name = match dtype.dequant_kernel_name() {
+        Some(kernel_name) => kernel_name,
+        None => crate::bail!("unsupported dtype for dequantize {dtype:?}"),
+    };
+    let (block_dim, num_blocks) = match dtype {
+        GgmlDType::Q5_0 | GgmlDType::Q5_1 => (
+            CUDA_DEQUANTIZE_BLOCK_SIZE,
+            ceil_div(elem_count, 2 * CUDA_DEQUANTIZE_BLOCK_SIZE),
+        ),
+        GgmlDType::BF16 => (
+            CUDA_DEQUANTIZE_BLOCK_SIZE,
+            ceil_div(elem_count, CUDA_DEQUANTIZE_BLOCK_SIZE),
+        ),
+        GgmlDType::Q2K | GgmlDType::Q3K | GgmlDType::Q5K | GgmlDType::Q6K => (64, nb),
+        _ => (32, nb),
+    };
+    let nb32 = match dtype {
+        GgmlDType::Q5_0 | GgmlDType::Q5_1 | GgmlDType::BF16 => elem_count,
+        _ => elem_count / 32,
+    };
+    // The kernels index the output with i32 values.
+    kernel_dim(elem_count, "elem_count")?;
+    let nb32 = kernel_dim(nb32, "nb32")?;
+    let suffix = if f16_output { "_f16" } else { "" };
+    Ok(DequantizeLaunch {
+        kernel_name: format!("{kernel_name}{suffix}"),
+        block_dim,
+        num_blocks,
+        nb32: if dtype.is_k_quant() { None } else { Some(nb32) },
+    })
+}
+
+/// Launch parameters of the column-major dequantize kernel, these only differ from
+/// [`dequantize_launch`] by the kernel name and its trailing `nrows` and `ncols` arguments.
+pub(crate) fn dequantize_colmajor_launch(
+    dtype: GgmlDType,
+    elem_count: usize,
+) -> Result<DequantizeLaunch> {
+    let launch = dequantize_launch(dtype, elem_count, false)?;
+    Ok(DequantizeLaunch {
+        kernel_name: format!("{}_colmajor", launch.kernel_name),
+        ..launch
+    })
+}
+
+/// Launch parameters of the dequantize kernel that also computes the output stats, it takes a
+/// trailing buffer of five floats per cuda block.
+pub(crate) fn dequantize_stats_launch(
+    dtype: GgmlDType,
+    elem_count: usize,
+) -> Result<DequantizeLaunch> {
+    let launch = dequantize_launch(dtype, elem_count, false)?;
+    Ok(DequantizeLaunch {
+        kernel_name: format!("{}_stats", launch.kernel_name),
+        ..launch
+    })
+}
+
+/// Launch parameters of the dequantize kernel computing the energy of each row instead of writing
+/// the values, it takes a buffer of `nrows` floats and a trailing `ncols` argument.
+pub(crate) fn dequantize_energy_launch(
+    dtype: GgmlDType,
+    elem_count: usize,
+) -> Result<DequantizeLaunch> {
+    let launch = dequantize_launch(dtype, elem_count, false)?;
+    Ok(DequantizeLaunch {
+        kernel_name: format!("{}_energy", launch.kernel_name),
+        ..launch
+    })
+}
+
+/// Launch parameters of the dequantize kernel clamping its output, it takes trailing `min` and
+/// `max` bounds and a zero-initialized counter of the NaN values.
+pub(crate) fn dequantize_clamped_launch(
+    dtype: GgmlDType,
+    elem_count: usize,
+) -> Result<DequantizeLaunch> {
+    let launch = dequantize_launch(dtype, elem_count, false)?;
+    Ok(DequantizeLaunch {
+        kernel_name: format!("{}_clamped", launch.kernel_name),
+        ..launch
+    })
+}
+
+/// Launch parameters of the dequantize kernel flushing the subnormal f32 values it writes to zero.
+pub(crate) fn dequantize_ftz_launch(
+    dtype: GgmlDType,
+    elem_count: usize,
+) -> Result<DequantizeLaunch> {
+    let launch = dequantize_launch(dtype, elem_count, false)?;
+    Ok(DequantizeLaunch {
+        kernel_name: format!("{}_ftz", launch.kernel_name),
+        ..launch
+    })
+}
+
+/// The kernel extracting the block scales of `dtype` and the number of scales per block, the
+/// k-quants having a scale per sub-block.
+pub(crate) fn dequantize_scales_kernel(dtype: GgmlDType) -> Result<(&'static str, usize)> {
+    let kernel = match dtype {
+        GgmlDType::Q4_0 => ("dequantize_scales_q4_0", 1),
+        GgmlDType::Q4_1 => ("dequantize_scales_q4_1", 1),
+        GgmlDType::Q5_0 => ("dequantize_scales_q5_0", 1),
+        GgmlDType::Q5_1 => ("dequantize_scales_q5_1", 1),
+        GgmlDType::Q8_0 => ("dequantize_scales_q8_0", 1),
+        GgmlDType::Q8_1 => ("dequantize_scales_q8_1", 1),
+        GgmlDType::Q2K => ("dequantize_scales_q2_K", 16),
+        GgmlDType::Q3K => ("dequantize_scales_q3_K", 16),
+        GgmlDType::Q4K => ("dequantize_scales_q4_K", 8),
+        GgmlDType::Q5K => ("dequantize_scales_q5_K", 8),
+        GgmlDType::Q6K => ("dequantize_scales_q6_K", 16),
+        GgmlDType::Q8K => ("dequantize_scales_q8_K", 1),
+        GgmlDType::F32 | GgmlDType::F16 | GgmlDType::BF16 => {
+            crate::bail!("{dtype:?} has no block scales")
+        }
+    };
+    Ok(kernel)
+}
+
+pub(crate) fn dmmv_kernel_name(dtype: GgmlDType) -> Result<&'static str> {
+    match dtype.dmmv_kernel_name() {
+        Some(kernel_name) => Ok(kernel_name),
+        None => crate::bail!("unsupported dtype for quantized matmul {dtype:?}"),
+    }
+}
+
+/// Number of rows handled by each block of the dmmv kernel of `dtype` and the resulting number
+/// of blocks, `mmv_y` is the configured value.
+pub(crate) fn dmmv_grid(dtype: GgmlDType, nrows: usize, mmv_y: usize) -> (usize, usize) {
+    // The q5_k kernel processes a single row per block.
+    let mmv_y = match dtype {
+        GgmlDType::Q5K => 1,
+        _ => mmv_y,
+    };
+    (mmv_y, ceil_div(nrows, mmv_y))
+}
+
+pub(crate) fn mmvq_kernel_name(dtype: GgmlDType) -> Result<&'static str> {
+    match dtype.q81_kernel_name() {
+        Some(kernel_name) => Ok(kernel_name),
+        None => crate::bail!("unsupported dtype for quantized matmul {dtype:?}"),
+    }
+}
+
+/// Largest number of activations handled by a single launch of the batched q8_1 matmul-vec
+/// kernels.
+pub(crate) const MMVQ_MAX_NCOLS_Y: usize = 8;
+
+/// The q8_1 matmul-vec kernel of `dtype` multiplying the weights with `ncols_y` activations and
+/// the number of weight rows handled by each of its blocks.
+pub(crate) fn mmvq_batched_kernel(dtype: GgmlDType, ncols_y: usize) -> Result<(String, usize)> {
+    let kernel_name = mmvq_kernel_name(dtype)?;
+    match ncols_y {
+        1 => Ok((kernel_name.to_string(), 1)),
+        2..=MMVQ_MAX_NCOLS_Y => Ok((format!("{kernel_name}{ncols_y}"), 2)),
+        _ => crate::bail!("unsupported number of activations {ncols_y} for the batched matmul-vec"),
+    }
+}
+
+/// The q8_1 matmul-vec kernel of `dtype` for the mixture of experts weights, it handles a single
+/// weight row per block and an output row per block row.
+pub(crate) fn mmvq_moe_kernel(dtype: GgmlDType) -> Result<String> {
+    Ok(format!("{}_moe", mmvq_kernel_name(dtype)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KERNELS: &str = include_str!("../../../candle-kernels/src/quantized.cu");
+
+    const MATMUL_DTYPES: [GgmlDType; 10] = [
+        GgmlDType::Q4_0,
+        GgmlDType::Q4_1,
+        GgmlDType::Q5_0,
+        GgmlDType::Q5_1,
+        GgmlDType::Q8_0,
+        GgmlDType::Q2K,
+        GgmlDType::Q3K,
+        GgmlDType::Q4K,
+        GgmlDType::Q5K,
+        GgmlDType::Q6K,
+    ];
+
+    // The kernel families instantiated for each row of the MMVQ_TYPES table, the batched names
+    // end with the number of activations.
+    const KERNEL_MACROS: [(&str, &str, &str); 2] = [
+        ("MUL_MAT_VEC_Q_MOE", "mul_mat_vec_", "_q8_1_cuda_moe"),
+        ("MUL_MAT_VEC_Q_BATCHED", "mul_mat_vec_", "_q8_1_cuda"),
+    ];
+
+    fn assert_kernel_exists(name: &str) {
+        let decl = format!("extern \"C\" __global__ void {name}(");
+        let generated = KERNEL_MACROS.iter().any(|(macro_name, prefix, suffix)| {
+            let name = match name.strip_suffix(|c: char| c.is_ascii_digit()) {
+                Some(n) if *macro_name == "MUL_MAT_VEC_Q_BATCHED" => n,
+                _ => name,
+            };
+            match name
+                .strip_prefix(prefix)
+                .and_then(|n| n.strip_suffix(suffix))
+            {
+                Some(dtype) => {
+                    KERNELS.contains(&format!("\nMMVQ_TYPES({macro_name})"))
+                        && KERNELS.contains(&format!("\n    X({dtype},"))
+                }
+                None => false,
+            }
+        });
+        assert!(
+            KERNELS.contains(&decl) || generated,
+            "missing kernel {name}"
+        )
+    }
+
+    #[test]
+    fn dequantize_launch_covers_all_elements() -> Result<()> {
+        let dtypes = [GgmlDType::Q8_1, GgmlDType::Q8K, GgmlDType::BF16];
+        for dtype in MATMUL_DTYPES.iter().chain(dtypes.iter()) {
+            for elem_count in [256, 512, 768, 4096, 256 * 1001] {
+                let launch = dequantize_launch(*dtype, elem_count, false)?;
+                // Number of values written by each cuda block.
+                let per_block = match dtype {
+                    GgmlDType::Q5_0 | GgmlDType::Q5_1 => 2 * launch.block_dim,
+                    GgmlDType::BF16 => launch.block_dim,
+                    _ => 256,
+                };
+                assert!(launch.num_blocks * per_block >= elem_count, "{dtype:?}");
+                assert!(
+                    (launch.num_blocks - 1) * per_block < elem_count,
+                    "{dtype:?}"
+                );
+                assert_eq!(launch.nb32.is_none(), dtype.is_k_quant(), "{dtype:?}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn kernel_names_exist() -> Result<()> {
+        let dtypes = [GgmlDType::Q8_1, GgmlDType::Q8K, GgmlDType::BF16];
+        for dtype in MATMUL_DTYPES.iter().chain(dtypes.iter()) {
+            for f16_output in [false, true] {
+                assert_kernel_exists(&dequantize_launch(*dtype, 256, f16_output)?.kernel_name)
+            }
+            assert_kernel_exists(&dequantize_colmajor_launch(*dtype, 256)?.kernel_name);
+            assert_kernel_exists(&dequantize_stats_launch(*dtype, 256)?.kernel_name);
+            assert_kernel_exists(&dequantize_energy_launch(*dtype, 256)?.kernel_name);
+            assert_kernel_exists(&dequantize_clamped_launch(*dtype, 256)?.kernel_name);
+            assert_kernel_exists(&dequantize_ftz_launch(*dtype, 256)?.kernel_name);
+        }
+        for dtype in MATMUL_DTYPES
+            .iter()
+            .chain([GgmlDType::Q8_1, GgmlDType::Q8K].iter())
+        {
+            let (kernel_name, scales_per_block) = dequantize_scales_kernel(*dtype)?;
+            assert_kernel_exists(kernel_name);
+            // Sub-blocks of 16 or 32 values for the k-quants.
+            assert!([1, 8, 16].contains(&scales_per_block), "{dtype:?}");
+        }
+        assert!(dequantize_scales_kernel(GgmlDType::F16).is_err());
+        for dtype in MATMUL_DTYPES {
+            assert_kernel_exists(dmmv_kernel_name(dtype)?);
+            assert_kernel_exists(mmvq_kernel_name(dtype)?);
+            for ncols_y in 1..=MMVQ_MAX_NCOLS_Y {
+                assert_kernel_exists(&mmvq_batched_kernel(dtype, ncols_y)?.0);
+            }
+            assert_kernel_exists(&mmvq_moe_kernel(dtype)?);
+        }
+        assert!(dequantize_launch(GgmlDType::F32, 256, false).is_err());
+        assert!(dmmv_kernel_name(GgmlDType::Q8K).is_err());
+        assert!(mmvq_kernel_name(GgmlDType::F16).is_err());
+        assert!(mmvq_moe_kernel(GgmlDType::F16).is_err());
+        assert!(mmvq_batched_kernel(GgmlDType::Q4K, 0).is_err());
+        assert!(mmvq_batched_kernel(GgmlDType::Q4K, MMVQ_MAX_NCOLS_Y + 1).is_err());
+        assert_eq!(
+            mmvq_batched_kernel(GgmlDType::Q4K, 1)?,
+            ("mul_mat_vec_q4_K_q8_1_cuda".to_string(), 1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dtype_kernel_names() -> Result<()> {
+        use GgmlDType::*;
+
+        let all = [
+            F32, F16, BF16, Q4_0, Q4_1, Q5_0, Q5_1, Q8_0, Q8_1, Q2K, Q3K, Q4K, Q5K, Q6K, Q8K,
+        ];
+        for dtype in all {
+            if let Some(kernel_name) = dtype.dequant_kernel_name() {
+                assert_kernel_exists(kernel_name);
+                assert_kernel_exists(&format!("{kernel_name}_f16"));
+            }
+            assert_eq!(
+                dequantize_launch(dtype, 256, false).is_ok(),
+                dtype.dequant_kernel_name().is_some(),
+                "{dtype:?}"
+            );
+            if let Some(kernel_name) = dtype.dmmv_kernel_name() {
+                assert_kernel_exists(kernel_name);
+            }
+            if let Some(kernel_name) = dtype.q81_kernel_name() {
+                assert_kernel_exists(kernel_name);
+            }
+            // The matmul-vec kernels come in pairs.
+            assert_eq!(
+                dtype.dmmv_kernel_name().is_some(),
+                dtype.q81_kernel_name().is_some(),
+                "{dtype:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn dmmv_grid_covers_all_rows() {
+        for dtype in MATMUL_DTYPES {
+            for (nrows, mmv_y) in [(1, 1), (7, 2), (4096, 4), (11008, 8)] {
+                let (rows_per_block, num_blocks) = dmmv_grid(dtype, nrows, mmv_y);
+                assert!(rows_per_block * num_blocks >= nrows, "{dtype:?}");
+                assert!(rows_per_block * (num_blocks - 1) < nrows, "{dtype:?}");
+            }
+        }
+        assert_eq!(dmmv_grid(GgmlDType::Q5K, 10, 4), (1, 10));
+    }
+
+    #[test]
+    fn q8_1_block_layout() {
+        // The f16 scale and sum of `block_q8_1` in quantized.cu, then the quants.
+        let dtype = GgmlDType::Q8_1;
+        assert_eq!(dtype.type_size(), 2 * 2 + dtype.block_size());
+        assert_eq!(q8_1_buffer_size(32, 32), 36);
+    }
+
+    #[test]
+    fn matmul_size_checks() -> Result<()> {
+        assert_eq!(data_elem_count(18 * 4, GgmlDType::Q4_0)?, 128);
+        let err = data_elem_count(18 * 4 + 1, GgmlDType::Q4_0).unwrap_err();
+        assert!(err.to_string().contains("truncated tensor"), "{err}");
+        check_matmul_data(18 * 4, GgmlDType::Q4_0, 64, 2)?;
+        assert!(check_matmul_data(18 * 4, GgmlDType::Q4_0, 64, 3).is_err());
+        // Rows of 40 values take two blocks.
+        check_matmul_data(18 * 4, GgmlDType::Q4_0, 40, 2)?;
+        assert!(check_matmul_data(18 * 3, GgmlDType::Q4_0, 40, 2).is_err());
+        // Rows padded to 512 values, i.e. 16 q8_1 blocks of 36 bytes.
+        assert_eq!(q8_1_buffer_size(1, MATRIX_ROW_PADDING), 16 * 36);
+        assert_eq!(q8_1_buffer_size(512, MATRIX_ROW_PADDING), 16 * 36);
+        assert_eq!(q8_1_buffer_size(513, MATRIX_ROW_PADDING), 32 * 36);
+        assert_eq!(q8_1_row_padding(GgmlDType::Q4_0), 32);
+        assert_eq!(q8_1_row_padding(GgmlDType::Q4K), 256);
+        for dtype in MATMUL_DTYPES {
+            let row_padding = q8_1_row_padding(dtype);
+            assert!(row_padding <= MATRIX_ROW_PADDING, "{dtype:?}");
+            // The kernels read ncols / QK8_1 blocks for a row made of whole weight blocks.
+            let ncols = 3 * dtype.block_size();
+            assert_eq!(q8_1_buffer_size(ncols, row_padding), ncols / 32 * 36);
+        }
+        assert_eq!(kernel_dim(i32::MAX as usize, "ncols")?, i32::MAX);
+        let err = kernel_dim(i32::MAX as usize + 1, "ncols").unwrap_err();
+        assert!(
+            err.to_string().contains("ncols 2147483648 exceeds"),
+            "{err}"
+        );
+        Ok(())
+    }
+}
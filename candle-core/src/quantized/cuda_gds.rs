@@ -0,0 +1,219 @@
+//! Loading of quantized tensors with GPU Direct Storage (GDS), the file data is transferred by
+//! DMA straight into device memory rather than going through a host buffer. This module is only
+//! available with the `gds` feature.
+//!
+//! Requirements:
+//! - Linux with the `nvidia-fs` kernel module loaded, see `/usr/local/cuda/gds/tools/gdscheck -p`.
+//! - A data center or workstation gpu supported by GDS and a driver version matching the CUDA
+//!   toolkit, `libcufile.so.0` is loaded at runtime and does not have to be present at build time.
+//! - The model files on a local NVMe drive or a supported distributed filesystem, with `O_DIRECT`
+//!   support.
+//!
+//! When one of these is missing, the loaders fall back to reading the file on the host and
+//! copying the data to the device like [`super::cuda::load_quantized_bytes`].
+use super::cuda::{QCudaStorage, ShardLocation};
+use super::GgmlDType;
+use crate::cuda_backend::WrapErr;
+use crate::{CudaDevice, Result};
+use cudarc::driver::DevicePtr;
+use std::ffi::c_void;
+
+// Minimal bindings of the cuFile api, see `cufile.h`.
+mod sys {
+    use std::ffi::{c_int, c_void};
+
+    pub const CU_FILE_SUCCESS: c_int = 0;
+    pub const CU_FILE_HANDLE_TYPE_OPAQUE_FD: c_int = 1;
+
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy)]
+    pub struct CUfileError {
+        pub err: c_int,
+        pub cu_err: c_int,
+    }
+
+    #[repr(C)]
+    pub union CUfileHandleUnion {
+        pub fd: c_int,
+        pub handle: *mut c_void,
+    }
+
+    #[repr(C)]
+    pub struct CUfileDescr {
+        pub type_: c_int,
+        pub handle: CUfileHandleUnion,
+        pub fs_ops: *const c_void,
+    }
+
+    pub type CUfileHandle = *mut c_void;
+
+    // The cuFile functions, resolved with `dlopen` so that a missing library only disables GDS.
+    pub struct CuFile {
+        pub driver_open: unsafe extern "C" fn() -> CUfileError,
+        pub handle_register:
+            unsafe extern "C" fn(fh: *mut CUfileHandle, descr: *mut CUfileDescr) -> CUfileError,
+        pub handle_deregister: unsafe extern "C" fn(fh: CUfileHandle),
+        pub read: unsafe extern "C" fn(
+            fh: CUfileHandle,
+            dev_ptr_base: *mut c_void,
+            size: usize,
+            file_offset: i64,
+            dev_ptr_offset: i64,
+        ) -> isize,
+    }
+
+    // `F` has to be the function pointer type of the symbol `name`, nul terminated.
+    unsafe fn symbol<F>(lib: *mut c_void, name: &[u8]) -> Option<F> {
+        let ptr = libc::dlsym(lib, name.as_ptr() as *const std::ffi::c_char);
+        (!ptr.is_null()).then(|| std::mem::transmute_copy(&ptr))
+    }
+
+    impl CuFile {
+        unsafe fn load() -> Option<Self> {
+            let lib = [&b"libcufile.so.0\0"[..], &b"libcufile.so\0"[..]]
+                .into_iter()
+                .map(|name| {
+                    libc::dlopen(
+                        name.as_ptr() as *const std::ffi::c_char,
+                        libc::RTLD_NOW | libc::RTLD_LOCAL,
+                    )
+                })
+                .find(|lib| !lib.is_null())?;
+            // The library is never closed, the functions are used for the life of the process.
+            Some(Self {
+                driver_open: symbol(lib, b"cuFileDriverOpen\0")?,
+                handle_register: symbol(lib, b"cuFileHandleRegister\0")?,
+                handle_deregister: symbol(lib, b"cuFileHandleDeregister\0")?,
+                read: symbol(lib, b"cuFileRead\0")?,
+            })
+        }
+    }
+
+    /// The cuFile library, `None` when it cannot be loaded.
+    pub fn cufile() -> Option<&'static CuFile> {
+        static CUFILE: std::sync::OnceLock<Option<CuFile>> = std::sync::OnceLock::new();
+        CUFILE.get_or_init(|| unsafe { CuFile::load() }).as_ref()
+    }
+}
+
+/// Whether the cuFile library could be loaded and its driver opened, this is only checked once
+/// per process.
+pub fn gds_available() -> bool {
+    driver().is_some()
+}
+
+// The cuFile library when its driver could be opened.
+fn driver() -> Option<&'static sys::CuFile> {
+    static DRIVER: std::sync::OnceLock<Option<&'static sys::CuFile>> = std::sync::OnceLock::new();
+    *DRIVER.get_or_init(|| {
+        let cufile = sys::cufile()?;
+        let status = unsafe { (cufile.driver_open)() };
+        (status.err == sys::CU_FILE_SUCCESS).then_some(cufile)
+    })
+}
+
+// A file registered with cuFile, deregistered on drop. The file has to outlive the handle.
+struct FileHandle {
+    cufile: &'static sys::CuFile,
+    handle: sys::CUfileHandle,
+    _file: std::fs::File,
+}
+
+impl FileHandle {
+    // Returns `None` when the file cannot be used with GDS, e.g. as its filesystem does not
+    // support `O_DIRECT`.
+    fn open(cufile: &'static sys::CuFile, path: &std::path::Path) -> Option<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::os::unix::io::AsRawFd;
+
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)
+            .ok()?;
+        let mut descr = sys::CUfileDescr {
+            type_: sys::CU_FILE_HANDLE_TYPE_OPAQUE_FD,
+            handle: sys::CUfileHandleUnion {
+                fd: file.as_raw_fd(),
+            },
+            fs_ops: std::ptr::null(),
+        };
+        let mut handle: sys::CUfileHandle = std::ptr::null_mut();
+        let status = unsafe { (cufile.handle_register)(&mut handle, &mut descr) };
+        if status.err != sys::CU_FILE_SUCCESS {
+            return None;
+        }
+        Some(Self {
+            cufile,
+            handle,
+            _file: file,
+        })
+    }
+}
+
+impl Drop for FileHandle {
+    fn drop(&mut self) {
+        unsafe { (self.cufile.handle_deregister)(self.handle) }
+    }
+}
+
+/// Loads the quantized data at `location` directly into device memory using GDS, falling back to
+/// a read on the host followed by a copy when GDS is not available for this file.
+pub fn load_quantized_direct(
+    device: &CudaDevice,
+    dtype: GgmlDType,
+    location: &ShardLocation,
+) -> Result<QCudaStorage> {
+    let handle = driver().and_then(|cufile| FileHandle::open(cufile, &location.path));
+    let handle = match handle {
+        Some(handle) => handle,
+        None => return load_quantized_on_host(device, dtype, location),
+    };
+    device.bind_to_thread().w()?;
+    let data = unsafe { device.alloc::<u8>(location.len).w()? };
+    // The allocation is stream ordered whereas cuFile writes to the buffer right away.
+    device.synchronize()?;
+    let ptr = *data.device_ptr() as *mut c_void;
+    let mut read = 0;
+    while read < location.len {
+        let file_offset = location.offset + read as u64;
+        let n = unsafe {
+            (handle.cufile.read)(
+                handle.handle,
+                ptr,
+                location.len - read,
+                file_offset as i64,
+                read as i64,
+            )
+        };
+        if n < 0 {
+            crate::bail!(
+                "cuFileRead failed on {:?} at offset {file_offset}, error {n}",
+                location.path
+            )
+        }
+        if n == 0 {
+            crate::bail!(
+                "unexpected end of file {:?} at offset {file_offset}, {} bytes missing",
+                location.path,
+                location.len - read
+            )
+        }
+        read += n as usize;
+    }
+    QCudaStorage::from_device_data(device, dtype, data)
+}
+
+fn load_quantized_on_host(
+    device: &CudaDevice,
+    dtype: GgmlDType,
+    location: &ShardLocation,
+) -> Result<QCudaStorage> {
+    use std::io::{Read, Seek};
+
+    let mut file = std::fs::File::open(&location.path)?;
+    let mut data = vec![0u8; location.len];
+    file.seek(std::io::SeekFrom::Start(location.offset))?;
+    file.read_exact(&mut data)?;
+    super::cuda::load_quantized_bytes(device, dtype, &data)
+}
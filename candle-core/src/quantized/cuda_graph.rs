@@ -0,0 +1,207 @@
+//! Capture of a sequence of quantized cuda kernels, typically the forward pass of a decode step,
+//! into a CUDA graph that is then replayed for each token without the per kernel launch overhead.
+//!
+//! ```ignore
+//! let (graph, (logits, _)) = QuantCudaGraph::capture(&dev, || qstorage.fwd(&shape, &x, &x_l))?;
+//! for token in tokens {
+//!     // Write the new activation into the buffer of `x`, then replay.
+//!     graph.replay()?;
+//!     // `logits` holds the output of this step.
+//! }
+//! ```
+//!
+//! The default stream of a cuda device is the legacy NULL stream which cannot be captured. The
+//! kernels are captured on a stream forked from it and the graph is replayed on that stream,
+//! ordered after the work already queued on the device stream and before the work queued after
+//! the replay.
+//!
+//! A graph replays the exact kernels and buffer addresses seen at capture time so the captured
+//! sequence has to satisfy the following constraints:
+//! - Only the kernels of the quantized cuda storages are captured. Other operations, e.g. the
+//!   cublas gemms of the non-vector matmuls, would run once during the capture and are not part of
+//!   the graph, the captured sequence should only use the matmul-vec kernels.
+//! - The inputs are read from the buffers used during the capture, new values have to be copied
+//!   into these buffers before each replay. The buffers must outlive the graph.
+//! - The outputs are written to the buffers returned by the capture closure, they are only valid
+//!   until the next replay and must be kept alive while the graph is in use. They are allocated
+//!   once during the capture.
+//! - The scratch buffers of the matmul-vec kernels, e.g. the quantized activations, are kept alive
+//!   by the graph.
+//! - Nothing may synchronize with the host: dtypes without a fast dequantize kernel, the
+//!   `q8_1_overflow_threshold` check and the tracer all do. The capture fails in this case.
+//! - The launch parameters are frozen, e.g. the kernel selected from the configuration, the
+//!   activation shapes and the matmul-vec row counts. A new graph has to be captured when any of
+//!   these change.
+use crate::cuda_backend::{DeviceId, WrapErr};
+use crate::{CudaDevice, Result};
+use cudarc::driver::{sys, CudaSlice, CudaStream};
+use std::rc::Rc;
+use std::sync::Arc;
+
+struct CaptureState {
+    device_id: DeviceId,
+    stream: Rc<CudaStream>,
+    scratch: Vec<Arc<CudaSlice<u8>>>,
+}
+
+thread_local! {
+    // The capture in progress on this thread, if any.
+    static CAPTURE: std::cell::RefCell<Option<CaptureState>> = const { std::cell::RefCell::new(None) };
+}
+
+// Clears the capture state of the thread, also when the capture closure panics.
+struct CaptureGuard;
+
+impl Drop for CaptureGuard {
+    fn drop(&mut self) {
+        CAPTURE.with(|c| c.borrow_mut().take());
+    }
+}
+
+// The stream on which the kernels for `dev` have to be launched while a graph is being captured
+// on this thread.
+pub(crate) fn capture_stream(dev: &CudaDevice) -> Option<Rc<CudaStream>> {
+    CAPTURE.with(|c| match &*c.borrow() {
+        Some(state) if state.device_id == dev.id() => Some(state.stream.clone()),
+        _ => None,
+    })
+}
+
+// Releases a scratch buffer used by the kernels launched for `dev`. It is dropped right away
+// unless a graph is being captured, in which case the graph keeps it alive for its replays.
+pub(crate) fn release_scratch(dev: &CudaDevice, buffer: impl Into<Arc<CudaSlice<u8>>>) {
+    CAPTURE.with(|c| {
+        if let Some(state) = c.borrow_mut().as_mut() {
+            if state.device_id == dev.id() {
+                state.scratch.push(buffer.into())
+            }
+        }
+    })
+}
+
+// Fails when a graph is being captured for `dev`, `what` synchronizing with the host.
+pub(crate) fn check_not_capturing(dev: &CudaDevice, what: &str) -> Result<()> {
+    if capture_stream(dev).is_some() {
+        crate::bail!("{what} synchronizes with the host and cannot be captured in a cuda graph")
+    }
+    Ok(())
+}
+
+/// An instantiated CUDA graph, see the module documentation for the capture constraints.
+pub struct QuantCudaGraph {
+    device: CudaDevice,
+    stream: CudaStream,
+    graph: sys::CUgraph,
+    exec: sys::CUgraphExec,
+    _scratch: Vec<Arc<CudaSlice<u8>>>,
+}
+
+// The graph handles are only used through the driver api which is thread safe.
+unsafe impl Send for QuantCudaGraph {}
+
+impl QuantCudaGraph {
+    /// Captures the kernels launched by `f` for `dev` and instantiates them as a graph. The
+    /// kernels are recorded but not run, the outputs returned by `f` only get written by
+    /// [`QuantCudaGraph::replay`].
+    pub fn capture<T, F: FnOnce() -> Result<T>>(dev: &CudaDevice, f: F) -> Result<(Self, T)> {
+        #[cfg(feature = "quant-trace")]
+        if super::cuda_trace::QuantTracer::is_enabled() {
+            crate::bail!("cuda graph capture is not supported while the quant tracer is enabled")
+        }
+        if CAPTURE.with(|c| c.borrow().is_some()) {
+            crate::bail!("a cuda graph is already being captured on this thread")
+        }
+        dev.bind_to_thread().w()?;
+        let stream = dev.fork_default_stream().w()?;
+        // The relaxed mode lets the kernels be loaded lazily and the outputs be allocated on the
+        // device stream during the capture.
+        unsafe {
+            sys::cuStreamBeginCapture_v2(
+                stream.stream,
+                sys::CUstreamCaptureMode::CU_STREAM_CAPTURE_MODE_RELAXED,
+            )
+        }
+        .result()
+        .w()?;
+        let stream = Rc::new(stream);
+        let guard = CaptureGuard;
+        CAPTURE.with(|c| {
+            *c.borrow_mut() = Some(CaptureState {
+                device_id: dev.id(),
+                stream: stream.clone(),
+                scratch: vec![],
+            })
+        });
+        let out = f();
+        let scratch = CAPTURE.with(|c| c.borrow_mut().take().map(|state| state.scratch));
+        drop(guard);
+        let mut graph = std::ptr::null_mut();
+        let end = unsafe { sys::cuStreamEndCapture(stream.stream, &mut graph) }.result();
+        let out = match (out, end) {
+            (Ok(out), Ok(())) => out,
+            (out, end) => {
+                if !graph.is_null() {
+                    let _ = unsafe { sys::cuGraphDestroy(graph) }.result();
+                }
+                // The error of `f` is the most specific one, the capture itself mostly fails
+                // because of a host synchronization.
+                out?;
+                crate::bail!(
+                    "cuda graph capture failed, did the captured sequence synchronize with the host? {:?}",
+                    end.err()
+                )
+            }
+        };
+        let stream = match Rc::try_unwrap(stream) {
+            Ok(stream) => stream,
+            Err(_) => {
+                let _ = unsafe { sys::cuGraphDestroy(graph) }.result();
+                crate::bail!("the cuda graph capture stream is still in use")
+            }
+        };
+        let mut exec = std::ptr::null_mut();
+        let instantiated =
+            unsafe { sys::cuGraphInstantiateWithFlags(&mut exec, graph, 0) }.result();
+        if let Err(err) = instantiated {
+            let _ = unsafe { sys::cuGraphDestroy(graph) }.result();
+            return Err(err).w();
+        }
+        let graph = Self {
+            device: dev.clone(),
+            stream,
+            graph,
+            exec,
+            _scratch: scratch.unwrap_or_default(),
+        };
+        Ok((graph, out))
+    }
+
+    /// Runs the captured kernels on the capture stream, after the work already queued on the
+    /// device stream, e.g. the copies of the new inputs. The work queued on the device stream
+    /// afterwards waits for the replay, this does not block the host.
+    pub fn replay(&self) -> Result<()> {
+        self.device.bind_to_thread().w()?;
+        self.stream.wait_for_default().w()?;
+        unsafe { sys::cuGraphLaunch(self.exec, self.stream.stream) }
+            .result()
+            .w()?;
+        self.device.wait_for(&self.stream).w()
+    }
+
+    /// Number of nodes of the captured graph, i.e. of kernel launches and copies.
+    pub fn num_nodes(&self) -> Result<usize> {
+        let mut num_nodes = 0;
+        unsafe { sys::cuGraphGetNodes(self.graph, std::ptr::null_mut(), &mut num_nodes) }
+            .result()
+            .w()?;
+        Ok(num_nodes)
+    }
+}
+
+impl Drop for QuantCudaGraph {
+    fn drop(&mut self) {
+        let _ = self.device.bind_to_thread();
+        let _ = unsafe { sys::cuGraphExecDestroy(self.exec) }.result();
+        let _ = unsafe { sys::cuGraphDestroy(self.graph) }.result();
+    }
+}
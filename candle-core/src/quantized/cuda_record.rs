@@ -0,0 +1,269 @@
+//! Recording of the inputs of [`QCudaStorage::fwd`] so that wrong outputs reported against the
+//! quantized kernels can be reproduced without the rest of the model. This is off unless the
+//! `QUANT_RECORD_DIR` environment variable is set to an existing directory, or a directory is
+//! set with [`QuantRecorder::set_record_dir`].
+//!
+//! A record is written for the first `fwd` call of the process, for the next call on a thread
+//! after [`QuantRecorder::arm`], e.g. once a bad output has been detected, and for every call
+//! that fails. Each record is a safetensors file `fwd-{pid}-{index}.safetensors` holding the raw
+//! quantized weights and the whole f32 buffer of the activation, with the weight dtype and shape,
+//! the activation layout, the configuration of the device and the error if any as metadata.
+//!
+//! ```ignore
+//! let record = QuantRecord::load(&dev, "records/fwd-1234-0.safetensors")?;
+//! let (out, shape) = record.replay()?;
+//! ```
+//! Writing a record copies the inputs to the host and synchronizes the device, this must not
+//! happen while capturing a cuda graph.
+use super::cuda::{QCudaStorage, QuantCudaConfig};
+use super::cuda::{SAFETENSORS_ELEM_COUNT_SUFFIX, SAFETENSORS_GGML_DTYPE_SUFFIX};
+use crate::backend::BackendStorage;
+use crate::cuda_backend::WrapErr;
+use crate::{CudaDevice, CudaStorage, Layout, Result, Shape};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+/// The environment variable holding the record directory.
+pub const RECORD_DIR_ENV: &str = "QUANT_RECORD_DIR";
+
+const WEIGHT: &str = "weight";
+const ACTIVATION: &str = "activation";
+
+// `None` until the first lookup, which reads the environment variable.
+static RECORD_DIR: std::sync::Mutex<Option<Option<PathBuf>>> = std::sync::Mutex::new(None);
+// Mirrors `RECORD_DIR` so that `fwd` does not take the lock while recording is off.
+static RECORD_STATE: AtomicU8 = AtomicU8::new(STATE_UNKNOWN);
+const STATE_UNKNOWN: u8 = 0;
+const STATE_OFF: u8 = 1;
+const STATE_ON: u8 = 2;
+// The error of the last record that could not be written.
+static LAST_ERROR: std::sync::Mutex<Option<crate::Error>> = std::sync::Mutex::new(None);
+// Whether the first call made while a directory is set has been recorded.
+static FIRST_RECORDED: AtomicBool = AtomicBool::new(false);
+// Index of the next record, part of the file name.
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static ARMED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Writes the inputs of the quantized matmuls to disk, see the module documentation.
+pub struct QuantRecorder;
+
+impl QuantRecorder {
+    /// The record directory, initialized from `QUANT_RECORD_DIR`.
+    pub fn record_dir() -> Option<PathBuf> {
+        let mut dir = RECORD_DIR.lock().unwrap();
+        let dir = dir
+            .get_or_insert_with(|| std::env::var_os(RECORD_DIR_ENV).map(PathBuf::from))
+            .clone();
+        RECORD_STATE.store(record_state(&dir), Ordering::SeqCst);
+        dir
+    }
+
+    /// Sets the record directory, overriding `QUANT_RECORD_DIR`. `None` disables recording.
+    pub fn set_record_dir(dir: Option<PathBuf>) {
+        let mut record_dir = RECORD_DIR.lock().unwrap();
+        RECORD_STATE.store(record_state(&dir), Ordering::SeqCst);
+        *record_dir = Some(dir)
+    }
+
+    /// Takes the error of the last record that could not be written, if any. Recording does not
+    /// change the result of `fwd` so these errors are only reported here.
+    pub fn take_error() -> Option<crate::Error> {
+        LAST_ERROR.lock().unwrap().take()
+    }
+
+    /// Records the next `fwd` call made on the current thread. This has no effect while no record
+    /// directory is set.
+    pub fn arm() {
+        ARMED.with(|armed| armed.set(true))
+    }
+
+    // Called by `fwd` with its inputs and result. Recording is best effort, the error of a record
+    // that cannot be written is kept for `take_error` and does not change the result of `fwd`.
+    pub(crate) fn on_fwd(
+        storage: &QCudaStorage,
+        self_shape: &Shape,
+        rhs: &CudaStorage,
+        rhs_l: &Layout,
+        result: &Result<(CudaStorage, Shape)>,
+    ) {
+        if RECORD_STATE.load(Ordering::SeqCst) == STATE_OFF {
+            return;
+        }
+        let dir = match Self::record_dir() {
+            Some(dir) => dir,
+            None => return,
+        };
+        let armed = ARMED.with(|armed| armed.replace(false));
+        let first = !FIRST_RECORDED.swap(true, Ordering::SeqCst);
+        if !(first || armed || result.is_err()) {
+            return;
+        }
+        let index = NEXT_INDEX.fetch_add(1, Ordering::SeqCst);
+        let path = dir.join(format!("fwd-{}-{index}.safetensors", std::process::id()));
+        let error = result.as_ref().err().map(|err| err.to_string());
+        if let Err(err) = write_record(&path, storage, self_shape, rhs, rhs_l, error) {
+            *LAST_ERROR.lock().unwrap() = Some(err.with_path(&path))
+        }
+    }
+}
+
+fn record_state(dir: &Option<PathBuf>) -> u8 {
+    match dir {
+        Some(_) => STATE_ON,
+        None => STATE_OFF,
+    }
+}
+
+fn join_dims(dims: &[usize]) -> String {
+    dims.iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn parse_dims(key: &str, value: &str) -> Result<Vec<usize>> {
+    if value.is_empty() {
+        return Ok(vec![]);
+    }
+    value
+        .split(',')
+        .map(|d| {
+            d.parse::<usize>().map_err(|_| {
+                crate::Error::Msg(format!("invalid record metadata {key}: {value}")).bt()
+            })
+        })
+        .collect()
+}
+
+fn write_record(
+    path: &Path,
+    storage: &QCudaStorage,
+    self_shape: &Shape,
+    rhs: &CudaStorage,
+    rhs_l: &Layout,
+    error: Option<String>,
+) -> Result<()> {
+    use safetensors::tensor::TensorView;
+
+    let dev = storage.device();
+    let weight = storage.data_to_host()?;
+    let activation = match rhs.as_cuda_slice::<f32>() {
+        Ok(slice) => dev.dtoh_sync_copy(slice).w()?,
+        Err(_) => crate::bail!("unsupported activation dtype {:?}", rhs.dtype()),
+    };
+    let activation: Vec<u8> = activation.iter().flat_map(|v| v.to_le_bytes()).collect();
+    let mut metadata: std::collections::HashMap<String, String> = [
+        (
+            format!("{WEIGHT}{SAFETENSORS_GGML_DTYPE_SUFFIX}"),
+            storage.dtype().to_u32().to_string(),
+        ),
+        (
+            format!("{WEIGHT}{SAFETENSORS_ELEM_COUNT_SUFFIX}"),
+            storage.elem_count().to_string(),
+        ),
+        (format!("{WEIGHT}.shape"), join_dims(self_shape.dims())),
+        (format!("{ACTIVATION}.shape"), join_dims(rhs_l.dims())),
+        (format!("{ACTIVATION}.stride"), join_dims(rhs_l.stride())),
+        (
+            format!("{ACTIVATION}.start_offset"),
+            rhs_l.start_offset().to_string(),
+        ),
+        (
+            "config".to_string(),
+            format!("{:?}", QuantCudaConfig::for_device(dev)),
+        ),
+    ]
+    .into_iter()
+    .collect();
+    if let Some(error) = error {
+        metadata.insert("error".to_string(), error);
+    }
+    let views = [
+        (
+            WEIGHT,
+            TensorView::new(safetensors::Dtype::U8, vec![weight.len()], &weight)?,
+        ),
+        (
+            ACTIVATION,
+            TensorView::new(
+                safetensors::Dtype::F32,
+                vec![activation.len() / 4],
+                &activation,
+            )?,
+        ),
+    ];
+    safetensors::tensor::serialize_to_file(views, &Some(metadata), path)?;
+    Ok(())
+}
+
+/// The inputs of a `fwd` call loaded from a record written by [`QuantRecorder`].
+pub struct QuantRecord {
+    pub weight: QCudaStorage,
+    pub weight_shape: Shape,
+    /// The whole buffer of the activation, `layout` selects the values used by the matmul.
+    pub activation: CudaStorage,
+    pub layout: Layout,
+    /// The configuration of the device when recording, in its debug representation. It is not
+    /// restored by [`QuantRecord::replay`].
+    pub config: String,
+    /// The error returned by the recorded call, if any.
+    pub error: Option<String>,
+}
+
+impl QuantRecord {
+    /// Loads a record on `device`.
+    pub fn load<P: AsRef<Path>>(device: &CudaDevice, path: P) -> Result<Self> {
+        let data = std::fs::read(path.as_ref())?;
+        let weight = QCudaStorage::from_safetensors_bytes(device, WEIGHT, &data)?;
+        let (_, metadata) = safetensors::SafeTensors::read_metadata(&data)?;
+        let metadata = match metadata.metadata() {
+            Some(metadata) => metadata.clone(),
+            None => crate::bail!("missing metadata in record {:?}", path.as_ref()),
+        };
+        let get = |key: &str| -> Result<&String> {
+            match metadata.get(key) {
+                Some(value) => Ok(value),
+                None => crate::bail!("missing record metadata {key}"),
+            }
+        };
+        let dims = |key: &str| -> Result<Vec<usize>> { parse_dims(key, get(key)?) };
+        let weight_shape = Shape::from(dims(&format!("{WEIGHT}.shape"))?);
+        let start_offset_key = format!("{ACTIVATION}.start_offset");
+        let start_offset = get(&start_offset_key)?.parse::<usize>().map_err(|_| {
+            crate::Error::Msg(format!("invalid record metadata {start_offset_key}")).bt()
+        })?;
+        let layout = Layout::new(
+            Shape::from(dims(&format!("{ACTIVATION}.shape"))?),
+            dims(&format!("{ACTIVATION}.stride"))?,
+            start_offset,
+        );
+        let st = safetensors::SafeTensors::deserialize(&data)?;
+        let view = st.tensor(ACTIVATION)?;
+        if view.dtype() != safetensors::Dtype::F32 {
+            crate::bail!("unexpected activation dtype {:?} in record", view.dtype())
+        }
+        let activation: Vec<f32> = view
+            .data()
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        let activation = device.htod_sync_copy(&activation).w()?;
+        Ok(Self {
+            weight,
+            weight_shape,
+            activation: CudaStorage::wrap_cuda_slice(activation, device.clone()),
+            layout,
+            config: get("config")?.clone(),
+            error: metadata.get("error").cloned(),
+        })
+    }
+
+    /// Runs the recorded matmul again with the configuration currently set for the device.
+    pub fn replay(&self) -> Result<(CudaStorage, Shape)> {
+        self.weight
+            .fwd(&self.weight_shape, &self.activation, &self.layout)
+    }
+}
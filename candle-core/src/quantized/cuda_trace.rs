@@ -0,0 +1,188 @@
+//! Recording of the quantized cuda kernel launches as a Chrome trace, this module is only
+//! available with the `quant-trace` feature.
+//!
+//! ```ignore
+//! QuantTracer::enable();
+//! let ys = model.forward(&xs)?;
+//! QuantTracer::write_chrome_trace("trace.json")?;
+//! ```
+//! The resulting file can be loaded in `chrome://tracing` or Perfetto.
+use super::GgmlDType;
+use crate::backend::BackendDevice;
+use crate::cuda_backend::WrapErr;
+use crate::{CudaDevice, Result};
+use cudarc::driver::{result, sys};
+
+// Raw cuda events are plain pointers, they are only used while holding the tracer lock.
+struct Event(sys::CUevent);
+unsafe impl Send for Event {}
+
+impl Event {
+    fn record(dev: &CudaDevice) -> Result<Self> {
+        dev.bind_to_thread().w()?;
+        let event = result::event::create(sys::CUevent_flags::CU_EVENT_DEFAULT).w()?;
+        unsafe { result::event::record(event, *dev.cu_stream()) }.w()?;
+        Ok(Self(event))
+    }
+
+    fn elapsed_ms(&self, end: &Event) -> Result<f32> {
+        unsafe { result::event::elapsed(self.0, end.0) }.w()
+    }
+}
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        let _ = unsafe { result::event::destroy(self.0) };
+    }
+}
+
+struct Launch {
+    name: String,
+    dtype: GgmlDType,
+    device: CudaDevice,
+    start: Event,
+    stop: Option<Event>,
+}
+
+struct TracerState {
+    // Distinguishes the successive enable calls so that a scope started before a re-enable does
+    // not end a launch of the new recording.
+    generation: u64,
+    // One reference event per device, recorded before its first traced launch, the timestamps
+    // of the launches on a device are relative to it.
+    origins: Vec<(usize, Event)>,
+    launches: Vec<Launch>,
+}
+
+static TRACER: std::sync::Mutex<Option<TracerState>> = std::sync::Mutex::new(None);
+
+/// Records the kernels launched by the quantized cuda ops, with their timings measured using cuda
+/// events, and exports them in the Chrome trace event format.
+pub struct QuantTracer;
+
+/// A traced launch, its stop event is recorded by [`LaunchScope::end`].
+pub(crate) struct LaunchScope {
+    index: Option<(u64, usize)>,
+}
+
+impl QuantTracer {
+    /// Starts recording, the previously recorded launches are dropped.
+    pub fn enable() {
+        static GENERATION: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let generation = GENERATION.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        *TRACER.lock().unwrap() = Some(TracerState {
+            generation,
+            origins: vec![],
+            launches: vec![],
+        })
+    }
+
+    /// Stops recording and drops the recorded launches.
+    pub fn disable() {
+        *TRACER.lock().unwrap() = None
+    }
+
+    pub fn is_enabled() -> bool {
+        TRACER.lock().unwrap().is_some()
+    }
+
+    /// The number of launches recorded since the tracer was enabled.
+    pub fn num_launches() -> usize {
+        match TRACER.lock().unwrap().as_ref() {
+            Some(state) => state.launches.len(),
+            None => 0,
+        }
+    }
+
+    pub(crate) fn begin(dev: &CudaDevice, name: &str, dtype: GgmlDType) -> Result<LaunchScope> {
+        let mut tracer = TRACER.lock().unwrap();
+        let state = match tracer.as_mut() {
+            None => return Ok(LaunchScope { index: None }),
+            Some(state) => state,
+        };
+        let ordinal = dev.ordinal();
+        if !state.origins.iter().any(|(o, _)| *o == ordinal) {
+            state.origins.push((ordinal, Event::record(dev)?))
+        }
+        state.launches.push(Launch {
+            name: name.to_string(),
+            dtype,
+            device: dev.clone(),
+            start: Event::record(dev)?,
+            stop: None,
+        });
+        Ok(LaunchScope {
+            index: Some((state.generation, state.launches.len() - 1)),
+        })
+    }
+
+    /// Returns the recorded launches as Chrome trace JSON, this waits for all of them to complete.
+    pub fn chrome_trace() -> Result<String> {
+        let tracer = TRACER.lock().unwrap();
+        let state = match tracer.as_ref() {
+            None => crate::bail!("the quant tracer is not enabled"),
+            Some(state) => state,
+        };
+        let mut events = vec![];
+        for launch in state.launches.iter() {
+            let stop = match launch.stop.as_ref() {
+                Some(stop) => stop,
+                // The launch failed or is still in progress on another thread.
+                None => continue,
+            };
+            launch.device.synchronize()?;
+            let ordinal = launch.device.ordinal();
+            let origin = match state.origins.iter().find(|(o, _)| *o == ordinal) {
+                Some((_, origin)) => origin,
+                None => crate::bail!("no trace origin for device {ordinal}"),
+            };
+            let ts = origin.elapsed_ms(&launch.start)? as f64 * 1000.;
+            let dur = launch.start.elapsed_ms(stop)? as f64 * 1000.;
+            events.push(format!(
+                r#"{{"name":"{}","cat":"quantized","ph":"X","ts":{ts:.3},"dur":{dur:.3},"pid":{ordinal},"tid":0,"args":{{"dtype":"{:?}"}}}}"#,
+                escape_json(&launch.name),
+                launch.dtype
+            ));
+        }
+        Ok(format!("{{\"traceEvents\":[{}]}}", events.join(",")))
+    }
+
+    /// Writes the recorded launches to `path`, e.g. `trace.json`, see [`QuantTracer::chrome_trace`].
+    pub fn write_chrome_trace<P: AsRef<std::path::Path>>(path: P) -> Result<()> {
+        let trace = Self::chrome_trace()?;
+        std::fs::write(path, trace)?;
+        Ok(())
+    }
+}
+
+impl LaunchScope {
+    pub(crate) fn end(self, dev: &CudaDevice) -> Result<()> {
+        let (generation, index) = match self.index {
+            None => return Ok(()),
+            Some(index) => index,
+        };
+        let mut tracer = TRACER.lock().unwrap();
+        // The tracer may have been re-enabled or disabled since the launch started.
+        if let Some(state) = tracer.as_mut() {
+            if state.generation == generation {
+                if let Some(launch) = state.launches.get_mut(index) {
+                    launch.stop = Some(Event::record(dev)?)
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
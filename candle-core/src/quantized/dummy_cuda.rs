@@ -32,6 +32,12 @@ impl QCudaStorage {
         0
     }
 
+    pub(crate) fn record_shape(&mut self, _shape: &crate::Shape) {}
+
+    pub(crate) fn auto_upload_activations(&self) -> bool {
+        false
+    }
+
     pub fn fwd(
         &self,
         _self_shape: &crate::Shape,
@@ -488,47 +488,119 @@ pub fn write<W: std::io::Seek + std::io::Write>(
     metadata: &[(&str, &Value)],
     tensors: &[(&str, &QTensor)],
 ) -> Result<()> {
-    w.write_u32::<LittleEndian>(0x46554747)?;
-    w.write_u32::<LittleEndian>(2)?; // version 2.
-    w.write_u64::<LittleEndian>(tensors.len() as u64)?;
-    w.write_u64::<LittleEndian>(metadata.len() as u64)?;
-    for (name, value) in metadata.iter() {
-        write_string(w, name)?;
-        w.write_u32::<LittleEndian>(value.value_type().to_u32())?;
-        value.write(w)?;
-    }
-    let mut offset = 0usize;
-    let mut offsets = Vec::with_capacity(tensors.len());
+    let infos: Vec<_> = tensors
+        .iter()
+        .map(|(name, tensor)| (*name, tensor.shape(), tensor.dtype()))
+        .collect();
+    let mut writer = TensorWriter::new(w, metadata, &infos)?;
     for (name, tensor) in tensors.iter() {
-        write_string(w, name)?;
-        let dims = tensor.shape().dims();
-        w.write_u32::<LittleEndian>(dims.len() as u32)?;
-        for &dim in dims.iter().rev() {
-            w.write_u64::<LittleEndian>(dim as u64)?;
-        }
-        w.write_u32::<LittleEndian>(tensor.dtype().to_u32())?;
-        w.write_u64::<LittleEndian>(offset as u64)?;
-        offsets.push(offset);
-        let size_in_bytes = tensor.storage_size_in_bytes();
-        let padding = 31 - (31 + size_in_bytes) % 32;
-        offset += size_in_bytes + padding;
+        writer.write_tensor(name, &tensor.data()?)?;
     }
-    let pos = w.stream_position()? as usize;
-    let padding = 31 - (31 + pos) % 32;
-    w.write_all(&vec![0u8; padding])?;
-    let tensor_start_pos = w.stream_position()? as usize;
-    for (offset, (_name, tensor)) in offsets.iter().zip(tensors.iter()) {
+    writer.finish()?;
+    Ok(())
+}
+
+/// Writes a gguf file one tensor at a time, so that the whole model never has to be held in
+/// memory. The header, including the name, shape and dtype of every tensor, is written on
+/// creation and the tensor data then has to be provided in the same order.
+pub struct TensorWriter<W> {
+    w: W,
+    // The tensors still to be written with their size in bytes.
+    pending: std::collections::VecDeque<(String, usize)>,
+    tensor_start_pos: usize,
+    offset: usize,
+}
+
+impl<W: std::io::Seek + std::io::Write> TensorWriter<W> {
+    /// Writes the gguf header with `metadata` and the name, shape and dtype of `tensors`, the
+    /// data of these tensors then has to be written in this order with `write_tensor`.
+    pub fn new(
+        mut w: W,
+        metadata: &[(&str, &Value)],
+        tensors: &[(&str, &crate::Shape, GgmlDType)],
+    ) -> Result<Self> {
+        w.write_u32::<LittleEndian>(0x46554747)?;
+        w.write_u32::<LittleEndian>(2)?; // version 2.
+        w.write_u64::<LittleEndian>(tensors.len() as u64)?;
+        w.write_u64::<LittleEndian>(metadata.len() as u64)?;
+        for (name, value) in metadata.iter() {
+            write_string(&mut w, name)?;
+            w.write_u32::<LittleEndian>(value.value_type().to_u32())?;
+            value.write(&mut w)?;
+        }
+        let mut offset = 0usize;
+        let mut pending = std::collections::VecDeque::with_capacity(tensors.len());
+        for (name, shape, dtype) in tensors.iter() {
+            write_string(&mut w, name)?;
+            let dims = shape.dims();
+            w.write_u32::<LittleEndian>(dims.len() as u32)?;
+            for &dim in dims.iter().rev() {
+                w.write_u64::<LittleEndian>(dim as u64)?;
+            }
+            w.write_u32::<LittleEndian>(dtype.to_u32())?;
+            w.write_u64::<LittleEndian>(offset as u64)?;
+            let elem_count = shape.elem_count();
+            if elem_count % dtype.block_size() != 0 {
+                crate::bail!(
+                    "tensor {name} size {elem_count} is not divisible by the {dtype:?} block size {}",
+                    dtype.block_size()
+                )
+            }
+            let size_in_bytes = elem_count / dtype.block_size() * dtype.type_size();
+            pending.push_back((name.to_string(), size_in_bytes));
+            let padding = 31 - (31 + size_in_bytes) % 32;
+            offset += size_in_bytes + padding;
+        }
         let pos = w.stream_position()? as usize;
-        if tensor_start_pos + offset != pos {
+        let padding = 31 - (31 + pos) % 32;
+        w.write_all(&vec![0u8; padding])?;
+        let tensor_start_pos = w.stream_position()? as usize;
+        Ok(Self {
+            w,
+            pending,
+            tensor_start_pos,
+            offset: 0,
+        })
+    }
+
+    /// Writes the raw ggml blocks of the next tensor, `name` has to match the header.
+    pub fn write_tensor(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let size_in_bytes = match self.pending.pop_front() {
+            Some((expected, size_in_bytes)) if expected == name => size_in_bytes,
+            Some((expected, _)) => crate::bail!("unexpected tensor {name}, expected {expected}"),
+            None => crate::bail!("unexpected tensor {name}, all the tensors have been written"),
+        };
+        if data.len() != size_in_bytes {
             crate::bail!(
-                "internal error, unexpected current position {tensor_start_pos} {offset} {pos}"
+                "unexpected size for tensor {name}, {} bytes instead of {size_in_bytes}",
+                data.len()
             )
         }
-        let data = tensor.data()?;
-        let size_in_bytes = data.len();
-        w.write_all(&data)?;
+        let pos = self.w.stream_position()? as usize;
+        if self.tensor_start_pos + self.offset != pos {
+            crate::bail!(
+                "internal error, unexpected current position {} {} {pos}",
+                self.tensor_start_pos,
+                self.offset
+            )
+        }
+        self.w.write_all(data)?;
         let padding = 31 - (31 + size_in_bytes) % 32;
-        w.write_all(&vec![0u8; padding])?;
+        self.w.write_all(&vec![0u8; padding])?;
+        self.offset += size_in_bytes + padding;
+        Ok(())
+    }
+
+    /// The names of the tensors that have not been written yet, in order.
+    pub fn pending(&self) -> impl Iterator<Item = &str> {
+        self.pending.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Checks that all the tensors have been written and returns the underlying writer.
+    pub fn finish(self) -> Result<W> {
+        if let Some((name, _)) = self.pending.front() {
+            crate::bail!("tensor {name} has not been written")
+        }
+        Ok(self.w)
     }
-    Ok(())
 }
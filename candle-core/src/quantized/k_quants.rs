@@ -1,11 +1,12 @@
 use super::utils::{
-    get_scale_min_k4, group_for_dequantization, group_for_quantization, make_q3_quants,
-    make_qkx1_quants, make_qx_quants, nearest_int,
+    check_imatrix, get_scale_min_k4, group_for_dequantization, group_for_quantization,
+    imatrix_weights, make_q3_quants, make_qkx1_quants, make_qx_quants, make_qx_quants_weighted,
+    nearest_int,
 };
 use super::GgmlDType;
 use crate::Result;
 use byteorder::{ByteOrder, LittleEndian};
-use half::f16;
+use half::{bf16, f16};
 use rayon::prelude::*;
 
 // Default to QK_K 256 rather than 64.
@@ -31,6 +32,20 @@ pub trait GgmlType: Sized + Clone + Send + Sync {
     fn to_float(xs: &[Self], ys: &mut [f32]) -> Result<()>;
     fn from_float(xs: &[f32], ys: &mut [Self]) -> Result<()>;
 
+    /// Quantizes rows of `n_per_row` values, the errors on each column are weighted by the
+    /// importance matrix `imatrix` which has `n_per_row` values.
+    fn from_float_imatrix(
+        _xs: &[f32],
+        _ys: &mut [Self],
+        _imatrix: &[f32],
+        _n_per_row: usize,
+    ) -> Result<()> {
+        crate::bail!(
+            "quantization with an importance matrix is not supported for {:?}",
+            Self::DTYPE
+        )
+    }
+
     /// Dot product used as a building block for quantized mat-mul.
     /// n is the number of elements to be considered.
     fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32>;
@@ -219,6 +234,31 @@ impl GgmlType for BlockQ4_0 {
         Ok(())
     }
 
+    // quantize_row_q4_0_impl
+    fn from_float_imatrix(
+        xs: &[f32],
+        ys: &mut [Self],
+        imatrix: &[f32],
+        n_per_row: usize,
+    ) -> Result<()> {
+        check_imatrix(xs, ys, imatrix, n_per_row)?;
+        let qk = Self::BLCK_SIZE;
+        let mut ws = [0f32; QK4_0];
+        let mut ls = [0i8; QK4_0];
+        for (row, ys) in xs.chunks(n_per_row).zip(ys.chunks_mut(n_per_row / qk)) {
+            let sigma2 = row.iter().map(|x| x * x).sum::<f32>() / n_per_row as f32;
+            for ((ys, xs), imatrix) in ys.iter_mut().zip(row.chunks(qk)).zip(imatrix.chunks(qk)) {
+                imatrix_weights(xs, imatrix, sigma2, &mut ws);
+                let d = make_qx_quants_weighted(8, xs, &mut ls, &ws);
+                ys.d = f16::from_f32(d);
+                for (j, q) in ys.qs.iter_mut().enumerate() {
+                    *q = ls[j] as u8 | ((ls[qk / 2 + j] as u8) << 4)
+                }
+            }
+        }
+        Ok(())
+    }
+
     // https://github.com/ggerganov/llama.cpp/blob/b5ffb2849d23afe73647f68eec7b68187af09be6/ggml.c#L2361C10-L2361C122
     #[allow(unreachable_code)]
     fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
@@ -424,6 +464,37 @@ impl GgmlType for BlockQ5_0 {
         Ok(())
     }
 
+    // quantize_row_q5_0_impl
+    fn from_float_imatrix(
+        xs: &[f32],
+        ys: &mut [Self],
+        imatrix: &[f32],
+        n_per_row: usize,
+    ) -> Result<()> {
+        check_imatrix(xs, ys, imatrix, n_per_row)?;
+        let qk = Self::BLCK_SIZE;
+        let mut ws = [0f32; QK5_0];
+        let mut ls = [0i8; QK5_0];
+        for (row, ys) in xs.chunks(n_per_row).zip(ys.chunks_mut(n_per_row / qk)) {
+            let sigma2 = row.iter().map(|x| x * x).sum::<f32>() / n_per_row as f32;
+            for ((ys, xs), imatrix) in ys.iter_mut().zip(row.chunks(qk)).zip(imatrix.chunks(qk)) {
+                imatrix_weights(xs, imatrix, sigma2, &mut ws);
+                let d = make_qx_quants_weighted(16, xs, &mut ls, &ws);
+                ys.d = f16::from_f32(d);
+                let mut qh = 0u32;
+                for j in 0..qk / 2 {
+                    let xi0 = ls[j] as u8;
+                    let xi1 = ls[j + qk / 2] as u8;
+                    ys.qs[j] = (xi0 & 0x0F) | ((xi1 & 0x0F) << 4);
+                    qh |= ((xi0 as u32 & 0x10) >> 4) << j;
+                    qh |= ((xi1 as u32 & 0x10) >> 4) << (j + qk / 2);
+                }
+                LittleEndian::write_u32(&mut ys.qh, qh)
+            }
+        }
+        Ok(())
+    }
+
     // https://github.com/ggerganov/llama.cpp/blob/468ea24fb4633a0d681f7ac84089566c1c6190cb/ggml.c#L1566
     fn to_float(xs: &[Self], ys: &mut [f32]) -> Result<()> {
         let k = ys.len();
@@ -614,6 +685,17 @@ impl GgmlType for BlockQ8_0 {
         Ok(())
     }
 
+    // As in llama.cpp, q8_0 is precise enough for the importance matrix not to be used.
+    fn from_float_imatrix(
+        xs: &[f32],
+        ys: &mut [Self],
+        imatrix: &[f32],
+        n_per_row: usize,
+    ) -> Result<()> {
+        check_imatrix(xs, ys, imatrix, n_per_row)?;
+        Self::from_float(xs, ys)
+    }
+
     #[allow(unreachable_code)]
     fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
         #[cfg(target_feature = "avx")]
@@ -690,8 +772,22 @@ impl GgmlType for BlockQ8_1 {
         Ok(())
     }
 
-    fn to_float(_xs: &[Self], _ys: &mut [f32]) -> Result<()> {
-        unimplemented!("no support for vec-dot on Q8_1")
+    fn to_float(xs: &[Self], ys: &mut [f32]) -> Result<()> {
+        let k = ys.len();
+        if k % QK8_1 != 0 {
+            crate::bail!("dequantize_row_q8_1: {k} is not divisible by {QK8_1}");
+        }
+
+        let nb = k / QK8_1;
+
+        for i in 0..nb {
+            let d = xs[i].d.to_f32();
+
+            for j in 0..QK8_1 {
+                ys[i * QK8_1 + j] = xs[i].qs[j] as f32 * d;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -1963,3 +2059,48 @@ impl GgmlType for f16 {
         Ok(())
     }
 }
+
+impl GgmlType for bf16 {
+    const DTYPE: GgmlDType = GgmlDType::BF16;
+    const BLCK_SIZE: usize = 1;
+    type VecDotType = bf16;
+
+    fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
+        Self::vec_dot_unopt(n, xs, ys)
+    }
+
+    fn vec_dot_unopt(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
+        if xs.len() < n {
+            crate::bail!("size mismatch {} < {n}", xs.len())
+        }
+        if ys.len() < n {
+            crate::bail!("size mismatch {} < {n}", ys.len())
+        }
+        let res = xs[..n]
+            .iter()
+            .zip(ys[..n].iter())
+            .map(|(x, y)| x.to_f32() * y.to_f32())
+            .sum();
+        Ok(res)
+    }
+
+    fn from_float(xs: &[f32], ys: &mut [Self]) -> Result<()> {
+        if xs.len() != ys.len() {
+            crate::bail!("size mismatch {} {}", xs.len(), ys.len());
+        }
+        for (x, y) in xs.iter().zip(ys.iter_mut()) {
+            *y = bf16::from_f32(*x)
+        }
+        Ok(())
+    }
+
+    fn to_float(xs: &[Self], ys: &mut [f32]) -> Result<()> {
+        if xs.len() != ys.len() {
+            crate::bail!("size mismatch {} {}", xs.len(), ys.len());
+        }
+        for (x, y) in xs.iter().zip(ys.iter_mut()) {
+            *y = x.to_f32()
+        }
+        Ok(())
+    }
+}
@@ -21,13 +21,26 @@ pub mod cuda;
 mod cuda {
     pub use super::dummy_cuda::*;
 }
+#[cfg(feature = "cuda")]
+pub mod cuda_dequant_cache;
+#[cfg(any(feature = "cuda", test))]
+mod cuda_dispatch;
+#[cfg(feature = "gds")]
+pub mod cuda_gds;
+#[cfg(feature = "cuda")]
+pub mod cuda_graph;
+#[cfg(feature = "cuda")]
+pub mod cuda_record;
+#[cfg(feature = "quant-trace")]
+pub mod cuda_trace;
 
 #[cfg(target_feature = "neon")]
 pub mod neon;
 #[cfg(target_feature = "simd128")]
 pub mod simd128;
+pub mod stream;
 pub mod utils;
-use half::f16;
+use half::{bf16, f16};
 
 pub use k_quants::GgmlType;
 
@@ -133,6 +146,7 @@ impl QStorage {
 pub enum GgmlDType {
     F32,
     F16,
+    BF16,
     Q4_0,
     Q4_1,
     Q5_0,
@@ -164,6 +178,7 @@ impl GgmlDType {
             13 => Self::Q5K,
             14 => Self::Q6K,
             15 => Self::Q8K,
+            30 => Self::BF16,
             _ => crate::bail!("unknown dtype for tensor {u}"),
         };
         Ok(dtype)
@@ -185,6 +200,7 @@ impl GgmlDType {
             Self::Q5K => 13,
             Self::Q6K => 14,
             Self::Q8K => 15,
+            Self::BF16 => 30,
         }
     }
 
@@ -193,6 +209,7 @@ impl GgmlDType {
         match self {
             Self::F32 => Box::new(vec![f32::zeros(); elem_count]),
             Self::F16 => Box::new(vec![f16::zeros(); elem_count]),
+            Self::BF16 => Box::new(vec![bf16::zeros(); elem_count]),
             Self::Q4_0 => Box::new(vec![BlockQ4_0::zeros(); elem_count / BlockQ4_0::BLCK_SIZE]),
             Self::Q4_1 => Box::new(vec![BlockQ4_1::zeros(); elem_count / BlockQ4_1::BLCK_SIZE]),
             Self::Q5_0 => Box::new(vec![BlockQ5_0::zeros(); elem_count / BlockQ5_0::BLCK_SIZE]),
@@ -212,7 +229,7 @@ impl GgmlDType {
         use k_quants::*;
         match self {
             Self::F32 => 4,
-            Self::F16 => 2,
+            Self::F16 | Self::BF16 => 2,
             Self::Q4_0 => std::mem::size_of::<BlockQ4_0>(),
             Self::Q4_1 => std::mem::size_of::<BlockQ4_1>(),
             Self::Q5_0 => std::mem::size_of::<BlockQ5_0>(),
@@ -233,7 +250,7 @@ impl GgmlDType {
     pub fn block_size(&self) -> usize {
         match self {
             Self::F32 => 1,
-            Self::F16 => 1,
+            Self::F16 | Self::BF16 => 1,
             Self::Q4_0 => k_quants::QK4_0,
             Self::Q4_1 => k_quants::QK4_1,
             Self::Q5_0 => k_quants::QK5_0,
@@ -243,6 +260,135 @@ impl GgmlDType {
             Self::Q2K | Self::Q3K | Self::Q4K | Self::Q5K | Self::Q6K | Self::Q8K => k_quants::QK_K,
         }
     }
+
+    /// A reasonable bound on the relative root mean square error `|x - q(x)| / |x|` introduced by
+    /// quantizing `x` with this dtype, for weights that are roughly uniformly distributed within
+    /// each block. This can be used as a tolerance when comparing quantized results to their f32
+    /// counterpart.
+    pub fn expected_rel_error(&self) -> f32 {
+        match self {
+            Self::F32 => 0.0,
+            Self::F16 => 1e-3,
+            Self::BF16 => 5e-3,
+            Self::Q8_0 | Self::Q8_1 | Self::Q8K => 0.01,
+            Self::Q6K => 0.03,
+            Self::Q5_0 | Self::Q5_1 | Self::Q5K => 0.05,
+            Self::Q4_0 | Self::Q4_1 | Self::Q4K => 0.1,
+            Self::Q3K => 0.2,
+            Self::Q2K => 0.45,
+        }
+    }
+
+    /// Whether this is one of the k-quants, these use super-blocks of `QK_K` elements.
+    pub fn is_k_quant(&self) -> bool {
+        match self {
+            Self::Q2K | Self::Q3K | Self::Q4K | Self::Q5K | Self::Q6K | Self::Q8K => true,
+            Self::F32
+            | Self::F16
+            | Self::BF16
+            | Self::Q4_0
+            | Self::Q4_1
+            | Self::Q5_0
+            | Self::Q5_1
+            | Self::Q8_0
+            | Self::Q8_1 => false,
+        }
+    }
+
+    /// The cuda kernel dequantizing blocks of this dtype to f32, `None` when the weights are
+    /// dequantized on the host. The f16 variant appends `_f16` to the name.
+    pub fn dequant_kernel_name(&self) -> Option<&'static str> {
+        match self {
+            Self::Q4_0 => Some("dequantize_block_q4_0"),
+            Self::Q4_1 => Some("dequantize_block_q4_1"),
+            Self::Q5_0 => Some("dequantize_block_q5_0"),
+            Self::Q5_1 => Some("dequantize_block_q5_1"),
+            Self::Q8_0 => Some("dequantize_block_q8_0"),
+            Self::Q8_1 => Some("dequantize_block_q8_1"),
+            Self::Q2K => Some("dequantize_block_q2_K"),
+            Self::Q3K => Some("dequantize_block_q3_K"),
+            Self::Q4K => Some("dequantize_block_q4_K"),
+            Self::Q5K => Some("dequantize_block_q5_K"),
+            Self::Q6K => Some("dequantize_block_q6_K"),
+            Self::Q8K => Some("dequantize_block_q8_K"),
+            Self::BF16 => Some("dequantize_block_bf16"),
+            Self::F32 | Self::F16 => None,
+        }
+    }
+
+    /// The cuda matmul-vec kernel dequantizing the weights on the fly against an f32 activation,
+    /// `None` if there is none for this dtype.
+    pub fn dmmv_kernel_name(&self) -> Option<&'static str> {
+        match self {
+            Self::Q4_0 => Some("dequantize_mul_mat_vec_q4_0_cuda"),
+            Self::Q4_1 => Some("dequantize_mul_mat_vec_q4_1_cuda"),
+            Self::Q5_0 => Some("dequantize_mul_mat_vec_q5_0_cuda"),
+            Self::Q5_1 => Some("dequantize_mul_mat_vec_q5_1_cuda"),
+            Self::Q8_0 => Some("dequantize_mul_mat_vec_q8_0_cuda"),
+            Self::Q2K => Some("dequantize_mul_mat_vec_q2_k"),
+            Self::Q3K => Some("dequantize_mul_mat_vec_q3_k"),
+            Self::Q4K => Some("dequantize_mul_mat_vec_q4_k"),
+            Self::Q5K => Some("dequantize_mul_mat_vec_q5_k"),
+            Self::Q6K => Some("dequantize_mul_mat_vec_q6_k"),
+            Self::F32 | Self::F16 | Self::BF16 | Self::Q8_1 | Self::Q8K => None,
+        }
+    }
+
+    /// The cuda matmul-vec kernel running on an activation quantized to q8_1, `None` if there is
+    /// none for this dtype. The batched variants append the number of activations to the name.
+    pub fn q81_kernel_name(&self) -> Option<&'static str> {
+        match self {
+            Self::Q4_0 => Some("mul_mat_vec_q4_0_q8_1_cuda"),
+            Self::Q4_1 => Some("mul_mat_vec_q4_1_q8_1_cuda"),
+            Self::Q5_0 => Some("mul_mat_vec_q5_0_q8_1_cuda"),
+            Self::Q5_1 => Some("mul_mat_vec_q5_1_q8_1_cuda"),
+            Self::Q8_0 => Some("mul_mat_vec_q8_0_q8_1_cuda"),
+            Self::Q2K => Some("mul_mat_vec_q2_K_q8_1_cuda"),
+            Self::Q3K => Some("mul_mat_vec_q3_K_q8_1_cuda"),
+            Self::Q4K => Some("mul_mat_vec_q4_K_q8_1_cuda"),
+            Self::Q5K => Some("mul_mat_vec_q5_K_q8_1_cuda"),
+            Self::Q6K => Some("mul_mat_vec_q6_K_q8_1_cuda"),
+            Self::F32 | Self::F16 | Self::BF16 | Self::Q8_1 | Self::Q8K => None,
+        }
+    }
+
+    /// The byte offset and size of the multi-byte fields of a block, i.e. of the fields whose
+    /// byte order depends on the host.
+    fn multi_byte_fields(&self) -> Vec<(usize, usize)> {
+        use k_quants::*;
+        match self {
+            Self::F32 => vec![(0, 4)],
+            Self::F16 | Self::BF16 => vec![(0, 2)],
+            Self::Q4_0 | Self::Q5_0 | Self::Q8_0 => vec![(0, 2)],
+            Self::Q4_1 | Self::Q5_1 | Self::Q8_1 | Self::Q4K | Self::Q5K => vec![(0, 2), (2, 2)],
+            Self::Q2K => vec![(QK_K / 16 + QK_K / 4, 2), (QK_K / 16 + QK_K / 4 + 2, 2)],
+            Self::Q3K => vec![(QK_K / 8 + QK_K / 4 + 12, 2)],
+            Self::Q6K => vec![(3 * QK_K / 4 + QK_K / 16, 2)],
+            Self::Q8K => std::iter::once((0, 4))
+                .chain((0..QK_K / 16).map(|i| (4 + QK_K + 2 * i, 2)))
+                .collect(),
+        }
+    }
+
+    /// Reverses the byte order of the multi-byte fields of the blocks in `data`, e.g. the f16
+    /// scales, converting blocks between the little-endian layout of ggml files and the layout of
+    /// the block types on a big-endian host. `data` has to be made of full blocks.
+    pub fn swap_block_byte_order(&self, data: &mut [u8]) -> Result<()> {
+        let type_size = self.type_size();
+        if data.len() % type_size != 0 {
+            crate::bail!(
+                "data size {} is not a multiple of the {self:?} type size {type_size}",
+                data.len()
+            )
+        }
+        let fields = self.multi_byte_fields();
+        for block in data.chunks_exact_mut(type_size) {
+            for &(offset, size) in fields.iter() {
+                block[offset..offset + size].reverse()
+            }
+        }
+        Ok(())
+    }
 }
 
 // A version of GgmlType without `vec_dot` so that it can be dyn boxed.
@@ -255,6 +401,8 @@ pub trait QuantizedType: Send + Sync {
     fn block_size(&self) -> usize;
     #[allow(clippy::wrong_self_convention)]
     fn from_float(&mut self, xs: &[f32]) -> Result<()>;
+    #[allow(clippy::wrong_self_convention)]
+    fn from_float_imatrix(&mut self, xs: &[f32], imatrix: &[f32], n_per_row: usize) -> Result<()>;
     fn size(&self) -> usize;
 }
 
@@ -271,6 +419,10 @@ impl<T: k_quants::GgmlType + Send + Sync> QuantizedType for Vec<T> {
         T::from_float(xs, self)
     }
 
+    fn from_float_imatrix(&mut self, xs: &[f32], imatrix: &[f32], n_per_row: usize) -> Result<()> {
+        T::from_float_imatrix(xs, self, imatrix, n_per_row)
+    }
+
     fn dtype(&self) -> GgmlDType {
         T::DTYPE
     }
@@ -315,9 +467,12 @@ fn check_shape(shape: &Shape, block_size: usize) -> Result<()> {
 }
 
 impl QTensor {
-    pub fn new<S: Into<Shape>>(storage: QStorage, shape: S) -> Result<Self> {
+    pub fn new<S: Into<Shape>>(mut storage: QStorage, shape: S) -> Result<Self> {
         let shape = shape.into();
         check_shape(&shape, storage.block_size())?;
+        if let QStorage::Cuda(storage) = &mut storage {
+            storage.record_shape(&shape)
+        }
         Ok(Self { storage, shape })
     }
 
@@ -335,6 +490,9 @@ impl QTensor {
         }
         let mut storage = src.device().qzeros(elem_count, dtype)?;
         storage.quantize(&src.storage())?;
+        if let QStorage::Cuda(storage) = &mut storage {
+            storage.record_shape(shape)
+        }
         Ok(Self {
             storage,
             shape: shape.clone(),
@@ -394,7 +552,7 @@ thread_local! {
 impl QMatMul {
     pub fn from_arc(qtensor: std::sync::Arc<QTensor>) -> Result<Self> {
         let dequantize = match qtensor.dtype() {
-            GgmlDType::F32 | GgmlDType::F16 => true,
+            GgmlDType::F32 | GgmlDType::F16 | GgmlDType::BF16 => true,
             _ => DEQUANTIZE_ALL.with(|b| *b),
         };
         let t = if dequantize {
@@ -440,7 +598,10 @@ impl crate::CustomOp1 for QTensor {
         #[allow(clippy::infallible_destructuring_match)]
         let self_storage = match &self.storage {
             QStorage::Cpu(storage) => storage,
-            QStorage::Metal(_) | QStorage::Cuda(_) => crate::bail!("Invalid storage"),
+            QStorage::Metal(_) => crate::bail!("Invalid storage"),
+            QStorage::Cuda(_) => crate::bail!(
+                "Invalid storage, the weights are on cuda but the input is on the cpu, move it to the device or set QuantCudaConfig::auto_upload_activations"
+            ),
         };
         let slice = storage.as_slice::<f32>()?;
         let slice = &slice[layout.start_offset()..layout.start_offset() + src_shape.elem_count()];
@@ -474,10 +635,66 @@ impl crate::CustomOp1 for QTensor {
     }
 }
 
+// With `auto_upload_activations` enabled for the cuda weights, a cpu activation is moved to their
+// device rather than being rejected.
+fn upload_activation(weight: &QTensor, x: &Tensor) -> Result<Option<Tensor>> {
+    match &weight.storage {
+        QStorage::Cuda(s) if x.device().is_cpu() && s.auto_upload_activations() => {
+            Ok(Some(x.to_device(&weight.device())?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Computes `x @ w.t()` for the quantized weights `w` of shape `(n, k)` and a f32 tensor `x` of
+/// shape `(.., k)`, the result has shape `(.., n)`. A one dimensional `x` is handled as a single
+/// row and results in a `(n,)` tensor. `w` and `x` have to be on the same device, unless `w` is
+/// on a cuda device with `QuantCudaConfig::auto_upload_activations` set.
+pub fn quantized_matmul(weight: &QTensor, x: &Tensor) -> Result<Tensor> {
+    if let Some(x) = upload_activation(weight, x)? {
+        return quantized_matmul(weight, &x);
+    }
+    if !weight.device().same_device(x.device()) {
+        crate::bail!(
+            "quantized_matmul: weights on {:?} but input on {:?}",
+            weight.device().location(),
+            x.device().location()
+        )
+    }
+    if x.dtype() != crate::DType::F32 {
+        crate::bail!(
+            "quantized_matmul: expected a f32 input, got {:?}",
+            x.dtype()
+        )
+    }
+    let (_n, k) = weight.shape().dims2()?;
+    if x.dims().last() != Some(&k) {
+        crate::bail!(
+            "quantized_matmul: input {:?} incompatible with weights {:?}",
+            x.shape(),
+            weight.shape()
+        )
+    }
+    if x.rank() == 1 {
+        return quantized_matmul(weight, &x.unsqueeze(0)?)?.squeeze(0);
+    }
+    // Only the cuda kernels support strided inputs.
+    let x = if x.device().is_cuda() {
+        x.clone()
+    } else {
+        x.contiguous()?
+    };
+    x.apply_op1_no_bwd(weight)
+}
+
 impl crate::Module for QMatMul {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         match self {
-            Self::QTensor(t) => xs.apply_op1_no_bwd(t.as_ref()),
+            Self::QTensor(t) => {
+                let xs_on_device = upload_activation(t, xs)?;
+                let xs = xs_on_device.as_ref().unwrap_or(xs);
+                xs.apply_op1_no_bwd(t.as_ref())
+            }
             Self::Tensor(w) => {
                 let w = match *xs.dims() {
                     [b1, b2, _, _] => w.broadcast_left((b1, b2))?.t()?,
@@ -0,0 +1,110 @@
+//! Conversion of a model to a quantized gguf file one tensor at a time, for models that do not
+//! fit in host memory. Each f32 tensor is quantized on the cpu and its blocks are handed to a
+//! writer thread, at most `in_flight` quantized tensors wait to be written so a slow disk makes
+//! [`QuantStream::push`] block rather than the buffered data grow.
+//!
+//! ```ignore
+//! let tensors = [("w1", &shape1, GgmlDType::Q4K), ("norm", &shape2, GgmlDType::F32)];
+//! let mut stream = QuantStream::new(File::create("out.gguf")?, &[], &tensors, 2)?;
+//! for (name, _, _) in tensors {
+//!     stream.push(name, &read_f32_tensor(name)?)?;
+//! }
+//! stream.finish()?;
+//! ```
+use super::gguf_file::{TensorWriter, Value};
+use super::{GgmlDType, QStorage, QuantizedType};
+use crate::Result;
+use std::collections::VecDeque;
+
+type Pending = (String, Box<dyn QuantizedType>);
+
+/// Quantizes tensors and streams them to a gguf file, see the module documentation.
+pub struct QuantStream<W> {
+    // The tensors still to be pushed with their dtype and number of values.
+    tensors: VecDeque<(String, GgmlDType, usize)>,
+    sender: Option<std::sync::mpsc::SyncSender<Pending>>,
+    writer: Option<std::thread::JoinHandle<Result<W>>>,
+}
+
+impl<W: std::io::Seek + std::io::Write + Send + 'static> QuantStream<W> {
+    /// Writes the gguf header for `tensors`, their names, shapes and target dtypes in the order
+    /// in which they will be pushed. At most `in_flight` quantized tensors are buffered while
+    /// waiting to be written, this has to be at least 1.
+    pub fn new(
+        w: W,
+        metadata: &[(&str, &Value)],
+        tensors: &[(&str, &crate::Shape, GgmlDType)],
+        in_flight: usize,
+    ) -> Result<Self> {
+        if in_flight == 0 {
+            crate::bail!("the in-flight limit of a quant stream has to be at least 1")
+        }
+        let mut writer = TensorWriter::new(w, metadata, tensors)?;
+        let (sender, receiver) = std::sync::mpsc::sync_channel::<Pending>(in_flight);
+        let writer = std::thread::spawn(move || {
+            for (name, data) in receiver.iter() {
+                writer.write_tensor(&name, &QStorage::Cpu(data).data()?)?
+            }
+            writer.finish()
+        });
+        let tensors = tensors
+            .iter()
+            .map(|(name, shape, dtype)| (name.to_string(), *dtype, shape.elem_count()))
+            .collect();
+        Ok(Self {
+            tensors,
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    /// Quantizes the next tensor, `src` holds its f32 values and `name` has to match the header.
+    /// This blocks while `in_flight` tensors are already waiting to be written.
+    pub fn push(&mut self, name: &str, src: &[f32]) -> Result<()> {
+        let (dtype, elem_count) = match self.tensors.front() {
+            Some((expected, dtype, elem_count)) if expected == name => (*dtype, *elem_count),
+            Some((expected, _, _)) => crate::bail!("unexpected tensor {name}, expected {expected}"),
+            None => crate::bail!("unexpected tensor {name}, all the tensors have been pushed"),
+        };
+        if src.len() != elem_count {
+            crate::bail!(
+                "unexpected size for tensor {name}, {} values instead of {elem_count}",
+                src.len()
+            )
+        }
+        let mut data = dtype.cpu_zeros(elem_count);
+        data.from_float(src)?;
+        self.tensors.pop_front();
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => crate::bail!("the quant stream has already failed"),
+        };
+        if sender.send((name.to_string(), data)).is_err() {
+            // The writer thread only stops early on an error, report it.
+            self.sender = None;
+            self.join()?;
+            crate::bail!("the quant stream writer stopped unexpectedly")
+        }
+        Ok(())
+    }
+
+    /// The names of the tensors that have not been pushed yet, in order.
+    pub fn pending(&self) -> impl Iterator<Item = &str> {
+        self.tensors.iter().map(|(name, _, _)| name.as_str())
+    }
+
+    /// Waits for all the tensors to be written and returns the underlying writer. This fails if
+    /// some tensors have not been pushed.
+    pub fn finish(mut self) -> Result<W> {
+        self.sender = None;
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<W> {
+        match self.writer.take().map(|writer| writer.join()) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => crate::bail!("the quant stream writer panicked"),
+            None => crate::bail!("the quant stream writer has already been joined"),
+        }
+    }
+}
@@ -197,6 +197,99 @@ pub(super) unsafe fn make_qx_quants(
     scale
 }
 
+/// Validates the sizes for a quantization using an importance matrix, `imatrix` holds one weight
+/// per column of the rows of `n_per_row` elements in `xs`.
+pub(super) fn check_imatrix<T: super::k_quants::GgmlType>(
+    xs: &[f32],
+    ys: &[T],
+    imatrix: &[f32],
+    n_per_row: usize,
+) -> Result<()> {
+    let dtype = T::DTYPE;
+    let block_size = T::BLCK_SIZE;
+    if n_per_row == 0 || n_per_row % block_size != 0 {
+        crate::bail!("quantize {dtype:?}: row length {n_per_row} is not divisible by {block_size}")
+    }
+    if imatrix.len() != n_per_row {
+        crate::bail!(
+            "quantize {dtype:?}: the importance matrix has {} values for rows of {n_per_row}",
+            imatrix.len()
+        )
+    }
+    if xs.len() % n_per_row != 0 {
+        crate::bail!(
+            "quantize {dtype:?}: {} values do not make whole rows of {n_per_row}",
+            xs.len()
+        )
+    }
+    if ys.len() * block_size != xs.len() {
+        crate::bail!(
+            "quantize {dtype:?}: expected {} blocks but {} were provided",
+            xs.len() / block_size,
+            ys.len()
+        )
+    }
+    Ok(())
+}
+
+/// The importance weights of a block for imatrix quantization, the per-column importance scaled by
+/// the magnitude of each value relative to the row variance `sigma2`.
+pub(super) fn imatrix_weights(xs: &[f32], imatrix: &[f32], sigma2: f32, ws: &mut [f32]) {
+    for ((w, &x), &qw) in ws.iter_mut().zip(xs.iter()).zip(imatrix.iter()) {
+        *w = qw * (sigma2 + x * x).sqrt()
+    }
+}
+
+/// Weighted version of `make_qx_quants`, the quants in `ls` are offset by `nmax` and the returned
+/// scale minimizes the squared error weighted by `w`. A few scales around the one mapping the
+/// largest value to `-nmax` are tried.
+pub(super) fn make_qx_quants_weighted(nmax: i32, x: &[f32], ls: &mut [i8], w: &[f32]) -> f32 {
+    let mut max = 0f32;
+    let mut amax = 0f32;
+    for &x in x.iter() {
+        if x.abs() > amax {
+            amax = x.abs();
+            max = x;
+        }
+    }
+    if amax < 1e-15 {
+        // all zero
+        ls.fill(0);
+        return 0.;
+    }
+    let quant = |iscale: f32, x: f32| nearest_int(iscale * x).clamp(-nmax, nmax - 1);
+    let sums = |iscale: f32| {
+        let mut sumlx = 0f32;
+        let mut suml2 = 0f32;
+        for (&x, &w) in x.iter().zip(w.iter()) {
+            let l = quant(iscale, x) as f32;
+            sumlx += w * x * l;
+            suml2 += w * l * l;
+        }
+        (sumlx, suml2)
+    };
+    let mut best_iscale = -(nmax as f32) / max;
+    let (sumlx, suml2) = sums(best_iscale);
+    let mut scale = if suml2 > 0. { sumlx / suml2 } else { 0. };
+    let mut best = scale * sumlx;
+    for is in -9..=9 {
+        if is == 0 {
+            continue;
+        }
+        let iscale = -(nmax as f32 + 0.1 * is as f32) / max;
+        let (sumlx, suml2) = sums(iscale);
+        if suml2 > 0. && sumlx * sumlx > best * suml2 {
+            best_iscale = iscale;
+            scale = sumlx / suml2;
+            best = scale * sumlx;
+        }
+    }
+    for (l, &x) in ls.iter_mut().zip(x.iter()) {
+        *l = (quant(best_iscale, x) + nmax) as i8
+    }
+    scale
+}
+
 // https://github.com/ggerganov/llama.cpp/blob/8183159cf3def112f6d1fe94815fce70e1bffa12/k_quants.c#L224
 pub(super) fn make_qkx1_quants(nmax: i32, ntry: usize, x: &[f32]) -> (f32, f32) {
     let n = x.len();
@@ -324,3 +417,65 @@ pub(super) fn make_q3_quants(x: &[f32], nmax: i32, do_rmse: bool) -> f32 {
     }
     1.0 / iscale
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quantized::k_quants::{BlockQ4_0, GgmlType};
+
+    #[test]
+    fn check_imatrix_sizes() {
+        let xs = [0f32; 128];
+        let ys = vec![BlockQ4_0::zeros(); 4];
+        let imatrix = [1f32; 64];
+        assert!(check_imatrix(&xs, &ys, &imatrix, 64).is_ok());
+        // Rows that are not made of whole blocks.
+        assert!(check_imatrix(&xs, &ys, &[1f32; 16], 16).is_err());
+        assert!(check_imatrix(&xs, &ys, &[], 0).is_err());
+        // An importance matrix that does not match the row length.
+        assert!(check_imatrix(&xs, &ys, &imatrix[..32], 64).is_err());
+        // Values that do not make whole rows.
+        assert!(check_imatrix(&xs[..96], &ys[..3], &imatrix, 64).is_err());
+        // Too few blocks for the values.
+        assert!(check_imatrix(&xs, &ys[..3], &imatrix, 64).is_err());
+    }
+
+    #[test]
+    fn imatrix_weights_scale() {
+        let mut ws = [0f32; 3];
+        imatrix_weights(&[3., 0., -4.], &[2., 1., 0.5], 16., &mut ws);
+        assert_eq!(ws, [10., 4., 0.5 * 32f32.sqrt()]);
+    }
+
+    #[test]
+    fn make_qx_quants_weighted_error() {
+        let mut ls = [1i8; 32];
+        assert_eq!(
+            make_qx_quants_weighted(8, &[0.; 32], &mut ls, &[1.; 32]),
+            0.
+        );
+        assert_eq!(ls, [0; 32]);
+
+        let x: Vec<f32> = (0..32).map(|i| ((i * 7) % 19) as f32 / 3. - 3.).collect();
+        let error = |w: &[f32], ls: &[i8], d: f32| -> f32 {
+            x.iter()
+                .zip(ls.iter())
+                .zip(w.iter())
+                .map(|((x, &l), w)| w * (x - d * (l as i32 - 8) as f32).powi(2))
+                .sum()
+        };
+        let uniform = [1f32; 32];
+        let mut ls = [0i8; 32];
+        let d = make_qx_quants_weighted(8, &x, &mut ls, &uniform);
+        assert!(ls.iter().all(|l| (0..16).contains(l)), "{ls:?}");
+        // The scale is the least squares one for the returned quants.
+        let e = error(&uniform, &ls, d);
+        assert!(e <= error(&uniform, &ls, d * 1.01) && e <= error(&uniform, &ls, d * 0.99));
+        // A heavily weighted value gets a weighted error no larger than with uniform weights.
+        let mut w = uniform;
+        w[5] = 1000.;
+        let mut ls_w = [0i8; 32];
+        let d_w = make_qx_quants_weighted(8, &x, &mut ls_w, &w);
+        assert!(error(&w, &ls_w, d_w) <= error(&w, &ls, d) * (1. + 1e-5));
+    }
+}
@@ -0,0 +1,167 @@
+# Writes the golden matmul-vec vectors checked by the `cuda_golden_vectors` test to
+# `quantized_golden/`, run it from the `tests` directory. This only needs the python standard
+# library. The weights are dequantized with a line by line port of the `dequantize_row_*`
+# reference functions of llama.cpp's ggml-quants.c and the expected outputs are then computed in
+# f64 from these values. llama.cpp's own mul_mat_vec kernels are not used.
+import os
+import random
+import struct
+
+QK_K = 256
+
+
+def f16(b, o):
+    return struct.unpack_from("<e", b, o)[0]
+
+
+def i8(v):
+    return v - 256 if v >= 128 else v
+
+
+def dequantize_q4_0(b):
+    d, qs = f16(b, 0), b[2:18]
+    return [((q & 0xF) - 8) * d for q in qs] + [((q >> 4) - 8) * d for q in qs]
+
+
+def dequantize_q4_1(b):
+    d, m, qs = f16(b, 0), f16(b, 2), b[4:20]
+    return [(q & 0xF) * d + m for q in qs] + [(q >> 4) * d + m for q in qs]
+
+
+def dequantize_q5_0(b):
+    d, qh, qs = f16(b, 0), struct.unpack_from("<I", b, 2)[0], b[6:22]
+    y0 = [(((qs[j] & 0xF) | (((qh >> j) << 4) & 0x10)) - 16) * d for j in range(16)]
+    y1 = [(((qs[j] >> 4) | ((qh >> (j + 12)) & 0x10)) - 16) * d for j in range(16)]
+    return y0 + y1
+
+
+def dequantize_q5_1(b):
+    d, m, qh, qs = f16(b, 0), f16(b, 2), struct.unpack_from("<I", b, 4)[0], b[8:24]
+    y0 = [((qs[j] & 0xF) | (((qh >> j) << 4) & 0x10)) * d + m for j in range(16)]
+    y1 = [((qs[j] >> 4) | ((qh >> (j + 12)) & 0x10)) * d + m for j in range(16)]
+    return y0 + y1
+
+
+def dequantize_q8_0(b):
+    d = f16(b, 0)
+    return [i8(q) * d for q in b[2:34]]
+
+
+def dequantize_q2_k(b):
+    scales, qs, d, dmin = b[0:16], b[16:80], f16(b, 80), f16(b, 82)
+    y, i = [], 0
+    for n in range(0, 64, 32):
+        for shift in range(0, 8, 2):
+            for l0 in (0, 16):
+                sc = scales[i]
+                i += 1
+                dl, ml = d * (sc & 0xF), dmin * (sc >> 4)
+                y += [dl * ((qs[n + l0 + l] >> shift) & 3) - ml for l in range(16)]
+    return y
+
+
+def dequantize_q3_k(b):
+    hm, qs, d = b[0:32], b[32:96], f16(b, 108)
+    kmask1, kmask2 = 0x03030303, 0x0F0F0F0F
+    aux = list(struct.unpack_from("<3I", b, 96)) + [0]
+    tmp = aux[2]
+    aux[2] = ((aux[0] >> 4) & kmask2) | (((tmp >> 4) & kmask1) << 4)
+    aux[3] = ((aux[1] >> 4) & kmask2) | (((tmp >> 6) & kmask1) << 4)
+    aux[0] = (aux[0] & kmask2) | (((tmp >> 0) & kmask1) << 4)
+    aux[1] = (aux[1] & kmask2) | (((tmp >> 2) & kmask1) << 4)
+    scales = struct.unpack("<16b", struct.pack("<4I", *aux))
+    y, i, m = [], 0, 1
+    for n in range(0, 64, 32):
+        for shift in range(0, 8, 2):
+            for l0 in (0, 16):
+                dl = d * (scales[i] - 32)
+                i += 1
+                y += [
+                    dl * (((qs[n + l0 + l] >> shift) & 3) - (0 if hm[l0 + l] & m else 4))
+                    for l in range(16)
+                ]
+            m <<= 1
+    return y
+
+
+def scale_min_k4(j, q):
+    if j < 4:
+        return q[j] & 63, q[j + 4] & 63
+    return (q[j + 4] & 0xF) | ((q[j - 4] >> 6) << 4), (q[j + 4] >> 4) | ((q[j] >> 6) << 4)
+
+
+def dequantize_q4_k(b):
+    d, dmin, scales, qs = f16(b, 0), f16(b, 2), b[4:16], b[16:144]
+    y = []
+    for i, n in enumerate(range(0, 128, 32)):
+        sc1, m1 = scale_min_k4(2 * i, scales)
+        sc2, m2 = scale_min_k4(2 * i + 1, scales)
+        y += [d * sc1 * (q & 0xF) - dmin * m1 for q in qs[n : n + 32]]
+        y += [d * sc2 * (q >> 4) - dmin * m2 for q in qs[n : n + 32]]
+    return y
+
+
+def dequantize_q5_k(b):
+    d, dmin, scales, qh, ql = f16(b, 0), f16(b, 2), b[4:16], b[16:48], b[48:176]
+    y = []
+    for i, n in enumerate(range(0, 128, 32)):
+        sc1, m1 = scale_min_k4(2 * i, scales)
+        sc2, m2 = scale_min_k4(2 * i + 1, scales)
+        u1, u2 = 1 << (2 * i), 2 << (2 * i)
+        y += [d * sc1 * ((ql[n + l] & 0xF) + (16 if qh[l] & u1 else 0)) - dmin * m1 for l in range(32)]
+        y += [d * sc2 * ((ql[n + l] >> 4) + (16 if qh[l] & u2 else 0)) - dmin * m2 for l in range(32)]
+    return y
+
+
+def dequantize_q6_k(b):
+    ql, qh, sc, d = b[0:128], b[128:192], struct.unpack_from("<16b", b, 192), f16(b, 208)
+    y = [0.0] * QK_K
+    for n in range(2):
+        ql_n, qh_n, sc_n = ql[64 * n :], qh[32 * n :], sc[8 * n :]
+        for l in range(32):
+            i = l // 16
+            q1 = ((ql_n[l] & 0xF) | (((qh_n[l] >> 0) & 3) << 4)) - 32
+            q2 = ((ql_n[l + 32] & 0xF) | (((qh_n[l] >> 2) & 3) << 4)) - 32
+            q3 = ((ql_n[l] >> 4) | (((qh_n[l] >> 4) & 3) << 4)) - 32
+            q4 = ((ql_n[l + 32] >> 4) | (((qh_n[l] >> 6) & 3) << 4)) - 32
+            y[128 * n + l] = d * sc_n[i] * q1
+            y[128 * n + l + 32] = d * sc_n[i + 2] * q2
+            y[128 * n + l + 64] = d * sc_n[i + 4] * q3
+            y[128 * n + l + 96] = d * sc_n[i + 6] * q4
+    return y
+
+
+# The ggml dtype id, block size and type size of each dtype with its reference dequantization and
+# the byte offsets of the f16 scales in its blocks. The scales are set to small values so that
+# random blocks do not hold inf or NaN.
+DTYPES = {
+    "q4_0": (2, 32, 18, dequantize_q4_0, [0]),
+    "q4_1": (3, 32, 20, dequantize_q4_1, [0, 2]),
+    "q5_0": (6, 32, 22, dequantize_q5_0, [0]),
+    "q5_1": (7, 32, 24, dequantize_q5_1, [0, 2]),
+    "q8_0": (8, 32, 34, dequantize_q8_0, [0]),
+    "q2k": (10, QK_K, 84, dequantize_q2_k, [80, 82]),
+    "q3k": (11, QK_K, 110, dequantize_q3_k, [108]),
+    "q4k": (12, QK_K, 144, dequantize_q4_k, [0, 2]),
+    "q5k": (13, QK_K, 176, dequantize_q5_k, [0, 2]),
+    "q6k": (14, QK_K, 210, dequantize_q6_k, [208]),
+}
+NROWS, NCOLS = 32, 512
+
+os.makedirs("quantized_golden", exist_ok=True)
+rng = random.Random(42)
+for name, (dtype_id, block_size, type_size, dequantize, scales) in DTYPES.items():
+    blocks = []
+    for _ in range(NROWS * NCOLS // block_size):
+        block = bytearray(rng.randrange(256) for _ in range(type_size))
+        for offset in scales:
+            struct.pack_into("<e", block, offset, rng.uniform(-0.01, 0.01))
+        blocks.append(bytes(block))
+    w = [v for block in blocks for v in dequantize(block)]
+    # Rounds the activation to f32 before computing the expected outputs.
+    x = struct.unpack(f"<{NCOLS}f", struct.pack(f"<{NCOLS}f", *(rng.uniform(-1, 1) for _ in range(NCOLS))))
+    y = [sum(w[r * NCOLS + c] * x[c] for c in range(NCOLS)) for r in range(NROWS)]
+    # The header holds the ggml dtype id, nrows and ncols.
+    header = struct.pack("<3I", dtype_id, NROWS, NCOLS)
+    with open(f"quantized_golden/mmv_{name}.bin", "wb") as f:
+        f.write(header + b"".join(blocks) + struct.pack(f"<{NCOLS}f", *x) + struct.pack(f"<{NROWS}f", *y))
@@ -3,7 +3,7 @@ use candle_core::{
     quantized::{self, GgmlDType},
     test_device,
     test_utils::to_vec2_round,
-    Device, Module, Result, Tensor,
+    DType, Device, Module, Result, Tensor,
 };
 use quantized::{k_quants, GgmlType};
 use rand::prelude::*;
@@ -177,6 +177,45 @@ test_device!(
     quantized_matmul_neg_metal
 );
 
+fn quantized_matmul_fn(device: &Device) -> Result<()> {
+    let (m, k, n) = (3, 256, 4);
+    let lhs = (0..(m * k)).map(|v| v as f32 / 100.).collect::<Vec<_>>();
+    let lhs = Tensor::from_slice(&lhs, (m, k), device)?;
+    let rhs = (0..(k * n)).map(|v| v as f32 / 200.).collect::<Vec<_>>();
+    let rhs = Tensor::from_slice(&rhs, (n, k), device)?;
+    let qtensor = quantized::QTensor::quantize(&rhs, GgmlDType::Q8_0)?;
+    let matmul = quantized::QTensor::quantize(&rhs, GgmlDType::Q8_0)?;
+    let expected = quantized::QMatMul::from_qtensor(matmul)?.forward(&lhs)?;
+
+    let res = quantized::quantized_matmul(&qtensor, &lhs)?;
+    assert_eq!(res.dims(), &[m, n]);
+    assert_eq!(to_vec2_round(&res, 2)?, to_vec2_round(&expected, 2)?);
+    // Batched and one dimensional inputs.
+    let res = quantized::quantized_matmul(&qtensor, &lhs.unsqueeze(0)?)?;
+    assert_eq!(res.dims(), &[1, m, n]);
+    let res = quantized::quantized_matmul(&qtensor, &lhs.get(1)?)?;
+    assert_eq!(res.dims(), &[n]);
+    let expected = expected.get(1)?.to_vec1::<f32>()?;
+    let res = res.to_vec1::<f32>()?;
+    for (r, e) in res.iter().zip(expected.iter()) {
+        assert!((r - e).abs() <= 1e-3 * e.abs().max(1.), "{r} {e}")
+    }
+
+    assert!(quantized::quantized_matmul(&qtensor, &lhs.narrow(1, 0, 128)?).is_err());
+    assert!(quantized::quantized_matmul(&qtensor, &lhs.to_dtype(DType::F16)?).is_err());
+    if !device.is_cpu() {
+        assert!(quantized::quantized_matmul(&qtensor, &lhs.to_device(&Device::Cpu)?).is_err());
+    }
+    Ok(())
+}
+
+test_device!(
+    quantized_matmul_fn,
+    quantized_matmul_fn_cpu,
+    quantized_matmul_fn_cuda,
+    quantized_matmul_fn_metal
+);
+
 fn quantize_q4_0(device: &Device) -> Result<()> {
     let src = (0..32 * 4).map(|v| v as f32).collect::<Vec<_>>();
 
@@ -988,3 +1027,108 @@ fn quantized_matmul_q8k() -> Result<()> {
     ggml_matmul_error_test::<BlockQ8K>()?;
     Ok(())
 }
+
+#[test]
+fn swap_block_byte_order() -> Result<()> {
+    // A q2k block is made of 16 bytes of scales and 64 bytes of quants followed by the f16 d and
+    // dmin.
+    let dtype = GgmlDType::Q2K;
+    let mut block: Vec<u8> = (0..dtype.type_size() as u8).collect();
+    let original = block.clone();
+    dtype.swap_block_byte_order(&mut block)?;
+    assert_eq!(&block[..80], &original[..80]);
+    assert_eq!(&block[80..], [81, 80, 83, 82]);
+    dtype.swap_block_byte_order(&mut block)?;
+    assert_eq!(block, original);
+
+    let d = half::f16::from_f32(0.25);
+    let mut block = vec![0u8; GgmlDType::Q4_0.type_size() * 2];
+    block[18..20].copy_from_slice(&d.to_le_bytes());
+    GgmlDType::Q4_0.swap_block_byte_order(&mut block)?;
+    assert_eq!(&block[18..20], d.to_be_bytes());
+    assert!(GgmlDType::Q4_0
+        .swap_block_byte_order(&mut block[..20])
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn quant_stream() -> Result<()> {
+    use quantized::{gguf_file, stream::QuantStream, QTensor};
+
+    let cpu = Device::Cpu;
+    let w1 = Tensor::arange(0f32, 4096., &cpu)?.reshape((16, 256))?;
+    let w2 = (Tensor::arange(0f32, 256., &cpu)?.reshape((8, 32))? * 0.1)?;
+    let tensors = [
+        ("w1", w1.shape(), GgmlDType::Q4K),
+        ("w2", w2.shape(), GgmlDType::Q8_0),
+    ];
+    let file = std::io::Cursor::new(Vec::new());
+    let mut stream = QuantStream::new(file, &[], &tensors, 1)?;
+    assert!(stream.push("w2", &w2.flatten_all()?.to_vec1()?).is_err());
+    stream.push("w1", &w1.flatten_all()?.to_vec1()?)?;
+    assert_eq!(stream.pending().collect::<Vec<_>>(), ["w2"]);
+    stream.push("w2", &w2.flatten_all()?.to_vec1()?)?;
+    let mut file = stream.finish()?;
+    file.set_position(0);
+    let content = gguf_file::Content::read(&mut file)?;
+    for ((name, _, dtype), w) in tensors.iter().zip([&w1, &w2]) {
+        let expected = QTensor::quantize(w, *dtype)?;
+        let loaded = content.tensor(&mut file, name, &cpu)?;
+        assert_eq!(loaded.shape(), w.shape());
+        assert_eq!(loaded.dtype(), *dtype);
+        assert_eq!(loaded.data()?.to_vec(), expected.data()?.to_vec());
+    }
+
+    // A stream with tensors that were never pushed fails to finish.
+    let file = std::io::Cursor::new(Vec::new());
+    let stream = QuantStream::new(file, &[], &tensors, 2)?;
+    assert!(stream.finish().is_err());
+    Ok(())
+}
+
+#[test]
+fn quantize_imatrix() -> Result<()> {
+    use k_quants::{BlockQ4K, BlockQ4_0, BlockQ5_0, BlockQ8_0};
+
+    // The imatrix weighted squared error of quantizing `xs` with or without the importance matrix.
+    fn weighted_error<T: GgmlType>(xs: &[f32], imatrix: &[f32], with_imatrix: bool) -> Result<f32> {
+        let mut ys = vec![T::zeros(); xs.len() / T::BLCK_SIZE];
+        if with_imatrix {
+            T::from_float_imatrix(xs, &mut ys, imatrix, imatrix.len())?
+        } else {
+            T::from_float(xs, &mut ys)?
+        }
+        let mut out = vec![0f32; xs.len()];
+        T::to_float(&ys, &mut out)?;
+        let error = xs
+            .iter()
+            .zip(out.iter())
+            .enumerate()
+            .map(|(i, (x, y))| imatrix[i % imatrix.len()] * (x - y) * (x - y))
+            .sum();
+        Ok(error)
+    }
+
+    let n_per_row = 64;
+    let xs: Vec<f32> = (0..4 * n_per_row)
+        .map(|i| ((i * 13) % 29) as f32 / 7. - 2.)
+        .collect();
+    // A few columns of each block matter far more than the others.
+    let imatrix: Vec<f32> = (0..n_per_row)
+        .map(|i| if i % 32 < 4 { 100. } else { 0.01 })
+        .collect();
+    let q4_0 = weighted_error::<BlockQ4_0>(&xs, &imatrix, true)?;
+    assert!(q4_0 < weighted_error::<BlockQ4_0>(&xs, &imatrix, false)?);
+    let q5_0 = weighted_error::<BlockQ5_0>(&xs, &imatrix, true)?;
+    assert!(q5_0 < weighted_error::<BlockQ5_0>(&xs, &imatrix, false)?);
+    // q8_0 ignores the importance matrix.
+    assert_eq!(
+        weighted_error::<BlockQ8_0>(&xs, &imatrix, true)?,
+        weighted_error::<BlockQ8_0>(&xs, &imatrix, false)?
+    );
+    assert!(weighted_error::<BlockQ4K>(&xs, &[1.; 256], true).is_err());
+    let mut ys = vec![BlockQ4_0::zeros(); xs.len() / 32];
+    assert!(BlockQ4_0::from_float_imatrix(&xs, &mut ys, &imatrix[..32], n_per_row).is_err());
+    Ok(())
+}
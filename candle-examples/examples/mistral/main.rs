@@ -227,7 +227,12 @@ fn main() -> Result<()> {
 
     let args = Args::parse();
     #[cfg(feature = "cuda")]
-    candle::quantized::cuda::set_force_dmmv(args.force_dmmv);
+    candle::quantized::cuda::QuantCudaConfig::set_default(
+        candle::quantized::cuda::QuantCudaConfig {
+            force_dmmv: args.force_dmmv,
+            ..Default::default()
+        },
+    )?;
 
     let _guard = if args.tracing {
         let (chrome_layer, guard) = ChromeLayerBuilder::new().build();
@@ -671,8 +671,252 @@ static __device__ __forceinline__ void dequantize_q8_0(const void * vx, const in
 }
 
 
+// The dequantize kernels below are templated on their output, either a pointer for the row-major
+// output or a colmajor_out which writes the element i of a row-major (nrows, ncols) matrix at its
+// column-major position instead. This only relies on the output being offset and indexed.
+template<typename T>
+struct colmajor_out {
+    T * y;
+    int nrows;
+    int ncols;
+    int offset;
+
+    __device__ colmajor_out operator+(const int o) const {
+        return {y, nrows, ncols, offset + o};
+    }
+
+    __device__ T & operator[](const int l) const {
+        const int i = offset + l;
+        return y[(i % ncols)*nrows + i / ncols];
+    }
+};
+
+// Stats of the values written by a thread of the dequantize kernels. The NaN values are only
+// counted, the min, max, sum and max-abs cover the other values.
+struct stats_acc {
+    float min_v;
+    float max_v;
+    float sum;
+    float abs_max;
+    unsigned int nan_count;
+};
+
+static __device__ __forceinline__ stats_acc empty_stats() {
+    return {INFINITY, -INFINITY, 0.0f, 0.0f, 0u};
+}
+
+// Output of the dequantize kernels which also accumulates the stats of the written values in the
+// stats_acc of the current thread, these are then combined with block_reduce_stats.
+struct stats_ref {
+    float * y;
+    stats_acc * acc;
+
+    __device__ void operator=(const float v) const {
+        *y = v;
+        if (isnan(v)) {
+            acc->nan_count += 1;
+            return;
+        }
+        acc->min_v = fminf(acc->min_v, v);
+        acc->max_v = fmaxf(acc->max_v, v);
+        acc->sum += v;
+        acc->abs_max = fmaxf(acc->abs_max, fabsf(v));
+    }
+};
+
+struct stats_out {
+    float * y;
+    stats_acc * acc;
+    int offset;
+
+    __device__ stats_out operator+(const int o) const {
+        return {y, acc, offset + o};
+    }
+
+    __device__ stats_ref operator[](const int l) const {
+        return {y + offset + l, acc};
+    }
+};
+
+static __device__ __forceinline__ stats_acc warp_reduce_stats(stats_acc acc) {
+#pragma unroll
+    for (int mask = 16; mask > 0; mask >>= 1) {
+        acc.min_v = fminf(acc.min_v, __shfl_xor_sync(0xffffffff, acc.min_v, mask, 32));
+        acc.max_v = fmaxf(acc.max_v, __shfl_xor_sync(0xffffffff, acc.max_v, mask, 32));
+        acc.sum += __shfl_xor_sync(0xffffffff, acc.sum, mask, 32);
+        acc.abs_max = fmaxf(acc.abs_max, __shfl_xor_sync(0xffffffff, acc.abs_max, mask, 32));
+        acc.nan_count += __shfl_xor_sync(0xffffffff, acc.nan_count, mask, 32);
+    }
+    return acc;
+}
+
+// Combines the stats of the threads of the cuda block, which has at most 32 full warps, and writes
+// the min, max, sum, max-abs and NaN count to the five floats of stats. All the threads of the
+// block have to call this.
+static __device__ void block_reduce_stats(stats_acc acc, float * __restrict__ stats) {
+    __shared__ stats_acc warp_stats[32];
+    const int warp = threadIdx.x / WARP_SIZE;
+    const int lane = threadIdx.x % WARP_SIZE;
+    acc = warp_reduce_stats(acc);
+    if (lane == 0) {
+        warp_stats[warp] = acc;
+    }
+    __syncthreads();
+    if (warp != 0) {
+        return;
+    }
+    acc = lane < (int) (blockDim.x / WARP_SIZE) ? warp_stats[lane] : empty_stats();
+    acc = warp_reduce_stats(acc);
+    if (lane == 0) {
+        stats[0] = acc.min_v;
+        stats[1] = acc.max_v;
+        stats[2] = acc.sum;
+        stats[3] = acc.abs_max;
+        stats[4] = (float) acc.nan_count;
+    }
+}
+
+// Energy of the values written by a thread of the dequantize kernels, the sum of their squares
+// and their row, -1 while nothing has been written.
+struct energy_acc {
+    int row;
+    float sum;
+};
+
+// Output of the dequantize kernels which writes nothing and accumulates the square of each value
+// in the energy_acc of the current thread, element i being in row i / ncols. The threads only
+// write values of a single quant block so they stay on one row, a thread moving to another row
+// adds its current sum to energy directly.
+struct energy_ref {
+    float * energy;
+    energy_acc * acc;
+    int i;
+    int ncols;
+
+    __device__ void operator=(const float v) const {
+        const int row = i / ncols;
+        if (acc->row != row) {
+            if (acc->row >= 0) {
+                atomicAdd(energy + acc->row, acc->sum);
+            }
+            acc->row = row;
+            acc->sum = 0.0f;
+        }
+        acc->sum += v*v;
+    }
+};
+
+struct energy_out {
+    float * energy;
+    energy_acc * acc;
+    int ncols;
+    int offset;
+
+    __device__ energy_out operator+(const int o) const {
+        return {energy, acc, ncols, offset + o};
+    }
+
+    __device__ energy_ref operator[](const int l) const {
+        return {energy, acc, offset + l, ncols};
+    }
+};
+
+// Combines the energies of the threads of the cuda block, which has at most 32 full warps, with a
+// single atomic add per row covered by the block. All the threads of the block have to call this.
+static __device__ void block_reduce_energy(const energy_acc acc, float * __restrict__ energy) {
+    __shared__ int rows[2];
+    __shared__ float warp_sums[32];
+    const int warp = threadIdx.x / WARP_SIZE;
+    const int lane = threadIdx.x % WARP_SIZE;
+    if (threadIdx.x == 0) {
+        rows[0] = 0x7fffffff;
+        rows[1] = -1;
+    }
+    __syncthreads();
+    if (acc.row >= 0) {
+        atomicMin(&rows[0], acc.row);
+        atomicMax(&rows[1], acc.row);
+    }
+    __syncthreads();
+    const int first_row = rows[0];
+    const int last_row = rows[1];
+    for (int row = first_row; row <= last_row; ++row) {
+        float sum = warp_reduce_sum(acc.row == row ? acc.sum : 0.0f);
+        if (lane == 0) {
+            warp_sums[warp] = sum;
+        }
+        __syncthreads();
+        if (warp == 0) {
+            sum = lane < (int) (blockDim.x / WARP_SIZE) ? warp_sums[lane] : 0.0f;
+            sum = warp_reduce_sum(sum);
+            if (lane == 0) {
+                atomicAdd(energy + row, sum);
+            }
+        }
+        __syncthreads();
+    }
+}
+
+// Output of the dequantize kernels which clamps the written values to [min_v, max_v], infinite
+// values going to the nearest bound. NaN values are written as is and counted in nan_count.
+struct clamp_ref {
+    float * y;
+    float min_v;
+    float max_v;
+    unsigned int * nan_count;
+
+    __device__ void operator=(const float v) const {
+        if (isnan(v)) {
+            *y = v;
+            atomicAdd(nan_count, 1u);
+        } else {
+            *y = fminf(fmaxf(v, min_v), max_v);
+        }
+    }
+};
+
+struct clamp_out {
+    float * y;
+    float min_v;
+    float max_v;
+    unsigned int * nan_count;
+    int offset;
+
+    __device__ clamp_out operator+(const int o) const {
+        return {y, min_v, max_v, nan_count, offset + o};
+    }
+
+    __device__ clamp_ref operator[](const int l) const {
+        return {y + offset + l, min_v, max_v, nan_count};
+    }
+};
+
+// Output of the dequantize kernels which sets the subnormal values to zero, keeping their sign, as
+// done by gemms running in flush-to-zero mode.
+struct ftz_ref {
+    float * y;
+
+    __device__ void operator=(const float v) const {
+        // 1.17549435e-38f is the smallest normal float.
+        *y = v != 0.0f && fabsf(v) < 1.17549435e-38f ? copysignf(0.0f, v) : v;
+    }
+};
+
+struct ftz_out {
+    float * y;
+    int offset;
+
+    __device__ ftz_out operator+(const int o) const {
+        return {y, offset + o};
+    }
+
+    __device__ ftz_ref operator[](const int l) const {
+        return {y + offset + l};
+    }
+};
+
 template <int qk, int qr, dequantize_kernel_t dequantize_kernel, typename dst_t>
-static __device__ void dequantize_block(const void * __restrict__ vx, dst_t * __restrict__ y, const int k) {
+static __device__ void dequantize_block(const void * __restrict__ vx, dst_t y, const int k) {
     const int i = 2*(blockDim.x*blockIdx.x + threadIdx.x);
 
     if (i >= k) {
@@ -692,7 +936,8 @@ static __device__ void dequantize_block(const void * __restrict__ vx, dst_t * __
     y[iybs + iqs + y_offset] = v.y;
 }
 
-extern "C" __global__ void dequantize_block_q4_0(const void * __restrict__ vx, float * __restrict__ yy, int nb32) {
+template<typename dst_t>
+static __device__ void dequantize_block_q4_0_impl(const void * __restrict__ vx, dst_t yy, int nb32) {
 
     const int i = blockIdx.x;
 
@@ -705,7 +950,7 @@ extern "C" __global__ void dequantize_block_q4_0(const void * __restrict__ vx, f
         return;
     }
 
-    float * y = yy + 256*i + 32*ir + 4*il;
+    auto y = yy + 256*i + 32*ir + 4*il;
 
     const block_q4_0 * x = (const block_q4_0 *)vx + ib;
     const float d = __half2float(x->d);
@@ -719,7 +964,8 @@ extern "C" __global__ void dequantize_block_q4_0(const void * __restrict__ vx, f
     }
 }
 
-extern "C" __global__ void dequantize_block_q4_1(const void * __restrict__ vx, float * __restrict__ yy, int nb32) {
+template<typename dst_t>
+static __device__ void dequantize_block_q4_1_impl(const void * __restrict__ vx, dst_t yy, int nb32) {
 
     const int i = blockIdx.x;
 
@@ -732,7 +978,7 @@ extern "C" __global__ void dequantize_block_q4_1(const void * __restrict__ vx, f
         return;
     }
 
-    float * y = yy + 256*i + 32*ir + 4*il;
+    auto y = yy + 256*i + 32*ir + 4*il;
 
     const block_q4_1 * x = (const block_q4_1 *)vx + ib;
     const float2 d = __half22float2(x->dm);
@@ -747,7 +993,8 @@ extern "C" __global__ void dequantize_block_q4_1(const void * __restrict__ vx, f
 
 //================================== k-quants
 
-extern "C" __global__ void dequantize_block_q2_K(const void * __restrict__ vx, float * __restrict__ yy) {
+template<typename dst_t>
+static __device__ void dequantize_block_q2_K_impl(const void * __restrict__ vx, dst_t yy) {
 
     const int i   = blockIdx.x;
     const block_q2_K * x = (const block_q2_K *) vx;
@@ -759,7 +1006,7 @@ extern "C" __global__ void dequantize_block_q2_K(const void * __restrict__ vx, f
     const int is  = 8*n + l/16;
 
     const uint8_t q = x[i].qs[32*n + l];
-    float * y = yy + i*QK_K + 128*n;
+    auto y = yy + i*QK_K + 128*n;
 
     float dall = __low2half(x[i].dm);
     float dmin = __high2half(x[i].dm);
@@ -771,7 +1018,7 @@ extern "C" __global__ void dequantize_block_q2_K(const void * __restrict__ vx, f
     const int is = tid/16;  // 0 or 1
     const int il = tid%16;  // 0...15
     const uint8_t q = x[i].qs[il] >> (2*is);
-    float * y = yy + i*QK_K + 16*is + il;
+    auto y = yy + i*QK_K + 16*is + il;
     float dall = __low2half(x[i].dm);
     float dmin = __high2half(x[i].dm);
     y[ 0] = dall * (x[i].scales[is+0] & 0xF) * ((q >> 0) & 3) - dmin * (x[i].scales[is+0] >> 4);
@@ -780,7 +1027,8 @@ extern "C" __global__ void dequantize_block_q2_K(const void * __restrict__ vx, f
 
 }
 
-extern "C" __global__ void dequantize_block_q3_K(const void * __restrict__ vx, float * __restrict__ yy) {
+template<typename dst_t>
+static __device__ void dequantize_block_q3_K_impl(const void * __restrict__ vx, dst_t yy) {
 
     const int i = blockIdx.x;
     const block_q3_K * x = (const block_q3_K *) vx;
@@ -804,7 +1052,7 @@ extern "C" __global__ void dequantize_block_q3_K(const void * __restrict__ vx, f
     float d_all = x[i].d;
     float dl = d_all * (us - 32);
 
-    float * y = yy + i*QK_K + 128*n + 32*j;
+    auto y = yy + i*QK_K + 128*n + 32*j;
     const uint8_t * q = x[i].qs + 32*n;
     const uint8_t * hm = x[i].hmask;
 
@@ -816,7 +1064,7 @@ extern "C" __global__ void dequantize_block_q3_K(const void * __restrict__ vx, f
     const int im  = il/8;    // 0...1
     const int in  = il%8;    // 0...7
 
-    float * y = yy + i*QK_K + 16*is + il;
+    auto y = yy + i*QK_K + 16*is + il;
 
     const uint8_t q = x[i].qs[il] >> (2*is);
     const uint8_t h = x[i].hmask[in] >> (2*is + im);
@@ -844,7 +1092,8 @@ static inline __device__ void get_scale_min_k4(int j, const uint8_t * q, uint8_t
 }
 #endif
 
-extern "C" __global__ void dequantize_block_q4_K(const void * __restrict__ vx, float * __restrict__ yy) {
+template<typename dst_t>
+static __device__ void dequantize_block_q4_K_impl(const void * __restrict__ vx, dst_t yy) {
     const block_q4_K * x = (const block_q4_K *) vx;
 
     const int i = blockIdx.x;
@@ -857,7 +1106,7 @@ extern "C" __global__ void dequantize_block_q4_K(const void * __restrict__ vx, f
     const int is  = 2*il;
     const int n   = 4;
 
-    float * y = yy + i*QK_K + 64*il + n*ir;
+    auto y = yy + i*QK_K + 64*il + n*ir;
 
     const float dall = __low2half(x[i].dm);
     const float dmin = __high2half(x[i].dm);
@@ -876,7 +1125,7 @@ extern "C" __global__ void dequantize_block_q4_K(const void * __restrict__ vx, f
 #else
     const int tid = threadIdx.x;
     const uint8_t * q = x[i].qs;
-    float * y = yy + i*QK_K;
+    auto y = yy + i*QK_K;
     const float d = (float)x[i].dm[0];
     const float m = (float)x[i].dm[1];
     y[tid+ 0] = d * (x[i].scales[0] & 0xF) * (q[tid] & 0xF) - m * (x[i].scales[0] >> 4);
@@ -884,7 +1133,8 @@ extern "C" __global__ void dequantize_block_q4_K(const void * __restrict__ vx, f
 #endif
 }
 
-extern "C" __global__ void dequantize_block_q5_K(const void * __restrict__ vx, float * __restrict__ yy) {
+template<typename dst_t>
+static __device__ void dequantize_block_q5_K_impl(const void * __restrict__ vx, dst_t yy) {
     const block_q5_K * x = (const block_q5_K *) vx;
 
     const int i = blockIdx.x;
@@ -896,7 +1146,7 @@ extern "C" __global__ void dequantize_block_q5_K(const void * __restrict__ vx, f
     const int ir  = tid%16;   // ir is in 0...15
     const int is  = 2*il;     // is is in 0...6
 
-    float * y = yy + i*QK_K + 64*il + 2*ir;
+    auto y = yy + i*QK_K + 64*il + 2*ir;
 
     const float dall = __low2half(x[i].dm);
     const float dmin = __high2half(x[i].dm);
@@ -924,13 +1174,14 @@ extern "C" __global__ void dequantize_block_q5_K(const void * __restrict__ vx, f
     const int is = tid/16; // 0 or 1
     const uint8_t h = x[i].qh[in] >> im;
     const float d = x[i].d;
-    float * y = yy + i*QK_K + tid;
+    auto y = yy + i*QK_K + tid;
     y[ 0] = d * x[i].scales[is+0] * ((q & 0xF) - ((h >> 0) & 1 ? 0 : 16));
     y[32] = d * x[i].scales[is+2] * ((q >>  4) - ((h >> 4) & 1 ? 0 : 16));
 #endif
 }
 
-extern "C" __global__ void dequantize_block_q6_K(const void * __restrict__ vx, float * __restrict__ yy) {
+template<typename dst_t>
+static __device__ void dequantize_block_q6_K_impl(const void * __restrict__ vx, dst_t yy) {
     const block_q6_K * x = (const block_q6_K *) vx;
 
     const int i = blockIdx.x;
@@ -942,7 +1193,7 @@ extern "C" __global__ void dequantize_block_q6_K(const void * __restrict__ vx, f
     const int il  = tid - 32*ip; // 0...32
     const int is  = 8*ip + il/16;
 
-    float * y = yy + i*QK_K + 128*ip + il;
+    auto y = yy + i*QK_K + 128*ip + il;
 
     const float d = x[i].d;
 
@@ -961,7 +1212,7 @@ extern "C" __global__ void dequantize_block_q6_K(const void * __restrict__ vx, f
     const int ip  = tid/16;         // 0 or 1
     const int il  = tid - 16*ip;    // 0...15
 
-    float * y = yy + i*QK_K + 16*ip + il;
+    auto y = yy + i*QK_K + 16*ip + il;
 
     const float d = x[i].d;
 
@@ -974,7 +1225,8 @@ extern "C" __global__ void dequantize_block_q6_K(const void * __restrict__ vx, f
 #endif
 }
 
-extern "C" __global__ void dequantize_block_q8_0(const void * __restrict__ vx, float * __restrict__ yy, int nb32) {
+template<typename dst_t>
+static __device__ void dequantize_block_q8_0_impl(const void * __restrict__ vx, dst_t yy, int nb32) {
     const int i = blockIdx.x;
 
     // assume 32 threads
@@ -986,7 +1238,7 @@ extern "C" __global__ void dequantize_block_q8_0(const void * __restrict__ vx, f
         return;
     }
 
-    float * y = yy + 256*i + 32*ir + 8*il;
+    auto y = yy + 256*i + 32*ir + 8*il;
 
     const block_q8_0 * x = (const block_q8_0 *)vx + ib;
     const float d = __half2float(x->d);
@@ -998,7 +1250,33 @@ extern "C" __global__ void dequantize_block_q8_0(const void * __restrict__ vx, f
     }
 }
 
-extern "C" __global__ void dequantize_block_q8_K(const void * __restrict__ vx, float * __restrict__ yy) {
+template<typename dst_t>
+static __device__ void dequantize_block_q8_1_impl(const void * __restrict__ vx, dst_t yy, int nb32) {
+    const int i = blockIdx.x;
+
+    // assume 32 threads
+    const int tid = threadIdx.x;
+    const int il  = tid/8;
+    const int ir  = tid%8;
+    const int ib = 8*i + ir;
+    if (ib >= nb32) {
+        return;
+    }
+
+    auto y = yy + 256*i + 32*ir + 8*il;
+
+    const block_q8_1 * x = (const block_q8_1 *)vx + ib;
+    const float d = __low2float(x->ds);
+
+    const int8_t * q = x->qs + 8*il;
+
+    for (int l = 0; l < 8; ++l) {
+        y[l] = d * q[l];
+    }
+}
+
+template<typename dst_t>
+static __device__ void dequantize_block_q8_K_impl(const void * __restrict__ vx, dst_t yy) {
     const block_q8_K * x = (const block_q8_K *) vx;
 
     const int i = blockIdx.x;
@@ -1010,7 +1288,7 @@ extern "C" __global__ void dequantize_block_q8_K(const void * __restrict__ vx, f
     const int ir  = tid%8;
     const int n   = 8;
 
-    float * y = yy + i*QK_K + 64*il + n*ir;
+    auto y = yy + i*QK_K + 64*il + n*ir;
 
     const int8_t * q = x[i].qs + 64*il + n*ir;
 
@@ -1020,22 +1298,531 @@ extern "C" __global__ void dequantize_block_q8_K(const void * __restrict__ vx, f
 #else
     const int tid = threadIdx.x;
     const uint8_t * q = x[i].qs;
-    float * y = yy + i*QK_K;
+    auto y = yy + i*QK_K;
     y[tid] = x[i].d * x[i].scales[0];
 #endif
 }
 
+extern "C" __global__ void dequantize_block_q4_0(const void * __restrict__ vx, float * __restrict__ yy, int nb32) {
+    dequantize_block_q4_0_impl(vx, yy, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q4_0_f16(const void * __restrict__ vx, half * __restrict__ yy, int nb32) {
+    dequantize_block_q4_0_impl(vx, yy, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q4_1(const void * __restrict__ vx, float * __restrict__ yy, int nb32) {
+    dequantize_block_q4_1_impl(vx, yy, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q4_1_f16(const void * __restrict__ vx, half * __restrict__ yy, int nb32) {
+    dequantize_block_q4_1_impl(vx, yy, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q2_K(const void * __restrict__ vx, float * __restrict__ yy) {
+    dequantize_block_q2_K_impl(vx, yy);
+}
+
+extern "C" __global__ void dequantize_block_q2_K_f16(const void * __restrict__ vx, half * __restrict__ yy) {
+    dequantize_block_q2_K_impl(vx, yy);
+}
+
+extern "C" __global__ void dequantize_block_q3_K(const void * __restrict__ vx, float * __restrict__ yy) {
+    dequantize_block_q3_K_impl(vx, yy);
+}
+
+extern "C" __global__ void dequantize_block_q3_K_f16(const void * __restrict__ vx, half * __restrict__ yy) {
+    dequantize_block_q3_K_impl(vx, yy);
+}
+
+extern "C" __global__ void dequantize_block_q4_K(const void * __restrict__ vx, float * __restrict__ yy) {
+    dequantize_block_q4_K_impl(vx, yy);
+}
+
+extern "C" __global__ void dequantize_block_q4_K_f16(const void * __restrict__ vx, half * __restrict__ yy) {
+    dequantize_block_q4_K_impl(vx, yy);
+}
+
+extern "C" __global__ void dequantize_block_q5_K(const void * __restrict__ vx, float * __restrict__ yy) {
+    dequantize_block_q5_K_impl(vx, yy);
+}
+
+extern "C" __global__ void dequantize_block_q5_K_f16(const void * __restrict__ vx, half * __restrict__ yy) {
+    dequantize_block_q5_K_impl(vx, yy);
+}
+
+extern "C" __global__ void dequantize_block_q6_K(const void * __restrict__ vx, float * __restrict__ yy) {
+    dequantize_block_q6_K_impl(vx, yy);
+}
+
+extern "C" __global__ void dequantize_block_q6_K_f16(const void * __restrict__ vx, half * __restrict__ yy) {
+    dequantize_block_q6_K_impl(vx, yy);
+}
+
+extern "C" __global__ void dequantize_block_q8_0(const void * __restrict__ vx, float * __restrict__ yy, int nb32) {
+    dequantize_block_q8_0_impl(vx, yy, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q8_0_f16(const void * __restrict__ vx, half * __restrict__ yy, int nb32) {
+    dequantize_block_q8_0_impl(vx, yy, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q8_1(const void * __restrict__ vx, float * __restrict__ yy, int nb32) {
+    dequantize_block_q8_1_impl(vx, yy, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q8_1_f16(const void * __restrict__ vx, half * __restrict__ yy, int nb32) {
+    dequantize_block_q8_1_impl(vx, yy, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q8_K(const void * __restrict__ vx, float * __restrict__ yy) {
+    dequantize_block_q8_K_impl(vx, yy);
+}
+
+extern "C" __global__ void dequantize_block_q8_K_f16(const void * __restrict__ vx, half * __restrict__ yy) {
+    dequantize_block_q8_K_impl(vx, yy);
+}
+
 extern "C" __global__ void dequantize_block_q5_0(const void * __restrict__ vx, float * __restrict__ yy, int nb32) {
   return dequantize_block<QK5_0, QR5_0, dequantize_q5_0>(vx, yy, nb32);
 }
 
+extern "C" __global__ void dequantize_block_q5_0_f16(const void * __restrict__ vx, half * __restrict__ yy, int nb32) {
+  return dequantize_block<QK5_0, QR5_0, dequantize_q5_0>(vx, yy, nb32);
+}
+
 extern "C" __global__ void dequantize_block_q5_1(const void * __restrict__ vx, float * __restrict__ yy, int nb32) {
   return dequantize_block<QK5_1, QR5_1, dequantize_q5_1>(vx, yy, nb32);
 }
 
+extern "C" __global__ void dequantize_block_q5_1_f16(const void * __restrict__ vx, half * __restrict__ yy, int nb32) {
+  return dequantize_block<QK5_1, QR5_1, dequantize_q5_1>(vx, yy, nb32);
+}
+
+template<typename dst_t>
+static __device__ void dequantize_block_bf16_impl(const void * __restrict__ vx, dst_t yy, const int k) {
+    const int i = blockDim.x*blockIdx.x + threadIdx.x;
+    if (i >= k) {
+        return;
+    }
+    const __nv_bfloat16 * x = (const __nv_bfloat16 *) vx;
+    yy[i] = __bfloat162float(x[i]);
+}
+
+extern "C" __global__ void dequantize_block_bf16(const void * __restrict__ vx, float * __restrict__ yy, int k) {
+    dequantize_block_bf16_impl(vx, yy, k);
+}
+
+extern "C" __global__ void dequantize_block_bf16_f16(const void * __restrict__ vx, half * __restrict__ yy, int k) {
+    dequantize_block_bf16_impl(vx, yy, k);
+}
+
+// Column-major variants, the output is the transpose of the (nrows, ncols) row-major weights.
+extern "C" __global__ void dequantize_block_q4_0_colmajor(const void * __restrict__ vx, float * __restrict__ yy, int nb32, const int nrows, const int ncols) {
+    dequantize_block_q4_0_impl(vx, colmajor_out<float>{yy, nrows, ncols, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q4_1_colmajor(const void * __restrict__ vx, float * __restrict__ yy, int nb32, const int nrows, const int ncols) {
+    dequantize_block_q4_1_impl(vx, colmajor_out<float>{yy, nrows, ncols, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q5_0_colmajor(const void * __restrict__ vx, float * __restrict__ yy, int nb32, const int nrows, const int ncols) {
+    dequantize_block<QK5_0, QR5_0, dequantize_q5_0>(vx, colmajor_out<float>{yy, nrows, ncols, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q5_1_colmajor(const void * __restrict__ vx, float * __restrict__ yy, int nb32, const int nrows, const int ncols) {
+    dequantize_block<QK5_1, QR5_1, dequantize_q5_1>(vx, colmajor_out<float>{yy, nrows, ncols, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q8_0_colmajor(const void * __restrict__ vx, float * __restrict__ yy, int nb32, const int nrows, const int ncols) {
+    dequantize_block_q8_0_impl(vx, colmajor_out<float>{yy, nrows, ncols, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q8_1_colmajor(const void * __restrict__ vx, float * __restrict__ yy, int nb32, const int nrows, const int ncols) {
+    dequantize_block_q8_1_impl(vx, colmajor_out<float>{yy, nrows, ncols, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q2_K_colmajor(const void * __restrict__ vx, float * __restrict__ yy, const int nrows, const int ncols) {
+    dequantize_block_q2_K_impl(vx, colmajor_out<float>{yy, nrows, ncols, 0});
+}
+
+extern "C" __global__ void dequantize_block_q3_K_colmajor(const void * __restrict__ vx, float * __restrict__ yy, const int nrows, const int ncols) {
+    dequantize_block_q3_K_impl(vx, colmajor_out<float>{yy, nrows, ncols, 0});
+}
+
+extern "C" __global__ void dequantize_block_q4_K_colmajor(const void * __restrict__ vx, float * __restrict__ yy, const int nrows, const int ncols) {
+    dequantize_block_q4_K_impl(vx, colmajor_out<float>{yy, nrows, ncols, 0});
+}
+
+extern "C" __global__ void dequantize_block_q5_K_colmajor(const void * __restrict__ vx, float * __restrict__ yy, const int nrows, const int ncols) {
+    dequantize_block_q5_K_impl(vx, colmajor_out<float>{yy, nrows, ncols, 0});
+}
+
+extern "C" __global__ void dequantize_block_q6_K_colmajor(const void * __restrict__ vx, float * __restrict__ yy, const int nrows, const int ncols) {
+    dequantize_block_q6_K_impl(vx, colmajor_out<float>{yy, nrows, ncols, 0});
+}
+
+extern "C" __global__ void dequantize_block_q8_K_colmajor(const void * __restrict__ vx, float * __restrict__ yy, const int nrows, const int ncols) {
+    dequantize_block_q8_K_impl(vx, colmajor_out<float>{yy, nrows, ncols, 0});
+}
+
+extern "C" __global__ void dequantize_block_bf16_colmajor(const void * __restrict__ vx, float * __restrict__ yy, int k, const int nrows, const int ncols) {
+    dequantize_block_bf16_impl(vx, colmajor_out<float>{yy, nrows, ncols, 0}, k);
+}
+
+// Variants also computing the stats of the output, stats holds the min, max, sum, max-abs and NaN
+// count of the values written by each cuda block, see block_reduce_stats.
+extern "C" __global__ void dequantize_block_q4_0_stats(const void * __restrict__ vx, float * __restrict__ yy, int nb32, float * __restrict__ stats) {
+    stats_acc acc = empty_stats();
+    dequantize_block_q4_0_impl(vx, stats_out{yy, &acc, 0}, nb32);
+    block_reduce_stats(acc, stats + 5*blockIdx.x);
+}
+
+extern "C" __global__ void dequantize_block_q4_1_stats(const void * __restrict__ vx, float * __restrict__ yy, int nb32, float * __restrict__ stats) {
+    stats_acc acc = empty_stats();
+    dequantize_block_q4_1_impl(vx, stats_out{yy, &acc, 0}, nb32);
+    block_reduce_stats(acc, stats + 5*blockIdx.x);
+}
+
+extern "C" __global__ void dequantize_block_q5_0_stats(const void * __restrict__ vx, float * __restrict__ yy, int nb32, float * __restrict__ stats) {
+    stats_acc acc = empty_stats();
+    dequantize_block<QK5_0, QR5_0, dequantize_q5_0>(vx, stats_out{yy, &acc, 0}, nb32);
+    block_reduce_stats(acc, stats + 5*blockIdx.x);
+}
+
+extern "C" __global__ void dequantize_block_q5_1_stats(const void * __restrict__ vx, float * __restrict__ yy, int nb32, float * __restrict__ stats) {
+    stats_acc acc = empty_stats();
+    dequantize_block<QK5_1, QR5_1, dequantize_q5_1>(vx, stats_out{yy, &acc, 0}, nb32);
+    block_reduce_stats(acc, stats + 5*blockIdx.x);
+}
+
+extern "C" __global__ void dequantize_block_q8_0_stats(const void * __restrict__ vx, float * __restrict__ yy, int nb32, float * __restrict__ stats) {
+    stats_acc acc = empty_stats();
+    dequantize_block_q8_0_impl(vx, stats_out{yy, &acc, 0}, nb32);
+    block_reduce_stats(acc, stats + 5*blockIdx.x);
+}
+
+extern "C" __global__ void dequantize_block_q8_1_stats(const void * __restrict__ vx, float * __restrict__ yy, int nb32, float * __restrict__ stats) {
+    stats_acc acc = empty_stats();
+    dequantize_block_q8_1_impl(vx, stats_out{yy, &acc, 0}, nb32);
+    block_reduce_stats(acc, stats + 5*blockIdx.x);
+}
+
+extern "C" __global__ void dequantize_block_q2_K_stats(const void * __restrict__ vx, float * __restrict__ yy, float * __restrict__ stats) {
+    stats_acc acc = empty_stats();
+    dequantize_block_q2_K_impl(vx, stats_out{yy, &acc, 0});
+    block_reduce_stats(acc, stats + 5*blockIdx.x);
+}
+
+extern "C" __global__ void dequantize_block_q3_K_stats(const void * __restrict__ vx, float * __restrict__ yy, float * __restrict__ stats) {
+    stats_acc acc = empty_stats();
+    dequantize_block_q3_K_impl(vx, stats_out{yy, &acc, 0});
+    block_reduce_stats(acc, stats + 5*blockIdx.x);
+}
+
+extern "C" __global__ void dequantize_block_q4_K_stats(const void * __restrict__ vx, float * __restrict__ yy, float * __restrict__ stats) {
+    stats_acc acc = empty_stats();
+    dequantize_block_q4_K_impl(vx, stats_out{yy, &acc, 0});
+    block_reduce_stats(acc, stats + 5*blockIdx.x);
+}
+
+extern "C" __global__ void dequantize_block_q5_K_stats(const void * __restrict__ vx, float * __restrict__ yy, float * __restrict__ stats) {
+    stats_acc acc = empty_stats();
+    dequantize_block_q5_K_impl(vx, stats_out{yy, &acc, 0});
+    block_reduce_stats(acc, stats + 5*blockIdx.x);
+}
+
+extern "C" __global__ void dequantize_block_q6_K_stats(const void * __restrict__ vx, float * __restrict__ yy, float * __restrict__ stats) {
+    stats_acc acc = empty_stats();
+    dequantize_block_q6_K_impl(vx, stats_out{yy, &acc, 0});
+    block_reduce_stats(acc, stats + 5*blockIdx.x);
+}
+
+extern "C" __global__ void dequantize_block_q8_K_stats(const void * __restrict__ vx, float * __restrict__ yy, float * __restrict__ stats) {
+    stats_acc acc = empty_stats();
+    dequantize_block_q8_K_impl(vx, stats_out{yy, &acc, 0});
+    block_reduce_stats(acc, stats + 5*blockIdx.x);
+}
+
+extern "C" __global__ void dequantize_block_bf16_stats(const void * __restrict__ vx, float * __restrict__ yy, int k, float * __restrict__ stats) {
+    stats_acc acc = empty_stats();
+    dequantize_block_bf16_impl(vx, stats_out{yy, &acc, 0}, k);
+    block_reduce_stats(acc, stats + 5*blockIdx.x);
+}
+
+// Variants computing the energy of each row of a row-major (nrows, ncols) matrix, i.e. the sum of
+// the squares of its values, without writing the values. energy holds nrows zero-initialized floats.
+extern "C" __global__ void dequantize_block_q4_0_energy(const void * __restrict__ vx, float * __restrict__ energy, int nb32, const int ncols) {
+    energy_acc acc = {-1, 0.0f};
+    dequantize_block_q4_0_impl(vx, energy_out{energy, &acc, ncols, 0}, nb32);
+    block_reduce_energy(acc, energy);
+}
+
+extern "C" __global__ void dequantize_block_q4_1_energy(const void * __restrict__ vx, float * __restrict__ energy, int nb32, const int ncols) {
+    energy_acc acc = {-1, 0.0f};
+    dequantize_block_q4_1_impl(vx, energy_out{energy, &acc, ncols, 0}, nb32);
+    block_reduce_energy(acc, energy);
+}
+
+extern "C" __global__ void dequantize_block_q5_0_energy(const void * __restrict__ vx, float * __restrict__ energy, int nb32, const int ncols) {
+    energy_acc acc = {-1, 0.0f};
+    dequantize_block<QK5_0, QR5_0, dequantize_q5_0>(vx, energy_out{energy, &acc, ncols, 0}, nb32);
+    block_reduce_energy(acc, energy);
+}
+
+extern "C" __global__ void dequantize_block_q5_1_energy(const void * __restrict__ vx, float * __restrict__ energy, int nb32, const int ncols) {
+    energy_acc acc = {-1, 0.0f};
+    dequantize_block<QK5_1, QR5_1, dequantize_q5_1>(vx, energy_out{energy, &acc, ncols, 0}, nb32);
+    block_reduce_energy(acc, energy);
+}
+
+extern "C" __global__ void dequantize_block_q8_0_energy(const void * __restrict__ vx, float * __restrict__ energy, int nb32, const int ncols) {
+    energy_acc acc = {-1, 0.0f};
+    dequantize_block_q8_0_impl(vx, energy_out{energy, &acc, ncols, 0}, nb32);
+    block_reduce_energy(acc, energy);
+}
+
+extern "C" __global__ void dequantize_block_q8_1_energy(const void * __restrict__ vx, float * __restrict__ energy, int nb32, const int ncols) {
+    energy_acc acc = {-1, 0.0f};
+    dequantize_block_q8_1_impl(vx, energy_out{energy, &acc, ncols, 0}, nb32);
+    block_reduce_energy(acc, energy);
+}
+
+extern "C" __global__ void dequantize_block_q2_K_energy(const void * __restrict__ vx, float * __restrict__ energy, const int ncols) {
+    energy_acc acc = {-1, 0.0f};
+    dequantize_block_q2_K_impl(vx, energy_out{energy, &acc, ncols, 0});
+    block_reduce_energy(acc, energy);
+}
+
+extern "C" __global__ void dequantize_block_q3_K_energy(const void * __restrict__ vx, float * __restrict__ energy, const int ncols) {
+    energy_acc acc = {-1, 0.0f};
+    dequantize_block_q3_K_impl(vx, energy_out{energy, &acc, ncols, 0});
+    block_reduce_energy(acc, energy);
+}
+
+extern "C" __global__ void dequantize_block_q4_K_energy(const void * __restrict__ vx, float * __restrict__ energy, const int ncols) {
+    energy_acc acc = {-1, 0.0f};
+    dequantize_block_q4_K_impl(vx, energy_out{energy, &acc, ncols, 0});
+    block_reduce_energy(acc, energy);
+}
+
+extern "C" __global__ void dequantize_block_q5_K_energy(const void * __restrict__ vx, float * __restrict__ energy, const int ncols) {
+    energy_acc acc = {-1, 0.0f};
+    dequantize_block_q5_K_impl(vx, energy_out{energy, &acc, ncols, 0});
+    block_reduce_energy(acc, energy);
+}
+
+extern "C" __global__ void dequantize_block_q6_K_energy(const void * __restrict__ vx, float * __restrict__ energy, const int ncols) {
+    energy_acc acc = {-1, 0.0f};
+    dequantize_block_q6_K_impl(vx, energy_out{energy, &acc, ncols, 0});
+    block_reduce_energy(acc, energy);
+}
 
+extern "C" __global__ void dequantize_block_q8_K_energy(const void * __restrict__ vx, float * __restrict__ energy, const int ncols) {
+    energy_acc acc = {-1, 0.0f};
+    dequantize_block_q8_K_impl(vx, energy_out{energy, &acc, ncols, 0});
+    block_reduce_energy(acc, energy);
+}
+
+extern "C" __global__ void dequantize_block_bf16_energy(const void * __restrict__ vx, float * __restrict__ energy, int k, const int ncols) {
+    energy_acc acc = {-1, 0.0f};
+    dequantize_block_bf16_impl(vx, energy_out{energy, &acc, ncols, 0}, k);
+    block_reduce_energy(acc, energy);
+}
+
+// Variants clamping the output to [min_v, max_v], the NaN values are counted in the
+// zero-initialized nan_count.
+extern "C" __global__ void dequantize_block_q4_0_clamped(const void * __restrict__ vx, float * __restrict__ yy, int nb32, const float min_v, const float max_v, unsigned int * __restrict__ nan_count) {
+    dequantize_block_q4_0_impl(vx, clamp_out{yy, min_v, max_v, nan_count, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q4_1_clamped(const void * __restrict__ vx, float * __restrict__ yy, int nb32, const float min_v, const float max_v, unsigned int * __restrict__ nan_count) {
+    dequantize_block_q4_1_impl(vx, clamp_out{yy, min_v, max_v, nan_count, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q5_0_clamped(const void * __restrict__ vx, float * __restrict__ yy, int nb32, const float min_v, const float max_v, unsigned int * __restrict__ nan_count) {
+    dequantize_block<QK5_0, QR5_0, dequantize_q5_0>(vx, clamp_out{yy, min_v, max_v, nan_count, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q5_1_clamped(const void * __restrict__ vx, float * __restrict__ yy, int nb32, const float min_v, const float max_v, unsigned int * __restrict__ nan_count) {
+    dequantize_block<QK5_1, QR5_1, dequantize_q5_1>(vx, clamp_out{yy, min_v, max_v, nan_count, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q8_0_clamped(const void * __restrict__ vx, float * __restrict__ yy, int nb32, const float min_v, const float max_v, unsigned int * __restrict__ nan_count) {
+    dequantize_block_q8_0_impl(vx, clamp_out{yy, min_v, max_v, nan_count, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q8_1_clamped(const void * __restrict__ vx, float * __restrict__ yy, int nb32, const float min_v, const float max_v, unsigned int * __restrict__ nan_count) {
+    dequantize_block_q8_1_impl(vx, clamp_out{yy, min_v, max_v, nan_count, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q2_K_clamped(const void * __restrict__ vx, float * __restrict__ yy, const float min_v, const float max_v, unsigned int * __restrict__ nan_count) {
+    dequantize_block_q2_K_impl(vx, clamp_out{yy, min_v, max_v, nan_count, 0});
+}
+
+extern "C" __global__ void dequantize_block_q3_K_clamped(const void * __restrict__ vx, float * __restrict__ yy, const float min_v, const float max_v, unsigned int * __restrict__ nan_count) {
+    dequantize_block_q3_K_impl(vx, clamp_out{yy, min_v, max_v, nan_count, 0});
+}
+
+extern "C" __global__ void dequantize_block_q4_K_clamped(const void * __restrict__ vx, float * __restrict__ yy, const float min_v, const float max_v, unsigned int * __restrict__ nan_count) {
+    dequantize_block_q4_K_impl(vx, clamp_out{yy, min_v, max_v, nan_count, 0});
+}
+
+extern "C" __global__ void dequantize_block_q5_K_clamped(const void * __restrict__ vx, float * __restrict__ yy, const float min_v, const float max_v, unsigned int * __restrict__ nan_count) {
+    dequantize_block_q5_K_impl(vx, clamp_out{yy, min_v, max_v, nan_count, 0});
+}
+
+extern "C" __global__ void dequantize_block_q6_K_clamped(const void * __restrict__ vx, float * __restrict__ yy, const float min_v, const float max_v, unsigned int * __restrict__ nan_count) {
+    dequantize_block_q6_K_impl(vx, clamp_out{yy, min_v, max_v, nan_count, 0});
+}
+
+extern "C" __global__ void dequantize_block_q8_K_clamped(const void * __restrict__ vx, float * __restrict__ yy, const float min_v, const float max_v, unsigned int * __restrict__ nan_count) {
+    dequantize_block_q8_K_impl(vx, clamp_out{yy, min_v, max_v, nan_count, 0});
+}
+
+extern "C" __global__ void dequantize_block_bf16_clamped(const void * __restrict__ vx, float * __restrict__ yy, int k, const float min_v, const float max_v, unsigned int * __restrict__ nan_count) {
+    dequantize_block_bf16_impl(vx, clamp_out{yy, min_v, max_v, nan_count, 0}, k);
+}
+
+// Variants flushing the subnormal values to zero as they are written, see ftz_out.
+extern "C" __global__ void dequantize_block_q4_0_ftz(const void * __restrict__ vx, float * __restrict__ yy, int nb32) {
+    dequantize_block_q4_0_impl(vx, ftz_out{yy, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q4_1_ftz(const void * __restrict__ vx, float * __restrict__ yy, int nb32) {
+    dequantize_block_q4_1_impl(vx, ftz_out{yy, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q5_0_ftz(const void * __restrict__ vx, float * __restrict__ yy, int nb32) {
+    dequantize_block<QK5_0, QR5_0, dequantize_q5_0>(vx, ftz_out{yy, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q5_1_ftz(const void * __restrict__ vx, float * __restrict__ yy, int nb32) {
+    dequantize_block<QK5_1, QR5_1, dequantize_q5_1>(vx, ftz_out{yy, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q8_0_ftz(const void * __restrict__ vx, float * __restrict__ yy, int nb32) {
+    dequantize_block_q8_0_impl(vx, ftz_out{yy, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q8_1_ftz(const void * __restrict__ vx, float * __restrict__ yy, int nb32) {
+    dequantize_block_q8_1_impl(vx, ftz_out{yy, 0}, nb32);
+}
+
+extern "C" __global__ void dequantize_block_q2_K_ftz(const void * __restrict__ vx, float * __restrict__ yy) {
+    dequantize_block_q2_K_impl(vx, ftz_out{yy, 0});
+}
+
+extern "C" __global__ void dequantize_block_q3_K_ftz(const void * __restrict__ vx, float * __restrict__ yy) {
+    dequantize_block_q3_K_impl(vx, ftz_out{yy, 0});
+}
+
+extern "C" __global__ void dequantize_block_q4_K_ftz(const void * __restrict__ vx, float * __restrict__ yy) {
+    dequantize_block_q4_K_impl(vx, ftz_out{yy, 0});
+}
+
+extern "C" __global__ void dequantize_block_q5_K_ftz(const void * __restrict__ vx, float * __restrict__ yy) {
+    dequantize_block_q5_K_impl(vx, ftz_out{yy, 0});
+}
+
+extern "C" __global__ void dequantize_block_q6_K_ftz(const void * __restrict__ vx, float * __restrict__ yy) {
+    dequantize_block_q6_K_impl(vx, ftz_out{yy, 0});
+}
+
+extern "C" __global__ void dequantize_block_q8_K_ftz(const void * __restrict__ vx, float * __restrict__ yy) {
+    dequantize_block_q8_K_impl(vx, ftz_out{yy, 0});
+}
+
+extern "C" __global__ void dequantize_block_bf16_ftz(const void * __restrict__ vx, float * __restrict__ yy, int k) {
+    dequantize_block_bf16_impl(vx, ftz_out{yy, 0}, k);
+}
+
+// Extraction of the block scales, one thread per scale. The k-quants have scales_per_block sub-block
+// scales per super-block, they are written in the order of the scale fields of the block and
+// multiplied by the super-block scale. The mins of the blocks are left out.
+template<typename block_t, int scales_per_block, typename F>
+static __device__ void dequantize_scales_impl(const void * __restrict__ vx, float * __restrict__ y, const int n_scales, F scale) {
+    const int ix = blockDim.x*blockIdx.x + threadIdx.x;
+    if (ix >= n_scales) {
+        return;
+    }
+    const block_t * x = (const block_t *) vx;
+    y[ix] = scale(x[ix / scales_per_block], ix % scales_per_block);
+}
+
+extern "C" __global__ void dequantize_scales_q4_0(const void * __restrict__ vx, float * __restrict__ y, const int n_scales) {
+    dequantize_scales_impl<block_q4_0, 1>(vx, y, n_scales, [](const block_q4_0 & b, int) { return __half2float(b.d); });
+}
+
+extern "C" __global__ void dequantize_scales_q4_1(const void * __restrict__ vx, float * __restrict__ y, const int n_scales) {
+    dequantize_scales_impl<block_q4_1, 1>(vx, y, n_scales, [](const block_q4_1 & b, int) { return __low2float(b.dm); });
+}
+
+extern "C" __global__ void dequantize_scales_q5_0(const void * __restrict__ vx, float * __restrict__ y, const int n_scales) {
+    dequantize_scales_impl<block_q5_0, 1>(vx, y, n_scales, [](const block_q5_0 & b, int) { return __half2float(b.d); });
+}
+
+extern "C" __global__ void dequantize_scales_q5_1(const void * __restrict__ vx, float * __restrict__ y, const int n_scales) {
+    dequantize_scales_impl<block_q5_1, 1>(vx, y, n_scales, [](const block_q5_1 & b, int) { return __low2float(b.dm); });
+}
+
+extern "C" __global__ void dequantize_scales_q8_0(const void * __restrict__ vx, float * __restrict__ y, const int n_scales) {
+    dequantize_scales_impl<block_q8_0, 1>(vx, y, n_scales, [](const block_q8_0 & b, int) { return __half2float(b.d); });
+}
+
+extern "C" __global__ void dequantize_scales_q8_1(const void * __restrict__ vx, float * __restrict__ y, const int n_scales) {
+    dequantize_scales_impl<block_q8_1, 1>(vx, y, n_scales, [](const block_q8_1 & b, int) { return __low2float(b.ds); });
+}
+
+#if QK_K == 256
+extern "C" __global__ void dequantize_scales_q2_K(const void * __restrict__ vx, float * __restrict__ y, const int n_scales) {
+    dequantize_scales_impl<block_q2_K, QK_K/16>(vx, y, n_scales, [](const block_q2_K & b, int is) {
+        return __low2float(b.dm) * (b.scales[is] & 0xF);
+    });
+}
+
+extern "C" __global__ void dequantize_scales_q3_K(const void * __restrict__ vx, float * __restrict__ y, const int n_scales) {
+    dequantize_scales_impl<block_q3_K, QK_K/16>(vx, y, n_scales, [](const block_q3_K & b, int is) {
+        const int8_t us = is <  4 ? (b.scales[is-0] & 0xF) | (((b.scales[is+8] >> 0) & 3) << 4) :
+                          is <  8 ? (b.scales[is-0] & 0xF) | (((b.scales[is+4] >> 2) & 3) << 4) :
+                          is < 12 ? (b.scales[is-8] >>  4) | (((b.scales[is+0] >> 4) & 3) << 4) :
+                                    (b.scales[is-8] >>  4) | (((b.scales[is-4] >> 6) & 3) << 4);
+        return __half2float(b.d) * (us - 32);
+    });
+}
+
+extern "C" __global__ void dequantize_scales_q4_K(const void * __restrict__ vx, float * __restrict__ y, const int n_scales) {
+    dequantize_scales_impl<block_q4_K, QK_K/32>(vx, y, n_scales, [](const block_q4_K & b, int is) {
+        uint8_t sc, m;
+        get_scale_min_k4(is, b.scales, sc, m);
+        return __low2float(b.dm) * sc;
+    });
+}
+
+extern "C" __global__ void dequantize_scales_q5_K(const void * __restrict__ vx, float * __restrict__ y, const int n_scales) {
+    dequantize_scales_impl<block_q5_K, QK_K/32>(vx, y, n_scales, [](const block_q5_K & b, int is) {
+        uint8_t sc, m;
+        get_scale_min_k4(is, b.scales, sc, m);
+        return __low2float(b.dm) * sc;
+    });
+}
+#endif
+
+extern "C" __global__ void dequantize_scales_q6_K(const void * __restrict__ vx, float * __restrict__ y, const int n_scales) {
+    dequantize_scales_impl<block_q6_K, QK_K/16>(vx, y, n_scales, [](const block_q6_K & b, int is) {
+        return __half2float(b.d) * b.scales[is];
+    });
+}
+
+extern "C" __global__ void dequantize_scales_q8_K(const void * __restrict__ vx, float * __restrict__ y, const int n_scales) {
+    dequantize_scales_impl<block_q8_K, 1>(vx, y, n_scales, [](const block_q8_K & b, int) { return b.d; });
+}
+
+
+// The matmul-vec kernels write zeros instead of the dot products for the rows from valid_rows on,
+// pass nrows to write every row.
 template <int qk, int qr, dequantize_kernel_t dequantize_kernel>
-static __device__ void dequantize_mul_mat_vec(const void * __restrict__ vx, const dfloat * __restrict__ y, float * __restrict__ dst, const int ncols, const int nrows) {
+static __device__ void dequantize_mul_mat_vec(const void * __restrict__ vx, const dfloat * __restrict__ y, float * __restrict__ dst, const int ncols, const int nrows, const float * __restrict__ bias, const int valid_rows) {
     // qk = quantized weights per x block
     // qr = number of quantized weights per data value in x block
     const int row = blockIdx.x*blockDim.y + threadIdx.y;
@@ -1095,38 +1882,38 @@ static __device__ void dequantize_mul_mat_vec(const void * __restrict__ vx, cons
 
     if (tid == 0) {
 #ifdef GGML_CUDA_F16
-        dst[row] = tmp.x + tmp.y;
+        dst[row] = row < valid_rows ? tmp.x + tmp.y + (bias ? bias[row] : 0.0f) : 0.0f;
 #else
-        dst[row] = tmp;
+        dst[row] = row < valid_rows ? tmp + (bias ? bias[row] : 0.0f) : 0.0f;
 #endif // GGML_CUDA_F16
     }
 }
 
-extern "C" __global__ void dequantize_mul_mat_vec_q4_0_cuda(const void * vx, const dfloat * y, float * dst, const int ncols, const int nrows) {
-    dequantize_mul_mat_vec<QK4_0, QR4_0, dequantize_q4_0>(vx, y, dst, ncols, nrows);
+extern "C" __global__ void dequantize_mul_mat_vec_q4_0_cuda(const void * vx, const dfloat * y, float * dst, const int ncols, const int nrows, const float * bias, const int valid_rows) {
+    dequantize_mul_mat_vec<QK4_0, QR4_0, dequantize_q4_0>(vx, y, dst, ncols, nrows, bias, valid_rows);
 }
 
-extern "C" __global__ void dequantize_mul_mat_vec_q4_1_cuda(const void * vx, const dfloat * y, float * dst, const int ncols, const int nrows) {
-    dequantize_mul_mat_vec<QK4_1, QR4_1, dequantize_q4_1>(vx, y, dst, ncols, nrows);
+extern "C" __global__ void dequantize_mul_mat_vec_q4_1_cuda(const void * vx, const dfloat * y, float * dst, const int ncols, const int nrows, const float * bias, const int valid_rows) {
+    dequantize_mul_mat_vec<QK4_1, QR4_1, dequantize_q4_1>(vx, y, dst, ncols, nrows, bias, valid_rows);
 }
 
-extern "C" __global__ void dequantize_mul_mat_vec_q5_0_cuda(const void * vx, const dfloat * y, float * dst, const int ncols, const int nrows) {
-    dequantize_mul_mat_vec<QK5_0, QR5_0, dequantize_q5_0>(vx, y, dst, ncols, nrows);
+extern "C" __global__ void dequantize_mul_mat_vec_q5_0_cuda(const void * vx, const dfloat * y, float * dst, const int ncols, const int nrows, const float * bias, const int valid_rows) {
+    dequantize_mul_mat_vec<QK5_0, QR5_0, dequantize_q5_0>(vx, y, dst, ncols, nrows, bias, valid_rows);
 }
 
-extern "C" __global__ void dequantize_mul_mat_vec_q5_1_cuda(const void * vx, const dfloat * y, float * dst, const int ncols, const int nrows) {
-    dequantize_mul_mat_vec<QK5_1, QR5_1, dequantize_q5_1>(vx, y, dst, ncols, nrows);
+extern "C" __global__ void dequantize_mul_mat_vec_q5_1_cuda(const void * vx, const dfloat * y, float * dst, const int ncols, const int nrows, const float * bias, const int valid_rows) {
+    dequantize_mul_mat_vec<QK5_1, QR5_1, dequantize_q5_1>(vx, y, dst, ncols, nrows, bias, valid_rows);
 }
-extern "C" __global__ void dequantize_mul_mat_vec_q8_0_cuda(const void * vx, const dfloat * y, float * dst, const int ncols, const int nrows) {
-    dequantize_mul_mat_vec<QK8_0, QR8_0, dequantize_q8_0>(vx, y, dst, ncols, nrows);
+extern "C" __global__ void dequantize_mul_mat_vec_q8_0_cuda(const void * vx, const dfloat * y, float * dst, const int ncols, const int nrows, const float * bias, const int valid_rows) {
+    dequantize_mul_mat_vec<QK8_0, QR8_0, dequantize_q8_0>(vx, y, dst, ncols, nrows, bias, valid_rows);
 }
 
-extern "C" __global__ void dequantize_mul_mat_vec_q2_k(const void * __restrict__ vx, const float * __restrict__ yy, float * __restrict__ dst, const int ncols, int nrows) {
+extern "C" __global__ void dequantize_mul_mat_vec_q2_k(const void * __restrict__ vx, const float * __restrict__ yy, float * __restrict__ dst, const int ncols, int nrows, const float * __restrict__ bias, const int valid_rows) {
 
     static_assert(16%K_QUANTS_PER_ITERATION == 0, "16 must be divisible by K_QUANTS_PER_ITERATION");
 
     const int row = blockIdx.x*blockDim.y + threadIdx.y;
-    if (row > nrows) return;
+    if (row >= nrows) return;
 
     const int num_blocks_per_row = ncols / QK_K;
     const int ib0 = row*num_blocks_per_row;
@@ -1223,14 +2010,14 @@ extern "C" __global__ void dequantize_mul_mat_vec_q2_k(const void * __restrict__
     }
 
     if (threadIdx.x == 0) {
-        dst[row] = tmp;
+        dst[row] = row < valid_rows ? tmp + (bias ? bias[row] : 0.0f) : 0.0f;
     }
 }
 
-extern "C" __global__ void dequantize_mul_mat_vec_q3_k(const void * __restrict__ vx, const float * __restrict__ yy, float * __restrict__ dst, const int ncols, int nrows) {
+extern "C" __global__ void dequantize_mul_mat_vec_q3_k(const void * __restrict__ vx, const float * __restrict__ yy, float * __restrict__ dst, const int ncols, int nrows, const float * __restrict__ bias, const int valid_rows) {
 
     const int row = blockIdx.x*blockDim.y + threadIdx.y;
-    if (row > nrows) return;
+    if (row >= nrows) return;
 
     const int num_blocks_per_row = ncols / QK_K;
     const int ib0 = row*num_blocks_per_row;
@@ -1327,14 +2114,14 @@ extern "C" __global__ void dequantize_mul_mat_vec_q3_k(const void * __restrict__
     }
 
     if (threadIdx.x == 0) {
-        dst[row] = tmp;
+        dst[row] = row < valid_rows ? tmp + (bias ? bias[row] : 0.0f) : 0.0f;
     }
 }
 
-extern "C" __global__ void dequantize_mul_mat_vec_q4_k(const void * __restrict__ vx, const float * __restrict__ yy, float * __restrict__ dst, const int ncols, int nrows) {
+extern "C" __global__ void dequantize_mul_mat_vec_q4_k(const void * __restrict__ vx, const float * __restrict__ yy, float * __restrict__ dst, const int ncols, int nrows, const float * __restrict__ bias, const int valid_rows) {
 
     const int row = blockIdx.x*blockDim.y + threadIdx.y;
-    if (row > nrows) return;
+    if (row >= nrows) return;
     const int num_blocks_per_row = ncols / QK_K;
     const int ib0 = row*num_blocks_per_row;
 
@@ -1463,11 +2250,11 @@ extern "C" __global__ void dequantize_mul_mat_vec_q4_k(const void * __restrict__
     }
 
     if (tid == 0) {
-        dst[row] = tmp;
+        dst[row] = row < valid_rows ? tmp + (bias ? bias[row] : 0.0f) : 0.0f;
     }
 }
 
-extern "C" __global__ void dequantize_mul_mat_vec_q5_k(const void * __restrict__ vx, const float * __restrict__ yy, float * __restrict__ dst, const int ncols) {
+extern "C" __global__ void dequantize_mul_mat_vec_q5_k(const void * __restrict__ vx, const float * __restrict__ yy, float * __restrict__ dst, const int ncols, int nrows, const float * __restrict__ bias, const int valid_rows) {
 
     const int row = blockIdx.x;
     const int num_blocks_per_row = ncols / QK_K;
@@ -1579,16 +2366,16 @@ extern "C" __global__ void dequantize_mul_mat_vec_q5_k(const void * __restrict__
     }
 
     if (threadIdx.x == 0) {
-        dst[row] = tmp;
+        dst[row] = row < valid_rows ? tmp + (bias ? bias[row] : 0.0f) : 0.0f;
     }
 }
 
-extern "C" __global__ void dequantize_mul_mat_vec_q6_k(const void * __restrict__ vx, const float * __restrict__ yy, float * __restrict__ dst, const int ncols, int nrows) {
+extern "C" __global__ void dequantize_mul_mat_vec_q6_k(const void * __restrict__ vx, const float * __restrict__ yy, float * __restrict__ dst, const int ncols, int nrows, const float * __restrict__ bias, const int valid_rows) {
 
     static_assert(16%K_QUANTS_PER_ITERATION == 0, "16 must be divisible by K_QUANTS_PER_ITERATION");
 
     const int row = blockIdx.x*blockDim.y + threadIdx.y;
-    if (row > nrows) return;
+    if (row >= nrows) return;
 
     const int num_blocks_per_row = ncols / QK_K;
     const int ib0 = row*num_blocks_per_row;
@@ -1689,7 +2476,7 @@ extern "C" __global__ void dequantize_mul_mat_vec_q6_k(const void * __restrict__
     }
 
     if (tid == 0) {
-        dst[row] = tmp;
+        dst[row] = row < valid_rows ? tmp + (bias ? bias[row] : 0.0f) : 0.0f;
     }
 }
 
@@ -2501,24 +3288,29 @@ static __device__ __forceinline__ float vec_dot_q6_K_q8_1(
 // https://github.com/ggerganov/llama.cpp/blob/c50a82ce0f71558cbb8e555146ba124251504b38/ggml-cuda/mmvq.cu#L4
 typedef float (*vec_dot_q_cuda_t)(const void * __restrict__ vbq, const block_q8_1 * __restrict__ bq8_1, const int & iqs);
 
+#define MMVQ_MAX_NWARPS 8
+
 template <int ncols_y, int qk, int qi, typename block_q_t, int vdr, vec_dot_q_cuda_t vec_dot_q_cuda>
 static __device__ void mul_mat_vec_q(
     const void * __restrict__ vx, const void * __restrict__ vy, float * __restrict__ dst,
-    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst) {
+    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst,
+    const float * __restrict__ bias, const int valid_rows) {
 
 #if defined(GGML_USE_HIPBLAS) && defined(__HIP_PLATFORM_AMD__) && (defined(RDNA2) || defined(RDNA3))
-    constexpr int nwarps              = 1;
     constexpr int rows_per_cuda_block = 1;
 #else
-    constexpr int nwarps              = ncols_y <= 4 ? 4 : 2;
     constexpr int rows_per_cuda_block = ncols_y == 1 ? 1 : 2;
 #endif // defined(GGML_USE_HIPBLAS) && defined(__HIP_PLATFORM_AMD__) && !defined(RDNA2) && !defined(RDNA3)
 
+    // The number of warps is set by the launch config, up to MMVQ_MAX_NWARPS.
+    const     int nwarps = blockDim.y;
     const     int tid = WARP_SIZE*threadIdx.y + threadIdx.x;
     const     int row0 = rows_per_cuda_block*blockIdx.x;
-    const     int blocks_per_row_x = ncols_x / qk;
+    // A partial block at the end of the rows is read whole, the q8_1 activation is zero padded
+    // past ncols_x so the weights in the padding do not contribute.
+    const     int blocks_per_row_x = (ncols_x + qk - 1) / qk;
     const     int blocks_per_col_y = nrows_y / QK8_1;
-    constexpr int blocks_per_iter = vdr * nwarps*WARP_SIZE / qi;
+    const     int blocks_per_iter = vdr * nwarps*WARP_SIZE / qi;
 
 // partial sum for each thread
     float tmp[ncols_y][rows_per_cuda_block] = {0.0f};
@@ -2536,13 +3328,16 @@ static __device__ void mul_mat_vec_q(
         for (int j = 0; j < ncols_y; ++j) {
 #pragma unroll
             for (int i = 0; i < rows_per_cuda_block; ++i) {
-                tmp[j][i] += vec_dot_q_cuda(
-                    &x[kbx + (row0 + i)*blocks_per_row_x], &y[j*blocks_per_col_y + kby], kqs);
+                // The last cuda block has a single row when nrows_x is odd.
+                if (row0 + i < nrows_x) {
+                    tmp[j][i] += vec_dot_q_cuda(
+                        &x[kbx + (row0 + i)*blocks_per_row_x], &y[j*blocks_per_col_y + kby], kqs);
+                }
             }
         }
     }
 
-    __shared__ float tmp_shared[nwarps-1 > 0 ? nwarps-1 : 1][ncols_y][rows_per_cuda_block][WARP_SIZE];
+    __shared__ float tmp_shared[MMVQ_MAX_NWARPS-1][ncols_y][rows_per_cuda_block][WARP_SIZE];
     if (threadIdx.y > 0) {
 #pragma unroll
         for (int j = 0; j < ncols_y; ++j) {
@@ -2562,116 +3357,223 @@ static __device__ void mul_mat_vec_q(
     for (int j = 0; j < ncols_y; ++j) {
 #pragma unroll
         for (int i = 0; i < rows_per_cuda_block; ++i) {
-#pragma unroll
             for (int l = 0; l < nwarps-1; ++l) {
                 tmp[j][i] += tmp_shared[l][j][i][threadIdx.x];
             }
             tmp[j][i] = warp_reduce_sum(tmp[j][i]);
         }
 
-        if (threadIdx.x < rows_per_cuda_block) {
-            dst[j*nrows_dst + row0 + threadIdx.x] = tmp[j][threadIdx.x];
+        if (threadIdx.x < rows_per_cuda_block && row0 + threadIdx.x < nrows_x) {
+            const int row = row0 + threadIdx.x;
+            dst[j*nrows_dst + row] = row < valid_rows ? tmp[j][threadIdx.x] + (bias ? bias[row] : 0.0f) : 0.0f;
         }
     }
 }
 
 extern "C" __global__ void mul_mat_vec_q4_0_q8_1_cuda(
     const void * vx, const void * vy, float * dst,
-    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst) {
+    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst,
+    const float * bias, const int valid_rows) {
 
     mul_mat_vec_q<1, QK4_0, QI4_0, block_q4_0, VDR_Q4_0_Q8_1_MMVQ, vec_dot_q4_0_q8_1>
-        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst);
+        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst, bias, valid_rows);
 }
 
 extern "C" __global__ void mul_mat_vec_q4_1_q8_1_cuda(
     const void * vx, const void * vy, float * dst,
-    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst) {
+    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst,
+    const float * bias, const int valid_rows) {
 
     mul_mat_vec_q<1, QK4_1, QI4_1, block_q4_1, VDR_Q4_1_Q8_1_MMVQ, vec_dot_q4_1_q8_1>
-        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst);
+        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst, bias, valid_rows);
 }
 
 extern "C" __global__ void mul_mat_vec_q5_0_q8_1_cuda(
     const void * vx, const void * vy, float * dst,
-    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst) {
+    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst,
+    const float * bias, const int valid_rows) {
 
     mul_mat_vec_q<1, QK5_0, QI5_0, block_q5_0, VDR_Q5_0_Q8_1_MMVQ, vec_dot_q5_0_q8_1>
-        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst);
+        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst, bias, valid_rows);
 }
 
 extern "C" __global__ void mul_mat_vec_q5_1_q8_1_cuda(
     const void * vx, const void * vy, float * dst,
-    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst) {
+    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst,
+    const float * bias, const int valid_rows) {
 
     mul_mat_vec_q<1, QK5_1, QI5_1, block_q5_1, VDR_Q5_1_Q8_1_MMVQ, vec_dot_q5_1_q8_1>
-        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst);
+        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst, bias, valid_rows);
 }
 
 extern "C" __global__ void mul_mat_vec_q8_0_q8_1_cuda(
     const void * vx, const void * vy, float * dst,
-    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst) {
+    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst,
+    const float * bias, const int valid_rows) {
 
     mul_mat_vec_q<1, QK8_0, QI8_0, block_q8_0, VDR_Q8_0_Q8_1_MMVQ, vec_dot_q8_0_q8_1>
-        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst);
+        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst, bias, valid_rows);
 }
 
 extern "C" __global__ void mul_mat_vec_q2_K_q8_1_cuda(
     const void * vx, const void * vy, float * dst,
-    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst) {
+    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst,
+    const float * bias, const int valid_rows) {
 
     mul_mat_vec_q<1, QK_K, QI2_K, block_q2_K, VDR_Q2_K_Q8_1_MMVQ, vec_dot_q2_K_q8_1>
-        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst);
+        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst, bias, valid_rows);
 }
 
 extern "C" __global__ void mul_mat_vec_q3_K_q8_1_cuda(
     const void * vx, const void * vy, float * dst,
-    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst) {
+    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst,
+    const float * bias, const int valid_rows) {
 
     mul_mat_vec_q<1, QK_K, QI3_K, block_q3_K, VDR_Q3_K_Q8_1_MMVQ, vec_dot_q3_K_q8_1>
-        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst);
+        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst, bias, valid_rows);
 }
 
 extern "C" __global__ void mul_mat_vec_q4_K_q8_1_cuda(
     const void * vx, const void * vy, float * dst,
-    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst) {
+    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst,
+    const float * bias, const int valid_rows) {
 
     mul_mat_vec_q<1, QK_K, QI4_K, block_q4_K, VDR_Q4_K_Q8_1_MMVQ, vec_dot_q4_K_q8_1>
-        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst);
+        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst, bias, valid_rows);
 }
 
 extern "C" __global__ void mul_mat_vec_q5_K_q8_1_cuda(
     const void * vx, const void * vy, float * dst,
-    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst) {
+    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst,
+    const float * bias, const int valid_rows) {
 
     mul_mat_vec_q<1, QK_K, QI5_K, block_q5_K, VDR_Q5_K_Q8_1_MMVQ, vec_dot_q5_K_q8_1>
-        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst);
+        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst, bias, valid_rows);
 }
 
 extern "C" __global__ void mul_mat_vec_q6_K_q8_1_cuda(
     const void * vx, const void * vy, float * dst,
-    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst) {
+    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst,
+    const float * bias, const int valid_rows) {
 
     mul_mat_vec_q<1, QK_K, QI6_K, block_q6_K, VDR_Q6_K_Q8_1_MMVQ, vec_dot_q6_K_q8_1>
-        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst);
+        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst, bias, valid_rows);
+}
+
+// The dtypes of the q8_1 matmul-vec kernels with their template parameters, X is applied to each
+// row to instantiate a family of kernels for all of them.
+#define MMVQ_TYPES(X) \
+    X(q4_0, QK4_0, QI4_0, block_q4_0, VDR_Q4_0_Q8_1_MMVQ, vec_dot_q4_0_q8_1) \
+    X(q4_1, QK4_1, QI4_1, block_q4_1, VDR_Q4_1_Q8_1_MMVQ, vec_dot_q4_1_q8_1) \
+    X(q5_0, QK5_0, QI5_0, block_q5_0, VDR_Q5_0_Q8_1_MMVQ, vec_dot_q5_0_q8_1) \
+    X(q5_1, QK5_1, QI5_1, block_q5_1, VDR_Q5_1_Q8_1_MMVQ, vec_dot_q5_1_q8_1) \
+    X(q8_0, QK8_0, QI8_0, block_q8_0, VDR_Q8_0_Q8_1_MMVQ, vec_dot_q8_0_q8_1) \
+    X(q2_K, QK_K, QI2_K, block_q2_K, VDR_Q2_K_Q8_1_MMVQ, vec_dot_q2_K_q8_1) \
+    X(q3_K, QK_K, QI3_K, block_q3_K, VDR_Q3_K_Q8_1_MMVQ, vec_dot_q3_K_q8_1) \
+    X(q4_K, QK_K, QI4_K, block_q4_K, VDR_Q4_K_Q8_1_MMVQ, vec_dot_q4_K_q8_1) \
+    X(q5_K, QK_K, QI5_K, block_q5_K, VDR_Q5_K_Q8_1_MMVQ, vec_dot_q5_K_q8_1) \
+    X(q6_K, QK_K, QI6_K, block_q6_K, VDR_Q6_K_Q8_1_MMVQ, vec_dot_q6_K_q8_1)
+
+// Mixture of experts variant, dst holds one row of nrows_x values per (token, expert) pair and
+// expert_ids the expert of each of these rows, top_k consecutive rows per token. The grid has a
+// block row per output row starting at row_offset, a launch only computes the rows routed to
+// `expert` and the other blocks exit right away so that each expert is a single launch.
+template <int qk, int qi, typename block_q_t, int vdr, vec_dot_q_cuda_t vec_dot_q_cuda>
+static __device__ void mul_mat_vec_q_moe(
+    const void * __restrict__ vx, const void * __restrict__ vy, float * __restrict__ dst,
+    const int ncols_x, const int nrows_x, const int nrows_y,
+    const unsigned int * __restrict__ expert_ids, const unsigned int expert, const int top_k,
+    const int row_offset) {
+
+    const int i = row_offset + blockIdx.y;
+    if (expert_ids[i] != expert) {
+        return;
+    }
+    const block_q8_1 * y = (const block_q8_1 *) vy + (i / top_k) * (nrows_y / QK8_1);
+    mul_mat_vec_q<1, qk, qi, block_q_t, vdr, vec_dot_q_cuda>
+        (vx, y, dst + (size_t) i * nrows_x, ncols_x, nrows_x, nrows_y, nrows_x, nullptr, nrows_x);
+}
+
+#define MUL_MAT_VEC_Q_MOE(type, qk, qi, block_q_t, vdr, vec_dot_q_cuda) \
+extern "C" __global__ void mul_mat_vec_##type##_q8_1_cuda_moe( \
+    const void * vx, const void * vy, float * dst, \
+    const int ncols_x, const int nrows_x, const int nrows_y, \
+    const unsigned int * expert_ids, const unsigned int expert, const int top_k, \
+    const int row_offset) { \
+    mul_mat_vec_q_moe<qk, qi, block_q_t, vdr, vec_dot_q_cuda> \
+        (vx, vy, dst, ncols_x, nrows_x, nrows_y, expert_ids, expert, top_k, row_offset); \
+}
+
+MMVQ_TYPES(MUL_MAT_VEC_Q_MOE)
+
+// Batched variants multiplying the weights with ncols_y q8_1 activations in a single launch, e.g.
+// the candidate tokens of speculative decoding. The activations are stored back to back with
+// nrows_y values each and dst holds ncols_y rows of nrows_dst values. Each cuda block handles
+// two weight rows.
+#define MUL_MAT_VEC_Q_BATCHED_N(type, ncols_y, qk, qi, block_q_t, vdr, vec_dot_q_cuda) \
+extern "C" __global__ void mul_mat_vec_##type##_q8_1_cuda##ncols_y( \
+    const void * vx, const void * vy, float * dst, \
+    const int ncols_x, const int nrows_x, const int nrows_y, const int nrows_dst, \
+    const float * bias, const int valid_rows) { \
+    mul_mat_vec_q<ncols_y, qk, qi, block_q_t, vdr, vec_dot_q_cuda> \
+        (vx, vy, dst, ncols_x, nrows_x, nrows_y, nrows_dst, bias, valid_rows); \
 }
 
-extern "C" __global__ void quantize_q8_1(const float * __restrict__ x, void * __restrict__ vy, const int kx, const int kx_padded) {
+// The batched variants of a dtype for 2 to 8 activations, see MMVQ_MAX_NCOLS_Y on the rust side.
+#define MUL_MAT_VEC_Q_BATCHED(type, ...) \
+    MUL_MAT_VEC_Q_BATCHED_N(type, 2, __VA_ARGS__) \
+    MUL_MAT_VEC_Q_BATCHED_N(type, 3, __VA_ARGS__) \
+    MUL_MAT_VEC_Q_BATCHED_N(type, 4, __VA_ARGS__) \
+    MUL_MAT_VEC_Q_BATCHED_N(type, 5, __VA_ARGS__) \
+    MUL_MAT_VEC_Q_BATCHED_N(type, 6, __VA_ARGS__) \
+    MUL_MAT_VEC_Q_BATCHED_N(type, 7, __VA_ARGS__) \
+    MUL_MAT_VEC_Q_BATCHED_N(type, 8, __VA_ARGS__)
+
+MMVQ_TYPES(MUL_MAT_VEC_Q_BATCHED)
+
+static __device__ __forceinline__ uint64_t splitmix64(uint64_t x) {
+    x += 0x9e3779b97f4a7c15ull;
+    x = (x ^ (x >> 30)) * 0xbf58476d1ce4e5b9ull;
+    x = (x ^ (x >> 27)) * 0x94d049bb133111ebull;
+    return x ^ (x >> 31);
+}
+
+// rounding: 0 rounds to nearest, 1 rounds toward zero and 2 rounds stochastically, the random
+// offsets being derived from seed and the value index so that the result is reproducible.
+// When int_fast_path is set, the blocks whose values are all integers in [-127, 127] are stored
+// as is with a scale of 1, skipping the max reduction. This is also exact whereas the regular
+// path rescales the values to the full int8 range.
+// The grid y dimension covers the rows from row_offset on, the rows beyond the grid y limit are
+// quantized by further launches.
+extern "C" __global__ void quantize_q8_1(const float * __restrict__ x, void * __restrict__ vy, const int kx, const int kx_padded, const int rounding, const uint64_t seed, const int int_fast_path, const int row_offset) {
     const int ix = blockDim.x*blockIdx.x + threadIdx.x;
 
     if (ix >= kx_padded) {
         return;
     }
 
-    const int iy = blockDim.y*blockIdx.y + threadIdx.y;
+    const long long iy = row_offset + blockDim.y*blockIdx.y + threadIdx.y;
 
-    const int i_padded = iy*kx_padded + ix;
+    const long long i_padded = iy*kx_padded + ix;
 
     block_q8_1 * y = (block_q8_1 *) vy;
 
-    const int ib = i_padded / QK8_1; // block index
+    const long long ib = i_padded / QK8_1; // block index
     const int iqs = i_padded % QK8_1; // quant index
 
     const float xi = ix < kx ? x[iy*kx + ix] : 0.0f;
+
+    // A warp covers exactly one q8_1 block.
+    if (int_fast_path && __all_sync(0xffffffff, xi == rintf(xi) && fabsf(xi) <= 127.0f)) {
+        const float sum = warp_reduce_sum(xi);
+        y[ib].qs[iqs] = (int8_t) xi;
+        if (iqs == 0) {
+            reinterpret_cast<half&>(y[ib].ds.x) = 1.0f;
+            reinterpret_cast<half&>(y[ib].ds.y) = sum;
+        }
+        return;
+    }
+
     float amax = fabsf(xi);
     float sum = xi;
 
@@ -2679,7 +3581,18 @@ extern "C" __global__ void quantize_q8_1(const float * __restrict__ x, void * __
     sum = warp_reduce_sum(sum);
 
     const float d = amax / 127;
-    const int8_t q = amax == 0.0f ? 0 : roundf(xi / d);
+    const float v = amax == 0.0f ? 0.0f : xi / d;
+    float r;
+    if (rounding == 1) {
+        r = truncf(v);
+    } else if (rounding == 2) {
+        // 24 random bits give a uniform offset in [0, 1).
+        const float u = (splitmix64(seed ^ (uint64_t) i_padded) >> 40) * (1.0f / 16777216.0f);
+        r = floorf(v + u);
+    } else {
+        r = roundf(v);
+    }
+    const int8_t q = r;
 
     y[ib].qs[iqs] = q;
 
@@ -2691,6 +3604,300 @@ extern "C" __global__ void quantize_q8_1(const float * __restrict__ x, void * __
     reinterpret_cast<half&>(y[ib].ds.y) = sum;
 }
 
+// Converts q8_0 blocks to q8_1, one warp per block. The q8_1 sum is recomputed from the quants.
+extern "C" __global__ void q8_0_to_q8_1(const void * __restrict__ vx, void * __restrict__ vy, const int nblocks) {
+    const int ib = blockIdx.x;
+    const int iqs = threadIdx.x;
+    if (ib >= nblocks) {
+        return;
+    }
+    const block_q8_0 * x = (const block_q8_0 *) vx;
+    block_q8_1 * y = (block_q8_1 *) vy;
+    const int q = x[ib].qs[iqs];
+    y[ib].qs[iqs] = q;
+    const float sum = warp_reduce_sum((float) q);
+    if (iqs == 0) {
+        const float d = __half2float(x[ib].d);
+        reinterpret_cast<half&>(y[ib].ds.x) = x[ib].d;
+        reinterpret_cast<half&>(y[ib].ds.y) = __float2half(d * sum);
+    }
+}
+
+// Multiplies the count half values at byte offset of each of the nblocks blocks of type_size bytes
+// by factor, this rescales quantized weights without touching their quants.
+extern "C" __global__ void scale_half_fields(
+    uint8_t * __restrict__ vx, const int nblocks, const int type_size, const int offset,
+    const int count, const float factor) {
+    const int ib = blockDim.x*blockIdx.x + threadIdx.x;
+    if (ib >= nblocks) {
+        return;
+    }
+    half * d = (half *) (vx + (size_t) ib*type_size + offset);
+    for (int i = 0; i < count; ++i) {
+        d[i] = __float2half(__half2float(d[i]) * factor);
+    }
+}
+
+// Overwrites the exception rows of dequantized weights with their f16 values. values holds
+// num_rows rows of ncols values, the i-th one replacing row rows[i] of the weights. y holds the
+// weights from row first_row on and only its first k values are written.
+template<typename dst_t>
+static __device__ void substitute_exception_rows(
+    const half * __restrict__ values, const unsigned int * __restrict__ rows, const int num_rows,
+    const int ncols, dst_t * __restrict__ y, const int first_row, const int k) {
+    const int i = blockDim.x*blockIdx.x + threadIdx.x;
+    if (i >= num_rows*ncols) {
+        return;
+    }
+    const int row = i / ncols;
+    const int col = i % ncols;
+    if (rows[row] < (unsigned int) first_row) {
+        return;
+    }
+    const long long dst = (long long) (rows[row] - first_row)*ncols + col;
+    if (dst < k) {
+        y[dst] = (dst_t) __half2float(values[i]);
+    }
+}
+
+extern "C" __global__ void substitute_exception_rows_f32(
+    const half * __restrict__ values, const unsigned int * __restrict__ rows, const int num_rows,
+    const int ncols, float * __restrict__ y, const int first_row, const int k) {
+    substitute_exception_rows<float>(values, rows, num_rows, ncols, y, first_row, k);
+}
+
+extern "C" __global__ void substitute_exception_rows_f16(
+    const half * __restrict__ values, const unsigned int * __restrict__ rows, const int num_rows,
+    const int ncols, half * __restrict__ y, const int first_row, const int k) {
+    substitute_exception_rows<half>(values, rows, num_rows, ncols, y, first_row, k);
+}
+
+// Recomputes the outputs of the exception rows of a matmul: dst is a row major (ny, nrows) matrix
+// holding x @ w.t() for the ny contiguous rows of x, the column rows[i] is replaced by the dot
+// products with the i-th row of values. A warp handles one exception row for one row of x.
+extern "C" __global__ void exception_rows_matmul(
+    const half * __restrict__ values, const unsigned int * __restrict__ rows, const int ncols,
+    const float * __restrict__ x, float * __restrict__ dst, const int nrows) {
+    const int iy = blockIdx.x;
+    const int row = blockIdx.y;
+    const half * w = values + (long long) row*ncols;
+    const float * xy = x + (long long) iy*ncols;
+    float sum = 0.0f;
+    for (int col = threadIdx.x; col < ncols; col += WARP_SIZE) {
+        sum += __half2float(w[col]) * xy[col];
+    }
+    sum = warp_reduce_sum(sum);
+    if (threadIdx.x == 0) {
+        dst[(long long) iy*nrows + rows[row]] = sum;
+    }
+}
+
+// Symmetric int4 weights with a half scale per group of group_size values, k values in total. The
+// quants are packed two per byte with the first value in the low nibble.
+extern "C" __global__ void dequantize_int4_grouped(
+    const uint8_t * __restrict__ qweight, const half * __restrict__ scales, float * __restrict__ dst,
+    const int group_size, const int k) {
+    const int i = blockDim.x*blockIdx.x + threadIdx.x;
+    if (2*i >= k) {
+        return;
+    }
+    const uint8_t q = qweight[i];
+    // group_size is even so both values of a byte share the same scale.
+    const float d = __half2float(scales[2*i / group_size]);
+    dst[2*i + 0] = ((int)(q & 0xF) - 8) * d;
+    dst[2*i + 1] = ((int)(q >> 4) - 8) * d;
+}
+
+// Hashes each 8 bytes word with its index and sums the hashes into dst, which has to be zero
+// initialized. The sum does not depend on the order in which the blocks run.
+extern "C" __global__ void checksum_u8(const uint8_t * __restrict__ x, unsigned long long * __restrict__ dst, const size_t n) {
+    const size_t i = (size_t) blockDim.x*blockIdx.x + threadIdx.x;
+    unsigned long long h = 0;
+    if (8*i < n) {
+        uint64_t w = 0;
+        for (size_t j = 0; j < 8 && 8*i + j < n; ++j) {
+            w |= (uint64_t) x[8*i + j] << (8*j);
+        }
+        h = splitmix64(w ^ splitmix64(i));
+    }
+#pragma unroll
+    for (int mask = 16; mask > 0; mask >>= 1) {
+        h += __shfl_xor_sync(0xffffffff, h, mask, 32);
+    }
+    if (threadIdx.x % WARP_SIZE == 0) {
+        atomicAdd(dst, h);
+    }
+}
+
+// Max of the absolute values of x written to dst, which has to be zero initialized. Non-negative
+// floats have the same ordering as their bit patterns so an integer atomicMax is enough.
+extern "C" __global__ void max_abs_f32(const float * __restrict__ x, float * __restrict__ dst, const int k) {
+    const int i = blockDim.x*blockIdx.x + threadIdx.x;
+    float amax = i < k ? fabsf(x[i]) : 0.0f;
+    amax = warp_reduce_max(amax);
+    if (threadIdx.x % WARP_SIZE == 0) {
+        atomicMax((int *) dst, __float_as_int(amax));
+    }
+}
+
+// Quantizes kx values to q8_0, kx has to be a multiple of QK8_0 which is also the warp size so
+// each warp computes the scale of one block.
+extern "C" __global__ void quantize_q8_0(const float * __restrict__ x, void * __restrict__ vy, const int kx) {
+    const int ix = blockDim.x*blockIdx.x + threadIdx.x;
+
+    if (ix >= kx) {
+        return;
+    }
+
+    block_q8_0 * y = (block_q8_0 *) vy;
+
+    const int ib = ix / QK8_0; // block index
+    const int iqs = ix % QK8_0; // quant index
+
+    const float xi = x[ix];
+    float amax = fabsf(xi);
+
+    amax = warp_reduce_max(amax);
+
+    const float d = amax / 127;
+    const int8_t q = amax == 0.0f ? 0 : roundf(xi / d);
+
+    y[ib].qs[iqs] = q;
+
+    if (iqs > 0) {
+        return;
+    }
+
+    y[ib].d = __float2half(d);
+}
+
+// Experimental int4 activations: 32 values quantized symmetrically to [-7, 7] and stored with an
+// offset of 8 using the q4_0 nibble layout, i.e. value i < 16 in the low nibble of qs[i] and value
+// i >= 16 in the high nibble of qs[i - 16].
+#define QK4_ACT 32
+typedef struct {
+    half    d;              // delta
+    uint8_t qs[QK4_ACT / 2]; // nibbles
+} block_q4_act;
+static_assert(sizeof(block_q4_act) == sizeof(ggml_fp16_t) + QK4_ACT / 2, "wrong q4_act block size/padding");
+
+// Each block of QK4_ACT values gets its own scale. The rows of a matrix are a multiple of QK4_ACT
+// long so the scales never span two rows and each row is quantized independently of the others.
+extern "C" __global__ void quantize_q4_act(const float * __restrict__ x, void * __restrict__ vy, const int kx) {
+    const int ix = blockDim.x*blockIdx.x + threadIdx.x;
+
+    // kx is a multiple of QK4_ACT so whole warps exit together.
+    if (ix >= kx) {
+        return;
+    }
+
+    block_q4_act * y = (block_q4_act *) vy;
+
+    const int ib = ix / QK4_ACT; // block index
+    const int iqs = ix % QK4_ACT; // quant index
+
+    const float xi = x[ix];
+    float amax = fabsf(xi);
+    amax = warp_reduce_max(amax);
+
+    const float d = amax / 7;
+    const int q = amax == 0.0f ? 8 : (int)roundf(xi / d) + 8;
+    // Fetch the quant that shares the same byte, lanes 16 to 31 only provide their value.
+    const int q_hi = __shfl_down_sync(0xffffffff, q, 16, 32);
+
+    if (iqs < QK4_ACT / 2) {
+        y[ib].qs[iqs] = (uint8_t)(q | (q_hi << 4));
+    }
+    if (iqs == 0) {
+        y[ib].d = __float2half(d);
+    }
+}
+
+// One warp per row of the q4_0 weights and activation row, the activations have been quantized
+// with quantize_q4_act. The grid is one dimensional with nrows consecutive blocks per activation
+// row, so that long prefills are not bound by the grid y limit. The activation rows are stored
+// contiguously, as are the matching rows of dst.
+extern "C" __global__ void mul_mat_vec_q4_0_q4_act_cuda(
+    const void * __restrict__ vx, const void * __restrict__ vy, float * __restrict__ dst,
+    const int ncols, const int nrows) {
+    const int row = blockIdx.x % nrows;
+    const int iy = blockIdx.x / nrows;
+    const int blocks_per_row = ncols / QK4_0;
+    const block_q4_0 * x = (const block_q4_0 *) vx + (size_t) row*blocks_per_row;
+    const block_q4_act * y = (const block_q4_act *) vy + (size_t) iy*blocks_per_row;
+    dst += (size_t) iy*nrows;
+
+    float tmp = 0.0f;
+    for (int ib = threadIdx.x; ib < blocks_per_row; ib += WARP_SIZE) {
+        int sumi = 0;
+#pragma unroll
+        for (int j = 0; j < QK4_0 / 2; ++j) {
+            const int xq = x[ib].qs[j];
+            const int yq = y[ib].qs[j];
+            sumi += ((xq & 0xF) - 8) * ((yq & 0xF) - 8) + ((xq >> 4) - 8) * ((yq >> 4) - 8);
+        }
+        tmp += sumi * __half2float(x[ib].d) * __half2float(y[ib].d);
+    }
+    tmp = warp_reduce_sum(tmp);
+    if (threadIdx.x == 0) {
+        dst[row] = tmp;
+    }
+}
+
+// Reorders q4_0 rows to the embedding layout used by gather_rows_q4_0, one thread per block.
+extern "C" __global__ void to_embedding_layout_q4_0(
+    const void * __restrict__ vx, uint8_t * __restrict__ dst, const int blocks_per_row,
+    const int nblocks) {
+    const int ib = blockIdx.x*blockDim.x + threadIdx.x;
+    if (ib >= nblocks) {
+        return;
+    }
+    const block_q4_0 * b = (const block_q4_0 *) vx + ib;
+    const int row = ib / blocks_per_row;
+    const int j = ib % blocks_per_row;
+    uint8_t * y = dst + (size_t) row*blocks_per_row*sizeof(block_q4_0);
+    ((half *) y)[j] = b->d;
+    uint8_t * qs = y + blocks_per_row*sizeof(half) + j*(QK4_0/2);
+#pragma unroll
+    for (int i = 0; i < QK4_0/2; ++i) {
+        qs[i] = b->qs[i];
+    }
+}
+
+// One block per gathered row. With the embedding layout each row stores all its half scales first
+// followed by the QK4_0/2 bytes of quants of each block. The rows of out of range ids are zeros.
+extern "C" __global__ void gather_rows_q4_0(
+    const void * __restrict__ vx, const uint32_t * __restrict__ ids, float * __restrict__ dst,
+    const int ncols, const int nrows, const int embedding_layout) {
+    const uint32_t row = ids[blockIdx.x];
+    float * y = dst + (size_t) blockIdx.x*ncols;
+    if (row >= (uint32_t) nrows) {
+        for (int i = threadIdx.x; i < ncols; i += blockDim.x) {
+            y[i] = 0.0f;
+        }
+        return;
+    }
+    const int blocks_per_row = ncols / QK4_0;
+    const uint8_t * x = (const uint8_t *) vx + (size_t) row*blocks_per_row*sizeof(block_q4_0);
+
+    for (int i = threadIdx.x; i < ncols; i += blockDim.x) {
+        const int ib = i / QK4_0;
+        const int iqs = i % QK4_0;
+        const half * d;
+        const uint8_t * qs;
+        if (embedding_layout) {
+            d = (const half *) x + ib;
+            qs = x + blocks_per_row*sizeof(half) + ib*(QK4_0/2);
+        } else {
+            const block_q4_0 * b = (const block_q4_0 *) x + ib;
+            d = &b->d;
+            qs = b->qs;
+        }
+        const int q = iqs < QK4_0/2 ? qs[iqs] & 0xF : qs[iqs - QK4_0/2] >> 4;
+        y[i] = (q - 8) * __half2float(*d);
+    }
+}
+
 // Kernels from https://github.com/ggerganov/llama.cpp/blob/master/ggml-cuda/mmq.cu
 
 template <int mmq_y> static __device__ __forceinline__ void allocate_tiles_q5_0(int ** x_ql, half2 ** x_dm, int ** x_qh, int ** x_sc) {